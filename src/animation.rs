@@ -0,0 +1,129 @@
+use crate::{Quaternion, Vec3};
+
+// Keyframe tracks: a value given at a few moments, and filled in between them. Bezier segments
+// are cubic curves through each pair of keyframes, pulled towards the handles set on them, and
+// are worked out by de Casteljau's construction, so anything that can be linearly interpolated
+// can follow one. Rotations interpolate along the sphere. Times are in seconds.
+
+/// How a track fills in the time between two keyframes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interpolation {
+    Step, // Holds each keyframe's value until the next.
+    Linear,
+    Bezier,
+}
+
+/// A value a track passes through, with the control points of the curve on either side of it
+/// for Bezier tracks.
+#[derive(Clone)]
+pub struct Keyframe<T> {
+    pub time: f64,
+    pub value: T,
+    pub in_handle: T,
+    pub out_handle: T,
+}
+
+/// A value that changes over time. Before the first keyframe and after the last it holds still.
+#[derive(Clone)]
+pub struct Track<T> {
+    pub keyframes: Vec<Keyframe<T>>, // Sorted by time, and never empty.
+    pub interpolation: Interpolation,
+}
+
+/// Values that tracks can move between.
+pub trait Interpolate: Clone {
+    fn lerp(&self, other: &Self, t: f64) -> Self;
+}
+
+impl Interpolate for f64 {
+    fn lerp(&self, other: &f64, t: f64) -> f64 {
+        self * (1.0 - t) + other * t
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn lerp(&self, other: &Vec3, t: f64) -> Vec3 {
+        self.mul(1.0 - t) + other.mul(t)
+    }
+}
+
+impl Interpolate for Quaternion {
+    fn lerp(&self, other: &Quaternion, t: f64) -> Quaternion {
+        self.slerp(other, t)
+    }
+}
+
+impl<T: Clone> Keyframe<T> {
+    /// A keyframe whose handles sit on its value, which eases a Bezier curve in and out of it.
+    pub fn new(time: f64, value: T) -> Keyframe<T> {
+        Keyframe {
+            time,
+            in_handle: value.clone(),
+            out_handle: value.clone(),
+            value,
+        }
+    }
+}
+
+impl<T: Interpolate> Track<T> {
+    /// A track through the keyframes, in whatever order they are given. Needs at least one.
+    pub fn new(interpolation: Interpolation, mut keyframes: Vec<Keyframe<T>>) -> Result<Track<T>, String> {
+        if keyframes.is_empty() {
+            return Err(String::from("a track needs at least one keyframe"));
+        }
+        if keyframes.iter().any(|k| !k.time.is_finite()) {
+            return Err(String::from("keyframe times must be finite"));
+        }
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal));
+        Ok(Track {
+            keyframes,
+            interpolation,
+        })
+    }
+
+    /// A track that never changes.
+    pub fn constant(value: T) -> Track<T> {
+        Track {
+            keyframes: vec![Keyframe::new(0.0, value)],
+            interpolation: Interpolation::Step,
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.keyframes.len() > 1
+    }
+
+    /// When the track starts and stops changing.
+    pub fn start(&self) -> f64 {
+        self.keyframes[0].time
+    }
+
+    pub fn end(&self) -> f64 {
+        self.keyframes[self.keyframes.len() - 1].time
+    }
+
+    pub fn at(&self, time: f64) -> T {
+        let keys = &self.keyframes;
+        let last = keys.len() - 1;
+        if time <= keys[0].time {
+            return keys[0].value.clone();
+        }
+        if time >= keys[last].time {
+            return keys[last].value.clone();
+        }
+
+        let next = keys.iter().position(|k| k.time > time).unwrap_or(last);
+        let (a, b) = (&keys[next - 1], &keys[next]);
+        let t = (time - a.time) / (b.time - a.time);
+        match self.interpolation {
+            Interpolation::Step => a.value.clone(),
+            Interpolation::Linear => a.value.lerp(&b.value, t),
+            Interpolation::Bezier => {
+                let p01 = a.value.lerp(&a.out_handle, t);
+                let p12 = a.out_handle.lerp(&b.in_handle, t);
+                let p23 = b.in_handle.lerp(&b.value, t);
+                p01.lerp(&p12, t).lerp(&p12.lerp(&p23, t), t)
+            }
+        }
+    }
+}
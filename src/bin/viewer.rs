@@ -0,0 +1,68 @@
+use rust_tracer::*;
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::video::Window;
+use std::mem::size_of;
+use std::rc::Rc;
+
+// use sdl2::gfx::primitives::DrawRenderer;
+
+fn main() -> Result<(), String>{
+    let sdl_context = sdl2::init()?;
+    let w = 800;
+    let res = (w, w * 9 / 16);
+
+    let settings = RenderSettings::default();
+
+    let scene = Scene::new(Rc::new(default_world()));
+    let camera = Camera::new(res.0, 16.0 / 9.0, 2.0, 1.0, unit_vector(0.0));
+    let arr = paint_pixels(&camera, &scene, &settings);
+
+    let window = init_window(&sdl_context, res.0, 16.0 / 9.0)?;
+
+    let mut canvas = window.into_canvas().accelerated().build().map_err(|e| e.to_string())?;
+
+    let mut event_pump = sdl_context.event_pump()?;
+
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator
+        .create_texture_streaming(None, camera.width, camera.height)
+        .map_err(|e| e.to_string())?;
+
+    texture
+        .update(None, arr.as_slice(), (camera.width * 4 * (size_of::<u8>() as u32)) as usize)
+        .map_err(|e| e.to_string())?;
+    canvas.copy(&texture, None, None)?;
+
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => break 'running,
+                _ => {
+                    canvas.present();
+                }
+            }
+
+        }
+    }
+
+    Ok(())
+}
+
+fn init_window(context: &sdl2::Sdl, width: u32, aspect_ratio: f64) -> Result<Window, String> {
+    let video_subsystem = context.video()?;
+
+    let window = video_subsystem
+        .window("SDL Window", width, (width as f64/ aspect_ratio) as u32)
+        .position_centered()
+        .opengl()
+        .build()
+        .map_err(|e| e.to_string());
+
+    window.map_err(|e| e.to_string())
+}
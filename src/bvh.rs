@@ -0,0 +1,206 @@
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use crate::math::INFINITY;
+use crate::{CsgOperation, HitRecord, Hittable, HittableList, Ray, Span, Vec3, combine_spans};
+
+/// An axis-aligned bounding box.
+#[derive(Clone, Default)]
+pub struct Aabb {
+    pub minimum: Vec3,
+    pub maximum: Vec3,
+}
+
+/// A node of a bounding volume hierarchy. Each node holds two children, which are either further
+/// nodes or the objects themselves.
+pub struct BvhNode {
+    pub left: Rc<dyn Hittable>,
+    pub right: Rc<dyn Hittable>,
+    pub bbox: Aabb,
+}
+
+impl Aabb {
+    pub fn new(minimum: Vec3, maximum: Vec3) -> Aabb {
+        Aabb {
+            minimum,
+            maximum,
+        }
+    }
+
+    /// A box covering all of space, used for objects that cannot be bounded.
+    pub fn infinite() -> Aabb {
+        Aabb::new(Vec3::from(-f64::INFINITY), Vec3::from(f64::INFINITY))
+    }
+
+    /// Returns true if the ray enters the box anywhere between `t_min` and `t_max`.
+    pub fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> bool {
+        self.intersect(ray, t_min, t_max).is_some()
+    }
+
+    /// The part of the ray between `t_min` and `t_max` that lies inside the box, if any.
+    pub fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+        for a in 0..3 {
+            let inv_d = 1.0 / ray.direction[a];
+            let mut t0 = (self.minimum[a] - ray.origin[a]) * inv_d;
+            let mut t1 = (self.maximum[a] - ray.origin[a]) * inv_d;
+            if inv_d < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            t_min = if t0 > t_min { t0 } else { t_min };
+            t_max = if t1 < t_max { t1 } else { t_max };
+            // Only an interval running backwards misses: a ray through a box with no thickness
+            // along one axis, like one around a flat triangle, enters and leaves it at once.
+            if t_max < t_min {
+                return None;
+            }
+        }
+        Some((t_min, t_max))
+    }
+
+    /// Grows the box to also contain the given point.
+    pub fn expand(&self, p: &Vec3) -> Aabb {
+        Aabb::new(self.minimum.min(p), self.maximum.max(p))
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.minimum.clone() + self.maximum.clone()).mul(0.5)
+    }
+
+    /// The eight corners of the box.
+    pub fn corners(&self) -> Vec<Vec3> {
+        let mut corners = Vec::with_capacity(8);
+        for i in 0..8 {
+            corners.push(Vec3::new(
+                if i & 1 == 0 { self.minimum.x } else { self.maximum.x },
+                if i & 2 == 0 { self.minimum.y } else { self.maximum.y },
+                if i & 4 == 0 { self.minimum.z } else { self.maximum.z },
+            ));
+        }
+        corners
+    }
+}
+
+/// Returns the smallest box containing both boxes.
+pub fn surrounding_box(box0: &Aabb, box1: &Aabb) -> Aabb {
+    Aabb::new(box0.minimum.min(&box1.minimum), box0.maximum.max(&box1.maximum))
+}
+
+impl BvhNode {
+    /// Builds a hierarchy over the objects in a list. The boxes cover the objects' motion between
+    /// `time0` and `time1`, which should match the camera's shutter interval.
+    pub fn new(list: &HittableList, time0: f64, time1: f64) -> BvhNode {
+        let mut objects = list.object_list.clone();
+        BvhNode::from_objects(&mut objects, time0, time1)
+    }
+
+    pub fn from_objects(objects: &mut [Rc<dyn Hittable>], time0: f64, time1: f64) -> BvhNode {
+        let boxes: Vec<Aabb> = objects.iter().map(|o| object_box(o.as_ref(), time0, time1)).collect();
+
+        // Split along the axis where the object centres are spread the furthest.
+        let mut centroids = Aabb::new(Vec3::from(f64::INFINITY), Vec3::from(-f64::INFINITY));
+        for b in &boxes {
+            centroids = centroids.expand(&b.centroid());
+        }
+        let extent = centroids.maximum.clone() - centroids.minimum.clone();
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+
+        let (left, right): (Rc<dyn Hittable>, Rc<dyn Hittable>) = match objects.len() {
+            0 => (Rc::new(HittableList::new()), Rc::new(HittableList::new())),
+            1 => (objects[0].clone(), objects[0].clone()),
+            2 => (objects[0].clone(), objects[1].clone()),
+            _ => {
+                let mut keyed: Vec<(f64, Rc<dyn Hittable>)> = boxes.iter()
+                    .map(|b| b.centroid()[axis])
+                    .zip(objects.iter().cloned())
+                    .collect();
+                keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+                for (slot, (_, object)) in objects.iter_mut().zip(keyed) {
+                    *slot = object;
+                }
+
+                let mid = objects.len() / 2;
+                let (lower, upper) = objects.split_at_mut(mid);
+                (Rc::new(BvhNode::from_objects(lower, time0, time1)),
+                 Rc::new(BvhNode::from_objects(upper, time0, time1)))
+            }
+        };
+
+        let bbox = surrounding_box(
+            &object_box(left.as_ref(), time0, time1),
+            &object_box(right.as_ref(), time0, time1),
+        );
+
+        BvhNode {
+            left,
+            right,
+            bbox,
+        }
+    }
+}
+
+/// The bounding box of an object, or an infinite one if it cannot be bounded.
+fn object_box(object: &dyn Hittable, time0: f64, time1: f64) -> Aabb {
+    let mut bbox = Aabb::default();
+    if object.bounding_box(time0, time1, &mut bbox) {
+        bbox
+    } else {
+        Aabb::infinite()
+    }
+}
+
+impl Hittable for BvhNode {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        if !self.bbox.hit(ray, t_min, t_max) {
+            return false;
+        }
+
+        let hit_left = self.left.hit(ray, t_min, t_max, rec);
+        let hit_right = self.right.hit(ray, t_min, if hit_left { rec.t } else { t_max }, rec);
+
+        hit_left || hit_right
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
+        *output_box = self.bbox.clone();
+        true
+    }
+
+    fn is_solid(&self) -> bool {
+        self.left.is_solid() && self.right.is_solid()
+    }
+
+    fn spans(&self, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+        if !self.bbox.hit(ray, -INFINITY, INFINITY) {
+            return true;
+        }
+
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        if !self.left.spans(ray, &mut left) || !self.right.spans(ray, &mut right) {
+            return false;
+        }
+        spans.extend(combine_spans(&left, &right, CsgOperation::Union));
+        true
+    }
+
+    fn transmittance(&self, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        if !self.bbox.hit(ray, t_min, t_max) {
+            return 1.0;
+        }
+
+        // A lone object fills both sides, so don't count it twice.
+        let left = self.left.transmittance(ray, t_min, t_max);
+        if left <= 0.0 || Rc::ptr_eq(&self.left, &self.right) {
+            return left;
+        }
+        left * self.right.transmittance(ray, t_min, t_max)
+    }
+}
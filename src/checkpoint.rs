@@ -0,0 +1,257 @@
+use crate::film::Reader;
+use crate::{Film, Image, PixelBounds, Vec3, luminance};
+
+// Everything a render has gathered so far, which is enough to carry on from where it left off.
+// Renders take their samples in passes over the image, and every sample seeds the random numbers
+// it uses from the render's seed, its pixel and how many samples that pixel has already taken.
+// Nothing else about the random numbers needs saving, and a render that is stopped after a pass,
+// saved and picked up again adds up exactly the same samples in the same order as one that never
+// stopped.
+
+/// A render in progress.
+pub struct Progress {
+    pub film: Film,
+    pub bounds: PixelBounds, // The pixels being sampled, which the film may reach beyond.
+    pub seed: u64,
+    pub passes: u32, // How many passes over the image have been made.
+    pub pixels: Vec<PixelStats>, // One for each pixel in the bounds, top row first.
+}
+
+/// The samples taken so far in a pixel, with the running mean and variance of their luminance
+/// kept by Welford's method.
+#[derive(Clone, Default)]
+pub struct PixelStats {
+    pub count: u32,
+    pub mean: f64,
+    pub squares: f64, // The sum of squared differences from the mean.
+    pub albedo: Vec3, // Summed over the samples, like the normal.
+    pub normal: Vec3,
+}
+
+// Keeps the relative error of dark pixels from blowing up, as noise there is hard to see anyway.
+const ERROR_FLOOR: f64 = 0.1;
+
+const MAGIC: &[u8; 4] = b"CKPT";
+const VERSION: u32 = 2;
+// The bytes each pixel's statistics take in a checkpoint.
+const STATS_SIZE: usize = 4 + 8 * 8;
+
+impl PixelStats {
+    pub fn add(&mut self, colour: &Vec3, albedo: Vec3, normal: Vec3) {
+        self.count += 1;
+        let l = luminance(colour);
+        let delta = l - self.mean;
+        self.mean += delta / self.count as f64;
+        self.squares += delta * (l - self.mean);
+        self.albedo = self.albedo.clone() + albedo;
+        self.normal = self.normal.clone() + normal;
+    }
+
+    /// The variance of the pixel's average, which falls as samples are added.
+    pub fn variance(&self) -> f64 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        self.squares / (self.count - 1) as f64 / self.count as f64
+    }
+
+    /// The standard error of the pixel relative to its brightness.
+    pub fn error(&self) -> f64 {
+        self.variance().sqrt() / (self.mean.max(0.0) + ERROR_FLOOR)
+    }
+}
+
+impl Progress {
+    /// A render of the pixels of a film that hasn't taken any samples yet.
+    pub fn new(film: Film, seed: u64) -> Progress {
+        let bounds = film.window;
+        Progress::with_bounds(film, bounds, seed)
+    }
+
+    /// A render of one tile of a film, whose samples are gathered on a film of their own to be
+    /// merged into it.
+    pub fn tile(film: &Film, tile: &PixelBounds, seed: u64) -> Progress {
+        Progress::with_bounds(film.tile(tile), tile.intersect(&film.window), seed)
+    }
+
+    fn with_bounds(film: Film, bounds: PixelBounds, seed: u64) -> Progress {
+        Progress {
+            film,
+            bounds,
+            seed,
+            passes: 0,
+            pixels: vec![PixelStats::default(); bounds.area()],
+        }
+    }
+
+    /// Adds a finished tile's samples and statistics into this render.
+    pub fn merge(&mut self, tile: &Progress) -> Result<(), String> {
+        self.film.merge(&tile.film)?;
+        let overlap = self.bounds.intersect(&tile.bounds);
+        for y in overlap.y0..overlap.y1 {
+            for x in overlap.x0..overlap.x1 {
+                let to = ((y - self.bounds.y0) * self.bounds.width() + x - self.bounds.x0) as usize;
+                let from = ((y - tile.bounds.y0) * tile.bounds.width() + x - tile.bounds.x0) as usize;
+                self.pixels[to] = tile.pixels[from].clone();
+            }
+        }
+        self.passes = self.passes.max(tile.passes);
+        Ok(())
+    }
+
+    /// The image as it stands.
+    pub fn image(&self) -> Image {
+        let size = self.pixels.len();
+        let mut image = Image {
+            width: self.bounds.width(),
+            height: self.bounds.height(),
+            colour: self.film.pixels_in(&self.bounds),
+            variance: Vec::with_capacity(size),
+            albedo: Vec::with_capacity(size),
+            normal: Vec::with_capacity(size),
+            samples: Vec::with_capacity(size),
+        };
+        for stats in &self.pixels {
+            let samples = stats.count.max(1) as f64;
+            image.variance.push(stats.variance());
+            image.albedo.push(stats.albedo.div(samples));
+            image.normal.push(if stats.normal.near_zero() { Vec3::default() } else { stats.normal.unit_vector() });
+            image.samples.push(stats.count);
+        }
+        image
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(MAGIC);
+        out.extend(VERSION.to_le_bytes());
+        out.extend(self.seed.to_le_bytes());
+        out.extend(self.passes.to_le_bytes());
+        let b = &self.bounds;
+        for v in [b.x0, b.y0, b.x1, b.y1] {
+            out.extend(v.to_le_bytes());
+        }
+        out.extend(self.film.encode());
+        for stats in &self.pixels {
+            out.extend(stats.count.to_le_bytes());
+            let (a, n) = (&stats.albedo, &stats.normal);
+            for v in [stats.mean, stats.squares, a.x, a.y, a.z, n.x, n.y, n.z] {
+                out.extend(v.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Progress, String> {
+        let mut reader = Reader::new(bytes);
+        if reader.take(4)? != MAGIC {
+            return Err(String::from("not a checkpoint"));
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(format!("checkpoint version {} isn't supported", version));
+        }
+        let seed = reader.u64()?;
+        let passes = reader.u32()?;
+        let bounds = PixelBounds::new(reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?);
+        let film = Film::read(&mut reader)?;
+        if bounds.intersect(&film.window) != bounds {
+            return Err(String::from("the checkpoint's pixels are outside its film"));
+        }
+        reader.expect(bounds.area(), STATS_SIZE)?;
+        let mut progress = Progress::with_bounds(film, bounds, seed);
+        progress.passes = passes;
+        for stats in progress.pixels.iter_mut() {
+            stats.count = reader.u32()?;
+            stats.mean = reader.f64()?;
+            stats.squares = reader.f64()?;
+            stats.albedo = Vec3::new(reader.f64()?, reader.f64()?, reader.f64()?);
+            stats.normal = Vec3::new(reader.f64()?, reader.f64()?, reader.f64()?);
+        }
+        Ok(progress)
+    }
+}
+
+/// Saves a render's progress. The checkpoint is written beside the file and then moved over it,
+/// so a crash while writing leaves the last one whole.
+pub fn write_checkpoint(path: &str, progress: &Progress) -> Result<(), String> {
+    let temporary = format!("{}.partial", path);
+    std::fs::write(&temporary, progress.encode()).map_err(|e| format!("couldn't write checkpoint {}: {}", path, e))?;
+    std::fs::rename(&temporary, path).map_err(|e| format!("couldn't write checkpoint {}: {}", path, e))
+}
+
+pub fn read_checkpoint(path: &str) -> Result<Progress, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("couldn't read checkpoint {}: {}", path, e))?;
+    Progress::decode(&bytes).map_err(|e| format!("couldn't read checkpoint {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Filter;
+
+    /// A render of one tile of a cropped film, partway through, with samples that reach past the
+    /// tile into the rest of its film.
+    fn progress() -> Progress {
+        let film = Film::cropped(16, 8, PixelBounds::new(2, 1, 14, 7), Filter::Gaussian { radius: 1.5, sigma: 0.5 });
+        let mut progress = Progress::tile(&film, &PixelBounds::new(4, 2, 8, 6), 0x1234_5678_9abc_def0);
+        progress.passes = 3;
+        for (i, stats) in progress.pixels.iter_mut().enumerate() {
+            for pass in 0..3 {
+                let colour = Vec3::new(i as f64 / 7.0, pass as f64 / 3.0, 0.25);
+                stats.add(&colour, Vec3::new(0.5, 0.25, 0.125), Vec3::new(0.0, 1.0, pass as f64));
+                let (x, y) = (4.0 + (i % 4) as f64 + 0.3 * pass as f64, 2.0 + (i / 4) as f64 + 0.7);
+                progress.film.add_sample(x, y, &colour);
+            }
+        }
+        progress
+    }
+
+    fn vectors(vectors: impl IntoIterator<Item = Vec3>) -> Vec<[f64; 3]> {
+        vectors.into_iter().map(|v| [v.x, v.y, v.z]).collect()
+    }
+
+    #[test]
+    fn decodes_what_was_encoded() {
+        let progress = progress();
+        let decoded = Progress::decode(&progress.encode()).unwrap();
+
+        assert_eq!((decoded.seed, decoded.passes, decoded.bounds), (progress.seed, progress.passes, progress.bounds));
+        let (film, expected) = (&decoded.film, &progress.film);
+        assert_eq!((film.width, film.height, film.window), (expected.width, expected.height, expected.window));
+        assert_eq!(film.filter, expected.filter);
+        assert_eq!(vectors(film.pixels()), vectors(expected.pixels()));
+
+        assert_eq!(decoded.pixels.len(), progress.pixels.len());
+        for (stats, expected) in decoded.pixels.iter().zip(&progress.pixels) {
+            assert_eq!((stats.count, stats.mean, stats.squares), (expected.count, expected.mean, expected.squares));
+            assert_eq!(vectors([stats.albedo.clone()]), vectors([expected.albedo.clone()]));
+            assert_eq!(vectors([stats.normal.clone()]), vectors([expected.normal.clone()]));
+        }
+        assert_eq!(decoded.encode(), progress.encode());
+    }
+
+    #[test]
+    fn refuses_broken_checkpoints() {
+        let bytes = progress().encode();
+        assert!(Progress::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Progress::decode(b"FILM").is_err());
+
+        let mut newer = bytes.clone();
+        newer[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(Progress::decode(&newer).is_err());
+
+        // Pixels reaching past the right of the film.
+        let mut outside = bytes.clone();
+        outside[28..32].copy_from_slice(&20u32.to_le_bytes());
+        assert!(Progress::decode(&outside).is_err());
+
+        // A film and pixels as big as can be, which mustn't be made room for when the data for
+        // them isn't there.
+        let mut huge = bytes;
+        for at in [28, 32, 40, 44, 56, 60] {
+            huge[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        }
+        assert_eq!(Progress::decode(&huge).err().as_deref(), Some("the data is cut short"));
+    }
+}
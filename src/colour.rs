@@ -0,0 +1,194 @@
+use std::sync::OnceLock;
+
+use crate::Vec3;
+
+// RGB colour spaces, each defined by the chromaticities of its primaries and white point. Colours
+// move between spaces through CIE XYZ with D65 as white, the white of the scene, so spaces with a
+// different white (the ACES ones) are adapted with the Bradford transform and white stays white.
+//
+// Rendering happens in a linear working space, and colours from files (which are sRGB unless they
+// say otherwise) are converted into it as they're loaded. Colours written in code are taken to be
+// in the working space already.
+
+/// A linear RGB colour space.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColourSpace {
+    LinearSrgb, // Rec.709 primaries, the space of sRGB images and glTF colours.
+    DisplayP3,
+    Rec2020,
+    AcesCg, // ACES AP1, the usual working space for ACES pipelines.
+    Aces2065, // ACES AP0, wide enough to hold every colour, for interchange and archives.
+}
+
+/// Where a colour space's primaries and white sit on the CIE xy chromaticity diagram.
+pub struct Chromaticities {
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white: (f64, f64),
+}
+
+const D65: (f64, f64) = (0.3127, 0.3290);
+const ACES_WHITE: (f64, f64) = (0.32168, 0.33767);
+
+type Matrix = [[f64; 3]; 3];
+
+const BRADFORD: Matrix = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+const ALL: [ColourSpace; 5] = [
+    ColourSpace::LinearSrgb,
+    ColourSpace::DisplayP3,
+    ColourSpace::Rec2020,
+    ColourSpace::AcesCg,
+    ColourSpace::Aces2065,
+];
+
+impl ColourSpace {
+    /// Reads a colour space from its name on the command line.
+    pub fn parse(name: &str) -> Result<ColourSpace, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "srgb" | "linear-srgb" | "rec709" => Ok(ColourSpace::LinearSrgb),
+            "p3" | "display-p3" => Ok(ColourSpace::DisplayP3),
+            "rec2020" => Ok(ColourSpace::Rec2020),
+            "acescg" => Ok(ColourSpace::AcesCg),
+            "aces" | "aces2065-1" => Ok(ColourSpace::Aces2065),
+            _ => Err(format!("unknown colour space {}", name)),
+        }
+    }
+
+    pub fn chromaticities(&self) -> Chromaticities {
+        let (red, green, blue, white) = match self {
+            ColourSpace::LinearSrgb => ((0.64, 0.33), (0.30, 0.60), (0.15, 0.06), D65),
+            ColourSpace::DisplayP3 => ((0.680, 0.320), (0.265, 0.690), (0.150, 0.060), D65),
+            ColourSpace::Rec2020 => ((0.708, 0.292), (0.170, 0.797), (0.131, 0.046), D65),
+            ColourSpace::AcesCg => ((0.713, 0.293), (0.165, 0.830), (0.128, 0.044), ACES_WHITE),
+            ColourSpace::Aces2065 => ((0.7347, 0.2653), (0.0, 1.0), (0.0001, -0.0770), ACES_WHITE),
+        };
+        Chromaticities {
+            red,
+            green,
+            blue,
+            white,
+        }
+    }
+
+    /// Converts a colour in this space to XYZ.
+    pub fn to_xyz(&self, c: &Vec3) -> Vec3 {
+        mul_matrix(&self.matrices().0, c)
+    }
+
+    /// Converts a colour in XYZ to this space.
+    pub fn from_xyz(&self, xyz: &Vec3) -> Vec3 {
+        mul_matrix(&self.matrices().1, xyz)
+    }
+
+    /// Converts a colour in this space to another.
+    pub fn convert(&self, c: &Vec3, to: ColourSpace) -> Vec3 {
+        if *self == to {
+            return c.clone();
+        }
+        to.from_xyz(&self.to_xyz(c))
+    }
+
+    /// The matrices to XYZ and back, worked out once for every space.
+    fn matrices(&self) -> &'static (Matrix, Matrix) {
+        static MATRICES: OnceLock<Vec<(Matrix, Matrix)>> = OnceLock::new();
+        let matrices = MATRICES.get_or_init(|| {
+            ALL.iter().map(|space| {
+                let to_xyz = space.rgb_to_xyz();
+                (to_xyz, invert(&to_xyz))
+            }).collect()
+        });
+        &matrices[ALL.iter().position(|s| s == self).unwrap_or(0)]
+    }
+
+    fn rgb_to_xyz(&self) -> Matrix {
+        let c = self.chromaticities();
+        let column = |(x, y): (f64, f64)| [x / y, 1.0, (1.0 - x - y) / y];
+        let (r, g, b) = (column(c.red), column(c.green), column(c.blue));
+        let primaries = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+
+        // Scale the primaries so that they add up to the white point.
+        let w = column(c.white);
+        let s = mul_matrix(&invert(&primaries), &Vec3::new(w[0], w[1], w[2]));
+        let scaled = primaries.map(|row| [row[0] * s.x, row[1] * s.y, row[2] * s.z]);
+        mul_matrices(&adaptation(c.white, D65), &scaled)
+    }
+}
+
+/// The Bradford transform taking colours seen under one white to how they'd look under another.
+fn adaptation(from: (f64, f64), to: (f64, f64)) -> Matrix {
+    if from == to {
+        return [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    }
+    let cone = |(x, y): (f64, f64)| mul_matrix(&BRADFORD, &Vec3::new(x / y, 1.0, (1.0 - x - y) / y));
+    let (source, destination) = (cone(from), cone(to));
+    let scale = [
+        [destination.x / source.x, 0.0, 0.0],
+        [0.0, destination.y / source.y, 0.0],
+        [0.0, 0.0, destination.z / source.z],
+    ];
+    mul_matrices(&invert(&BRADFORD), &mul_matrices(&scale, &BRADFORD))
+}
+
+/// Encodes linear pixels in one space as 8-bit ARGB for a display in another, using the sRGB
+/// curve that both sRGB and Display P3 share.
+pub fn encode_display(pixels: &[Vec3], from: ColourSpace, to: ColourSpace) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(pixels.len() * 4);
+    for pixel in pixels {
+        let c = from.convert(pixel, to);
+        let encode = |v: f64| (linear_to_srgb(v.clamp(0.0, 1.0)) * 255.0).round() as u8;
+        bytes.extend([255, encode(c.x), encode(c.y), encode(c.z)]);
+    }
+    bytes
+}
+
+/// Undoes the sRGB transfer curve.
+pub fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Applies the sRGB transfer curve.
+pub fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn mul_matrix(m: &Matrix, v: &Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    )
+}
+
+fn mul_matrices(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+pub fn invert(m: &Matrix) -> Matrix {
+    let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let det = m[0][0] * cofactor(1, 2, 1, 2) - m[0][1] * cofactor(1, 2, 0, 2) + m[0][2] * cofactor(1, 2, 0, 1);
+    [
+        [cofactor(1, 2, 1, 2) / det, -cofactor(0, 2, 1, 2) / det, cofactor(0, 1, 1, 2) / det],
+        [-cofactor(1, 2, 0, 2) / det, cofactor(0, 2, 0, 2) / det, -cofactor(0, 1, 0, 2) / det],
+        [cofactor(1, 2, 0, 1) / det, -cofactor(0, 2, 0, 1) / det, cofactor(0, 1, 0, 1) / det],
+    ]
+}
@@ -0,0 +1,187 @@
+use std::rc::Rc;
+
+use crate::bvh::surrounding_box;
+use crate::{Aabb, HitRecord, Hittable, Ray};
+
+/// A stretch of a ray lying inside a solid, from where it enters to where it leaves. Both records
+/// have the normal pointing out of the solid, whichever way the ray is going.
+#[derive(Clone)]
+pub struct Span {
+    pub enter: HitRecord,
+    pub exit: HitRecord,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    Difference, // Everything in the left solid that is not in the right one.
+}
+
+/// A solid made by combining two closed solids with a boolean operation. Only objects whose
+/// `spans` give their insides can be combined: spheres, quadrics, other CSG solids, and instances
+/// and lists of them. Meshes, signed distance fields and open surfaces can't, and are refused when
+/// the CSG is made.
+pub struct Csg {
+    pub left: Rc<dyn Hittable>,
+    pub right: Rc<dyn Hittable>,
+    pub operation: CsgOperation,
+}
+
+impl Span {
+    pub fn new(enter: HitRecord, exit: HitRecord) -> Span {
+        Span {
+            enter,
+            exit,
+        }
+    }
+}
+
+impl CsgOperation {
+    fn inside(&self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            CsgOperation::Union => in_left || in_right,
+            CsgOperation::Intersection => in_left && in_right,
+            CsgOperation::Difference => in_left && !in_right,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            CsgOperation::Union => "union",
+            CsgOperation::Intersection => "intersection",
+            CsgOperation::Difference => "difference",
+        }
+    }
+}
+
+impl Csg {
+    pub fn new(left: Rc<dyn Hittable>, right: Rc<dyn Hittable>, operation: CsgOperation) -> Result<Csg, String> {
+        for (side, object) in [("left", &left), ("right", &right)] {
+            if !object.is_solid() {
+                return Err(format!("can't make a CSG {}, as its {} object is not a closed solid",
+                                   operation.name(), side));
+            }
+        }
+        Ok(Csg {
+            left,
+            right,
+            operation,
+        })
+    }
+
+    pub fn union(left: Rc<dyn Hittable>, right: Rc<dyn Hittable>) -> Result<Csg, String> {
+        Csg::new(left, right, CsgOperation::Union)
+    }
+
+    pub fn intersection(left: Rc<dyn Hittable>, right: Rc<dyn Hittable>) -> Result<Csg, String> {
+        Csg::new(left, right, CsgOperation::Intersection)
+    }
+
+    pub fn difference(left: Rc<dyn Hittable>, right: Rc<dyn Hittable>) -> Result<Csg, String> {
+        Csg::new(left, right, CsgOperation::Difference)
+    }
+}
+
+/// Combines two sorted lists of spans with a boolean operation.
+pub fn combine_spans(left: &[Span], right: &[Span], operation: CsgOperation) -> Vec<Span> {
+    // Every span boundary, marked with which solid it belongs to and whether the ray enters it.
+    let mut events: Vec<(&HitRecord, bool, bool)> = Vec::with_capacity(2 * (left.len() + right.len()));
+    for span in left {
+        events.push((&span.enter, false, true));
+        events.push((&span.exit, false, false));
+    }
+    for span in right {
+        events.push((&span.enter, true, true));
+        events.push((&span.exit, true, false));
+    }
+    events.sort_by(|a, b| a.0.t.partial_cmp(&b.0.t).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut output = Vec::new();
+    let mut in_left = false;
+    let mut in_right = false;
+    let mut enter: Option<HitRecord> = None;
+
+    for (rec, is_right, entering) in events {
+        let was_inside = operation.inside(in_left, in_right);
+        if is_right {
+            in_right = entering;
+        } else {
+            in_left = entering;
+        }
+        let inside = operation.inside(in_left, in_right);
+        if inside == was_inside {
+            continue;
+        }
+
+        // Surfaces carved out by the right solid face the other way in the result.
+        let mut boundary = rec.clone();
+        if is_right && operation == CsgOperation::Difference {
+            boundary.normal = boundary.normal.mul(-1.0);
+        }
+
+        if inside {
+            enter = Some(boundary);
+        } else if let Some(enter) = enter.take() {
+            output.push(Span::new(enter, boundary));
+        }
+    }
+    output
+}
+
+/// Finds the first span boundary between `t_min` and `t_max`, as an ordinary hit.
+pub fn first_boundary(spans: &[Span], ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+    for span in spans {
+        for boundary in [&span.enter, &span.exit].iter() {
+            if boundary.t >= t_min && boundary.t <= t_max {
+                let outward_normal = boundary.normal.clone();
+                *rec = (*boundary).clone();
+                rec.set_face_normal(ray, &outward_normal);
+                return true;
+            }
+        }
+    }
+    false
+}
+
+impl Hittable for Csg {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let mut spans = Vec::new();
+        self.spans(ray, &mut spans) && first_boundary(&spans, ray, t_min, t_max, rec)
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut Aabb) -> bool {
+        let mut left_box = Aabb::default();
+        let mut right_box = Aabb::default();
+        if !self.left.bounding_box(time0, time1, &mut left_box) {
+            return false;
+        }
+        let has_right = self.right.bounding_box(time0, time1, &mut right_box);
+
+        *output_box = match self.operation {
+            CsgOperation::Union if has_right => surrounding_box(&left_box, &right_box),
+            CsgOperation::Union => return false,
+            CsgOperation::Intersection if has_right => Aabb::new(
+                left_box.minimum.max(&right_box.minimum),
+                left_box.maximum.min(&right_box.maximum),
+            ),
+            _ => left_box,
+        };
+        true
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn spans(&self, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+        // Leaving out an operand that isn't a solid would quietly give the wrong shape.
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        if !self.left.spans(ray, &mut left) || !self.right.spans(ray, &mut right) {
+            return false;
+        }
+        spans.extend(combine_spans(&left, &right, self.operation));
+        true
+    }
+}
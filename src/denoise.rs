@@ -0,0 +1,148 @@
+use crate::{Image, Vec3, luminance};
+
+// An edge-avoiding À-Trous wavelet filter, as in SVGF (Schied et al. 2017). Each pass blurs with a
+// 5×5 B-spline kernel whose taps are spread twice as far apart as the last pass's, so a few passes
+// reach a wide area cheaply. Taps are weighed down where the normal or albedo changes, which finds
+// the edges of objects and textures, and where the luminance differs by more than the pixel's
+// noise would explain, which keeps lighting detail the samples agree on.
+//
+// The filter works on the lighting with the albedo divided out, so texture detail isn't blurred
+// along with the noise, and multiplies it back at the end.
+
+/// Cleans up noisy renders using the albedo and normals the render gathered along the way.
+pub struct Denoiser {
+    pub strength: f64, // How much luminance difference is put down to noise. 0 leaves the image alone.
+    pub iterations: u32, // Each pass doubles the reach of the filter.
+}
+
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// How sharply the normal and albedo weights fall off.
+const NORMAL_POWER: i32 = 128;
+const ALBEDO_SIGMA: f64 = 0.1;
+
+// Albedo below this is too dark to divide by, and the colour is filtered as it is.
+const MIN_ALBEDO: f64 = 0.01;
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser::new(1.0)
+    }
+}
+
+impl Denoiser {
+    pub fn new(strength: f64) -> Denoiser {
+        Denoiser {
+            strength,
+            iterations: 5,
+        }
+    }
+
+    /// Denoises an image, giving its colours, top scan-line first.
+    pub fn denoise(&self, image: &Image) -> Vec<Vec3> {
+        if self.strength <= 0.0 || self.iterations == 0 {
+            return image.colour.clone();
+        }
+
+        let mut lighting: Vec<Vec3> = image.colour.iter().zip(&image.albedo).map(|(c, a)| demodulate(c, a)).collect();
+        let mut variance: Vec<f64> = image.variance.iter().zip(&image.albedo).map(|(v, a)| {
+            let l = luminance(a);
+            if l > MIN_ALBEDO { v / (l * l) } else { *v }
+        }).collect();
+
+        for i in 0..self.iterations {
+            (lighting, variance) = self.pass(image, &lighting, &variance, 1 << i);
+        }
+
+        lighting.iter().zip(&image.albedo).map(|(c, a)| remodulate(c, a)).collect()
+    }
+
+    /// One pass of the filter, with its taps `step` pixels apart. Gives the filtered lighting and
+    /// its variance, which falls as the noise is averaged away.
+    fn pass(&self, image: &Image, lighting: &[Vec3], variance: &[f64], step: i64) -> (Vec<Vec3>, Vec<f64>) {
+        let (width, height) = (image.width as i64, image.height as i64);
+        let blurred = blur_variance(variance, width, height);
+
+        let mut out_lighting = Vec::with_capacity(lighting.len());
+        let mut out_variance = Vec::with_capacity(variance.len());
+        for y in 0..height {
+            for x in 0..width {
+                let p = (y * width + x) as usize;
+                let l_p = luminance(&lighting[p]);
+                let sigma = self.strength * 4.0 * blurred[p].sqrt() + 1e-6;
+
+                let mut total = Vec3::default();
+                let mut total_variance = 0.0;
+                let mut total_weight = 0.0;
+                for (dy, ky) in KERNEL.iter().enumerate() {
+                    for (dx, kx) in KERNEL.iter().enumerate() {
+                        let qx = x + (dx as i64 - 2) * step;
+                        let qy = y + (dy as i64 - 2) * step;
+                        if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                            continue;
+                        }
+                        let q = (qy * width + qx) as usize;
+
+                        let w_luminance = (-(l_p - luminance(&lighting[q])).abs() / sigma).exp();
+                        let w_normal = normal_weight(&image.normal[p], &image.normal[q]);
+                        let albedo_change = image.albedo[p].clone() - image.albedo[q].clone();
+                        let w_albedo = (-albedo_change.dot(&albedo_change) / (ALBEDO_SIGMA * ALBEDO_SIGMA)).exp();
+
+                        let w = kx * ky * w_luminance * w_normal * w_albedo;
+                        total = total + lighting[q].mul(w);
+                        total_variance += w * w * variance[q];
+                        total_weight += w;
+                    }
+                }
+
+                // The pixel itself always has some weight, so there's no dividing by zero.
+                out_lighting.push(total.div(total_weight));
+                out_variance.push(total_variance / (total_weight * total_weight));
+            }
+        }
+        (out_lighting, out_variance)
+    }
+}
+
+/// How alike two normals are, where zero means the sky, which is only like itself.
+fn normal_weight(a: &Vec3, b: &Vec3) -> f64 {
+    match (a.near_zero(), b.near_zero()) {
+        (true, true) => 1.0,
+        (false, false) => a.dot(b).max(0.0).powi(NORMAL_POWER),
+        _ => 0.0,
+    }
+}
+
+/// The variance smoothed with a 3×3 Gaussian, so the luminance weights aren't thrown by the noise
+/// in the variance itself.
+fn blur_variance(variance: &[f64], width: i64, height: i64) -> Vec<f64> {
+    let kernel = [0.25, 0.5, 0.25];
+    let mut out = Vec::with_capacity(variance.len());
+    for y in 0..height {
+        for x in 0..width {
+            let (mut total, mut total_weight) = (0.0, 0.0);
+            for (dy, ky) in kernel.iter().enumerate() {
+                for (dx, kx) in kernel.iter().enumerate() {
+                    let (qx, qy) = (x + dx as i64 - 1, y + dy as i64 - 1);
+                    if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                        continue;
+                    }
+                    total += kx * ky * variance[(qy * width + qx) as usize];
+                    total_weight += kx * ky;
+                }
+            }
+            out.push(total / total_weight);
+        }
+    }
+    out
+}
+
+fn demodulate(colour: &Vec3, albedo: &Vec3) -> Vec3 {
+    let divide = |c: f64, a: f64| if a > MIN_ALBEDO { c / a } else { c };
+    Vec3::new(divide(colour.x, albedo.x), divide(colour.y, albedo.y), divide(colour.z, albedo.z))
+}
+
+fn remodulate(lighting: &Vec3, albedo: &Vec3) -> Vec3 {
+    let multiply = |c: f64, a: f64| if a > MIN_ALBEDO { c * a } else { c };
+    Vec3::new(multiply(lighting.x, albedo.x), multiply(lighting.y, albedo.y), multiply(lighting.z, albedo.z))
+}
@@ -0,0 +1,546 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::film::Reader;
+use crate::lens::FILM_DIAGONAL;
+use crate::render::SKY_LUMINANCE;
+use crate::{
+    AdaptiveSampling, Camera, ColourSpace, Exposure, Filter, Fog, HenyeyGreenstein, LensSystem, PixelBounds, Progress,
+    Projection, RenderSettings, Scene, Vec3, continue_render, default_camera, default_world, embed_gltf, parse_gltf, start_render,
+};
+
+// Rendering one image on many machines. A coordinator listens for workers, sends each one the job
+// (the scene, with any files it refers to embedded in it, and the render settings, so workers need
+// nothing but an address), and then hands out tiles of the image one at a time. A worker renders
+// its tile's samples onto a film reaching a filter's radius around the tile and sends it back with
+// the tile's pixel statistics, and the coordinator merges it in. Samples are seeded by their
+// pixel, so the image comes out the same whichever worker renders which tile.
+//
+// Workers send a heartbeat every few seconds while they render a tile, however long its passes
+// take. One that drops its connection or goes quiet for too long is given up on, and its tile goes
+// back on the queue for someone else.
+//
+// Messages are a byte giving their kind, the length of what follows as a u64, and then that many
+// bytes, all little-endian.
+
+/// Everything a worker needs to render its share of an image.
+pub struct Job {
+    pub scene_path: Option<String>, // Where the scene file was, if it was one, for error messages.
+    pub scene: Vec<u8>, // A glTF or GLB scene with its files embedded, or nothing for the default scene.
+    pub width: u32,
+    pub settings: RenderSettings,
+    pub time: f64, // How far into the scene's animation the image is taken.
+    pub projection: Option<Projection>, // Replaces the camera's own.
+    pub lens: Option<String>, // A lens prescription to trace through in place of the camera's thin lens.
+    pub focus_distance: f64, // How far in front of the camera the lens is focused, in metres.
+    pub film_diagonal: f64, // The size of the film behind the lens, in metres corner to corner.
+    pub shutter_speed: Option<f64>, // How long moving things blur for, in seconds.
+    pub f_number: Option<f64>, // Opens the thin lens, or stops down the lens, to this.
+    pub iso: Option<f64>, // With a shutter speed and an f-number, photographs the scene with an exposure.
+}
+
+// Far enough for a lens to keep most of a room sharp, in metres.
+pub const DEFAULT_FOCUS_DISTANCE: f64 = 10.0;
+
+// How long a worker can go without a heartbeat before its tile is given to someone else.
+const WORKER_TIMEOUT: Duration = Duration::from_secs(60);
+// How often a worker lets the coordinator know it's still going.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const TILE_SIZE: u32 = 32;
+// The longest message accepted, a gigabyte, which has to have room for a whole scene.
+const MAX_MESSAGE_LENGTH: u64 = 1 << 30;
+
+const JOB: u8 = 1;
+const TILE: u8 = 2;
+const HEARTBEAT: u8 = 3;
+const RESULT: u8 = 4;
+const FINISHED: u8 = 5;
+
+const SPACES: [ColourSpace; 5] = [
+    ColourSpace::LinearSrgb,
+    ColourSpace::DisplayP3,
+    ColourSpace::Rec2020,
+    ColourSpace::AcesCg,
+    ColourSpace::Aces2065,
+];
+
+impl Job {
+    /// A job for a scene file, or the default scene if there isn't one. Any files the scene refers
+    /// to are embedded in it, so workers don't need them.
+    pub fn load(scene_path: Option<&str>, width: u32, settings: RenderSettings) -> Result<Job, String> {
+        let scene = match scene_path {
+            Some(path) => {
+                let bytes = std::fs::read(path).map_err(|e| format!("couldn't read scene {}: {}", path, e))?;
+                let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+                embed_gltf(&bytes, base).map_err(|e| format!("couldn't load scene {}: {}", path, e))?
+            }
+            None => Vec::new(),
+        };
+        Ok(Job {
+            scene_path: scene_path.map(String::from),
+            scene,
+            width,
+            settings,
+            time: 0.0,
+            projection: None,
+            lens: None,
+            focus_distance: DEFAULT_FOCUS_DISTANCE,
+            film_diagonal: FILM_DIAGONAL,
+            shutter_speed: None,
+            f_number: None,
+            iso: None,
+        })
+    }
+
+    /// Builds the job's scene as it is at the job's time, giving it the default camera if it has
+    /// none of its own, so the first camera is always the one to render through. That camera is
+    /// given the job's focus distance, and its projection, lens, shutter speed and f-number if it
+    /// has them, though a lens only goes with a perspective projection, and it sees as much of the
+    /// scene as its focal length and the film's size let it rather than the camera's field of
+    /// view. With an ISO too, it photographs the scene with an exposure, taking the f-number
+    /// from the lens if the job doesn't give one, and the sky is made as bright as a real one.
+    /// Nothing is read from disk, so the scene's files have to be embedded in it, as `load` does.
+    pub fn scene(&self) -> Result<Scene, String> {
+        let mut scene = if self.scene.is_empty() {
+            Scene::new(Rc::new(default_world()))
+        } else {
+            let shutter_speed = self.shutter_speed.unwrap_or(0.0);
+            let space = self.settings.working_space;
+            parse_gltf(&self.scene, None, self.width, space, self.time, shutter_speed).map_err(|e| match &self.scene_path {
+                Some(path) => format!("couldn't load scene {}: {}", path, e),
+                None => format!("couldn't load scene: {}", e),
+            })?
+        };
+        if scene.cameras.is_empty() {
+            let mut camera = default_camera(self.width);
+            camera.shutter_open = self.time;
+            camera.shutter_close = self.time;
+            scene.cameras.push(camera);
+        }
+        let camera = &mut scene.cameras[0];
+        camera.focus_distance = self.focus_distance;
+        if let Some(projection) = self.projection {
+            camera.set_projection(projection);
+        }
+        if let Some(shutter_speed) = self.shutter_speed {
+            camera.shutter_close = camera.shutter_open + shutter_speed;
+        }
+        match (&self.lens, self.f_number) {
+            (Some(lens), f_number) => {
+                // A lens makes its own perspective picture, so it can't be had with another
+                // projection, whether the job's or the scene camera's.
+                if camera.projection != Projection::Perspective {
+                    return Err(String::from("a lens can only be used with a perspective projection"));
+                }
+                let lens = LensSystem::parse(lens, self.focus_distance, f_number, self.film_diagonal)
+                    .map_err(|e| format!("couldn't load lens: {}", e))?;
+                camera.lens = Some(Rc::new(lens));
+            }
+            (None, Some(f_number)) => camera.set_f_number(f_number),
+            (None, None) => {}
+        }
+        if let Some(iso) = self.iso {
+            let shutter_speed = self.shutter_speed.ok_or("an exposure needs a shutter speed as well as an ISO")?;
+            let f_number = camera.f_number().ok_or("an exposure needs an f-number or a lens as well as an ISO")?;
+            camera.exposure = Some(Exposure { iso, shutter_speed, f_number });
+            scene.sky = SKY_LUMINANCE;
+        }
+        Ok(scene)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let path = self.scene_path.as_deref().unwrap_or("");
+        out.extend((path.len() as u64).to_le_bytes());
+        out.extend(path.as_bytes());
+        out.extend((self.scene.len() as u64).to_le_bytes());
+        out.extend(&self.scene);
+        out.extend(self.width.to_le_bytes());
+
+        let s = &self.settings;
+        for v in [s.samples_per_pixel, s.max_depth] {
+            out.extend(v.to_le_bytes());
+        }
+        out.push(s.spectral as u8);
+        out.push(SPACES.iter().position(|space| *space == s.working_space).unwrap_or(0) as u8);
+        match &s.adaptive {
+            Some(adaptive) => {
+                out.push(1);
+                out.extend(adaptive.threshold.to_le_bytes());
+                out.extend(adaptive.max_samples.to_le_bytes());
+            }
+            None => out.push(0),
+        }
+        match &s.fog {
+            Some(fog) => {
+                out.push(1);
+                for v in [fog.density, fog.falloff, fog.base_height, fog.albedo.x, fog.albedo.y, fog.albedo.z, fog.phase.g] {
+                    out.extend(v.to_le_bytes());
+                }
+            }
+            None => out.push(0),
+        }
+        s.filter.write(&mut out);
+        match &s.crop {
+            Some(crop) => {
+                out.push(1);
+                for v in [crop.x0, crop.y0, crop.x1, crop.y1] {
+                    out.extend(v.to_le_bytes());
+                }
+            }
+            None => out.push(0),
+        }
+        out.extend(s.seed.to_le_bytes());
+        out.extend(self.time.to_le_bytes());
+        match &self.projection {
+            Some(projection) => {
+                out.push(1);
+                projection.write(&mut out);
+            }
+            None => out.push(0),
+        }
+        let lens = self.lens.as_deref().unwrap_or("");
+        out.extend((lens.len() as u64).to_le_bytes());
+        out.extend(lens.as_bytes());
+        out.extend(self.focus_distance.to_le_bytes());
+        out.extend(self.film_diagonal.to_le_bytes());
+        for value in [self.shutter_speed, self.f_number, self.iso] {
+            match value {
+                Some(value) => {
+                    out.push(1);
+                    out.extend(value.to_le_bytes());
+                }
+                None => out.push(0),
+            }
+        }
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Job, String> {
+        let mut reader = Reader::new(bytes);
+        let length = reader.u64()? as usize;
+        let path = String::from_utf8(reader.take(length)?.to_vec()).map_err(|_| String::from("bad scene path"))?;
+        let length = reader.u64()? as usize;
+        let scene = reader.take(length)?.to_vec();
+        let width = reader.u32()?;
+
+        let mut settings = RenderSettings {
+            samples_per_pixel: reader.u32()?,
+            max_depth: reader.u32()?,
+            spectral: reader.take(1)?[0] != 0,
+            working_space: *SPACES.get(reader.take(1)?[0] as usize).ok_or_else(|| String::from("bad colour space"))?,
+            ..Default::default()
+        };
+        if reader.take(1)?[0] != 0 {
+            settings.adaptive = Some(AdaptiveSampling {
+                threshold: reader.f64()?,
+                max_samples: reader.u32()?,
+            });
+        }
+        if reader.take(1)?[0] != 0 {
+            settings.fog = Some(Fog {
+                density: reader.f64()?,
+                falloff: reader.f64()?,
+                base_height: reader.f64()?,
+                albedo: Vec3::new(reader.f64()?, reader.f64()?, reader.f64()?),
+                phase: HenyeyGreenstein::new(reader.f64()?),
+            });
+        }
+        settings.filter = Filter::read(&mut reader)?;
+        if reader.take(1)?[0] != 0 {
+            settings.crop = Some(PixelBounds::new(reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?));
+        }
+        settings.seed = reader.u64()?;
+        let time = reader.f64()?;
+        let projection = if reader.take(1)?[0] != 0 { Some(Projection::read(&mut reader)?) } else { None };
+        let length = reader.u64()? as usize;
+        let lens = String::from_utf8(reader.take(length)?.to_vec()).map_err(|_| String::from("bad lens"))?;
+        let focus_distance = reader.f64()?;
+        let film_diagonal = reader.f64()?;
+        let mut optional = || -> Result<Option<f64>, String> {
+            if reader.take(1)?[0] != 0 { Ok(Some(reader.f64()?)) } else { Ok(None) }
+        };
+        let (shutter_speed, f_number, iso) = (optional()?, optional()?, optional()?);
+
+        Ok(Job {
+            scene_path: if path.is_empty() { None } else { Some(path) },
+            scene,
+            width,
+            settings,
+            time,
+            projection,
+            lens: if lens.is_empty() { None } else { Some(lens) },
+            focus_distance,
+            film_diagonal,
+            shutter_speed,
+            f_number,
+            iso,
+        })
+    }
+}
+
+/// Something that happened in a distributed render, for whoever started it to report.
+pub enum JobEvent {
+    WorkerConnected(String), // The worker's address.
+    WorkerLost(String, String), // The worker's address and what went wrong.
+    TileMerged { remaining: usize, total: usize },
+    Connected, // To the coordinator, which has sent the job.
+    TileRendered(PixelBounds),
+}
+
+/// What the threads looking after workers send back to the coordinator.
+enum Update {
+    Tile(Progress),
+    Worker(JobEvent),
+}
+
+/// The tiles still to be rendered, shared between the threads looking after workers.
+struct Queue {
+    tiles: Mutex<VecDeque<PixelBounds>>,
+    finished: AtomicBool,
+    workers: AtomicUsize, // How many workers are connected.
+    films: HashMap<(u32, u32), FilmShape>, // What each tile's film has to be, by its top left.
+}
+
+/// The size of the image a film is for, the pixels it holds and its filter.
+type FilmShape = (u32, u32, PixelBounds, Filter);
+
+/// A tile handed out to a worker, which goes back on the queue unless it's returned, even if the
+/// thread looking after the worker panics.
+struct Lease<'a> {
+    queue: &'a Queue,
+    tile: Option<PixelBounds>,
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        if let Some(tile) = self.tile.take() {
+            let mut tiles = self.queue.tiles.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            tiles.push_back(tile);
+        }
+    }
+}
+
+/// Counts a worker as connected for as long as it's held, however the thread holding it ends.
+struct Connected(Arc<Queue>);
+
+impl Connected {
+    fn new(queue: Arc<Queue>) -> Connected {
+        queue.workers.fetch_add(1, Ordering::SeqCst);
+        Connected(queue)
+    }
+}
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        self.0.workers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Renders a job by sharing it out between workers that connect to `address`, and gives back
+/// the merged render once every tile is done. Workers coming and going and tiles coming back are
+/// passed to `report` as they happen.
+pub fn coordinate(address: &str,
+                  job: &Job,
+                  camera: &Camera,
+                  report: &mut dyn FnMut(JobEvent)
+) -> Result<Progress, String> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("couldn't listen on {}: {}", address, e))?;
+
+    let mut progress = start_render(camera, &job.settings);
+    let tiles = progress.bounds.tiles(TILE_SIZE);
+    let mut remaining: HashSet<(u32, u32)> = tiles.iter().map(|t| (t.x0, t.y0)).collect();
+    let film = &progress.film;
+    let films = tiles.iter()
+        .map(|t| ((t.x0, t.y0), (film.width, film.height, film.tile_window(t), film.filter)))
+        .collect();
+    let queue = Arc::new(Queue {
+        tiles: Mutex::new(tiles.into_iter().collect()),
+        finished: AtomicBool::new(false),
+        workers: AtomicUsize::new(0),
+        films,
+    });
+    let job_bytes = Arc::new(job.encode());
+    let (results, updates) = mpsc::channel();
+
+    // The listener doesn't block, so the thread accepting workers sees when the render is done and
+    // lets it go rather than waiting on it forever.
+    listener.set_nonblocking(true).map_err(|e| format!("couldn't listen on {}: {}", address, e))?;
+    let accepting = queue.clone();
+    let acceptor = thread::spawn(move || {
+        while !accepting.finished.load(Ordering::SeqCst) {
+            let stream = match listener.accept() {
+                Ok((stream, _)) if stream.set_nonblocking(false).is_ok() => stream,
+                Ok(_) => continue,
+                Err(_) => {
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+            };
+            let (job_bytes, results) = (job_bytes.clone(), results.clone());
+            let connected = Connected::new(accepting.clone());
+            thread::spawn(move || {
+                let queue = &connected.0;
+                let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                let _ = results.send(Update::Worker(JobEvent::WorkerConnected(peer.clone())));
+                if let Err(e) = look_after_worker(stream, &job_bytes, queue, &results) {
+                    let _ = results.send(Update::Worker(JobEvent::WorkerLost(peer, e)));
+                }
+            });
+        }
+    });
+
+    let total = remaining.len();
+    let merged = (|| {
+        while !remaining.is_empty() {
+            let tile = match updates.recv().map_err(|_| String::from("the workers' results stopped coming"))? {
+                Update::Tile(tile) => tile,
+                Update::Worker(event) => {
+                    report(event);
+                    continue;
+                }
+            };
+            // Each tile is only counted once, however many times it comes back.
+            if remaining.remove(&(tile.bounds.x0, tile.bounds.y0)) {
+                progress.merge(&tile)?;
+                report(JobEvent::TileMerged { remaining: remaining.len(), total });
+            }
+        }
+        Ok::<(), String>(())
+    })();
+
+    // Let the workers know there's nothing more to do before the coordinator goes away, whether
+    // or not the render got to the end.
+    if merged.is_err() {
+        if let Ok(mut tiles) = queue.tiles.lock() {
+            tiles.clear();
+        }
+    }
+    queue.finished.store(true, Ordering::SeqCst);
+    acceptor.join().map_err(|_| String::from("the thread accepting workers panicked"))?;
+    while queue.workers.load(Ordering::SeqCst) > 0 {
+        thread::sleep(Duration::from_millis(50));
+    }
+    merged.map(|_| progress)
+}
+
+/// Sends a worker the job and then tiles until there are none left, putting its tile back on the
+/// queue if it's lost or sends back something that isn't the tile.
+fn look_after_worker(mut stream: TcpStream, job: &[u8], queue: &Queue, results: &Sender<Update>) -> Result<(), String> {
+    stream.set_read_timeout(Some(WORKER_TIMEOUT)).map_err(|e| e.to_string())?;
+    send(&mut stream, JOB, job)?;
+    loop {
+        let tile = queue.tiles.lock().map_err(|_| String::from("the queue is poisoned"))?.pop_front();
+        let tile = match tile {
+            Some(tile) => tile,
+            None if queue.finished.load(Ordering::SeqCst) => return send(&mut stream, FINISHED, &[]),
+            None => {
+                // Other workers still have tiles, and may yet give them back.
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+
+        let mut lease = Lease { queue, tile: Some(tile) };
+        let film = queue.films.get(&(tile.x0, tile.y0)).ok_or("the tile isn't one of the image's")?;
+        let progress = render_remotely(&mut stream, &tile, film)?;
+        lease.tile = None;
+        results.send(Update::Tile(progress)).map_err(|_| String::from("the coordinator has gone"))?;
+    }
+}
+
+/// Asks a worker for one tile and waits for it, as long as the worker keeps up its heartbeat. The
+/// tile has to come back on a film like `film`, ready to merge into the coordinator's.
+fn render_remotely(stream: &mut TcpStream, tile: &PixelBounds, film: &FilmShape) -> Result<Progress, String> {
+    let bounds: Vec<u8> = [tile.x0, tile.y0, tile.x1, tile.y1].iter().flat_map(|v| v.to_le_bytes()).collect();
+    send(stream, TILE, &bounds)?;
+    loop {
+        match receive(stream)? {
+            (HEARTBEAT, _) => continue,
+            (RESULT, bytes) => {
+                let progress = Progress::decode(&bytes)?;
+                let f = &progress.film;
+                if progress.bounds != *tile || (f.width, f.height, f.window, f.filter) != *film {
+                    return Err(String::from("the worker sent back the wrong tile"));
+                }
+                return Ok(progress);
+            }
+            (kind, _) => return Err(format!("unexpected message {}", kind)),
+        }
+    }
+}
+
+/// Connects to a coordinator and renders the tiles it hands out until it says the job is done,
+/// passing to `report` when it has the job and each time it finishes a tile.
+pub fn work(address: &str, report: &mut dyn FnMut(JobEvent)) -> Result<(), String> {
+    let mut stream = TcpStream::connect(address).map_err(|e| format!("couldn't connect to {}: {}", address, e))?;
+    let job = match receive(&mut stream)? {
+        (JOB, bytes) => Job::decode(&bytes)?,
+        (kind, _) => return Err(format!("expected a job, not message {}", kind)),
+    };
+    let scene = job.scene()?;
+    let camera = &scene.cameras[0];
+    let film = start_render(camera, &job.settings).film;
+    report(JobEvent::Connected);
+
+    loop {
+        let tile = match receive(&mut stream)? {
+            (TILE, bytes) => {
+                let mut reader = Reader::new(&bytes);
+                PixelBounds::new(reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?)
+            }
+            (FINISHED, _) => return Ok(()),
+            (kind, _) => return Err(format!("unexpected message {}", kind)),
+        };
+
+        let mut progress = Progress::tile(&film, &tile, job.settings.seed);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let mut heartbeat = stream.try_clone().map_err(|e| e.to_string())?;
+        let heart = thread::spawn(move || {
+            // Beats until the tile is done, which drops `stop`. If the coordinator has gone,
+            // that'll come out when the tile is sent.
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(HEARTBEAT_INTERVAL) {
+                let _ = send(&mut heartbeat, HEARTBEAT, &[]);
+            }
+        });
+        continue_render(camera, &scene, &job.settings, &mut progress, &mut |_| true);
+        drop(stop);
+        heart.join().map_err(|_| String::from("the heartbeat thread panicked"))?;
+        send(&mut stream, RESULT, &progress.encode())?;
+        report(JobEvent::TileRendered(tile));
+    }
+}
+
+fn send(stream: &mut TcpStream, kind: u8, payload: &[u8]) -> Result<(), String> {
+    let mut message = Vec::with_capacity(payload.len() + 9);
+    message.push(kind);
+    message.extend((payload.len() as u64).to_le_bytes());
+    message.extend(payload);
+    stream.write_all(&message).map_err(|e| format!("couldn't send: {}", e))
+}
+
+fn receive(stream: &mut TcpStream) -> Result<(u8, Vec<u8>), String> {
+    let mut header = [0; 9];
+    stream.read_exact(&mut header).map_err(|e| format!("couldn't receive: {}", e))?;
+    let mut length = [0; 8];
+    length.copy_from_slice(&header[1..]);
+    let length = u64::from_le_bytes(length);
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(format!("a message of {} bytes is too long", length));
+    }
+    // Read as it arrives rather than allocating the whole length up front, in case it's a lie.
+    let mut payload = Vec::new();
+    stream.take(length).read_to_end(&mut payload).map_err(|e| format!("couldn't receive: {}", e))?;
+    if payload.len() as u64 != length {
+        return Err(String::from("couldn't receive: the connection closed mid-message"));
+    }
+    Ok((header[0], payload))
+}
@@ -0,0 +1,77 @@
+use crate::{ColourSpace, Vec3};
+
+// OpenEXR images, written as uncompressed 32-bit float scanlines with the chromaticities of their
+// colour space in the header, so that anything reading them knows what the RGB values mean.
+
+/// Writes linear pixels, top row first, to an EXR file. They're already in `space`, which is
+/// recorded in the file.
+pub fn write_exr(path: &str, width: u32, height: u32, pixels: &[Vec3], space: ColourSpace) -> Result<(), String> {
+    let bytes = encode_exr(width, height, pixels, space)?;
+    std::fs::write(path, bytes).map_err(|e| format!("couldn't write image {}: {}", path, e))
+}
+
+pub fn encode_exr(width: u32, height: u32, pixels: &[Vec3], space: ColourSpace) -> Result<Vec<u8>, String> {
+    if pixels.len() != (width * height) as usize || width == 0 || height == 0 {
+        return Err(String::from("the pixels don't fill the image"));
+    }
+    let mut out = Vec::new();
+    out.extend(20000630_u32.to_le_bytes()); // The magic number.
+    out.extend(2_u32.to_le_bytes()); // Version 2, single part scanlines.
+
+    // Channels are stored in alphabetical order.
+    let mut channels = Vec::new();
+    for name in ["B", "G", "R"] {
+        channels.extend(name.as_bytes());
+        channels.push(0);
+        channels.extend(2_i32.to_le_bytes()); // 32-bit float.
+        channels.extend([0, 0, 0, 0]); // Not perceptually linear, and reserved bytes.
+        channels.extend(1_i32.to_le_bytes()); // No subsampling.
+        channels.extend(1_i32.to_le_bytes());
+    }
+    channels.push(0);
+    attribute(&mut out, "channels", "chlist", &channels);
+
+    let c = space.chromaticities();
+    let chromaticities: Vec<u8> = [c.red, c.green, c.blue, c.white]
+        .iter()
+        .flat_map(|(x, y)| [*x as f32, *y as f32])
+        .flat_map(f32::to_le_bytes)
+        .collect();
+    attribute(&mut out, "chromaticities", "chromaticities", &chromaticities);
+    attribute(&mut out, "compression", "compression", &[0]);
+
+    let window: Vec<u8> = [0, 0, width as i32 - 1, height as i32 - 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+    attribute(&mut out, "dataWindow", "box2i", &window);
+    attribute(&mut out, "displayWindow", "box2i", &window);
+    attribute(&mut out, "lineOrder", "lineOrder", &[0]); // Increasing y, top row first.
+    attribute(&mut out, "pixelAspectRatio", "float", &1.0_f32.to_le_bytes());
+    attribute(&mut out, "screenWindowCenter", "v2f", &[0.0_f32.to_le_bytes(), 0.0_f32.to_le_bytes()].concat());
+    attribute(&mut out, "screenWindowWidth", "float", &1.0_f32.to_le_bytes());
+    out.push(0); // The end of the header.
+
+    // A table of where each scanline starts, then the scanlines themselves.
+    let line_size = width as usize * 3 * 4;
+    let table_end = out.len() + height as usize * 8;
+    for y in 0..height as usize {
+        out.extend(((table_end + y * (8 + line_size)) as u64).to_le_bytes());
+    }
+    for (y, row) in pixels.chunks(width as usize).enumerate() {
+        out.extend((y as i32).to_le_bytes());
+        out.extend((line_size as i32).to_le_bytes());
+        for channel in [2, 1, 0] {
+            for pixel in row {
+                out.extend((pixel[channel] as f32).to_le_bytes());
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend(name.as_bytes());
+    out.push(0);
+    out.extend(kind.as_bytes());
+    out.push(0);
+    out.extend((value.len() as i32).to_le_bytes());
+    out.extend(value);
+}
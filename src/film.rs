@@ -0,0 +1,396 @@
+use std::f64::consts::PI;
+
+use crate::Vec3;
+
+// The film collects the samples of a render. Each sample is spread over the pixels around it,
+// weighted by a reconstruction filter centred on it, and each pixel keeps the weighted sum of the
+// samples it has seen and the sum of their weights. Only at the end is one divided by the other.
+//
+// Keeping sums rather than averages means films of the same image can be added together: several
+// threads or machines can each render some of the samples, or some tiles of the image, and merge
+// what they have into one film. A tile's film covers the tile and a filter's radius around it, as
+// its samples reach that far, and the overlaps add up in the merge.
+
+/// How much a sample counts towards the pixels around it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filter {
+    Box { radius: f64 },
+    Tent { radius: f64 },
+    Gaussian { radius: f64, sigma: f64 },
+    MitchellNetravali { radius: f64, b: f64, c: f64 },
+    Lanczos { radius: f64, tau: f64 }, // A sinc windowed by a wider sinc, tau lobes across.
+}
+
+/// A rectangle of pixels, from (x0, y0) up to but not including (x1, y1), counting from the top
+/// left of the image.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PixelBounds {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+/// Float RGB pixels collected from weighted samples.
+pub struct Film {
+    pub width: u32, // Of the whole image, which the film may only hold part of.
+    pub height: u32,
+    pub window: PixelBounds, // The pixels the film holds.
+    pub filter: Filter,
+    pixels: Vec<FilmPixel>,
+}
+
+#[derive(Clone, Default)]
+struct FilmPixel {
+    colour: Vec3, // The sum of the weighted samples.
+    weight: f64,
+}
+
+const MAGIC: &[u8; 4] = b"FILM";
+// The bytes each pixel takes when the film is encoded.
+const PIXEL_SIZE: usize = 32;
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter::Box { radius: 0.5 }
+    }
+}
+
+impl Filter {
+    /// Reads a filter from its name on the command line, with its usual settings.
+    pub fn parse(name: &str) -> Result<Filter, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "box" => Ok(Filter::Box { radius: 0.5 }),
+            "tent" | "triangle" => Ok(Filter::Tent { radius: 1.0 }),
+            "gaussian" => Ok(Filter::Gaussian { radius: 1.5, sigma: 0.5 }),
+            "mitchell" => Ok(Filter::MitchellNetravali { radius: 2.0, b: 1.0 / 3.0, c: 1.0 / 3.0 }),
+            "lanczos" => Ok(Filter::Lanczos { radius: 3.0, tau: 3.0 }),
+            _ => Err(format!("unknown filter {}", name)),
+        }
+    }
+
+    /// How far from a sample, in pixels, the filter reaches.
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius }
+            | Filter::Tent { radius }
+            | Filter::Gaussian { radius, .. }
+            | Filter::MitchellNetravali { radius, .. }
+            | Filter::Lanczos { radius, .. } => radius,
+        }
+    }
+
+    /// The weight of a sample at an offset of (x, y) pixels from a pixel's centre. It can be
+    /// negative for the filters that sharpen.
+    pub fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        let x = x.abs();
+        if x > self.radius() {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box { .. } => 1.0,
+            Filter::Tent { radius } => radius - x,
+            Filter::Gaussian { radius, sigma } => {
+                // Shifted down so it reaches zero at the radius, rather than stopping short.
+                let gaussian = |x: f64| (-x * x / (2.0 * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(radius)).max(0.0)
+            }
+            Filter::MitchellNetravali { radius, b, c } => {
+                let x = 2.0 * x / radius;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x.powi(3) + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+                } else {
+                    ((-b - 6.0 * c) * x.powi(3) + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                }
+            }
+            Filter::Lanczos { tau, .. } => sinc(x) * sinc(x / tau),
+        }
+    }
+
+    /// Writes the filter as bytes, to be read back with `read`.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let (kind, a, b, c) = match *self {
+            Filter::Box { radius } => (0_u32, radius, 0.0, 0.0),
+            Filter::Tent { radius } => (1, radius, 0.0, 0.0),
+            Filter::Gaussian { radius, sigma } => (2, radius, sigma, 0.0),
+            Filter::MitchellNetravali { radius, b, c } => (3, radius, b, c),
+            Filter::Lanczos { radius, tau } => (4, radius, tau, 0.0),
+        };
+        out.extend(kind.to_le_bytes());
+        for v in [a, b, c] {
+            out.extend(v.to_le_bytes());
+        }
+    }
+
+    pub fn read(reader: &mut Reader) -> Result<Filter, String> {
+        let kind = reader.u32()?;
+        let (a, b, c) = (reader.f64()?, reader.f64()?, reader.f64()?);
+        match kind {
+            0 => Ok(Filter::Box { radius: a }),
+            1 => Ok(Filter::Tent { radius: a }),
+            2 => Ok(Filter::Gaussian { radius: a, sigma: b }),
+            3 => Ok(Filter::MitchellNetravali { radius: a, b, c }),
+            4 => Ok(Filter::Lanczos { radius: a, tau: b }),
+            _ => Err(format!("unknown filter {}", kind)),
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+impl PixelBounds {
+    pub fn new(x0: u32, y0: u32, x1: u32, y1: u32) -> PixelBounds {
+        PixelBounds {
+            x0,
+            y0,
+            x1,
+            y1,
+        }
+    }
+
+    /// Reads bounds written as x0,y0,x1,y1 in pixels.
+    pub fn parse(text: &str) -> Result<PixelBounds, String> {
+        let values: Vec<u32> = text.split(',').map(|v| v.trim().parse()).collect::<Result<_, _>>()
+            .map_err(|_| format!("bad pixel bounds {}", text))?;
+        match values[..] {
+            [x0, y0, x1, y1] if x0 < x1 && y0 < y1 => Ok(PixelBounds::new(x0, y0, x1, y1)),
+            _ => Err(format!("bad pixel bounds {}, which should be x0,y0,x1,y1", text)),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.x1.saturating_sub(self.x0)
+    }
+
+    pub fn height(&self) -> u32 {
+        self.y1.saturating_sub(self.y0)
+    }
+
+    pub fn area(&self) -> usize {
+        self.width() as usize * self.height() as usize
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+    }
+
+    /// The pixels in both bounds, which may be none.
+    pub fn intersect(&self, other: &PixelBounds) -> PixelBounds {
+        PixelBounds::new(
+            self.x0.max(other.x0),
+            self.y0.max(other.y0),
+            self.x1.min(other.x1).max(self.x0.max(other.x0)),
+            self.y1.min(other.y1).max(self.y0.max(other.y0)),
+        )
+    }
+
+    /// Splits the bounds into tiles no bigger than `size` pixels square, in rows from the top.
+    pub fn tiles(&self, size: u32) -> Vec<PixelBounds> {
+        let size = size.max(1);
+        let mut tiles = Vec::new();
+        for y in (self.y0..self.y1).step_by(size as usize) {
+            for x in (self.x0..self.x1).step_by(size as usize) {
+                tiles.push(PixelBounds::new(x, y, (x + size).min(self.x1), (y + size).min(self.y1)));
+            }
+        }
+        tiles
+    }
+}
+
+impl Film {
+    /// A film for the whole of an image.
+    pub fn new(width: u32, height: u32, filter: Filter) -> Film {
+        Film::cropped(width, height, PixelBounds::new(0, 0, width, height), filter)
+    }
+
+    /// A film holding only some of an image's pixels.
+    pub fn cropped(width: u32, height: u32, window: PixelBounds, filter: Filter) -> Film {
+        let window = window.intersect(&PixelBounds::new(0, 0, width, height));
+        Film {
+            width,
+            height,
+            window,
+            filter,
+            pixels: vec![FilmPixel::default(); window.area()],
+        }
+    }
+
+    /// An empty film for the samples of one tile of this one, reaching a filter's radius beyond
+    /// it, to be merged back in when the tile is done.
+    pub fn tile(&self, tile: &PixelBounds) -> Film {
+        Film::cropped(self.width, self.height, self.tile_window(tile), self.filter)
+    }
+
+    /// The pixels `tile` gives a tile's film.
+    pub fn tile_window(&self, tile: &PixelBounds) -> PixelBounds {
+        let reach = self.filter.radius().ceil() as u32;
+        let grown = PixelBounds::new(
+            tile.x0.saturating_sub(reach),
+            tile.y0.saturating_sub(reach),
+            tile.x1.saturating_add(reach),
+            tile.y1.saturating_add(reach),
+        );
+        grown.intersect(&self.window)
+    }
+
+    /// Adds a sample taken at (x, y), in pixels from the top left of the image, to every pixel
+    /// the filter reaches.
+    pub fn add_sample(&mut self, x: f64, y: f64, colour: &Vec3) {
+        let radius = self.filter.radius();
+        let first = |p: f64, min: u32| ((p - 0.5 - radius).ceil().max(min as f64)) as u32;
+        let last = |p: f64, max: u32| ((p - 0.5 + radius).floor() + 1.0).clamp(0.0, max as f64) as u32;
+
+        let w = &self.window;
+        for py in first(y, w.y0)..last(y, w.y1) {
+            for px in first(x, w.x0)..last(x, w.x1) {
+                let weight = self.filter.evaluate(px as f64 + 0.5 - x, py as f64 + 0.5 - y);
+                if weight == 0.0 {
+                    continue;
+                }
+                let index = self.index(px, py);
+                let pixel = &mut self.pixels[index];
+                pixel.colour = pixel.colour.clone() + colour.mul(weight);
+                pixel.weight += weight;
+            }
+        }
+    }
+
+    /// Adds another film of the same image into this one, wherever their windows overlap.
+    pub fn merge(&mut self, other: &Film) -> Result<(), String> {
+        if (other.width, other.height) != (self.width, self.height) {
+            return Err(String::from("films of different images can't be merged"));
+        }
+        let overlap = self.window.intersect(&other.window);
+        for y in overlap.y0..overlap.y1 {
+            for x in overlap.x0..overlap.x1 {
+                let (to, from) = (self.index(x, y), other.index(x, y));
+                let pixel = &mut self.pixels[to];
+                pixel.colour = pixel.colour.clone() + other.pixels[from].colour.clone();
+                pixel.weight += other.pixels[from].weight;
+            }
+        }
+        Ok(())
+    }
+
+    /// The film's pixels, with the filter's weights divided out, top row first. Pixels no sample
+    /// reached are black.
+    pub fn pixels(&self) -> Vec<Vec3> {
+        self.pixels_in(&self.window)
+    }
+
+    /// The pixels inside some bounds, as `pixels` gives them, leaving out any the film doesn't hold.
+    pub fn pixels_in(&self, bounds: &PixelBounds) -> Vec<Vec3> {
+        let bounds = bounds.intersect(&self.window);
+        let mut out = Vec::with_capacity(bounds.area());
+        for y in bounds.y0..bounds.y1 {
+            for x in bounds.x0..bounds.x1 {
+                let p = &self.pixels[self.index(x, y)];
+                out.push(if p.weight.abs() < 1e-12 { Vec3::default() } else { p.colour.div(p.weight) });
+            }
+        }
+        out
+    }
+
+    /// Writes the film out as bytes, to be read back with `decode` by another process.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(48 + self.pixels.len() * PIXEL_SIZE);
+        out.extend(MAGIC);
+        let w = &self.window;
+        for v in [self.width, self.height, w.x0, w.y0, w.x1, w.y1] {
+            out.extend(v.to_le_bytes());
+        }
+        self.filter.write(&mut out);
+        for pixel in &self.pixels {
+            for v in [pixel.colour.x, pixel.colour.y, pixel.colour.z, pixel.weight] {
+                out.extend(v.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    /// Reads a film written by `encode`.
+    pub fn decode(bytes: &[u8]) -> Result<Film, String> {
+        Film::read(&mut Reader::new(bytes))
+    }
+
+    /// Reads a film written by `encode` from the front of some bytes, leaving whatever follows.
+    pub fn read(reader: &mut Reader) -> Result<Film, String> {
+        if reader.take(4)? != MAGIC {
+            return Err(String::from("not a film"));
+        }
+        let (width, height) = (reader.u32()?, reader.u32()?);
+        let window = PixelBounds::new(reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?);
+        let filter = Filter::read(reader)?;
+        if window.intersect(&PixelBounds::new(0, 0, width, height)) != window {
+            return Err(String::from("the film's window is outside its image"));
+        }
+        // The window's size comes from the data, so make sure its pixels are all there before
+        // making room for them.
+        reader.expect(window.area(), PIXEL_SIZE)?;
+
+        let mut film = Film::cropped(width, height, window, filter);
+        for pixel in film.pixels.iter_mut() {
+            pixel.colour = Vec3::new(reader.f64()?, reader.f64()?, reader.f64()?);
+            pixel.weight = reader.f64()?;
+        }
+        Ok(film)
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        ((y - self.window.y0) * self.window.width() + (x - self.window.x0)) as usize
+    }
+}
+
+/// Reads little-endian values from the front of some bytes.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    pub fn new(bytes: &[u8]) -> Reader<'_> {
+        Reader { bytes, offset: 0 }
+    }
+
+    pub fn take(&mut self, count: usize) -> Result<&[u8], String> {
+        let cut_short = || String::from("the data is cut short");
+        let end = self.offset.checked_add(count).ok_or_else(cut_short)?;
+        let taken = self.bytes.get(self.offset..end).ok_or_else(cut_short)?;
+        self.offset = end;
+        Ok(taken)
+    }
+
+    /// Checks that there are `count` more records of `size` bytes to come, before room is made
+    /// for them.
+    pub fn expect(&self, count: usize, size: usize) -> Result<(), String> {
+        match count.checked_mul(size) {
+            Some(length) if length <= self.bytes.len() - self.offset => Ok(()),
+            _ => Err(String::from("the data is cut short")),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let b = self.take(8)?;
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+}
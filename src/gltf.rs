@@ -0,0 +1,1028 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs;
+use std::path::{Component, Path};
+use std::rc::Rc;
+
+use serde_json::Value;
+
+use crate::{
+    AnimatedTransform, BvhNode, Camera, ColourSpace, DirectionalLight, HittableList, Hittable, ImageTexture, Instance,
+    Interpolate, Interpolation, Keyframe, Light, Material, NormalMap, PointLight, Principled, Projection, Quaternion, Scene,
+    SpotLight, Texture, Track, Transform, TriangleMesh, Trs, Vec3, Wrap,
+};
+
+// glTF 2.0 scenes, as a .gltf JSON file with its buffers and images either beside it or embedded
+// as base64 data URIs, or as a single binary .glb file. Everything is read from local files in the
+// scene's folder or below it; a scene referring to anything over the network, or anywhere else on
+// disk, is rejected.
+//
+// Each node becomes an instance holding its mesh and its children, so the hierarchy of transforms
+// is kept and meshes used by several nodes are only built once. Materials become principled
+// materials, with texture coordinates from TEXCOORD_0 and any normal map applied over the top.
+// Cameras and KHR_lights_punctual lights are placed by their nodes' transforms, and
+// light intensities are used as they are, so scenes exported from Blender should use its
+// "Unitless" lighting mode.
+//
+// glTF has no way of displacing a surface, so a material's extras can give one:
+//
+//     "extras": {"displacement": {"texture": {"index": 2}, "scale": 0.05, "subdivisions": 4}}
+//
+// Meshes with the material are then subdivided that many times as they're loaded, at most four,
+// and their vertices pushed out along their normals by the texture's height times the scale. A
+// scene can have up to 16 million displaced triangles.
+//
+// Animations move nodes, and through KHR_animation_pointer can also change cameras' fields of view
+// and materials' colours, metalness, roughness and emission. A scene is loaded as it is at a given
+// time. Nodes are given their whole animation, so anything caught moving while the shutter is open
+// is blurred, but cameras, lights and materials are fixed as they are at that time.
+
+// Extensions we can load a scene needing. Any other optional extensions are ignored.
+const SUPPORTED_EXTENSIONS: [&str; 9] = [
+    "KHR_animation_pointer",
+    "KHR_lights_punctual",
+    "KHR_materials_anisotropy",
+    "KHR_materials_clearcoat",
+    "KHR_materials_emissive_strength",
+    "KHR_materials_ior",
+    "KHR_materials_sheen",
+    "KHR_materials_specular",
+    "KHR_materials_transmission",
+];
+
+// The most times a displaced mesh can be subdivided, each of which makes four triangles of one,
+// and the most triangles a scene's displaced meshes can have between them.
+const MAX_SUBDIVISIONS: usize = 4;
+const MAX_DISPLACED_TRIANGLES: usize = 1 << 24;
+
+// The most elements an accessor without a buffer view can have, as nothing else limits how many
+// it asks for.
+const MAX_UNBUFFERED_ELEMENTS: usize = 1 << 22;
+
+// The aspect ratio of cameras that don't give one.
+const DEFAULT_ASPECT_RATIO: f64 = 16.0 / 9.0;
+
+/// Loads a glTF or GLB scene as it is `time` seconds into its animation. Cameras in it render
+/// images `image_width` pixels wide, with their shutters open for `shutter_speed` seconds from
+/// then, and its colours, which glTF gives in linear sRGB, are converted to `working_space`.
+pub fn load_gltf(path: &str,
+                 image_width: u32,
+                 working_space: ColourSpace,
+                 time: f64,
+                 shutter_speed: f64
+) -> Result<Scene, String> {
+    let bytes = fs::read(path).map_err(|e| format!("couldn't read scene {}: {}", path, e))?;
+    let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    parse_gltf(&bytes, Some(base), image_width, working_space, time, shutter_speed)
+        .map_err(|e| format!("couldn't load scene {}: {}", path, e))
+}
+
+/// Reads a glTF or GLB scene, finding any files it refers to relative to `base`. Without a base,
+/// the scene can't refer to files, and everything has to be embedded in it.
+pub fn parse_gltf(bytes: &[u8],
+                  base: Option<&Path>,
+                  image_width: u32,
+                  working_space: ColourSpace,
+                  time: f64,
+                  shutter_speed: f64
+) -> Result<Scene, String> {
+    let (json, bin) = if bytes.starts_with(b"glTF") {
+        split_glb(bytes)?
+    } else {
+        (bytes, None)
+    };
+    let json: Value = serde_json::from_slice(json).map_err(|e| format!("bad JSON: {}", e))?;
+
+    let version = json.get("asset").and_then(|a| a.get("version")).and_then(Value::as_str).unwrap_or("");
+    if !version.starts_with("2.") {
+        return Err(format!("unsupported glTF version '{}'", version));
+    }
+    for extension in array(&json, "extensionsRequired") {
+        let name = extension.as_str().unwrap_or("");
+        if !SUPPORTED_EXTENSIONS.contains(&name) {
+            return Err(format!("needs the unsupported extension {}", name));
+        }
+    }
+
+    let mut buffers = Vec::new();
+    for (i, buffer) in array(&json, "buffers").iter().enumerate() {
+        let data = match buffer.get("uri").and_then(Value::as_str) {
+            Some(uri) => load_uri(uri, base)?,
+            None if i == 0 => bin.ok_or("buffer 0 has no uri and there is no binary chunk")?.to_vec(),
+            None => return Err(format!("buffer {} has no uri", i)),
+        };
+        if data.len() < index_of(buffer, "byteLength").unwrap_or(0) {
+            return Err(format!("buffer {} is shorter than its byteLength", i));
+        }
+        buffers.push(data);
+    }
+
+    let mut importer = Importer {
+        json: &json,
+        base,
+        image_width,
+        working_space,
+        time,
+        shutter_speed,
+        buffers,
+        animation: Animation::default(),
+        textures: HashMap::new(),
+        materials: Vec::new(),
+        displacements: Vec::new(),
+        displaced_triangles: 0,
+        meshes: vec![None; array(&json, "meshes").len()],
+        default_material: Rc::new(Principled {
+            metallic: 1.0,
+            roughness: 1.0,
+            ..Principled::new(Vec3::from(1.0))
+        }),
+        lights: Vec::new(),
+        cameras: Vec::new(),
+    };
+    importer.animation = importer.animations()?;
+    for i in 0..array(&json, "materials").len() {
+        let material = importer.material(i).map_err(|e| format!("material {}: {}", i, e))?;
+        importer.materials.push(material);
+        let displacement = importer.displacement(i).map_err(|e| format!("material {}: {}", i, e))?;
+        importer.displacements.push(displacement);
+    }
+
+    let scene_index = index_of(&json, "scene").unwrap_or(0);
+    let scene = array(&json, "scenes").get(scene_index).ok_or("there is no scene to load")?;
+
+    let mut world = HittableList::new();
+    let identity = Trs::default().to_transform();
+    for node in array(scene, "nodes") {
+        let node = node.as_u64().ok_or("bad node index")? as usize;
+        if let Some(object) = importer.node(node, &identity, 0)? {
+            world.add(object);
+        }
+    }
+
+    let world: Rc<dyn Hittable> = if world.object_list.is_empty() {
+        Rc::new(world)
+    } else {
+        Rc::new(BvhNode::new(&world, time, time + shutter_speed))
+    };
+    Ok(Scene {
+        world,
+        lights: importer.lights,
+        cameras: importer.cameras,
+        duration: importer.animation.end,
+        sky: 1.0,
+    })
+}
+
+/// Rewrites a glTF or GLB scene with every file it refers to, found relative to `base`, embedded
+/// in it as a data URI, so it can be loaded anywhere without the files that were beside it.
+pub fn embed_gltf(bytes: &[u8], base: &Path) -> Result<Vec<u8>, String> {
+    let (json, bin) = if bytes.starts_with(b"glTF") {
+        split_glb(bytes)?
+    } else {
+        (bytes, None)
+    };
+    let mut json: Value = serde_json::from_slice(json).map_err(|e| format!("bad JSON: {}", e))?;
+
+    for (key, default_type) in [("buffers", "application/octet-stream"), ("images", "image/png")] {
+        let Some(entries) = json.get_mut(key).and_then(Value::as_array_mut) else {
+            continue;
+        };
+        for entry in entries {
+            let uri = match entry.get("uri").and_then(Value::as_str) {
+                Some(uri) if !uri.starts_with("data:") => uri,
+                _ => continue,
+            };
+            let data = load_uri(uri, Some(base))?;
+            let media_type = entry.get("mimeType").and_then(Value::as_str).unwrap_or(default_type);
+            entry["uri"] = Value::from(format!("data:{};base64,{}", media_type, encode_base64(&data)));
+        }
+    }
+
+    let mut json = serde_json::to_vec(&json).map_err(|e| e.to_string())?;
+    let Some(bin) = bin else {
+        return Ok(json);
+    };
+    // GLB chunks are padded to four bytes, the JSON with spaces and the binary chunk with zeros.
+    json.resize(json.len().div_ceil(4) * 4, b' ');
+    let mut bin = bin.to_vec();
+    bin.resize(bin.len().div_ceil(4) * 4, 0);
+    let mut out = Vec::with_capacity(28 + json.len() + bin.len());
+    out.extend(b"glTF");
+    out.extend(2u32.to_le_bytes());
+    out.extend(((28 + json.len() + bin.len()) as u32).to_le_bytes());
+    for (chunk_type, data) in [(0x4E4F534Au32, &json), (0x004E4942, &bin)] {
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend(chunk_type.to_le_bytes());
+        out.extend(data);
+    }
+    Ok(out)
+}
+
+/// Adds a track to an animation's tracks of its kind, giving when it ends.
+fn insert<T: Interpolate>(tracks: &mut HashMap<usize, Track<T>>, index: usize, track: Track<T>) -> f64 {
+    let end = track.end();
+    tracks.insert(index, track);
+    end
+}
+
+/// The track animating something, or one holding it still if it isn't animated.
+fn track_or<T: Interpolate>(tracks: &HashMap<usize, Track<T>>, index: usize, still: T) -> Track<T> {
+    tracks.get(&index).cloned().unwrap_or_else(|| Track::constant(still))
+}
+
+/// Splits a GLB file into its JSON chunk and its binary chunk, if it has one.
+fn split_glb(bytes: &[u8]) -> Result<(&[u8], Option<&[u8]>), String> {
+    let read_u32 = |at: usize| -> Result<usize, String> {
+        bytes.get(at..at + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+            .ok_or_else(|| String::from("GLB file ends early"))
+    };
+    if read_u32(4)? != 2 {
+        return Err(String::from("only version 2 GLB files are supported"));
+    }
+    let length = read_u32(8)?.min(bytes.len());
+
+    let mut json = None;
+    let mut bin = None;
+    let mut at = 12;
+    while at + 8 <= length {
+        let chunk_length = read_u32(at)?;
+        let chunk_type = read_u32(at + 4)?;
+        let data = bytes.get(at + 8..at + 8 + chunk_length).ok_or("GLB chunk runs off the end of the file")?;
+        match chunk_type {
+            0x4E4F534A => json = json.or(Some(data)),
+            0x004E4942 => bin = bin.or(Some(data)),
+            _ => {} // Chunks of unknown types are skipped.
+        }
+        at += 8 + chunk_length;
+    }
+
+    Ok((json.ok_or("GLB file has no JSON chunk")?, bin))
+}
+
+/// Reads the data behind a URI, which is either embedded in it or a path relative to `base` that
+/// stays inside it.
+fn load_uri(uri: &str, base: Option<&Path>) -> Result<Vec<u8>, String> {
+    if let Some(rest) = uri.strip_prefix("data:") {
+        let (header, data) = rest.split_once(',').ok_or("bad data URI")?;
+        if !header.ends_with(";base64") {
+            return Err(String::from("only base64 data URIs are supported"));
+        }
+        return decode_base64(data);
+    }
+    if uri.contains("://") {
+        return Err(format!("can't load {}, as only local files are read", uri));
+    }
+
+    let base = base.ok_or_else(|| format!("can't load {}, as this scene can't refer to files", uri))?;
+    let relative = percent_decode(uri);
+    if !Path::new(&relative).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Err(format!("can't load {}, as only files in the scene's folder or below it are read", uri));
+    }
+    let path = base.join(relative);
+    fs::read(&path).map_err(|e| format!("couldn't read {}: {}", path.display(), e))
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in data.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            c if c.is_ascii_whitespace() => continue,
+            _ => return Err(String::from("bad base64 data")),
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Ok(out)
+}
+
+fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Turns escapes like %20 in a relative URI back into the characters they stand for.
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn array<'a>(v: &'a Value, key: &str) -> &'a [Value] {
+    v.get(key).and_then(Value::as_array).map_or(&[], |a| a.as_slice())
+}
+
+fn index_of(v: &Value, key: &str) -> Option<usize> {
+    v.get(key).and_then(Value::as_u64).map(|i| i as usize)
+}
+
+fn number(v: &Value, key: &str, default: f64) -> f64 {
+    v.get(key).and_then(Value::as_f64).unwrap_or(default)
+}
+
+fn numbers(v: &Value, key: &str) -> Option<Vec<f64>> {
+    v.get(key).and_then(Value::as_array).map(|a| a.iter().map(|n| n.as_f64().unwrap_or(0.0)).collect())
+}
+
+fn vec3(v: &Value, key: &str, default: f64) -> Vec3 {
+    match numbers(v, key) {
+        Some(n) if n.len() >= 3 => Vec3::new(n[0], n[1], n[2]),
+        _ => Vec3::from(default),
+    }
+}
+
+/// The first three components of an accessor element, as a vector.
+fn element_vec3(c: &[f64]) -> Vec3 {
+    let at = |i: usize| c.get(i).copied().unwrap_or(0.0);
+    Vec3::new(at(0), at(1), at(2))
+}
+
+/// A rotation from an accessor element, which glTF gives as x, y, z and w.
+fn element_rotation(c: &[f64]) -> Quaternion {
+    let at = |i: usize| c.get(i).copied().unwrap_or(0.0);
+    Quaternion { w: at(3), x: at(0), y: at(1), z: at(2) }.normalised()
+}
+
+/// Reads one component of an accessor, mapping normalized integers onto 0 to 1 (or -1 to 1).
+fn read_component(b: &[u8], component_type: u64, normalized: bool) -> f64 {
+    let (value, max) = match component_type {
+        5120 => (b[0] as i8 as f64, 127.0),
+        5121 => (b[0] as f64, 255.0),
+        5122 => (i16::from_le_bytes([b[0], b[1]]) as f64, 32767.0),
+        5123 => (u16::from_le_bytes([b[0], b[1]]) as f64, 65535.0),
+        5125 => (u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+        _ => (f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+    };
+    if normalized {
+        (value / max).max(-1.0)
+    } else {
+        value
+    }
+}
+
+fn component_size(component_type: u64) -> Result<usize, String> {
+    match component_type {
+        5120 | 5121 => Ok(1),
+        5122 | 5123 => Ok(2),
+        5125 | 5126 => Ok(4),
+        _ => Err(format!("unknown component type {}", component_type)),
+    }
+}
+
+fn wrap_mode(sampler: &Value, key: &str) -> Wrap {
+    match index_of(sampler, key) {
+        Some(33071) => Wrap::Clamp,
+        Some(33648) => Wrap::Mirror,
+        _ => Wrap::Repeat,
+    }
+}
+
+/// Builds the scene up from the parsed JSON, keeping anything shared so it is only built once.
+struct Importer<'a> {
+    json: &'a Value,
+    base: Option<&'a Path>,
+    image_width: u32,
+    working_space: ColourSpace,
+    time: f64, // Into the animation, which is when the scene is loaded as it is.
+    shutter_speed: f64, // How long cameras' shutters stay open from then.
+    buffers: Vec<Vec<u8>>,
+    animation: Animation,
+    textures: HashMap<(usize, bool), Rc<dyn Texture>>, // By texture index and whether it is sRGB.
+    materials: Vec<Rc<dyn Material>>,
+    displacements: Vec<Option<Displacement>>, // By material.
+    displaced_triangles: usize, // How many the meshes displaced so far have.
+    meshes: Vec<Option<Rc<dyn Hittable>>>,
+    default_material: Rc<dyn Material>,
+    lights: Vec<Rc<dyn Light>>,
+    cameras: Vec<Camera>,
+}
+
+/// A height texture pushing the meshes with a material out along their normals.
+struct Displacement {
+    height: Rc<dyn Texture>,
+    scale: f64,
+    subdivisions: usize,
+}
+
+/// The tracks of every animation in the file, by the index of the node, camera or material they
+/// animate.
+#[derive(Default)]
+struct Animation {
+    translations: HashMap<usize, Track<Vec3>>,
+    rotations: HashMap<usize, Track<Quaternion>>,
+    scales: HashMap<usize, Track<Vec3>>,
+    fields_of_view: HashMap<usize, Track<f64>>,
+    base_colours: HashMap<usize, Track<Vec3>>,
+    metallic: HashMap<usize, Track<f64>>,
+    roughness: HashMap<usize, Track<f64>>,
+    emissive: HashMap<usize, Track<Vec3>>,
+    end: f64, // When the last track stops changing.
+}
+
+impl<'a> Importer<'a> {
+    /// A colour from the file, in the working space.
+    fn colour(&self, c: Vec3) -> Vec3 {
+        ColourSpace::LinearSrgb.convert(&c, self.working_space)
+    }
+
+    /// The bytes of a buffer view, and the stride between its elements if it gives one.
+    fn view(&self, index: usize) -> Result<(&[u8], Option<usize>), String> {
+        let view = array(self.json, "bufferViews").get(index).ok_or(format!("no buffer view {}", index))?;
+        let buffer = index_of(view, "buffer").and_then(|b| self.buffers.get(b)).ok_or("buffer view has no buffer")?;
+        let offset = index_of(view, "byteOffset").unwrap_or(0);
+        let length = index_of(view, "byteLength").unwrap_or(0);
+        let data = offset.checked_add(length)
+            .and_then(|end| buffer.get(offset..end))
+            .ok_or("buffer view runs off the end of its buffer")?;
+        Ok((data, index_of(view, "byteStride").filter(|s| *s > 0)))
+    }
+
+    /// Reads `count` elements of `components` components each from a buffer view.
+    fn read_elements(&self,
+                     view: usize,
+                     offset: usize,
+                     count: usize,
+                     components: usize,
+                     component_type: u64,
+                     normalized: bool
+    ) -> Result<Vec<Vec<f64>>, String> {
+        let (data, stride) = self.view(view)?;
+        let size = component_size(component_type)?;
+        let stride = stride.unwrap_or(size * components);
+
+        // The count comes from the file, so check the view holds that many elements before making
+        // room for them.
+        let end = match count.checked_sub(1) {
+            Some(last) => last.checked_mul(stride)
+                .and_then(|start| start.checked_add(offset))
+                .and_then(|start| start.checked_add(size * components)),
+            None => Some(0),
+        };
+        if end.is_none_or(|end| end > data.len()) {
+            return Err(String::from("accessor runs off the end of its buffer view"));
+        }
+
+        let mut elements = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut element = Vec::with_capacity(components);
+            for c in 0..components {
+                let at = offset + i * stride + c * size;
+                let b = data.get(at..at + size).ok_or("accessor runs off the end of its buffer view")?;
+                element.push(read_component(b, component_type, normalized));
+            }
+            elements.push(element);
+        }
+        Ok(elements)
+    }
+
+    /// Reads every element of an accessor, applying any sparse substitutions.
+    fn accessor(&self, index: usize) -> Result<Vec<Vec<f64>>, String> {
+        let accessor = array(self.json, "accessors").get(index).ok_or(format!("no accessor {}", index))?;
+        let count = index_of(accessor, "count").ok_or("accessor has no count")?;
+        let component_type = accessor.get("componentType").and_then(Value::as_u64).unwrap_or(5126);
+        let normalized = accessor.get("normalized").and_then(Value::as_bool).unwrap_or(false);
+        let components = match accessor.get("type").and_then(Value::as_str) {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            other => return Err(format!("unsupported accessor type {:?}", other)),
+        };
+
+        // An accessor without a buffer view is all zeros, before any sparse values.
+        let mut elements = match index_of(accessor, "bufferView") {
+            Some(view) => {
+                let offset = index_of(accessor, "byteOffset").unwrap_or(0);
+                self.read_elements(view, offset, count, components, component_type, normalized)?
+            }
+            None if count <= MAX_UNBUFFERED_ELEMENTS => vec![vec![0.0; components]; count],
+            None => return Err(format!("accessor {} has too many elements", index)),
+        };
+
+        if let Some(sparse) = accessor.get("sparse") {
+            let sparse_count = index_of(sparse, "count").ok_or("sparse accessor has no count")?;
+            let indices = sparse.get("indices").ok_or("sparse accessor has no indices")?;
+            let values = sparse.get("values").ok_or("sparse accessor has no values")?;
+            let targets = self.read_elements(
+                index_of(indices, "bufferView").ok_or("sparse indices have no buffer view")?,
+                index_of(indices, "byteOffset").unwrap_or(0),
+                sparse_count,
+                1,
+                indices.get("componentType").and_then(Value::as_u64).unwrap_or(5125),
+                false,
+            )?;
+            let replacements = self.read_elements(
+                index_of(values, "bufferView").ok_or("sparse values have no buffer view")?,
+                index_of(values, "byteOffset").unwrap_or(0),
+                sparse_count,
+                components,
+                component_type,
+                normalized,
+            )?;
+            for (target, replacement) in targets.iter().zip(replacements) {
+                let element = elements.get_mut(target[0] as usize).ok_or("sparse index out of range")?;
+                *element = replacement;
+            }
+        }
+        Ok(elements)
+    }
+
+    /// The texture a material refers to with a texture info object, if it refers to one.
+    fn texture(&mut self, info: Option<&Value>, srgb: bool) -> Result<Option<Rc<dyn Texture>>, String> {
+        let json = self.json;
+        let texture_index = match info.and_then(|i| index_of(i, "index")) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        if let Some(texture) = self.textures.get(&(texture_index, srgb)) {
+            return Ok(Some(texture.clone()));
+        }
+
+        let texture = array(json, "textures").get(texture_index).ok_or(format!("no texture {}", texture_index))?;
+        let source = index_of(texture, "source").ok_or(format!("texture {} has no PNG or JPEG image", texture_index))?;
+        let image = array(json, "images").get(source).ok_or(format!("no image {}", source))?;
+        let bytes = match (image.get("uri").and_then(Value::as_str), index_of(image, "bufferView")) {
+            (Some(uri), _) => load_uri(uri, self.base)?,
+            (None, Some(view)) => self.view(view)?.0.to_vec(),
+            (None, None) => return Err(format!("image {} has no data", source)),
+        };
+
+        let mut decoded = ImageTexture::decode(&bytes, srgb).map_err(|e| format!("image {}: {}", source, e))?;
+        if let Some(sampler) = index_of(texture, "sampler").and_then(|s| array(json, "samplers").get(s)) {
+            decoded.wrap_u = wrap_mode(sampler, "wrapS");
+            decoded.wrap_v = wrap_mode(sampler, "wrapT");
+        }
+        if srgb {
+            decoded.convert(ColourSpace::LinearSrgb, self.working_space);
+        }
+
+        let decoded: Rc<dyn Texture> = Rc::new(decoded);
+        self.textures.insert((texture_index, srgb), decoded.clone());
+        Ok(Some(decoded))
+    }
+
+    /// A principled material, from the metallic-roughness model and whichever of the material
+    /// extensions that map onto its sliders are present, wrapped in the material's normal map if
+    /// it has one. The extensions' textures are ignored.
+    fn material(&mut self, index: usize) -> Result<Rc<dyn Material>, String> {
+        let json = self.json;
+        let material = &array(json, "materials")[index];
+        let pbr = material.get("pbrMetallicRoughness").unwrap_or(&Value::Null);
+        let extension = |name: &str| material.get("extensions").and_then(|e| e.get(name));
+
+        let animation = &self.animation;
+        let base_colour = self.animated(&animation.base_colours, index, vec3(pbr, "baseColorFactor", 1.0));
+        let mut out = Principled::new(self.colour(base_colour));
+        out.metallic = self.animated(&animation.metallic, index, number(pbr, "metallicFactor", 1.0));
+        out.roughness = self.animated(&animation.roughness, index, number(pbr, "roughnessFactor", 1.0));
+        out.base_colour_texture = self.texture(pbr.get("baseColorTexture"), true)?;
+        out.metallic_roughness_texture = self.texture(pbr.get("metallicRoughnessTexture"), false)?;
+
+        let strength = extension("KHR_materials_emissive_strength").map_or(1.0, |e| number(e, "emissiveStrength", 1.0));
+        let emissive = self.animated(&self.animation.emissive, index, vec3(material, "emissiveFactor", 0.0));
+        out.emissive = self.colour(emissive).mul(strength);
+        out.emissive_texture = self.texture(material.get("emissiveTexture"), true)?;
+
+        // The specular slider is the head-on reflectance over 8%, which glTF gives by the index
+        // of refraction, scaled by the specular extension.
+        out.ior = extension("KHR_materials_ior").map_or(1.5, |e| number(e, "ior", 1.5));
+        let f0 = ((out.ior - 1.0) / (out.ior + 1.0)).powi(2);
+        let specular = extension("KHR_materials_specular").map_or(1.0, |e| {
+            let colour = vec3(e, "specularColorFactor", 1.0);
+            number(e, "specularFactor", 1.0) * colour.x.max(colour.y).max(colour.z)
+        });
+        out.specular = (f0 * specular / 0.08).min(1.0);
+
+        if let Some(e) = extension("KHR_materials_transmission") {
+            out.transmission = number(e, "transmissionFactor", 0.0);
+        }
+        if let Some(e) = extension("KHR_materials_clearcoat") {
+            out.clearcoat = number(e, "clearcoatFactor", 0.0);
+            out.clearcoat_gloss = 1.0 - number(e, "clearcoatRoughnessFactor", 0.0);
+        }
+        if let Some(e) = extension("KHR_materials_sheen") {
+            let colour = vec3(e, "sheenColorFactor", 0.0);
+            out.sheen = colour.x.max(colour.y).max(colour.z);
+            out.sheen_tint = 0.0;
+        }
+        if let Some(e) = extension("KHR_materials_anisotropy") {
+            out.anisotropic = number(e, "anisotropyStrength", 0.0);
+        }
+
+        // Normal maps point green along +v, which is up the image as glTF expects once its
+        // texture coordinates have been flipped.
+        let normal_info = material.get("normalTexture");
+        match self.texture(normal_info, false)? {
+            Some(map) => {
+                let strength = normal_info.map_or(1.0, |i| number(i, "scale", 1.0));
+                Ok(Rc::new(NormalMap::new(Rc::new(out), map, strength)))
+            }
+            None => Ok(Rc::new(out)),
+        }
+    }
+
+    /// The displacement in a material's extras, if it has one.
+    fn displacement(&mut self, index: usize) -> Result<Option<Displacement>, String> {
+        let json = self.json;
+        let extras = match array(json, "materials")[index].get("extras").and_then(|e| e.get("displacement")) {
+            Some(extras) => extras,
+            None => return Ok(None),
+        };
+        let height = self.texture(extras.get("texture"), false)?.ok_or("the displacement has no texture")?;
+        let subdivisions = index_of(extras, "subdivisions").unwrap_or(0);
+        if subdivisions > MAX_SUBDIVISIONS {
+            return Err(format!("a mesh can be subdivided at most {} times", MAX_SUBDIVISIONS));
+        }
+        Ok(Some(Displacement {
+            height,
+            scale: number(extras, "scale", 1.0),
+            subdivisions,
+        }))
+    }
+
+    /// A value as it is at the time the scene is loaded at, if it is animated.
+    fn animated<T: Interpolate>(&self, tracks: &HashMap<usize, Track<T>>, index: usize, still: T) -> T {
+        tracks.get(&index).map_or(still, |track| track.at(self.time))
+    }
+
+    /// Reads the tracks of every animation. Channels animating anything we can't, such as morph
+    /// target weights, are skipped.
+    fn animations(&self) -> Result<Animation, String> {
+        let mut animation = Animation::default();
+        for (i, json) in array(self.json, "animations").iter().enumerate() {
+            let samplers = array(json, "samplers");
+            for channel in array(json, "channels") {
+                let sampler = index_of(channel, "sampler").and_then(|s| samplers.get(s))
+                    .ok_or(format!("animation {} has a channel without a sampler", i))?;
+                let target = channel.get("target").unwrap_or(&Value::Null);
+                let pointer = match target.get("path").and_then(Value::as_str) {
+                    Some("pointer") => target.get("extensions")
+                        .and_then(|e| e.get("KHR_animation_pointer"))
+                        .and_then(|p| p.get("pointer"))
+                        .and_then(Value::as_str)
+                        .map(String::from),
+                    Some(path) => index_of(target, "node").map(|node| format!("/nodes/{}/{}", node, path)),
+                    None => None,
+                };
+                let pointer = match pointer {
+                    Some(pointer) => pointer,
+                    None => continue,
+                };
+
+                let parts: Vec<&str> = pointer.trim_start_matches('/').split('/').collect();
+                let index = match parts.get(1).and_then(|i| i.parse::<usize>().ok()) {
+                    Some(index) => index,
+                    None => continue,
+                };
+                let end = self.add_track(&mut animation, parts[0], &parts[2..], index, sampler)
+                    .map_err(|e| format!("animation {}, {}: {}", i, pointer, e))?;
+                if let Some(end) = end {
+                    animation.end = animation.end.max(end);
+                }
+            }
+        }
+        Ok(animation)
+    }
+
+    /// Adds the track for a property of a node, camera or material, giving when it ends, or None
+    /// if it isn't a property we can animate.
+    fn add_track(&self,
+                 animation: &mut Animation,
+                 kind: &str,
+                 property: &[&str],
+                 index: usize,
+                 sampler: &Value
+    ) -> Result<Option<f64>, String> {
+        let scalar = |c: &[f64]| c[0];
+        Ok(Some(match (kind, property) {
+            ("nodes", ["translation"]) => insert(&mut animation.translations, index, self.track(sampler, element_vec3)?),
+            ("nodes", ["rotation"]) => insert(&mut animation.rotations, index, self.track(sampler, element_rotation)?),
+            ("nodes", ["scale"]) => insert(&mut animation.scales, index, self.track(sampler, element_vec3)?),
+            ("cameras", ["perspective", "yfov"]) => {
+                insert(&mut animation.fields_of_view, index, self.track(sampler, scalar)?)
+            }
+            ("materials", ["pbrMetallicRoughness", "baseColorFactor"]) => {
+                insert(&mut animation.base_colours, index, self.track(sampler, element_vec3)?)
+            }
+            ("materials", ["pbrMetallicRoughness", "metallicFactor"]) => {
+                insert(&mut animation.metallic, index, self.track(sampler, scalar)?)
+            }
+            ("materials", ["pbrMetallicRoughness", "roughnessFactor"]) => {
+                insert(&mut animation.roughness, index, self.track(sampler, scalar)?)
+            }
+            ("materials", ["emissiveFactor"]) => insert(&mut animation.emissive, index, self.track(sampler, element_vec3)?),
+            _ => return Ok(None),
+        }))
+    }
+
+    /// A track from an animation sampler, making its values from the components of each of the
+    /// sampler's outputs.
+    fn track<T: Interpolate>(&self, sampler: &Value, value: impl Fn(&[f64]) -> T) -> Result<Track<T>, String> {
+        let times = self.accessor(index_of(sampler, "input").ok_or("the sampler has no input")?)?;
+        let outputs = self.accessor(index_of(sampler, "output").ok_or("the sampler has no output")?)?;
+        let interpolation = match sampler.get("interpolation").and_then(Value::as_str).unwrap_or("LINEAR") {
+            "LINEAR" => Interpolation::Linear,
+            "STEP" => Interpolation::Step,
+            "CUBICSPLINE" => Interpolation::Bezier,
+            other => return Err(format!("unknown interpolation {}", other)),
+        };
+        let per_keyframe = if interpolation == Interpolation::Bezier { 3 } else { 1 };
+        if outputs.len() != times.len() * per_keyframe || outputs.iter().any(Vec::is_empty) {
+            return Err(String::from("the sampler's input and output don't match"));
+        }
+        let time = |i: usize| times[i].first().copied().unwrap_or(0.0);
+
+        let mut keyframes = Vec::with_capacity(times.len());
+        for i in 0..times.len() {
+            if interpolation != Interpolation::Bezier {
+                keyframes.push(Keyframe::new(time(i), value(&outputs[i])));
+                continue;
+            }
+            // Cubic splines are given as an in-tangent, the value and an out-tangent. Those are
+            // the tangents of a Hermite curve, and its Bezier handles are a third of the way along
+            // them to the keyframes on either side.
+            let (in_tangent, v, out_tangent) = (&outputs[3 * i], &outputs[3 * i + 1], &outputs[3 * i + 2]);
+            let before = if i > 0 { time(i) - time(i - 1) } else { 0.0 };
+            let after = if i + 1 < times.len() { time(i + 1) - time(i) } else { 0.0 };
+            let handle = |tangent: &[f64], dt: f64| -> Vec<f64> {
+                v.iter().zip(tangent).map(|(v, m)| v + m * dt / 3.0).collect()
+            };
+            keyframes.push(Keyframe {
+                time: time(i),
+                value: value(v),
+                in_handle: value(&handle(in_tangent, -before)),
+                out_handle: value(&handle(out_tangent, after)),
+            });
+        }
+        Track::new(interpolation, keyframes)
+    }
+
+    /// All the primitives of a mesh, gathered into one BVH.
+    fn mesh(&mut self, index: usize) -> Result<Rc<dyn Hittable>, String> {
+        if let Some(Some(mesh)) = self.meshes.get(index) {
+            return Ok(mesh.clone());
+        }
+        let mesh = array(self.json, "meshes").get(index).ok_or(format!("no mesh {}", index))?;
+
+        let mut triangles = HittableList::new();
+        for (p, primitive) in array(mesh, "primitives").iter().enumerate() {
+            let material_index = index_of(primitive, "material");
+            let material = match material_index {
+                Some(m) => self.materials.get(m).ok_or(format!("no material {}", m))?.clone(),
+                None => self.default_material.clone(),
+            };
+            let displacements = &self.displacements;
+            let displacement = material_index.and_then(|m| displacements[m].as_ref());
+            let primitive = self.primitive(primitive).map_err(|e| format!("mesh {} primitive {}: {}", index, p, e))?;
+            let primitive = match (primitive, displacement) {
+                (Some(primitive), Some(d)) => {
+                    self.displaced_triangles += primitive.indices.len() << (2 * d.subdivisions);
+                    if self.displaced_triangles > MAX_DISPLACED_TRIANGLES {
+                        return Err(format!("the displaced meshes have more than {} triangles", MAX_DISPLACED_TRIANGLES));
+                    }
+                    Some(primitive.displaced(d.height.as_ref(), d.scale, d.subdivisions))
+                }
+                (primitive, _) => primitive,
+            };
+            if let Some(primitive) = primitive {
+                triangles.object_list.extend(Rc::new(primitive).triangles(material).object_list);
+            }
+        }
+
+        let mesh: Rc<dyn Hittable> = if triangles.object_list.is_empty() {
+            Rc::new(triangles)
+        } else {
+            Rc::new(BvhNode::new(&triangles, 0.0, 0.0))
+        };
+        self.meshes[index] = Some(mesh.clone());
+        Ok(mesh)
+    }
+
+    /// Reads one primitive as a triangle mesh. Points and lines have no surface to hit, so give
+    /// None.
+    fn primitive(&self, primitive: &Value) -> Result<Option<TriangleMesh>, String> {
+        let mode = index_of(primitive, "mode").unwrap_or(4);
+        if mode < 4 {
+            return Ok(None);
+        }
+
+        let attributes = primitive.get("attributes").ok_or("primitive has no attributes")?;
+        let attribute = |name: &str| -> Result<Option<Vec<Vec<f64>>>, String> {
+            index_of(attributes, name).map(|a| self.accessor(a)).transpose()
+        };
+        let to_vec3 = |e: &Vec<f64>| Vec3::new(e[0], e.get(1).cloned().unwrap_or(0.0), e.get(2).cloned().unwrap_or(0.0));
+
+        let positions: Vec<Vec3> = attribute("POSITION")?.ok_or("primitive has no positions")?.iter().map(to_vec3).collect();
+        let vertices: Vec<usize> = match index_of(primitive, "indices") {
+            Some(i) => self.accessor(i)?.iter().map(|e| e[0] as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+
+        let mut indices = Vec::new();
+        match mode {
+            4 => indices.extend(vertices.chunks_exact(3).map(|t| [t[0], t[1], t[2]])),
+            // Strips flip every other triangle to keep the winding consistent.
+            5 => {
+                for i in 0..vertices.len().saturating_sub(2) {
+                    indices.push(if i % 2 == 0 {
+                        [vertices[i], vertices[i + 1], vertices[i + 2]]
+                    } else {
+                        [vertices[i + 1], vertices[i], vertices[i + 2]]
+                    });
+                }
+            }
+            6 => {
+                for i in 1..vertices.len().saturating_sub(1) {
+                    indices.push([vertices[i], vertices[i + 1], vertices[0]]);
+                }
+            }
+            _ => return Err(format!("unknown primitive mode {}", mode)),
+        }
+
+        let mut mesh = TriangleMesh::new(positions, indices);
+        mesh.normals = attribute("NORMAL")?.map(|n| n.iter().map(to_vec3).collect());
+        // glTF puts v = 0 at the top of the image, where we put it at the bottom.
+        mesh.uvs = attribute("TEXCOORD_0")?.map(|t| t.iter().map(|e| (e[0], 1.0 - e[1])).collect());
+        mesh.colours = attribute("COLOR_0")?.map(|c| c.iter().map(to_vec3).collect());
+        mesh.validate()?;
+        Ok(Some(mesh))
+    }
+
+    /// Places a node and everything below it, under its parent's transform to the world. Gives
+    /// None for nodes with no objects in or under them.
+    fn node(&mut self, index: usize, parent: &Transform, depth: usize) -> Result<Option<Rc<dyn Hittable>>, String> {
+        let json = self.json;
+        let nodes = array(json, "nodes");
+        let node = nodes.get(index).ok_or(format!("no node {}", index))?;
+        if depth > nodes.len() {
+            return Err(String::from("the node hierarchy has a cycle"));
+        }
+
+        let trs = match numbers(node, "matrix") {
+            Some(m) if m.len() == 16 => {
+                // Column major.
+                let mut matrix = [[0.0; 4]; 4];
+                for (i, row) in matrix.iter_mut().enumerate() {
+                    for (j, v) in row.iter_mut().enumerate() {
+                        *v = m[j * 4 + i];
+                    }
+                }
+                Trs::from_matrix(&matrix)
+            }
+            _ => {
+                let r = numbers(node, "rotation").filter(|r| r.len() == 4).unwrap_or_else(|| vec![0.0, 0.0, 0.0, 1.0]);
+                Trs::new(
+                    vec3(node, "translation", 0.0),
+                    Quaternion { w: r[3], x: r[0], y: r[1], z: r[2] },
+                    vec3(node, "scale", 1.0),
+                )
+            }
+        };
+        let animation = &self.animation;
+        let transform = AnimatedTransform {
+            translation: track_or(&animation.translations, index, trs.translation.clone()),
+            rotation: track_or(&animation.rotations, index, trs.rotation.clone()),
+            scale: track_or(&animation.scales, index, trs.scale.clone()),
+        };
+        let to_world = parent.compose(&transform.at(self.time));
+
+        if let Some(camera) = index_of(node, "camera") {
+            if let Some(camera) = self.camera(camera, &to_world)? {
+                self.cameras.push(camera);
+            }
+        }
+        let light = node.get("extensions").and_then(|e| e.get("KHR_lights_punctual")).and_then(|l| index_of(l, "light"));
+        if let Some(light) = light {
+            let light = self.light(light, &to_world)?;
+            self.lights.push(light);
+        }
+
+        let mut contents = HittableList::new();
+        if let Some(mesh) = index_of(node, "mesh") {
+            contents.add(self.mesh(mesh)?);
+        }
+        for child in array(node, "children") {
+            let child = child.as_u64().ok_or("bad child index")? as usize;
+            if let Some(object) = self.node(child, &to_world, depth + 1)? {
+                contents.add(object);
+            }
+        }
+
+        let object: Rc<dyn Hittable> = match contents.object_list.len() {
+            0 => return Ok(None),
+            1 => contents.object_list.remove(0),
+            _ => Rc::new(contents),
+        };
+        Ok(Some(Rc::new(Instance::new(object, transform))))
+    }
+
+    /// A camera looking down its node's negative z axis, with y up, opening its shutter at the time
+    /// the scene is loaded at. Orthographic cameras see xmag either side of the middle and ymag
+    /// above and below it. Gives None for cameras that are neither.
+    fn camera(&self, index: usize, to_world: &Transform) -> Result<Option<Camera>, String> {
+        let camera = array(self.json, "cameras").get(index).ok_or(format!("no camera {}", index))?;
+        let (vertical_fov, aspect_ratio, projection) = if let Some(perspective) = camera.get("perspective") {
+            let yfov = self.animated(&self.animation.fields_of_view, index, number(perspective, "yfov", PI / 3.0));
+            (yfov.to_degrees(), number(perspective, "aspectRatio", DEFAULT_ASPECT_RATIO), Projection::Perspective)
+        } else if let Some(orthographic) = camera.get("orthographic") {
+            let (xmag, ymag) = (number(orthographic, "xmag", 1.0).abs(), number(orthographic, "ymag", 1.0).abs());
+            if xmag == 0.0 || ymag == 0.0 {
+                return Err(format!("camera {} has no size", index));
+            }
+            (90.0, xmag / ymag, Projection::Orthographic { height: 2.0 * ymag })
+        } else {
+            return Ok(None);
+        };
+
+        let position = to_world.apply_point(&Vec3::default());
+        let forward = to_world.apply_vector(&Vec3::new(0.0, 0.0, -1.0));
+        let up = to_world.apply_vector(&Vec3::new(0.0, 1.0, 0.0));
+
+        let mut camera = Camera::looking_at(
+            self.image_width,
+            aspect_ratio,
+            vertical_fov,
+            position.clone(),
+            position + forward,
+            up,
+        );
+        camera.projection = projection;
+        camera.shutter_open = self.time;
+        camera.shutter_close = self.time + self.shutter_speed;
+        Ok(Some(camera))
+    }
+
+    /// A punctual light, shining down its node's negative z axis if it has a direction.
+    fn light(&self, index: usize, to_world: &Transform) -> Result<Rc<dyn Light>, String> {
+        let lights = self.json.get("extensions")
+            .and_then(|e| e.get("KHR_lights_punctual"))
+            .map_or(&[][..], |l| array(l, "lights"));
+        let light = lights.get(index).ok_or(format!("no light {}", index))?;
+
+        let intensity = self.colour(vec3(light, "color", 1.0)).mul(number(light, "intensity", 1.0));
+        let range = light.get("range").and_then(Value::as_f64);
+        let position = to_world.apply_point(&Vec3::default());
+        let direction = to_world.apply_vector(&Vec3::new(0.0, 0.0, -1.0));
+
+        Ok(match light.get("type").and_then(Value::as_str) {
+            Some("directional") => Rc::new(DirectionalLight::new(direction, intensity)),
+            Some("point") => {
+                let mut point = PointLight::new(position, intensity);
+                point.range = range;
+                Rc::new(point)
+            }
+            Some("spot") => {
+                let cone = light.get("spot").unwrap_or(&Value::Null);
+                let mut spot = SpotLight::new(
+                    position,
+                    direction,
+                    intensity,
+                    number(cone, "innerConeAngle", 0.0),
+                    number(cone, "outerConeAngle", PI / 4.0),
+                );
+                spot.range = range;
+                Rc::new(spot)
+            }
+            other => return Err(format!("unknown light type {:?}", other)),
+        })
+    }
+}
@@ -0,0 +1,366 @@
+use std::fs;
+
+use crate::Vec3;
+use crate::microfacet::refract;
+
+// A camera lens made of real glass, for the bokeh, vignetting and distortion a thin lens can't
+// give. A lens prescription lists the lens's spherical surfaces from the front, facing the scene,
+// to the back, facing the film, one to a line:
+//
+//     # radius  thickness  index  aperture
+//     29.475    3.76       1.67   25.2
+//     0         4.5        0      17.1
+//
+// giving each surface's radius of curvature (positive when it bulges towards the scene, and 0 for
+// the aperture stop or a flat surface), the distance along the axis to the next surface, the
+// refractive index of the glass behind it (0 or 1 for air), and the diameter of its opening, all in
+// millimetres. The last thickness is how far the film sits behind the back of the lens, which is
+// worked out again to focus the lens at the distance asked for. The lens can be stopped down to a
+// given f-number by narrowing the aperture stop.
+//
+// Rays are traced from a point on the film through the surfaces out into the scene, in the
+// camera's space with the film at z = 0 and the lens in front of it along -z. Most rays aimed at the
+// back of the lens are blocked on the way through, so the exit pupil, the region of the back
+// surface that light from a point on the film can get through, is worked out beforehand for rings
+// of the film at increasing distances from its middle, and rays are aimed only into it. This is
+// after the realistic camera in "Physically Based Rendering" by Pharr, Jakob and Humphreys.
+
+/// A lens traced surface by surface, with the film behind it.
+pub struct LensSystem {
+    elements: Vec<LensElement>, // From the front of the lens to the back.
+    exit_pupils: Vec<Option<PupilBounds>>, // For each ring of the film, from the middle outwards.
+    f_number: f64, // As it's been stopped down to, or as wide as it opens.
+    film_diagonal: f64, // In metres.
+}
+
+/// One surface of a lens, measured in metres.
+struct LensElement {
+    radius: f64, // 0 for the aperture stop.
+    thickness: f64,
+    eta: f64, // Of the glass or air behind the surface.
+    aperture_radius: f64,
+    z: f64, // Where it crosses the axis.
+}
+
+/// The part of the back surface that light reaches a point on the film through, for a point on
+/// the positive x axis.
+#[derive(Clone, Copy)]
+struct PupilBounds {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+// The diagonal of a 35 mm full-frame sensor, in metres, which films are unless told otherwise.
+pub const FILM_DIAGONAL: f64 = 0.04327;
+// How many rings of the film exit pupils are found for, and how many rays across the back of the
+// lens each is found with.
+const PUPIL_RINGS: usize = 64;
+const PUPIL_SAMPLES: usize = 128;
+
+impl PupilBounds {
+    fn area(&self) -> f64 {
+        (self.max_x - self.min_x) * (self.max_y - self.min_y)
+    }
+}
+
+impl LensSystem {
+    pub fn load(path: &str,
+                focus_distance: f64,
+                f_number: Option<f64>,
+                film_diagonal: f64
+    ) -> Result<LensSystem, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("couldn't read lens {}: {}", path, e))?;
+        LensSystem::parse(&text, focus_distance, f_number, film_diagonal)
+            .map_err(|e| format!("couldn't load lens {}: {}", path, e))
+    }
+
+    /// Reads a lens prescription, focusing the lens on things `focus_distance` metres in front of
+    /// the film, and stopping it down to `f_number` if there is one, rather than leaving it wide
+    /// open. The film is `film_diagonal` metres corner to corner, which with the lens's focal
+    /// length decides how much of the scene the picture takes in.
+    pub fn parse(text: &str,
+                 focus_distance: f64,
+                 f_number: Option<f64>,
+                 film_diagonal: f64
+    ) -> Result<LensSystem, String> {
+        if !(film_diagonal.is_finite() && film_diagonal > 0.0) {
+            return Err(String::from("the film has no size"));
+        }
+        let mut elements = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let values = line.split_whitespace().map(str::parse::<f64>).collect::<Result<Vec<f64>, _>>();
+            let values = match values {
+                Ok(values) if values.len() == 4 && values.iter().all(|v| v.is_finite()) => values,
+                _ => return Err(format!("line {} should be a radius, thickness, index and aperture", number + 1)),
+            };
+            if values[1] < 0.0 || values[2] < 0.0 || values[3] <= 0.0 {
+                return Err(format!("line {} has a negative size or index", number + 1));
+            }
+            elements.push(LensElement {
+                radius: values[0] / 1000.0,
+                thickness: values[1] / 1000.0,
+                eta: if values[2] == 0.0 { 1.0 } else { values[2] },
+                aperture_radius: values[3] / 2000.0,
+                z: 0.0,
+            });
+        }
+        if elements.is_empty() {
+            return Err(String::from("the lens has no surfaces"));
+        }
+
+        let mut lens = LensSystem {
+            elements,
+            exit_pupils: Vec::new(),
+            f_number: 0.0,
+            film_diagonal,
+        };
+        lens.place_elements();
+        let focal_length = lens.focus(focus_distance)?;
+        lens.f_number = lens.widest_f_number(focal_length);
+        if let Some(f_number) = f_number {
+            lens.stop_down(f_number)?;
+        }
+        lens.exit_pupils = (0..PUPIL_RINGS).map(|ring| lens.exit_pupil(ring)).collect();
+        if lens.exit_pupils[0].is_none() {
+            return Err(String::from("no light gets through the lens"));
+        }
+        Ok(lens)
+    }
+
+    /// The ray into the scene for a point on the film, from `s` = 0 at the left to 1 at the right
+    /// and `t` = 0 at the bottom to 1 at the top, in the camera's space, with the irradiance it
+    /// brings the film for each unit of radiance along it. Gives None if the ray is blocked inside
+    /// the lens.
+    pub fn sample(&self, s: f64, t: f64, aspect_ratio: f64) -> Option<(Vec3, Vec3, f64)> {
+        // The lens turns the image upside down, so the top of the picture is at the bottom of the
+        // film.
+        let height = self.film_diagonal / (1.0 + aspect_ratio * aspect_ratio).sqrt();
+        let film = Vec3::new((0.5 - s) * height * aspect_ratio, (0.5 - t) * height, 0.0);
+
+        let r = (film.x * film.x + film.y * film.y).sqrt();
+        let ring = ((r / (self.film_diagonal / 2.0) * PUPIL_RINGS as f64) as usize).min(PUPIL_RINGS - 1);
+        let bounds = self.exit_pupils[ring]?;
+        let x = bounds.min_x + fastrand::f64() * (bounds.max_x - bounds.min_x);
+        let y = bounds.min_y + fastrand::f64() * (bounds.max_y - bounds.min_y);
+        // The pupil was found along the x axis, so turn it round to where the point is.
+        let (sin, cos) = if r > 0.0 { (film.y / r, film.x / r) } else { (0.0, 1.0) };
+        let rear = Vec3::new(cos * x - sin * y, sin * x + cos * y, self.rear_z());
+
+        let direction = (rear - film.clone()).unit_vector();
+        let (origin, out) = self.trace_from_film(film, direction.clone())?;
+        // Light reaching the film at a slant is spread more thinly over it, and the pupil it's
+        // sampled over spans a solid angle of about its area over its distance squared.
+        let cos2 = direction.z * direction.z;
+        let weight = cos2 * cos2 * bounds.area() / (self.rear_z() * self.rear_z());
+        Some((origin, out, weight))
+    }
+
+    /// Works out where each surface is along the axis from their thicknesses.
+    fn place_elements(&mut self) {
+        let mut z = 0.0;
+        for element in self.elements.iter_mut().rev() {
+            z -= element.thickness;
+            element.z = z;
+        }
+    }
+
+    fn rear_z(&self) -> f64 {
+        self.elements[self.elements.len() - 1].z
+    }
+
+    /// Follows a ray from the film out through the front of the lens, or gives None if it's
+    /// blocked.
+    fn trace_from_film(&self, origin: Vec3, direction: Vec3) -> Option<(Vec3, Vec3)> {
+        let (mut origin, mut direction) = (origin, direction);
+        for (i, element) in self.elements.iter().enumerate().rev() {
+            let eta_after = if i > 0 { self.elements[i - 1].eta } else { 1.0 };
+            let (point, bent) = element.pass(&origin, &direction, element.eta, eta_after)?;
+            origin = point;
+            direction = bent;
+        }
+        Some((origin, direction))
+    }
+
+    /// Follows a ray from the scene in through the back of the lens.
+    fn trace_from_scene(&self, origin: Vec3, direction: Vec3) -> Option<(Vec3, Vec3)> {
+        let (mut origin, mut direction) = (origin, direction);
+        for (i, element) in self.elements.iter().enumerate() {
+            let eta_before = if i > 0 { self.elements[i - 1].eta } else { 1.0 };
+            let (point, bent) = element.pass(&origin, &direction, eta_before, element.eta)?;
+            origin = point;
+            direction = bent;
+        }
+        Some((origin, direction))
+    }
+
+    /// Moves the film so that things `distance` in front of it are sharp, treating the lens as a
+    /// thick lens found by tracing rays parallel to the axis through it from each side.
+    /// Gives the lens's focal length.
+    fn focus(&mut self, distance: f64) -> Result<f64, String> {
+        let height = self.film_diagonal / 1000.0;
+        let front_z = self.elements[0].z;
+
+        // From the scene side: where the rays cross the axis behind the lens, and where they seem
+        // to have bent.
+        let from_scene = self.trace_from_scene(Vec3::new(height, 0.0, front_z - 1.0), Vec3::new(0.0, 0.0, 1.0));
+        // From the film side: the same in front of the lens.
+        let from_film = self.trace_from_film(Vec3::new(height, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let (image, object) = match (from_scene, from_film) {
+            (Some(image), Some(object)) => (image, object),
+            _ => return Err(String::from("rays along the axis don't get through the lens")),
+        };
+        let cardinal_points = |(origin, direction): &(Vec3, Vec3)| {
+            let focal = origin.z + direction.z * -origin.x / direction.x;
+            let principal = origin.z + direction.z * (height - origin.x) / direction.x;
+            (focal, principal)
+        };
+        let (image_focal, image_principal) = cardinal_points(&image);
+        let (_, object_principal) = cardinal_points(&object);
+        let focal_length = image_focal - image_principal;
+        if !(focal_length.is_finite() && focal_length > 0.0) {
+            return Err(String::from("the lens doesn't bring light to a focus"));
+        }
+
+        // Moving the lens out by `delta` has to put the object and its image on either side of it
+        // at distances that satisfy the thin lens equation from its principal planes.
+        let a = object_principal + distance;
+        let b = -image_principal;
+        let discriminant = (a + b) * (a + b - 4.0 * focal_length);
+        if distance <= 0.0 || discriminant.is_nan() || discriminant < 0.0 {
+            return Err(format!("the lens can't focus as close as {} m", distance));
+        }
+        let delta = (a - b - discriminant.sqrt()) / 2.0;
+        let last = self.elements.len() - 1;
+        self.elements[last].thickness += delta;
+        self.place_elements();
+        Ok(focal_length)
+    }
+
+    /// The f-number the lens is open at.
+    pub fn f_number(&self) -> f64 {
+        self.f_number
+    }
+
+    /// The lens's f-number wide open: its focal length over the width of the widest beam of light
+    /// along the axis it lets in.
+    fn widest_f_number(&self, focal_length: f64) -> f64 {
+        // Find the widest beam that gets through by halving the range it could be in.
+        let front = &self.elements[0];
+        let (mut through, mut blocked) = (0.0, front.aperture_radius);
+        for _ in 0..32 {
+            let height = (through + blocked) / 2.0;
+            let ray = self.trace_from_scene(Vec3::new(height, 0.0, front.z - 1.0), Vec3::new(0.0, 0.0, 1.0));
+            if ray.is_some() {
+                through = height;
+            } else {
+                blocked = height;
+            }
+        }
+        focal_length / (2.0 * through)
+    }
+
+    /// Narrows the aperture stop until the lens's f-number is `f_number`.
+    fn stop_down(&mut self, f_number: f64) -> Result<(), String> {
+        let stop = self.elements.iter().position(|e| e.radius == 0.0).ok_or("the lens has no aperture stop")?;
+        // Lenses are sold with their f-numbers rounded, so one a little wider than the lens can
+        // manage just leaves it wide open.
+        let widest = self.f_number;
+        if f_number < widest * 0.95 {
+            return Err(format!("the lens opens no wider than f/{:.1}", widest));
+        }
+        // The beam narrows in step with the stop while the stop is what limits it.
+        self.elements[stop].aperture_radius *= (widest / f_number).min(1.0);
+        self.f_number = f_number.max(widest);
+        Ok(())
+    }
+
+    /// Finds the exit pupil for a ring of the film by aiming a grid of rays from points across the
+    /// ring at the back of the lens, and bounding those that get through. None if none do.
+    fn exit_pupil(&self, ring: usize) -> Option<PupilBounds> {
+        let inner = ring as f64 / PUPIL_RINGS as f64 * self.film_diagonal / 2.0;
+        let outer = (ring + 1) as f64 / PUPIL_RINGS as f64 * self.film_diagonal / 2.0;
+        let rear = &self.elements[self.elements.len() - 1];
+        let extent = rear.aperture_radius * 1.5;
+        let step = 2.0 * extent / PUPIL_SAMPLES as f64;
+
+        let mut bounds: Option<PupilBounds> = None;
+        let count = PUPIL_SAMPLES * PUPIL_SAMPLES;
+        for i in 0..count {
+            let (column, row) = (i % PUPIL_SAMPLES, i / PUPIL_SAMPLES);
+            let film = Vec3::new(inner + (outer - inner) * (i as f64 + 0.5) / count as f64, 0.0, 0.0);
+            let x = -extent + (column as f64 + 0.5) * step;
+            let y = -extent + (row as f64 + 0.5) * step;
+            let direction = Vec3::new(x, y, rear.z) - film.clone();
+            if self.trace_from_film(film, direction).is_none() {
+                continue;
+            }
+            let (min_x, min_y, max_x, max_y) = match bounds {
+                Some(b) => (b.min_x.min(x), b.min_y.min(y), b.max_x.max(x), b.max_y.max(y)),
+                None => (x, y, x, y),
+            };
+            bounds = Some(PupilBounds { min_x, min_y, max_x, max_y });
+        }
+        // Widen the bounds by a step, to cover rays that slipped through between the samples.
+        bounds.map(|b| PupilBounds {
+            min_x: b.min_x - step,
+            min_y: b.min_y - step,
+            max_x: b.max_x + step,
+            max_y: b.max_y + step,
+        })
+    }
+}
+
+impl LensElement {
+    /// Carries a ray to the surface and bends it into the glass or air beyond, going from a
+    /// refractive index of `eta_from` to `eta_to`. Gives None if the ray misses the opening or is
+    /// reflected back.
+    fn pass(&self, origin: &Vec3, direction: &Vec3, eta_from: f64, eta_to: f64) -> Option<(Vec3, Vec3)> {
+        if self.radius == 0.0 {
+            // The aperture stop just blocks.
+            if direction.z == 0.0 {
+                return None;
+            }
+            let point = origin.clone() + direction.mul((self.z - origin.z) / direction.z);
+            return self.within_opening(&point).then(|| (point, direction.clone()));
+        }
+
+        let centre = Vec3::new(0.0, 0.0, self.z + self.radius);
+        let oc = origin.clone() - centre.clone();
+        let a = direction.length_sq();
+        let half_b = oc.dot(direction);
+        let c = oc.length_sq() - self.radius * self.radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        // A surface bulging towards the ray is met at the nearer crossing of its sphere.
+        let nearer = (direction.z > 0.0) != (self.radius < 0.0);
+        let root = discriminant.sqrt();
+        let t = if nearer { (-half_b - root) / a } else { (-half_b + root) / a };
+        if t <= 0.0 {
+            return None;
+        }
+        let point = origin.clone() + direction.mul(t);
+        if !self.within_opening(&point) {
+            return None;
+        }
+
+        let incoming = direction.unit_vector().mul(-1.0);
+        let mut normal = (point.clone() - centre).unit_vector();
+        if normal.dot(&incoming) < 0.0 {
+            normal = normal.mul(-1.0);
+        }
+        let bent = refract(&incoming, &normal, eta_to / eta_from)?;
+        Some((point, bent))
+    }
+
+    fn within_opening(&self, point: &Vec3) -> bool {
+        point.x * point.x + point.y * point.y <= self.aperture_radius * self.aperture_radius
+    }
+}
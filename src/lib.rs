@@ -1,14 +1,77 @@
+mod animation;
+mod bvh;
+mod checkpoint;
+mod colour;
+mod csg;
+mod denoise;
+mod distributed;
+mod exr;
+mod film;
+mod gltf;
+mod lens;
+mod light;
+mod material;
 mod math;
+mod mesh;
+mod microfacet;
+mod mtl;
 mod objects;
+mod ply;
+mod principled;
+mod projection;
+mod quadrics;
+mod render;
+mod sdf;
+mod server;
+mod shading;
+mod spectrum;
+mod stl;
+mod texture;
+mod transform;
+mod volume;
+mod video;
+mod voxels;
 
 use std::ops::*;
 use std::rc::Rc; // Use this to allow multiple geometry to share the same instance.
 use std::convert::From;
-use fastrand::*;
 
+pub use crate::animation::{Interpolate, Interpolation, Keyframe, Track};
+pub use crate::bvh::{Aabb, BvhNode, surrounding_box};
+pub use crate::checkpoint::{PixelStats, Progress, read_checkpoint, write_checkpoint};
+pub use crate::colour::{Chromaticities, ColourSpace, encode_display};
+pub use crate::csg::{Csg, CsgOperation, Span, combine_spans};
+pub use crate::denoise::Denoiser;
+pub use crate::distributed::{Job, JobEvent, coordinate, work};
+pub use crate::exr::{encode_exr, write_exr};
+pub use crate::film::{Film, Filter, PixelBounds};
+pub use crate::gltf::{embed_gltf, load_gltf, parse_gltf};
+pub use crate::lens::LensSystem;
+pub use crate::light::{DirectionalLight, Light, LightSample, PointLight, SpotLight};
+pub use crate::material::{Lambertian, Material};
 pub use crate::math::degrees_to_radians;
-pub use crate::objects::Camera;
-use sdl2::pixels::Color;
+pub use crate::mesh::{Triangle, TriangleMesh};
+pub use crate::microfacet::{Conductor, Ggx, RoughDielectric};
+pub use crate::mtl::{load_mtl, parse_mtl};
+pub use crate::objects::{Camera, Exposure};
+pub use crate::principled::Principled;
+pub use crate::projection::{FisheyeMapping, Projection, StereoLayout};
+pub use crate::quadrics::{Cone, Cylinder, Hyperboloid, Paraboloid, Torus};
+pub use crate::render::{
+    AdaptiveSampling, Image, RenderSettings, Scene, continue_render, default_camera, default_world, paint_pixels, ray_to_pixel,
+    render_image, sample_heatmap, start_render,
+};
+pub use crate::sdf::{
+    Sdf, SdfBlend, SdfHittable, SdfMandelbulb, SdfRepeat, SdfRoundBox, SdfSphere, SdfTorus, SdfTranslate, SdfTwist,
+};
+pub use crate::server::serve;
+pub use crate::shading::{BumpMap, NormalMap};
+pub use crate::spectrum::{Dispersion, Spectrum, Wavelengths, luminance};
+pub use crate::texture::{ImageTexture, SolidColour, Texture, VertexColour, Wrap};
+pub use crate::transform::{AnimatedTransform, Instance, Quaternion, Transform, Trs};
+pub use crate::video::{GifWriter, VideoWriter, Y4mWriter, create_video};
+pub use crate::volume::{ConstantMedium, Fog, GridMedium, HenyeyGreenstein, PhaseMaterial};
+pub use crate::voxels::VoxelGrid;
 
 pub struct Vec3 {
     pub x: f64,
@@ -16,29 +79,82 @@ pub struct Vec3 {
     pub z: f64,
 }
 
+#[derive(Default)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    pub time: f64, // The moment within the camera's shutter interval that the ray was cast.
+    pub wavelength: Option<f64>, // The hero wavelength in nanometres, when rendering spectrally.
 }
 
 pub struct HitRecord {
     pub point: Vec3,
     pub normal: Vec3,
     pub t: f64,
+    pub u: f64, // Surface coordinates of the hit, each from 0 to 1.
+    pub v: f64,
     pub front_face: bool,
+    pub material: Option<Rc<dyn Material>>,
+    pub colour: Option<Vec3>, // Interpolated from the vertices, for meshes that have colours.
+    pub dpdu: Vec3, // How the point moves with u and v, for shading frames. Zero when unknown.
+    pub dpdv: Vec3,
 }
 
 pub struct Sphere {
     pub centre: Vec3,
     pub radius: f64,
+    pub material: Rc<dyn Material>,
 }
 
+/// A sphere moving in a straight line from `centre0` at `time0` to `centre1` at `time1`.
+pub struct MovingSphere {
+    pub centre0: Vec3,
+    pub centre1: Vec3,
+    pub time0: f64,
+    pub time1: f64,
+    pub radius: f64,
+    pub material: Rc<dyn Material>,
+}
+
+/// An 8-bit RGBA pixel. Has the same fields as SDL's `Color`, without the library needing SDL.
+pub struct Pixel {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+#[derive(Default)]
 pub struct HittableList {
     pub object_list: Vec<Rc<dyn Hittable>>, // Using a Box as we don't want to be copying around many objects.
 }
 
 pub trait Hittable {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool;
+
+    /// Bounds the object over all of its positions between `time0` and `time1`. Returns false if
+    /// the object has no finite bounds.
+    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut Aabb) -> bool;
+
+    /// Whether the object is a closed solid, whose insides `spans` can give. Only solids can be
+    /// combined with CSG.
+    fn is_solid(&self) -> bool {
+        false
+    }
+
+    /// Lists every stretch of the ray, over its whole length, that lies inside the object, sorted
+    /// along the ray. The spans are added to the end of `spans`, leaving anything already in it
+    /// alone. Returns false if the object is not a closed solid.
+    fn spans(&self, _ray: &Ray, _spans: &mut Vec<Span>) -> bool {
+        false
+    }
+
+    /// The fraction of light passing straight along the ray between `t_min` and `t_max`. Surfaces
+    /// block the light outright, while media let some of it through.
+    fn transmittance(&self, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let mut rec = HitRecord::default();
+        if self.hit(ray, t_min, t_max, &mut rec) { 0.0 } else { 1.0 }
+    }
 }
 
 impl Vec3 {
@@ -67,7 +183,7 @@ impl Vec3 {
     }
 
     pub fn sub(&self, f: f64) -> Vec3 {
-        self.add(f * -1.0)
+        self.add(-f)
     }
 
     pub fn div(&self, f: f64) -> Vec3 {
@@ -98,6 +214,16 @@ impl Vec3 {
         self.div(self.length())
     }
 
+    /// The component-wise minimum of two vectors.
+    pub fn min(&self, o: &Vec3) -> Vec3 {
+        Vec3::new(self.x.min(o.x), self.y.min(o.y), self.z.min(o.z))
+    }
+
+    /// The component-wise maximum of two vectors.
+    pub fn max(&self, o: &Vec3) -> Vec3 {
+        Vec3::new(self.x.max(o.x), self.y.max(o.y), self.z.max(o.z))
+    }
+
     pub fn random_vector() -> Vec3 {
         Vec3::from((fastrand::f64(), fastrand::f64(), fastrand::f64()))
     }
@@ -114,13 +240,42 @@ impl Vec3 {
 
     pub fn random_in_unit_sphere() -> Vec3 {
         loop {
-            let p = Vec3::i_random_vector(-1, 1);
+            let p = Vec3::random_vector().mul(2.0) - unit_vector(1.0);
             if p.length_sq() < 1.0 {
                 return p;
             }
         }
     }
 
+    /// A random direction, picked uniformly over the sphere.
+    pub fn random_unit_vector() -> Vec3 {
+        Vec3::random_in_unit_sphere().unit_vector()
+    }
+
+    /// Returns true if the vector is close to zero in every dimension.
+    pub fn near_zero(&self) -> bool {
+        let s = 1e-8;
+        self.x.abs() < s && self.y.abs() < s && self.z.abs() < s
+    }
+
+    /// Multiplies each component by the matching component of another vector, as when one colour
+    /// filters another.
+    pub fn mul_vec(&self, o: &Vec3) -> Vec3 {
+        Vec3::new(self.x * o.x, self.y * o.y, self.z * o.z)
+    }
+
+    /// Two unit vectors that, together with this one (which must be a unit vector), form an
+    /// orthonormal basis.
+    pub fn orthonormal_basis(&self) -> (Vec3, Vec3) {
+        let sign = 1.0_f64.copysign(self.z);
+        let a = -1.0 / (sign + self.z);
+        let b = self.x * self.y * a;
+        (
+            Vec3::new(1.0 + sign * self.x * self.x * a, sign * b, -sign * self.x),
+            Vec3::new(b, sign + self.y * self.y * a, -self.y),
+        )
+    }
+
 }
 
 impl From<i32> for Vec3 {
@@ -177,11 +332,25 @@ impl Sub for Vec3 {
     }
 }
 
+impl Index<usize> for Vec3 {
+    type Output = f64;
+
+    fn index(&self, axis: usize) -> &f64 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            _ => &self.z,
+        }
+    }
+}
+
 impl Ray {
-    pub fn new(origin: Vec3, direction: Vec3) -> Ray {
+    pub fn new(origin: Vec3, direction: Vec3, time: f64) -> Ray {
         Ray {
             origin,
             direction,
+            time,
+            wavelength: None,
         }
     }
 
@@ -191,18 +360,49 @@ impl Ray {
 }
 
 impl Sphere {
-    pub fn new(centre: Vec3, radius: f64) -> Sphere {
+    pub fn new(centre: Vec3, radius: f64, material: Rc<dyn Material>) -> Sphere {
         Sphere {
             centre,
-            radius
+            radius,
+            material
+        }
+    }
+}
+
+impl MovingSphere {
+    pub fn new(centre0: Vec3,
+               centre1: Vec3,
+               time0: f64,
+               time1: f64,
+               radius: f64,
+               material: Rc<dyn Material>
+    ) -> MovingSphere {
+        MovingSphere {
+            centre0,
+            centre1,
+            time0,
+            time1,
+            radius,
+            material
         }
     }
+
+    /// The centre of the sphere at a given time. A sphere that moves in no time at all stays at
+    /// its first centre.
+    pub fn centre(&self, time: f64) -> Vec3 {
+        if self.time1 == self.time0 {
+            return self.centre0.clone();
+        }
+        let t = (time - self.time0) / (self.time1 - self.time0);
+        self.centre0.clone() + (self.centre1.clone() - self.centre0.clone()).mul(t)
+    }
 }
 
 impl HitRecord {
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: &Vec3) {
         let front_face = ray.direction.dot(outward_normal) < 0.0;
 
+        self.front_face = front_face;
         self.normal = if front_face {
             outward_normal.clone()
         } else {
@@ -213,35 +413,130 @@ impl HitRecord {
 
 impl Hittable for Sphere {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
-        // Calculate the discriminant
-        let oc = ray.origin.clone() - self.centre.clone();
-        let a = ray.direction.length_sq();
-        let half_b = oc.dot(&ray.direction); // we removed the '2' as we can consider the case b = 2h
-        let c = oc.length_sq() - (self.radius * self.radius);
-        let discriminant = half_b * half_b - a * c;
-
-        if discriminant < 0.0 {
-            return false
+        if !hit_sphere(&self.centre, self.radius, ray, t_min, t_max, rec) {
+            return false;
         }
-        let sqrt_d = discriminant.sqrt();
+        rec.material = Some(self.material.clone());
+        true
+    }
 
-        // Find the nearest root that lies in the acceptable range.
-        let mut root = (-half_b - sqrt_d) / a;
-        if root < t_min || t_max < root {
-            root = (-half_b + sqrt_d) / a;
-            if root < t_min || t_max < root {
-                return false;
-            }
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
+        let r = Vec3::from(self.radius);
+        *output_box = Aabb::new(self.centre.clone() - r.clone(), self.centre.clone() + r);
+        true
+    }
+
+    fn is_solid(&self) -> bool {
+        true
+    }
+
+    fn spans(&self, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+        sphere_spans(&self.centre, self.radius, &self.material, ray, spans)
+    }
+}
+
+impl Hittable for MovingSphere {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        if !hit_sphere(&self.centre(ray.time), self.radius, ray, t_min, t_max, rec) {
+            return false;
         }
+        rec.material = Some(self.material.clone());
+        true
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut Aabb) -> bool {
+        let r = Vec3::from(self.radius);
+        let box0 = Aabb::new(self.centre(time0) - r.clone(), self.centre(time0) + r.clone());
+        let box1 = Aabb::new(self.centre(time1) - r.clone(), self.centre(time1) + r);
+        *output_box = surrounding_box(&box0, &box1);
+        true
+    }
 
-        rec.t = root;
-        rec.point = ray.at(rec.t);
-        rec.normal = (rec.point.clone() - self.centre.clone()).div(self.radius);
-        let outward_normal = (rec.point.clone() - self.centre.clone()).div(self.radius);
-        rec.set_face_normal(ray, &outward_normal);
+    fn is_solid(&self) -> bool {
+        true
+    }
 
+    fn spans(&self, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+        sphere_spans(&self.centre(ray.time), self.radius, &self.material, ray, spans)
+    }
+}
+
+fn sphere_spans(centre: &Vec3, radius: f64, material: &Rc<dyn Material>, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+    let oc = ray.origin.clone() - centre.clone();
+    let a = ray.direction.length_sq();
+    let half_b = oc.dot(&ray.direction);
+    let c = oc.length_sq() - (radius * radius);
+    let discriminant = half_b * half_b - a * c;
+
+    // A ray grazing the sphere passes through no volume.
+    if discriminant <= 0.0 || a == 0.0 {
         return true;
     }
+    let sqrt_d = discriminant.sqrt();
+
+    let boundary = |t: f64| {
+        let mut rec = HitRecord::default();
+        rec.t = t;
+        rec.point = ray.at(t);
+        rec.normal = (rec.point.clone() - centre.clone()).div(radius);
+        set_sphere_uv(&rec.normal.clone(), radius, &mut rec);
+        rec.material = Some(material.clone());
+        rec
+    };
+    spans.push(Span::new(boundary((-half_b - sqrt_d) / a), boundary((-half_b + sqrt_d) / a)));
+    true
+}
+
+fn hit_sphere(centre: &Vec3, radius: f64, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+    // Calculate the discriminant
+    let oc = ray.origin.clone() - centre.clone();
+    let a = ray.direction.length_sq();
+    let half_b = oc.dot(&ray.direction); // we removed the '2' as we can consider the case b = 2h
+    let c = oc.length_sq() - (radius * radius);
+    let discriminant = half_b * half_b - a * c;
+
+    if discriminant < 0.0 {
+        return false
+    }
+    let sqrt_d = discriminant.sqrt();
+
+    // Find the nearest root that lies in the acceptable range.
+    let mut root = (-half_b - sqrt_d) / a;
+    if root < t_min || t_max < root {
+        root = (-half_b + sqrt_d) / a;
+        if root < t_min || t_max < root {
+            return false;
+        }
+    }
+
+    rec.t = root;
+    rec.point = ray.at(rec.t);
+    let outward_normal = (rec.point.clone() - centre.clone()).div(radius);
+    rec.set_face_normal(ray, &outward_normal);
+    set_sphere_uv(&outward_normal, radius, rec);
+
+    true
+}
+
+/// Sets the surface coordinates for a point on a sphere, given its outward normal. u goes around
+/// from -x, and v goes from the bottom to the top.
+fn set_sphere_uv(outward_normal: &Vec3, radius: f64, rec: &mut HitRecord) {
+    let n = outward_normal;
+    let theta = (-n.y).acos();
+    let phi = (-n.z).atan2(n.x) + std::f64::consts::PI;
+    rec.u = phi / (2.0 * std::f64::consts::PI);
+    rec.v = theta / std::f64::consts::PI;
+
+    // The derivatives of the point with respect to u and v. Both vanish or blow up at the poles,
+    // where they're left at zero.
+    let s = (1.0 - n.y * n.y).max(0.0).sqrt();
+    if s > 1e-8 {
+        rec.dpdu = Vec3::new(n.z, 0.0, -n.x).mul(2.0 * std::f64::consts::PI * radius);
+        rec.dpdv = Vec3::new(-n.y * n.x / s, s, -n.y * n.z / s).mul(std::f64::consts::PI * radius);
+    } else {
+        rec.dpdu = Vec3::default();
+        rec.dpdv = Vec3::default();
+    }
 }
 
 impl HittableList {
@@ -251,11 +546,11 @@ impl HittableList {
         }
     }
 
-    pub fn clear(&mut self) -> () {
+    pub fn clear(&mut self) {
         self.object_list.clear()
     }
 
-    pub fn add(&mut self, object: Rc<dyn Hittable>) -> () {
+    pub fn add(&mut self, object: Rc<dyn Hittable>) {
         self.object_list.push(object)
     }
 }
@@ -263,12 +558,7 @@ impl HittableList {
 impl Hittable for HittableList {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
 
-        let mut temp_record = HitRecord {
-            point: Default::default(),
-            normal: Default::default(),
-            t: 0.0,
-            front_face: false
-        };
+        let mut temp_record = HitRecord::default();
 
         let mut hit_anything = false;
         let mut closest_current = t_max;
@@ -277,17 +567,57 @@ impl Hittable for HittableList {
         for i in &self.object_list {
             if i.hit(ray, t_min, closest_current, &mut temp_record) {
                 hit_anything = true;
-                closest_current = temp_record.clone().t;
-
-                // Find a better way to do this- basically changing rec to the values in temp_record.
-                rec.point = temp_record.point.clone();
-                rec.normal = temp_record.normal.clone();
-                rec.t = temp_record.t.clone();
-                rec.front_face = temp_record.front_face.clone();
+                closest_current = temp_record.t;
+                *rec = temp_record.clone();
             }
         }
         hit_anything
     }
+
+    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut Aabb) -> bool {
+        if self.object_list.is_empty() {
+            return false;
+        }
+
+        let mut temp_box = Aabb::default();
+        for (i, object) in self.object_list.iter().enumerate() {
+            if !object.bounding_box(time0, time1, &mut temp_box) {
+                return false;
+            }
+            *output_box = if i == 0 { temp_box.clone() } else { surrounding_box(output_box, &temp_box) };
+        }
+        true
+    }
+
+    fn transmittance(&self, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let mut transmittance = 1.0;
+        for object in &self.object_list {
+            transmittance *= object.transmittance(ray, t_min, t_max);
+            if transmittance <= 0.0 {
+                return 0.0;
+            }
+        }
+        transmittance
+    }
+
+    fn is_solid(&self) -> bool {
+        self.object_list.iter().all(|object| object.is_solid())
+    }
+
+    /// A list counts as the union of its objects.
+    fn spans(&self, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+        let mut union = Vec::new();
+        let mut object_spans = Vec::new();
+        for object in &self.object_list {
+            object_spans.clear();
+            if !object.spans(ray, &mut object_spans) {
+                return false;
+            }
+            union = combine_spans(&union, &object_spans, CsgOperation::Union);
+        }
+        spans.extend(union);
+        true
+    }
 }
 
 impl Default for Vec3 {
@@ -307,30 +637,19 @@ impl Clone for Vec3 {
     }
 }
 
-impl Default for Ray {
-    fn default() -> Ray {
-        Ray {
-            origin: Default::default(),
-            direction: Default::default(),
-        }
-    }
-}
-
 impl Default for HitRecord {
     fn default() -> HitRecord {
         HitRecord {
             point: Default::default(),
             normal: Default::default(),
             t: 0.0,
-            front_face: false
-        }
-    }
-}
-
-impl Default for HittableList {
-    fn default() -> HittableList {
-        HittableList {
-            object_list: vec![]
+            u: 0.0,
+            v: 0.0,
+            front_face: false,
+            material: None,
+            colour: None,
+            dpdu: Default::default(),
+            dpdv: Default::default(),
         }
     }
 }
@@ -341,7 +660,13 @@ impl Clone for HitRecord {
             point: self.point.clone(),
             normal: self.normal.clone(),
             t: self.t,
-            front_face: self.front_face
+            u: self.u,
+            v: self.v,
+            front_face: self.front_face,
+            material: self.material.clone(),
+            colour: self.colour.clone(),
+            dpdu: self.dpdu.clone(),
+            dpdv: self.dpdv.clone(),
         }
     }
 }
@@ -368,7 +693,18 @@ pub fn clamp(x: f64, min: f64, max: f64) -> f64 {
     x
 }
 
-pub fn sample_colour(col: (f64, f64, f64), samples: u32) -> sdl2::pixels::Color {
+impl Pixel {
+    pub fn new(r: u8, g: u8, b: u8, a: u8) -> Pixel {
+        Pixel {
+            r,
+            g,
+            b,
+            a,
+        }
+    }
+}
+
+pub fn sample_colour(col: (f64, f64, f64), samples: u32) -> Pixel {
     let mut r = col.0;
     let mut g = col.1;
     let mut b = col.2;
@@ -380,8 +716,8 @@ pub fn sample_colour(col: (f64, f64, f64), samples: u32) -> sdl2::pixels::Color
     b *= scale;
 
     // Return the clamped colour.
-    Color::RGBA((clamp(r, 0.0, 255.0)) as u8,
-                (clamp(g, 0.0, 255.0)) as u8,
-                (clamp(b, 0.0, 255.0)) as u8,
-                255)
-}
\ No newline at end of file
+    Pixel::new((clamp(r, 0.0, 255.0)) as u8,
+               (clamp(g, 0.0, 255.0)) as u8,
+               (clamp(b, 0.0, 255.0)) as u8,
+               255)
+}
@@ -0,0 +1,144 @@
+use crate::{Spectrum, Vec3, clamp, luminance};
+
+/// A light that is not an object in the world, so rays can never hit it and it is only seen by
+/// sampling it directly from the points it lights.
+pub trait Light {
+    /// The light arriving at a point, or None if none does.
+    fn sample(&self, point: &Vec3) -> Option<LightSample>;
+}
+
+/// Light arriving at a point from one direction.
+pub struct LightSample {
+    pub direction: Vec3, // A unit vector pointing towards the light.
+    pub distance: f64, // How far a shadow ray has to reach, which is infinite for distant lights.
+    pub irradiance: Vec3, // Measured on a surface facing the light, in linear sRGB if it has a spectrum.
+    pub spectrum: Option<Spectrum>, // The shape of the light's spectrum, if it has a physical one.
+}
+
+/// Light shining equally in all directions from a point.
+pub struct PointLight {
+    pub position: Vec3,
+    pub intensity: Vec3, // Colour times intensity, for the light leaving in any direction.
+    pub range: Option<f64>, // The distance at which the light fades out, if it ever does.
+    pub spectrum: Option<Spectrum>, // Replaces the colour, keeping the intensity's brightness.
+}
+
+/// A point light that only shines into a cone, fading out between the inner and outer angles.
+pub struct SpotLight {
+    pub position: Vec3,
+    pub direction: Vec3,
+    pub intensity: Vec3,
+    pub range: Option<f64>,
+    pub inner_cone: f64, // Half-angles of the cone in radians.
+    pub outer_cone: f64,
+    pub spectrum: Option<Spectrum>,
+}
+
+/// Parallel light from infinitely far away, like the sun.
+pub struct DirectionalLight {
+    pub direction: Vec3, // The way the light travels.
+    pub irradiance: Vec3,
+    pub spectrum: Option<Spectrum>,
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, intensity: Vec3) -> PointLight {
+        PointLight {
+            position,
+            intensity,
+            range: None,
+            spectrum: None,
+        }
+    }
+}
+
+impl SpotLight {
+    pub fn new(position: Vec3, direction: Vec3, intensity: Vec3, inner_cone: f64, outer_cone: f64) -> SpotLight {
+        SpotLight {
+            position,
+            direction: direction.unit_vector(),
+            intensity,
+            range: None,
+            inner_cone,
+            outer_cone,
+            spectrum: None,
+        }
+    }
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3, irradiance: Vec3) -> DirectionalLight {
+        DirectionalLight {
+            direction: direction.unit_vector(),
+            irradiance,
+            spectrum: None,
+        }
+    }
+}
+
+/// A light's colour times its intensity, with the colour taken from its spectrum if it has one.
+fn coloured(intensity: &Vec3, spectrum: &Option<Spectrum>) -> Vec3 {
+    match spectrum {
+        Some(spectrum) => spectrum.colour().mul(luminance(intensity)),
+        None => intensity.clone(),
+    }
+}
+
+/// Inverse square falloff from a point, smoothly windowed to nothing at `range`.
+fn falloff(distance: f64, range: Option<f64>) -> f64 {
+    let window = match range {
+        Some(range) if range > 0.0 => clamp(1.0 - (distance / range).powi(4), 0.0, 1.0),
+        _ => 1.0,
+    };
+    window / (distance * distance).max(1e-8)
+}
+
+impl Light for PointLight {
+    fn sample(&self, point: &Vec3) -> Option<LightSample> {
+        let to_light = self.position.clone() - point.clone();
+        let distance = to_light.length();
+        Some(LightSample {
+            direction: to_light.div(distance),
+            distance,
+            irradiance: coloured(&self.intensity, &self.spectrum).mul(falloff(distance, self.range)),
+            spectrum: self.spectrum.clone(),
+        })
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, point: &Vec3) -> Option<LightSample> {
+        let to_light = self.position.clone() - point.clone();
+        let distance = to_light.length();
+        let direction = to_light.div(distance);
+
+        let cos_angle = -direction.dot(&self.direction);
+        let (cos_inner, cos_outer) = (self.inner_cone.cos(), self.outer_cone.cos());
+        if cos_angle <= cos_outer {
+            return None;
+        }
+        let t = if cos_inner > cos_outer {
+            clamp((cos_angle - cos_outer) / (cos_inner - cos_outer), 0.0, 1.0)
+        } else {
+            1.0
+        };
+
+        Some(LightSample {
+            direction,
+            distance,
+            irradiance: coloured(&self.intensity, &self.spectrum).mul(t * t * falloff(distance, self.range)),
+            spectrum: self.spectrum.clone(),
+        })
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _point: &Vec3) -> Option<LightSample> {
+        Some(LightSample {
+            direction: self.direction.mul(-1.0),
+            distance: f64::INFINITY,
+            irradiance: coloured(&self.irradiance, &self.spectrum),
+            spectrum: self.spectrum.clone(),
+        })
+    }
+}
@@ -1,282 +1,437 @@
-use std::fs::File;
+use std::env;
+use std::fs::{self, File};
 use std::io::prelude::*;
+use std::time::Instant;
 use rust_tracer::*;
-use std::rc::Rc;
 
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use std::time::Duration;
-use sdl2::render::{WindowCanvas, Texture};
-use sdl2::video::Window;
-use std::task::Context;
-use sdl2::rect::Point;
-use std::mem::size_of;
-use std::thread::sleep;
-use std::{thread, time};
-
-// use sdl2::gfx::primitives::DrawRenderer;
-
-fn main() -> Result<(), String>{
-    println!("Hello, world!");
-
-    let sdl_context = sdl2::init()?;
-    // let video_subsystem = sdl_context.video()?;
-    let w = 800;
-    let res = (w, w / (16 / 9));
-
-    let mut offset = 1.0;
-    let arr = paint_pixels(res.1, offset);
-
-    let window = init_window(&sdl_context, res.0, 16.0 / 9.0)?;
-
-    let mut canvas = window.into_canvas().accelerated().build().map_err(|e| e.to_string())?;
-
-    let mut event_pump = sdl_context.event_pump()?;
-
-    let timer = sdl_context.timer()?;
-
-    let texture_creator = canvas.texture_creator();
-    let mut texture = texture_creator
-        .create_texture_streaming(None, res.0, res.0 * 9 / 16)
-        .map_err(|e| e.to_string())?;
-
-    texture.update(None, arr.as_slice(), (res.1 * 4 * (size_of::<u8>() as u32)) as usize);
-    canvas.copy(&texture, None, None);
-
-    'running: loop {
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => break 'running,
-                _ => {
-                    // eprintln!("Event pump is pumping: {:?}", event);
-                    canvas.present();
+/// Renders a scene without a window and writes it to a PPM or EXR image. The scene is a glTF or GLB
+/// file given after the image path, or the default scene if there isn't one. Given `serve` and an
+/// address instead, it serves an HTTP API for queueing renders (see server.rs). Options:
+///
+/// --spectral                 renders with wavelengths of light instead of RGB.
+/// --working-space=SPACE      lights the scene in srgb (the default), acescg or rec2020.
+/// --output-space=SPACE       writes srgb or p3 for PPM (srgb by default), and any space for EXR
+///                            (aces, meaning ACES2065-1, by default).
+/// --denoise[=STRENGTH]       cleans up the noise of a render with few samples, more strongly for a
+///                            strength above 1.
+/// --keep-raw                 also writes the image before denoising, with -raw added to its name.
+/// --samples=N                takes N samples in every pixel (50 by default).
+/// --adaptive[=THRESHOLD]     keeps sampling pixels until their noise is below the threshold (0.02
+///                            by default) relative to their brightness.
+/// --max-samples=N            stops adaptive sampling at N samples in a pixel (1024 by default).
+/// --heatmap=PATH             writes an image of how many samples each pixel took.
+/// --filter=FILTER            weighs samples into pixels with a box (the default), tent, gaussian,
+///                            mitchell or lanczos filter.
+/// --crop=X0,Y0,X1,Y1         renders only the pixels from (X0, Y0) up to (X1, Y1), counting from
+///                            the top left, as an image of their size.
+/// --checkpoint=PATH          saves the render's progress to a checkpoint file as it goes, and when
+///                            it is done.
+/// --checkpoint-every=SECONDS saves a checkpoint at most this often (60 by default).
+/// --resume                   carries on from the checkpoint, which may be asked for more samples
+///                            than it was started with.
+/// --coordinator=ADDRESS      shares the render out between workers connecting to ADDRESS, such as
+///                            0.0.0.0:7878, instead of rendering it here.
+/// --worker=ADDRESS           renders tiles for the coordinator at ADDRESS until its render is done.
+///                            Needs no image path or scene, as the coordinator sends them.
+/// --frames[=FIRST..LAST]     renders the frames of the scene's animation from FIRST to LAST, or all
+///                            of them, to numbered images. A run of # in the image path is replaced
+///                            by the frame number, which is otherwise added to the end of the name.
+/// --fps=N                    the number of frames in each second of animation (24 by default).
+/// --projection=NAME[:N]      renders through a perspective, orthographic (N high), equirectangular,
+///                            cubemap, fisheye or equisolid (N degrees across), or ods-top-bottom or
+///                            ods-side-by-side stereo (N metres between the eyes) projection instead
+///                            of the camera's own. The 360° ones reshape the image to suit.
+/// --lens=PATH              traces rays through the lens described in a lens prescription file
+///                            instead of the camera's thin lens (see lens.rs for the format). The
+///                            lens and --film-diagonal decide the field of view, not the camera,
+///                            and it can't be used with a --projection other than perspective.
+/// --focus-distance=METRES    focuses the lens, or the thin lens of a camera with an f-number, on
+///                            things this far away (10 by default).
+/// --film-diagonal=MM         the size of the film behind a --lens, corner to corner, which with
+///                            the lens's focal length decides the field of view (43.27, a 35 mm
+///                            full-frame camera's, by default).
+/// --shutter=SECONDS          how long the shutter is open, such as 1/125, which is how long
+///                            moving things blur for.
+/// --f-number=N               how wide the lens is open, such as 2.8, which decides how blurred
+///                            things out of focus are. Stops down a --lens, which is otherwise
+///                            left wide open.
+/// --iso=N                    the film's sensitivity, which with --shutter and --f-number or a
+///                            --lens exposes the image like a camera would. The light in the
+///                            scene is taken to be in cd/m², and the sky is made as bright as a
+///                            clear day's.
+/// --video=PATH               also writes the frames to a video, an animated GIF if PATH ends in
+///                            .gif and an uncompressed Y4M otherwise.
+fn main() -> Result<(), String> {
+    let (flags, args): (Vec<String>, Vec<String>) = env::args().skip(1).partition(|a| a.starts_with("--"));
+    if args.first().map(String::as_str) == Some("serve") {
+        let address = args.get(1).map_or("127.0.0.1:8000", String::as_str);
+        println!("Serving on http://{}", address);
+        return serve(address, |e| eprintln!("{}", e));
+    }
+    let path = args.first().cloned().unwrap_or_else(|| String::from("new-img.ppm"));
+    let scene_path = args.get(1);
+
+    let width = 800;
+    let mut settings = RenderSettings::default();
+    let exr = path.to_ascii_lowercase().ends_with(".exr");
+    let mut output_space = if exr { ColourSpace::Aces2065 } else { ColourSpace::LinearSrgb };
+    let mut denoiser = None;
+    let mut keep_raw = false;
+    let mut max_samples = None;
+    let mut heatmap = None;
+    let mut checkpoint = None;
+    let mut checkpoint_every = 60.0;
+    let mut resume = false;
+    let mut coordinator = None;
+    let mut worker = None;
+    let mut frames = None;
+    let mut fps = 24.0;
+    let mut video_path = None;
+    let mut projection = None;
+    let mut lens = None;
+    let mut focus_distance = None;
+    let mut film_diagonal = None;
+    let (mut shutter_speed, mut f_number, mut iso) = (None, None, None);
+    for flag in &flags {
+        match flag.split_once('=') {
+            None if flag == "--spectral" => settings.spectral = true,
+            None if flag == "--denoise" => denoiser = Some(Denoiser::default()),
+            None if flag == "--keep-raw" => keep_raw = true,
+            Some(("--denoise", strength)) => {
+                let strength = strength.parse().map_err(|_| format!("bad denoising strength {}", strength))?;
+                denoiser = Some(Denoiser::new(strength));
+            }
+            None if flag == "--adaptive" => settings.adaptive = Some(AdaptiveSampling::new(0.02)),
+            Some(("--adaptive", threshold)) => {
+                let threshold = threshold.parse().map_err(|_| format!("bad noise threshold {}", threshold))?;
+                settings.adaptive = Some(AdaptiveSampling::new(threshold));
+            }
+            Some(("--samples", count)) => {
+                settings.samples_per_pixel = count.parse().map_err(|_| format!("bad sample count {}", count))?;
+            }
+            Some(("--max-samples", count)) => {
+                max_samples = Some(count.parse().map_err(|_| format!("bad sample count {}", count))?);
+            }
+            Some(("--heatmap", path)) => heatmap = Some(String::from(path)),
+            Some(("--filter", filter)) => settings.filter = Filter::parse(filter)?,
+            Some(("--crop", bounds)) => settings.crop = Some(PixelBounds::parse(bounds)?),
+            Some(("--checkpoint", path)) => checkpoint = Some(String::from(path)),
+            Some(("--checkpoint-every", seconds)) => {
+                checkpoint_every = seconds.parse().map_err(|_| format!("bad checkpoint interval {}", seconds))?;
+            }
+            None if flag == "--resume" => resume = true,
+            Some(("--coordinator", address)) => coordinator = Some(String::from(address)),
+            Some(("--worker", address)) => worker = Some(String::from(address)),
+            None if flag == "--frames" => frames = Some(None),
+            Some(("--frames", range)) => frames = Some(Some(parse_frames(range)?)),
+            Some(("--video", path)) => video_path = Some(String::from(path)),
+            Some(("--projection", name)) => projection = Some(Projection::parse(name)?),
+            Some(("--lens", path)) => {
+                lens = Some(fs::read_to_string(path).map_err(|e| format!("couldn't read lens {}: {}", path, e))?);
+            }
+            Some(("--iso", value)) => {
+                iso = Some(value.parse().ok().filter(|iso: &f64| *iso > 0.0).ok_or(format!("bad ISO {}", value))?);
+            }
+            Some(("--shutter", speed)) => shutter_speed = Some(Exposure::parse_shutter_speed(speed)?),
+            Some(("--f-number", number)) => {
+                f_number = Some(number.parse().ok().filter(|n: &f64| *n > 0.0).ok_or(format!("bad f-number {}", number))?);
+            }
+            Some(("--focus-distance", metres)) => {
+                focus_distance = Some(metres.parse().map_err(|_| format!("bad focus distance {}", metres))?);
+            }
+            Some(("--film-diagonal", mm)) => {
+                let mm: f64 = mm.parse().ok().filter(|mm: &f64| *mm > 0.0).ok_or(format!("bad film size {}", mm))?;
+                film_diagonal = Some(mm / 1000.0);
+            }
+            Some(("--fps", rate)) => {
+                fps = rate.parse().ok().filter(|fps: &f64| *fps > 0.0).ok_or(format!("bad frame rate {}", rate))?;
+            }
+            Some(("--working-space", space)) => {
+                settings.working_space = ColourSpace::parse(space)?;
+                if ![ColourSpace::LinearSrgb, ColourSpace::AcesCg, ColourSpace::Rec2020].contains(&settings.working_space) {
+                    return Err(format!("{} can't be used as a working space", space));
                 }
             }
-
+            Some(("--output-space", space)) => output_space = ColourSpace::parse(space)?,
+            _ => return Err(format!("unknown option {}", flag)),
         }
-
-        // offset += (timer.ticks() as f64).sin() * 0.5;
+    }
+    if !exr && ![ColourSpace::LinearSrgb, ColourSpace::DisplayP3].contains(&output_space) {
+        return Err(String::from("only sRGB and Display P3 can be written to PPM; use an .exr image"));
+    }
+    if let (Some(adaptive), Some(max_samples)) = (&mut settings.adaptive, max_samples) {
+        adaptive.max_samples = max_samples;
+    }
+    if iso.is_some() && (shutter_speed.is_none() || (f_number.is_none() && lens.is_none())) {
+        return Err(String::from("--iso needs --shutter, and --f-number or a --lens, to make an exposure"));
+    }
+    if resume && checkpoint.is_none() {
+        return Err(String::from("--resume needs a --checkpoint to resume from"));
+    }
+    if coordinator.is_some() && checkpoint.is_some() {
+        return Err(String::from("distributed renders can't be checkpointed"));
+    }
+    if video_path.is_some() && frames.is_none() {
+        return Err(String::from("--video needs --frames to make the video from"));
+    }
+    if frames.is_some() && (checkpoint.is_some() || coordinator.is_some()) {
+        return Err(String::from("animations can't be checkpointed or rendered on other machines"));
+    }
+    if let Some(address) = &worker {
+        let worked = work(address, &mut report);
+        println!();
+        return worked;
     }
 
-    // write_pixels();
+    // World
+    let mut job = Job::load(scene_path.map(String::as_str), width, settings)?;
+    job.projection = projection;
+    job.lens = lens;
+    job.shutter_speed = shutter_speed;
+    job.f_number = f_number;
+    job.iso = iso;
+    if let Some(focus_distance) = focus_distance {
+        job.focus_distance = focus_distance;
+    }
+    if let Some(film_diagonal) = film_diagonal {
+        job.film_diagonal = film_diagonal;
+    }
+    let options = Options {
+        output: Output {
+            exr,
+            working_space: job.settings.working_space,
+            space: output_space,
+        },
+        denoiser,
+        keep_raw,
+        checkpoint,
+        checkpoint_every,
+        resume,
+        coordinator,
+    };
 
+    // Render a still, or each frame of the animation
+    let (first, last) = match frames {
+        None => return render(&job, &path, heatmap.as_deref(), &options).map(|_| ()),
+        Some(Some(range)) => range,
+        Some(None) => (0, (job.scene()?.duration * fps).ceil() as u32),
+    };
+    let mut video: Option<Box<dyn VideoWriter>> = None;
+    for frame in first..=last {
+        println!("Frame {} of {} to {}", frame, first, last);
+        job.time = frame as f64 / fps;
+        let heatmap = heatmap.as_ref().map(|heatmap| frame_path(heatmap, frame));
+        let (image, pixels) = render(&job, &frame_path(&path, frame), heatmap.as_deref(), &options)?;
+        if let Some(video_path) = &video_path {
+            let writer = match &mut video {
+                Some(writer) => writer,
+                None => video.insert(create_video(video_path, image.width, image.height, fps)?),
+            };
+            writer.write_frame(&encode_display(&pixels, job.settings.working_space, ColourSpace::LinearSrgb))?;
+        }
+    }
+    if let Some(video) = &mut video {
+        video.finish()?;
+        println!("Video successfully written!");
+    }
     Ok(())
 }
 
-fn init_window(context: &sdl2::Sdl, width: u32, aspect_ratio: f64) -> Result<Window, String> {
-    let video_subsystem = context.video()?;
-
-    let window = video_subsystem
-        .window("SDL Window", width, (width as f64/ aspect_ratio) as u32)
-        .position_centered()
-        .opengl()
-        .build()
-        .map_err(|e| e.to_string());
-
-    window.map_err(|e| e.to_string())
+/// How images are rendered and written.
+struct Options {
+    output: Output,
+    denoiser: Option<Denoiser>,
+    keep_raw: bool,
+    checkpoint: Option<String>,
+    checkpoint_every: f64,
+    resume: bool,
+    coordinator: Option<String>,
 }
 
-/// Used to paint the scene to an array of pixel components.
-fn paint_pixels(width: u32, offset: f64) -> Vec<u8> {
-    // Use canvas.drawPoint
-    // let (width, height) = canvas.drawable_size();
-
-    let aspect_ratio = 16.0 / 9.0;
-    let img_width: f64 = width as f64;
-    let img_height: f64 = img_width / aspect_ratio;
-    let samples_per_pixel = 50;
-
-    let max_depth = 50;
-
-    // World
-    let mut world: HittableList = Default::default();
-    world.add(Rc::new(Sphere {
-        centre: Vec3::from((0.0, -105.5, -1.0)),
-        radius: 100.0,
-    }));
-    world.add(Rc::new(Sphere {
-        centre: Vec3::from((0.0, 0.0, -1.0)),
-        radius: 0.5,
-    }));
-    // world.add(Rc::new(Sphere {
-    //     centre: Vec3::from((1.0, 1.0 * offset, -1.5)),
-    //     radius: 0.25,
-    // }));
-
-    // Camera
-    let camera = Camera::new(width, 16.0 / 9.0, 2, 1.0, unit_vector(0.0));
-
-    let mut color_vector: Vec<u8> = Vec::new();
-
-    for i in (0..=((camera.height as i64)-1)).rev() {
-        // print!("\rScan-lines remaining: {}\n", i);
-        for j in 0..=((camera.width as i64)-1) {
-
-            // let mut pixel = ray_to_pixel(&ray, &world);
-            let mut r_temp = 0.0;
-            let mut g_temp = 0.0;
-            let mut b_temp = 0.0;
-
-            for k in 0..samples_per_pixel {
-
-                let u = (j as f64 + fastrand::f64()) / (img_width - 1.0); // Scan across left to right of the viewport
-                let v = (i as f64 + fastrand::f64()) / (img_height - 1.0); // Scan from bottom to top of the viewport
-                let ray = camera.get_ray(u, v);
-
-                let progress = (j / camera.width as i64) / camera.height as i64 * 100;
-
-                let col = ray_to_pixel(&ray, &world, max_depth);
-                r_temp += col.r as f64;
-                g_temp += col.g as f64;
-                b_temp += col.b as f64;
-
-                print!("\rScan-lines remaining: {} Sample: {}", i, k);
-            }
-
-            let pixel = sample_colour((r_temp, g_temp, b_temp), samples_per_pixel);
-
-            color_vector.push(255);
-            color_vector.push(pixel.r);
-            color_vector.push(pixel.g);
-            color_vector.push(pixel.b);
-            // println!("({} {} {})   ", pixel.r, pixel.g, pixel.b);
+/// Renders the job's scene, here or on other machines, and writes the image to `path` and its
+/// sample heatmap to `heatmap`. Gives the image along with the pixels written, which have been
+/// denoised if asked for.
+fn render(job: &Job, path: &str, heatmap: Option<&str>, options: &Options) -> Result<(Image, Vec<Vec3>), String> {
+    let settings = &job.settings;
+    let scene = job.scene()?;
+    let camera = &scene.cameras[0];
+
+    let progress = match &options.coordinator {
+        Some(address) => {
+            println!("Waiting for workers on {}", address);
+            coordinate(address, job, camera, &mut report)?
         }
+        None => render_here(camera, &scene, settings, options.checkpoint.as_deref(), options.checkpoint_every, options.resume)?,
+    };
+    let image = progress.image();
+    if let Some(heatmap) = heatmap {
+        let map = Output {
+            exr: heatmap.to_ascii_lowercase().ends_with(".exr"),
+            working_space: ColourSpace::LinearSrgb,
+            space: ColourSpace::LinearSrgb,
+        };
+        map.write(heatmap, &image, &sample_heatmap(&image))?;
     }
-
-    color_vector
+    let pixels = match &options.denoiser {
+        Some(denoiser) => {
+            if options.keep_raw {
+                options.output.write(&raw_path(path), &image, &image.colour)?;
+            }
+            denoiser.denoise(&image)
+        }
+        None => image.colour.clone(),
+    };
+    options.output.write(path, &image, &pixels)?;
+    Ok((image, pixels))
 }
 
-/// Used to write a PPM image.
-fn write_pixels() {
-
-    // Image
-    let aspect_ratio = 16.0 / 9.0;
-    let img_width: f64 = 1200.0;
-    let img_height: f64 = img_width / aspect_ratio;
-
-    // World
-    let mut world: HittableList = Default::default();
-    world.add(Rc::new(Sphere {
-        centre: Vec3 { x: 0.0, y: -105.0, z: -1.0 },
-        radius: 100.0,
-    }));
-    world.add(Rc::new(Sphere {
-            centre: Vec3 { x: 0.0, y: 0.0, z: -1.0 },
-            radius: 0.5,
-        }));
-    world.add(Rc::new(Sphere {
-        centre: Vec3 { x: 1.0, y: 1.0, z: -1.5 },
-        radius: 0.25,
-    }));
-
-    // Camera
-    let viewport_height = 2.0;
-    let viewport_width = aspect_ratio * viewport_height;
-    let focal_length = 1.0; // <- MAGIC NUMBER ALERT.
-
-    let origin: Vec3 = Default::default(); // A zero vector.
-    let horizontal = Vec3 { x: viewport_width, y: 0.0, z: 0.0, };
-    let vertical = Vec3 { x: 0.0, y: viewport_height, z: 0.0, };
-    let lower_left_corner = origin.clone() - horizontal.div(2.0) - vertical.div(2.0) - Vec3 {x: 0.0, y: 0.0, z: focal_length, };
-
-    // Render
-
-    let mut image_string = String::new();
-
-    image_string.push_str(format!("P3\n{} {}\n255\n", img_width, img_height).as_str());
-
-    for i in (0..=((img_height.round() as i64)-1)).rev() {
-        print!("\rScan-lines remaining: {}", i);
-        for j in 0..=((img_width.round() as i64)-1) {
-
-            let u = j as f64 / (img_width - 1.0); // Scan across left to right of the viewport
-            let v = i as f64 / (img_height - 1.0); // Scan from bottom to top of the viewport
-
-            let ray = Ray {
-                origin: origin.clone(),
-                direction: lower_left_corner.clone() + horizontal.mul(u) + vertical.mul(v) - origin.clone()
-            };
-
-            let colour = ray_to_colour(&ray, &world);
-
-            image_string.push_str(write_colour(ray_to_colour(&ray, &world)).as_str());
-            // image_string.push_str(write_colour(colour).as_str())
+/// Renders the scene on this machine, saving checkpoints as it goes if asked to, and picking up
+/// from the last one if resuming.
+fn render_here(camera: &Camera,
+               scene: &Scene,
+               settings: &RenderSettings,
+               checkpoint: Option<&str>,
+               checkpoint_every: f64,
+               resume: bool
+) -> Result<Progress, String> {
+    let fresh = start_render(camera, settings);
+    let mut progress = match checkpoint {
+        Some(checkpoint) if resume => {
+            let progress = read_checkpoint(checkpoint)?;
+            let (film, expected) = (&progress.film, &fresh.film);
+            let same_image = (film.width, film.height, film.window) == (expected.width, expected.height, expected.window);
+            if !same_image || film.filter != expected.filter {
+                return Err(format!("the checkpoint {} is of a different image", checkpoint));
+            }
+            println!("Resuming after {} passes", progress.passes);
+            progress
         }
-    }
-    print!("\n");
-    let path = "new-img.ppm";
-
-    let mut file = match File::create(&path) {
-        Err(why) => panic!("couldn't create image file {}", why),
-        Ok(file) => file,
+        _ => fresh,
     };
 
-    write_file(image_string.as_str(), &mut file);
+    let passes = settings.passes();
+    let report = |progress: &Progress| {
+        print!("\rPasses remaining: {}", passes.saturating_sub(progress.passes));
+        std::io::stdout().flush().ok();
+    };
+    report(&progress);
+    let mut last_saved = Instant::now();
+    continue_render(camera, scene, settings, &mut progress, &mut |progress| {
+        report(progress);
+        if let Some(checkpoint) = checkpoint {
+            if last_saved.elapsed().as_secs_f64() >= checkpoint_every {
+                save(checkpoint, progress);
+                last_saved = Instant::now();
+            }
+        }
+        true
+    });
+    println!();
+    if let Some(checkpoint) = checkpoint {
+        save(checkpoint, &progress);
+    }
+    Ok(progress)
 }
 
-/// Used to convert a Vector3 into a string.
-fn write_colour(col: Vec3) -> String {
-    format!("{} {} {}\n", (col.x * 255.0) as i32, (col.y * 255.0) as i32, (col.z * 255.0) as i32)
+/// Prints what's happening in a render shared out between machines.
+fn report(event: JobEvent) {
+    match event {
+        JobEvent::WorkerConnected(peer) => println!("\rWorker {} connected", peer),
+        JobEvent::WorkerLost(peer, e) => println!("\rWorker {} lost: {}", peer, e),
+        JobEvent::TileMerged { remaining, total } => {
+            print!("\rTiles remaining: {} of {}", remaining, total);
+            if remaining == 0 {
+                println!();
+            }
+        }
+        JobEvent::Connected => println!("Connected to the coordinator"),
+        JobEvent::TileRendered(tile) => print!("\rRendered the tile at ({}, {})", tile.x0, tile.y0),
+    }
+    std::io::stdout().flush().ok();
 }
 
-/// Creates a PPM file given an input string.
-fn write_file(str: &str, file: &mut File) {
-    match file.write_all(str.as_bytes()) {
-        Err(e) => panic!("Couldn't write to image: {}", e),
-        Ok(_) => println!("Image successfully written!"),
+/// Saves a checkpoint, carrying on without one if it can't, as losing the render would be worse.
+fn save(path: &str, progress: &Progress) {
+    if let Err(e) = write_checkpoint(path, progress) {
+        eprintln!("\n{}", e);
     }
 }
 
-/// Converts a ray to a Vec3 representing a single colour, given a world object too.
-fn ray_to_colour(ray: &Ray, world: &dyn Hittable) -> Vec3 {
-    let mut rec: HitRecord = Default::default();
-
-    if world.hit(ray, 0.0, f64::INFINITY, &mut rec) {
-        return (rec.normal + unit_vector(1.0)).mul(0.5);
-    }
+/// How rendered pixels are written out.
+struct Output {
+    exr: bool,
+    working_space: ColourSpace,
+    space: ColourSpace,
+}
 
-    let unit_direction = ray.direction.unit_vector(); // Get the unit vector of the ray
+impl Output {
+    fn write(&self, path: &str, image: &Image, pixels: &[Vec3]) -> Result<(), String> {
+        if self.exr {
+            let pixels: Vec<Vec3> = pixels.iter().map(|p| self.working_space.convert(p, self.space)).collect();
+            write_exr(path, image.width, image.height, &pixels, self.space)?;
+            println!("Image successfully written!");
+            return Ok(());
+        }
+        let arr = encode_display(pixels, self.working_space, self.space);
 
-    let t = (unit_direction.y + 1.0) * 0.5;
+        let mut image_string = String::new();
+        image_string.push_str(format!("P3\n{} {}\n255\n", image.width, image.height).as_str());
+        for pixel in arr.chunks(4) {
+            image_string.push_str(write_colour(pixel).as_str());
+        }
 
-    return unit_vector(1.0).mul(1.0-t) + Vec3{x: 0.5, y: 0.7, z: 1.0,}.mul(t)
+        let mut file = File::create(path).map_err(|e| format!("couldn't create image file {}", e))?;
+        write_file(image_string.as_str(), &mut file)
+    }
 }
 
-/// Traces a ray in a scene to an SDL pixel.
-fn ray_to_pixel(ray: &Ray, world: &dyn Hittable, depth: u32) -> sdl2::pixels::Color {
-    let mut rec: HitRecord = Default::default();
-
-    if depth <= 0 {
-        return Color::RGBA(0, 0, 0, 255);
+/// The path for the image before denoising: the same as the final one, with -raw on the name.
+fn raw_path(path: &str) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{}-raw.{}", stem, extension),
+        _ => format!("{}-raw", path),
     }
+}
 
-    print!("\rCurrently on depth pass {}", depth);
-    if world.hit(ray, 0.0, f64::INFINITY, &mut rec) {
-        // return (rec.normal + unit_vector(1.0)).mul(0.5);
-        let target = rec.point.clone() + rec.normal.clone() + Vec3::random_in_unit_sphere();
-        let temp_ray = Ray { origin: rec.point.clone(), direction: target - rec.point };
-        let cache_col = ray_to_pixel(&temp_ray, world, depth - 1);
-        return Color::RGBA(
-            (cache_col.r as f64 * 0.5) as u8,
-            (cache_col.g as f64 * 0.5) as u8,
-            (cache_col.b as f64 * 0.5) as u8,
-            cache_col.a,
-        );
-        return vec_to_col(&(rec.normal + unit_vector(1.0)).mul(0.5));
+/// Reads a range of frames, given as FIRST..LAST, or as one frame on its own.
+fn parse_frames(range: &str) -> Result<(u32, u32), String> {
+    let bad = || format!("bad frame range {}", range);
+    let (first, last) = range.split_once("..").unwrap_or((range, range));
+    let first = first.parse().map_err(|_| bad())?;
+    let last = last.parse().map_err(|_| bad())?;
+    if last < first {
+        return Err(bad());
     }
+    Ok((first, last))
+}
 
-    let unit_direction = ray.direction.unit_vector(); // Get the unit vector of the ray
+/// The path for one frame of an animation: a run of # in the path is replaced by the frame
+/// number, padded with zeros to its length, or if there isn't one, the number is added to the
+/// name with four digits.
+fn frame_path(path: &str, frame: u32) -> String {
+    match (path.find('#'), path.rfind('#')) {
+        (Some(start), Some(end)) => {
+            format!("{}{:0width$}{}", &path[..start], frame, &path[end + 1..], width = end + 1 - start)
+        }
+        _ => match path.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => format!("{}-{:04}.{}", stem, frame, extension),
+            _ => format!("{}-{:04}", path, frame),
+        },
+    }
+}
 
-    let t = (unit_direction.y + 1.0) * 0.5;
-    let vec = unit_vector(1.0).mul(1.0-t) + Vec3{x: 0.5, y: 0.7, z: 1.0,}.mul(t);
-    vec_to_col(&vec)
+/// Used to convert an ARGB pixel into a string.
+fn write_colour(argb: &[u8]) -> String {
+    format!("{} {} {}\n", argb[1], argb[2], argb[3])
 }
 
-fn vec_to_col(v: &Vec3) -> sdl2::pixels::Color {
-    Color::RGBA((v.x * 255.0) as u8, (v.y * 255.0) as u8, (v.z * 255.0) as u8, 255)
-}
\ No newline at end of file
+/// Creates a PPM file given an input string.
+fn write_file(str: &str, file: &mut File) -> Result<(), String> {
+    match file.write_all(str.as_bytes()) {
+        Err(e) => Err(format!("Couldn't write to image: {}", e)),
+        Ok(_) => {
+            println!("Image successfully written!");
+            Ok(())
+        }
+    }
+}
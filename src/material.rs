@@ -0,0 +1,79 @@
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use crate::{HitRecord, Ray, SolidColour, Texture, Vec3};
+
+/// Describes how light scatters off (or through) whatever a ray hit.
+pub trait Material {
+    /// Scatters an incoming ray, setting the colour it is attenuated by and the ray it continues
+    /// as. Returns false if the ray is absorbed.
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool;
+
+    /// The fraction of light arriving from `direction` that is scattered back along the incoming
+    /// ray, including the cosine term, for lighting the hit directly from lights. Materials that
+    /// only scatter into exact directions can't be lit this way, and return black.
+    fn eval(&self, _ray_in: &Ray, _rec: &HitRecord, _direction: &Vec3) -> Vec3 {
+        Vec3::default()
+    }
+
+    /// The light given off by the surface itself.
+    fn emitted(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::default()
+    }
+
+    /// Whether the material bends each wavelength differently, so that in a spectral render only
+    /// the ray's hero wavelength can be followed through it.
+    fn dispersive(&self) -> bool {
+        false
+    }
+
+    /// The surface's overall colour at a hit, for the denoiser to tell texture from noise.
+    /// Materials without one, like clear glass, are white.
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::from(1.0)
+    }
+}
+
+/// A perfectly diffuse surface.
+pub struct Lambertian {
+    pub albedo: Rc<dyn Texture>,
+}
+
+impl Lambertian {
+    pub fn new(albedo: Vec3) -> Lambertian {
+        Lambertian::textured(Rc::new(SolidColour::new(albedo)))
+    }
+
+    pub fn textured(albedo: Rc<dyn Texture>) -> Lambertian {
+        Lambertian {
+            albedo,
+        }
+    }
+}
+
+impl Material for Lambertian {
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+        let mut direction = rec.normal.clone() + Vec3::random_unit_vector();
+
+        // Catch the random vector almost exactly cancelling out the normal.
+        if direction.near_zero() {
+            direction = rec.normal.clone();
+        }
+
+        *scattered = Ray::new(rec.point.clone(), direction, ray_in.time);
+        *attenuation = self.albedo.value(rec);
+        true
+    }
+
+    fn eval(&self, _ray_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        let cos_theta = rec.normal.dot(&direction.unit_vector());
+        if cos_theta <= 0.0 {
+            return Vec3::default();
+        }
+        self.albedo.value(rec).mul(cos_theta / PI)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(rec)
+    }
+}
@@ -1,8 +1,161 @@
 use num::traits::FloatConst;
 
 // Math constants we may need.
-const INFINITY: f64 = f64::INFINITY;
+pub const INFINITY: f64 = f64::INFINITY;
 
 pub fn degrees_to_radians(degrees: f64) -> f64 {
     degrees * f64::PI() / 180.0
 }
+
+/// The real roots of `a x^2 + b x + c = 0`, smallest first.
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a.abs() < 1e-12 {
+        return if b.abs() < 1e-12 { vec![] } else { vec![-c / b] };
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return vec![];
+    }
+
+    // Avoid subtracting nearly equal numbers, which loses precision in the smaller root.
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let (r0, r1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+    if r0 < r1 { vec![r0, r1] } else { vec![r1, r0] }
+}
+
+/// The real roots of `a x^3 + b x^2 + c x + d = 0`, smallest first.
+pub fn solve_cubic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    if a.abs() < 1e-12 {
+        return solve_quadratic(b, c, d);
+    }
+
+    // Reduce to t^3 + p t + q = 0 with x = t - b / 3a.
+    let (b, c, d) = (b / a, c / a, d / a);
+    let shift = b / 3.0;
+    let p = c - b * b / 3.0;
+    let q = 2.0 * b * b * b / 27.0 - b * c / 3.0 + d;
+
+    let discriminant = q * q / 4.0 + p * p * p / 27.0;
+    let mut roots = if discriminant > 0.0 {
+        let s = discriminant.sqrt();
+        vec![(-q / 2.0 + s).cbrt() + (-q / 2.0 - s).cbrt()]
+    } else if p == 0.0 {
+        vec![0.0]
+    } else {
+        // Three real roots, found with the trigonometric method.
+        let r = (-p / 3.0).sqrt();
+        let phi = crate::clamp(3.0 * q / (2.0 * p * r), -1.0, 1.0).acos() / 3.0;
+        (0..3).map(|k| 2.0 * r * (phi - 2.0 * std::f64::consts::PI * k as f64 / 3.0).cos()).collect()
+    };
+
+    for root in roots.iter_mut() {
+        *root -= shift;
+    }
+    roots.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    roots
+}
+
+/// The real roots of `a x^4 + b x^3 + c x^2 + d x + e = 0`, smallest first, found with Ferrari's
+/// method and then polished with a few Newton steps.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    if a.abs() < 1e-12 {
+        return solve_cubic(b, c, d, e);
+    }
+
+    // Reduce to y^4 + p y^2 + q y + r = 0 with x = y - b / 4a.
+    let (b1, c1, d1, e1) = (b / a, c / a, d / a, e / a);
+    let shift = b1 / 4.0;
+    let p = c1 - 3.0 * b1 * b1 / 8.0;
+    let q = d1 - b1 * c1 / 2.0 + b1 * b1 * b1 / 8.0;
+    let r = e1 - b1 * d1 / 4.0 + b1 * b1 * c1 / 16.0 - 3.0 * b1 * b1 * b1 * b1 / 256.0;
+
+    let mut roots = Vec::new();
+    if q.abs() < 1e-12 {
+        // Quadratic in y^2.
+        for z in solve_quadratic(1.0, p, r) {
+            if z >= 0.0 {
+                roots.push(z.sqrt());
+                roots.push(-z.sqrt());
+            }
+        }
+    } else {
+        // Pick m so both sides of (y^2 + p/2 + m)^2 = 2m (y - q/4m)^2 are perfect squares. The
+        // resolvent cubic always has a positive root when q is not zero.
+        let m = solve_cubic(1.0, p, p * p / 4.0 - r, -q * q / 8.0)
+            .into_iter()
+            .fold(f64::NEG_INFINITY, f64::max);
+        if m <= 0.0 {
+            return vec![];
+        }
+        let s = (2.0 * m).sqrt();
+        roots.extend(solve_quadratic(1.0, -s, p / 2.0 + m + q / (2.0 * s)));
+        roots.extend(solve_quadratic(1.0, s, p / 2.0 + m - q / (2.0 * s)));
+    }
+
+    for root in roots.iter_mut() {
+        let mut x = *root - shift;
+        for _ in 0..3 {
+            let f = (((a * x + b) * x + c) * x + d) * x + e;
+            let df = ((4.0 * a * x + 3.0 * b) * x + 2.0 * c) * x + d;
+            if df == 0.0 {
+                break;
+            }
+            x -= f / df;
+        }
+        *root = x;
+    }
+    roots.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_roots(roots: Vec<f64>, expected: &[f64], tolerance: f64) {
+        assert_eq!(roots.len(), expected.len(), "found {:?}, expected {:?}", roots, expected);
+        for (root, expected) in roots.iter().zip(expected) {
+            assert!((root - expected).abs() < tolerance, "found {:?}, expected {:?}", roots, expected);
+        }
+    }
+
+    #[test]
+    fn cubic_with_three_roots() {
+        // (x - 1)(x - 2)(x - 3)
+        assert_roots(solve_cubic(1.0, -6.0, 11.0, -6.0), &[1.0, 2.0, 3.0], 1e-9);
+    }
+
+    #[test]
+    fn cubic_with_one_root() {
+        // (x - 1)(x^2 + x + 2)
+        assert_roots(solve_cubic(1.0, 0.0, 1.0, -2.0), &[1.0], 1e-9);
+    }
+
+    #[test]
+    fn quartic_with_four_roots() {
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        assert_roots(solve_quartic(1.0, -10.0, 35.0, -50.0, 24.0), &[1.0, 2.0, 3.0, 4.0], 1e-9);
+    }
+
+    #[test]
+    fn quartic_in_x_squared() {
+        // (x^2 - 1)(x^2 - 4)
+        assert_roots(solve_quartic(1.0, 0.0, -5.0, 0.0, 4.0), &[-2.0, -1.0, 1.0, 2.0], 1e-9);
+    }
+
+    #[test]
+    fn quartic_with_a_double_root() {
+        // (x - 1)^2 (x - 3)(x + 2), where the double root is only just touched, so it's found less
+        // precisely than the others.
+        let roots = solve_quartic(1.0, -3.0, -3.0, 11.0, -6.0);
+        assert_roots(roots, &[-2.0, 1.0, 1.0, 3.0], 1e-6);
+    }
+
+    #[test]
+    fn quartic_without_roots() {
+        assert_roots(solve_quartic(1.0, 0.0, 0.0, 0.0, 1.0), &[], 0.0);
+        // (x^2 + 1)(x^2 + 2x + 5), which isn't symmetric about 0.
+        assert_roots(solve_quartic(1.0, 2.0, 6.0, 2.0, 5.0), &[], 0.0);
+    }
+}
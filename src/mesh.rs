@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::ply::parse_ply;
+use crate::shading;
+use crate::stl::parse_stl;
+use crate::{Aabb, HitRecord, Hittable, HittableList, Material, Ray, Texture, Vec3};
+
+/// A mesh of triangles sharing a list of vertices. Normals, texture coordinates and colours are
+/// optional, but when present there is one per vertex.
+pub struct TriangleMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    pub uvs: Option<Vec<(f64, f64)>>,
+    pub colours: Option<Vec<Vec3>>,
+    pub indices: Vec<[usize; 3]>,
+}
+
+/// One triangle of a mesh.
+pub struct Triangle {
+    pub mesh: Rc<TriangleMesh>,
+    pub index: usize,
+    pub material: Rc<dyn Material>,
+}
+
+impl TriangleMesh {
+    pub fn new(positions: Vec<Vec3>, indices: Vec<[usize; 3]>) -> TriangleMesh {
+        TriangleMesh {
+            positions,
+            normals: None,
+            uvs: None,
+            colours: None,
+            indices,
+        }
+    }
+
+    /// Loads a PLY or STL file, going by its extension.
+    pub fn load(path: &str) -> Result<TriangleMesh, String> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        let bytes = std::fs::read(path).map_err(|e| format!("couldn't read mesh {}: {}", path, e))?;
+
+        let mesh = match extension.as_deref() {
+            Some("ply") => parse_ply(&bytes),
+            Some("stl") => parse_stl(&bytes),
+            _ => Err(String::from("unknown mesh format")),
+        };
+        mesh.map_err(|e| format!("couldn't load mesh {}: {}", path, e))
+    }
+
+    /// Checks that every triangle refers to vertices that exist.
+    pub fn validate(&self) -> Result<(), String> {
+        let count = self.positions.len();
+        if self.indices.iter().any(|t| t.iter().any(|i| *i >= count)) {
+            return Err(String::from("a face refers to a missing vertex"));
+        }
+        let matches = |n: Option<usize>| n.is_none_or(|n| n == count);
+        if !matches(self.normals.as_ref().map(|n| n.len()))
+            || !matches(self.uvs.as_ref().map(|n| n.len()))
+            || !matches(self.colours.as_ref().map(|n| n.len())) {
+            return Err(String::from("vertex attributes don't match the number of vertices"));
+        }
+        Ok(())
+    }
+
+    /// Splits every triangle into four at the midpoints of its edges, `levels` times over. Vertex
+    /// attributes are interpolated along the edges, and edges shared between triangles are only
+    /// split once so the mesh stays watertight.
+    pub fn subdivide(&self, levels: usize) -> TriangleMesh {
+        let mut mesh = TriangleMesh {
+            positions: self.positions.clone(),
+            normals: self.normals.clone(),
+            uvs: self.uvs.clone(),
+            colours: self.colours.clone(),
+            indices: self.indices.clone(),
+        };
+        for _ in 0..levels {
+            let mut midpoints: HashMap<(usize, usize), usize> = HashMap::new();
+            let mut indices = Vec::with_capacity(mesh.indices.len() * 4);
+            for t in mesh.indices.clone() {
+                let mut midpoint = |a: usize, b: usize| {
+                    *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| mesh.add_midpoint(a, b))
+                };
+                let m = [midpoint(t[0], t[1]), midpoint(t[1], t[2]), midpoint(t[2], t[0])];
+                indices.push([t[0], m[0], m[2]]);
+                indices.push([m[0], t[1], m[1]]);
+                indices.push([m[2], m[1], t[2]]);
+                indices.push([m[0], m[1], m[2]]);
+            }
+            mesh.indices = indices;
+        }
+        mesh
+    }
+
+    /// Adds a vertex halfway between two others, giving its index.
+    fn add_midpoint(&mut self, a: usize, b: usize) -> usize {
+        let halfway = |values: &[Vec3]| (values[a].clone() + values[b].clone()).mul(0.5);
+        self.positions.push(halfway(&self.positions));
+        if let Some(normals) = &mut self.normals {
+            normals.push(halfway(normals).unit_vector());
+        }
+        if let Some(uvs) = &mut self.uvs {
+            uvs.push(((uvs[a].0 + uvs[b].0) * 0.5, (uvs[a].1 + uvs[b].1) * 0.5));
+        }
+        if let Some(colours) = &mut self.colours {
+            colours.push(halfway(colours));
+        }
+        self.positions.len() - 1
+    }
+
+    /// Sets smooth vertex normals from the faces around each vertex, weighted by their areas.
+    pub fn compute_normals(&mut self) {
+        let mut normals = vec![Vec3::default(); self.positions.len()];
+        for t in &self.indices {
+            let p = &self.positions;
+            let edge1 = p[t[1]].clone() - p[t[0]].clone();
+            let edge2 = p[t[2]].clone() - p[t[0]].clone();
+            let area_normal = edge1.cross(&edge2); // Twice the area long.
+            for i in t {
+                normals[*i] = normals[*i].clone() + area_normal.clone();
+            }
+        }
+        self.normals = Some(normals.iter().map(|n| if n.near_zero() { n.clone() } else { n.unit_vector() }).collect());
+    }
+
+    /// A copy of the mesh subdivided `subdivisions` times, with every vertex then pushed out along
+    /// its normal by the height texture (the average of its channels) times `scale`. Normals are
+    /// worked out for the mesh first if it has none, and again for the displaced surface.
+    pub fn displaced(&self, height: &dyn Texture, scale: f64, subdivisions: usize) -> TriangleMesh {
+        let mut mesh = self.subdivide(subdivisions);
+        if mesh.normals.is_none() {
+            mesh.compute_normals();
+        }
+
+        let normals = mesh.normals.clone().unwrap_or_default();
+        for (i, normal) in normals.iter().enumerate() {
+            let (u, v) = mesh.uvs.as_ref().map_or((0.0, 0.0), |uvs| uvs[i]);
+            let rec = HitRecord {
+                point: mesh.positions[i].clone(),
+                normal: normal.clone(),
+                u,
+                v,
+                front_face: true,
+                colour: mesh.colours.as_ref().map(|c| c[i].clone()),
+                ..Default::default()
+            };
+            mesh.positions[i] = mesh.positions[i].clone() + normal.mul(shading::height(height, &rec) * scale);
+        }
+        mesh.compute_normals();
+        mesh
+    }
+
+    /// Splits the mesh into a list of its triangles, ready to be put in a BVH.
+    pub fn triangles(self: &Rc<Self>, material: Rc<dyn Material>) -> HittableList {
+        let mut list = HittableList::new();
+        for index in 0..self.indices.len() {
+            list.add(Rc::new(Triangle {
+                mesh: self.clone(),
+                index,
+                material: material.clone(),
+            }));
+        }
+        list
+    }
+}
+
+impl Triangle {
+    fn vertices(&self) -> [usize; 3] {
+        self.mesh.indices[self.index]
+    }
+}
+
+/// Blends three per-vertex values with barycentric weights.
+fn interpolate(values: &[Vec3], v: [usize; 3], b: (f64, f64, f64)) -> Vec3 {
+    values[v[0]].mul(b.0) + values[v[1]].mul(b.1) + values[v[2]].mul(b.2)
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        // Möller-Trumbore.
+        let v = self.vertices();
+        let p0 = &self.mesh.positions[v[0]];
+        let edge1 = self.mesh.positions[v[1]].clone() - p0.clone();
+        let edge2 = self.mesh.positions[v[2]].clone() - p0.clone();
+
+        let h = ray.direction.cross(&edge2);
+        let det = edge1.dot(&h);
+        if det.abs() < 1e-12 {
+            return false; // The ray runs parallel to the triangle.
+        }
+        let inv_det = 1.0 / det;
+
+        let s = ray.origin.clone() - p0.clone();
+        let b1 = s.dot(&h) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return false;
+        }
+        let q = s.cross(&edge1);
+        let b2 = ray.direction.dot(&q) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return false;
+        }
+        let t = edge2.dot(&q) * inv_det;
+        if t < t_min || t > t_max {
+            return false;
+        }
+
+        let b = (1.0 - b1 - b2, b1, b2);
+        rec.t = t;
+        rec.point = ray.at(t);
+
+        let geometric_normal = edge1.cross(&edge2).unit_vector();
+        let outward_normal = match &self.mesh.normals {
+            Some(normals) => interpolate(normals, v, b).unit_vector(),
+            None => geometric_normal,
+        };
+        rec.set_face_normal(ray, &outward_normal);
+
+        // Without texture coordinates, u and v are the barycentric coordinates of the hit.
+        let uv = match &self.mesh.uvs {
+            Some(uvs) => [uvs[v[0]], uvs[v[1]], uvs[v[2]]],
+            None => [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)],
+        };
+        rec.u = uv[0].0 * b.0 + uv[1].0 * b.1 + uv[2].0 * b.2;
+        rec.v = uv[0].1 * b.0 + uv[1].1 * b.1 + uv[2].1 * b.2;
+
+        // Solve for the directions across the triangle in which u and v increase.
+        let (du1, dv1) = (uv[1].0 - uv[0].0, uv[1].1 - uv[0].1);
+        let (du2, dv2) = (uv[2].0 - uv[0].0, uv[2].1 - uv[0].1);
+        let uv_det = du1 * dv2 - dv1 * du2;
+        if uv_det.abs() > 1e-12 {
+            rec.dpdu = (edge1.mul(dv2) - edge2.mul(dv1)).div(uv_det);
+            rec.dpdv = (edge2.mul(du1) - edge1.mul(du2)).div(uv_det);
+        } else {
+            (rec.dpdu, rec.dpdv) = outward_normal.orthonormal_basis();
+        }
+        rec.colour = self.mesh.colours.as_ref().map(|colours| interpolate(colours, v, b));
+        rec.material = Some(self.material.clone());
+        true
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
+        let v = self.vertices();
+        let p = &self.mesh.positions;
+        let minimum = p[v[0]].min(&p[v[1]]).min(&p[v[2]]);
+        let maximum = p[v[0]].max(&p[v[1]]).max(&p[v[2]]);
+        *output_box = Aabb::new(minimum, maximum);
+        true
+    }
+}
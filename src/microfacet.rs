@@ -0,0 +1,405 @@
+use std::f64::consts::PI;
+
+use num::complex::Complex64;
+
+use crate::spectrum::SODIUM_D;
+use crate::{Dispersion, HitRecord, Material, Ray, Vec3};
+
+// Rough surfaces modelled as a mass of tiny mirror facets, using the GGX (Trowbridge-Reitz)
+// distribution of facet normals with Smith height-correlated shadowing. Everything here works in
+// a local frame around the shading normal, which is the z axis, and with both directions pointing
+// away from the surface.
+
+// Below this roughness a surface is treated as a perfect mirror, as the distribution gets too
+// sharp to sample or evaluate reliably.
+const SMOOTH_ALPHA: f64 = 1e-3;
+
+/// The GGX distribution of microfacet normals, with separate roughness along the tangent and
+/// bitangent for brushed, anisotropic surfaces.
+#[derive(Clone)]
+pub struct Ggx {
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+/// A metal, given by its complex index of refraction per colour channel. Measured values for
+/// common metals are provided.
+pub struct Conductor {
+    pub eta: Vec3,
+    pub k: Vec3, // The extinction coefficient, the imaginary part of the index.
+    pub distribution: Ggx,
+}
+
+/// Glass or another clear dielectric with a rough surface, reflecting some light and refracting
+/// the rest. A roughness of zero gives smooth glass.
+pub struct RoughDielectric {
+    pub ior: f64,
+    pub dispersion: Option<Dispersion>, // Overrides the index when rendering spectrally.
+    pub distribution: Ggx,
+}
+
+/// An orthonormal frame around a shading normal. The tangent circles the y axis, which keeps the
+/// grain of anisotropic surfaces continuous everywhere but straight up and down.
+pub struct Frame {
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+    pub normal: Vec3,
+}
+
+impl Frame {
+    pub fn new(normal: &Vec3) -> Frame {
+        let normal = normal.unit_vector();
+        let around_y = Vec3::new(0.0, 1.0, 0.0).cross(&normal);
+        let (tangent, bitangent) = if around_y.length_sq() > 1e-12 {
+            let tangent = around_y.unit_vector();
+            let bitangent = normal.cross(&tangent);
+            (tangent, bitangent)
+        } else {
+            normal.orthonormal_basis()
+        };
+        Frame {
+            tangent,
+            bitangent,
+            normal,
+        }
+    }
+
+    /// The shading frame at a hit, with the tangent following the direction u increases in when
+    /// the surface provides one.
+    pub fn from_hit(rec: &HitRecord) -> Frame {
+        let normal = rec.normal.unit_vector();
+        let tangent = rec.dpdu.clone() - normal.mul(normal.dot(&rec.dpdu));
+        if tangent.length_sq() < 1e-12 {
+            return Frame::new(&normal);
+        }
+        let tangent = tangent.unit_vector();
+        Frame {
+            bitangent: normal.cross(&tangent),
+            tangent,
+            normal,
+        }
+    }
+
+    pub fn to_local(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(v.dot(&self.tangent), v.dot(&self.bitangent), v.dot(&self.normal))
+    }
+
+    pub fn to_world(&self, v: &Vec3) -> Vec3 {
+        self.tangent.mul(v.x) + self.bitangent.mul(v.y) + self.normal.mul(v.z)
+    }
+}
+
+impl Ggx {
+    /// Roughness is perceptual, so it is squared to get the width of the distribution.
+    pub fn new(roughness_x: f64, roughness_y: f64) -> Ggx {
+        Ggx {
+            alpha_x: (roughness_x * roughness_x).max(1e-4),
+            alpha_y: (roughness_y * roughness_y).max(1e-4),
+        }
+    }
+
+    pub fn isotropic(roughness: f64) -> Ggx {
+        Ggx::new(roughness, roughness)
+    }
+
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ALPHA
+    }
+
+    /// The density of facets facing along `h`.
+    pub fn d(&self, h: &Vec3) -> f64 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let e = (h.x / self.alpha_x).powi(2) + (h.y / self.alpha_y).powi(2) + h.z * h.z;
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    /// Smith's auxiliary function, from which the shadowing terms are built.
+    pub fn lambda(&self, w: &Vec3) -> f64 {
+        if w.z == 0.0 {
+            return f64::INFINITY;
+        }
+        let a2 = ((self.alpha_x * w.x).powi(2) + (self.alpha_y * w.y).powi(2)) / (w.z * w.z);
+        ((1.0 + a2).sqrt() - 1.0) / 2.0
+    }
+
+    /// The fraction of facets visible from one direction.
+    pub fn g1(&self, w: &Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// The fraction of facets visible from both directions, accounting for facets that are
+    /// visible from one being more likely to be visible from the other.
+    pub fn g2(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Samples a facet normal in proportion to how much of it can be seen from `wo`, which cuts
+    /// out the facets that couldn't have been hit (Heitz 2018).
+    pub fn sample_visible_normal(&self, wo: &Vec3) -> Vec3 {
+        // Stretch the view direction so the distribution becomes a hemisphere.
+        let wh = Vec3::new(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).unit_vector();
+        let len_sq = wh.x * wh.x + wh.y * wh.y;
+        let t1 = if len_sq > 0.0 {
+            Vec3::new(-wh.y, wh.x, 0.0).div(len_sq.sqrt())
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = wh.cross(&t1);
+
+        // Sample the projected area of the hemisphere seen from wh.
+        let r = fastrand::f64().sqrt();
+        let phi = 2.0 * PI * fastrand::f64();
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + wh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = t1.mul(p1) + t2.mul(p2) + wh.mul((1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt());
+
+        // And squash it back.
+        Vec3::new(self.alpha_x * nh.x, self.alpha_y * nh.y, nh.z.max(1e-6)).unit_vector()
+    }
+}
+
+/// The fraction of light reflected by a dielectric, where `eta` is the index of refraction on
+/// the far side relative to the near one. Light from behind, with a negative cosine, is handled
+/// too.
+pub fn fresnel_dielectric(cos_theta_i: f64, eta: f64) -> f64 {
+    let (mut cos_i, mut eta) = (cos_theta_i.clamp(-1.0, 1.0), eta);
+    if cos_i < 0.0 {
+        eta = 1.0 / eta;
+        cos_i = -cos_i;
+    }
+
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0; // Total internal reflection.
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parallel * r_parallel + r_perpendicular * r_perpendicular) / 2.0
+}
+
+/// The fraction of light reflected by a conductor with a complex index of refraction.
+pub fn fresnel_complex(cos_theta_i: f64, eta: Complex64) -> f64 {
+    let cos_i = cos_theta_i.clamp(0.0, 1.0);
+    let sin2_t = Complex64::new(1.0 - cos_i * cos_i, 0.0) / (eta * eta);
+    let cos_t = (Complex64::new(1.0, 0.0) - sin2_t).sqrt();
+
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parallel.norm_sqr() + r_perpendicular.norm_sqr()) / 2.0
+}
+
+/// Mirrors `w` about `n`, where both point away from the surface.
+pub fn reflect(w: &Vec3, n: &Vec3) -> Vec3 {
+    n.mul(2.0 * w.dot(n)) - w.clone()
+}
+
+/// Bends `w` through a surface with normal `n` on the same side as it, and a relative index of
+/// refraction `eta`. Gives None for total internal reflection.
+pub fn refract(w: &Vec3, n: &Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = w.dot(n);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(w.mul(-1.0 / eta) + n.mul(cos_i / eta - cos_t))
+}
+
+impl Conductor {
+    pub fn new(eta: Vec3, k: Vec3, roughness: f64) -> Conductor {
+        Conductor::anisotropic(eta, k, roughness, roughness)
+    }
+
+    /// A conductor rougher along one tangent direction than the other, like brushed metal.
+    pub fn anisotropic(eta: Vec3, k: Vec3, roughness_x: f64, roughness_y: f64) -> Conductor {
+        Conductor {
+            eta,
+            k,
+            distribution: Ggx::new(roughness_x, roughness_y),
+        }
+    }
+
+    pub fn gold(roughness: f64) -> Conductor {
+        Conductor::new(Vec3::new(0.143, 0.374, 1.442), Vec3::new(3.983, 2.385, 1.603), roughness)
+    }
+
+    pub fn silver(roughness: f64) -> Conductor {
+        Conductor::new(Vec3::new(0.155, 0.117, 0.138), Vec3::new(4.828, 3.122, 2.147), roughness)
+    }
+
+    pub fn copper(roughness: f64) -> Conductor {
+        Conductor::new(Vec3::new(0.200, 0.924, 1.102), Vec3::new(3.912, 2.452, 2.142), roughness)
+    }
+
+    pub fn aluminium(roughness: f64) -> Conductor {
+        Conductor::new(Vec3::new(1.657, 0.880, 0.521), Vec3::new(9.224, 6.270, 4.837), roughness)
+    }
+
+    fn fresnel(&self, cos_theta: f64) -> Vec3 {
+        Vec3::new(
+            fresnel_complex(cos_theta, Complex64::new(self.eta.x, self.k.x)),
+            fresnel_complex(cos_theta, Complex64::new(self.eta.y, self.k.y)),
+            fresnel_complex(cos_theta, Complex64::new(self.eta.z, self.k.z)),
+        )
+    }
+}
+
+impl Material for Conductor {
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+        let frame = Frame::from_hit(rec);
+        let wo = frame.to_local(&ray_in.direction.unit_vector().mul(-1.0));
+        if wo.z <= 0.0 {
+            return false;
+        }
+
+        let ggx = &self.distribution;
+        let h = if ggx.is_smooth() { Vec3::new(0.0, 0.0, 1.0) } else { ggx.sample_visible_normal(&wo) };
+        let wi = reflect(&wo, &h);
+        if wi.z <= 0.0 {
+            return false; // Reflected into the surface, so shadowed by another facet.
+        }
+
+        // Sampling visible normals leaves only Fresnel and the part of G2 not in G1.
+        let shadowing = if ggx.is_smooth() { 1.0 } else { ggx.g2(&wo, &wi) / ggx.g1(&wo) };
+        *attenuation = self.fresnel(wo.dot(&h)).mul(shadowing);
+        *scattered = Ray::new(rec.point.clone(), frame.to_world(&wi), ray_in.time);
+        true
+    }
+
+    fn eval(&self, ray_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        let ggx = &self.distribution;
+        if ggx.is_smooth() {
+            return Vec3::default();
+        }
+        let frame = Frame::from_hit(rec);
+        let wo = frame.to_local(&ray_in.direction.unit_vector().mul(-1.0));
+        let wi = frame.to_local(&direction.unit_vector());
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::default();
+        }
+
+        let h = (wo.clone() + wi.clone()).unit_vector();
+        self.fresnel(wo.dot(&h)).mul(ggx.d(&h) * ggx.g2(&wo, &wi) / (4.0 * wo.z))
+    }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        self.fresnel(1.0)
+    }
+}
+
+impl RoughDielectric {
+    pub fn new(ior: f64, roughness: f64) -> RoughDielectric {
+        RoughDielectric::anisotropic(ior, roughness, roughness)
+    }
+
+    pub fn anisotropic(ior: f64, roughness_x: f64, roughness_y: f64) -> RoughDielectric {
+        RoughDielectric {
+            ior,
+            dispersion: None,
+            distribution: Ggx::new(roughness_x, roughness_y),
+        }
+    }
+
+    /// A dielectric whose index changes with wavelength, splitting light into colours in a
+    /// spectral render. Otherwise its index at the sodium D line is used.
+    pub fn with_dispersion(dispersion: Dispersion, roughness: f64) -> RoughDielectric {
+        RoughDielectric {
+            ior: dispersion.ior(SODIUM_D),
+            dispersion: Some(dispersion),
+            distribution: Ggx::new(roughness, roughness),
+        }
+    }
+
+    /// The index of refraction for a ray, and its inverse from the inside.
+    fn eta(&self, ray: &Ray, rec: &HitRecord) -> f64 {
+        let ior = match (&self.dispersion, ray.wavelength) {
+            (Some(dispersion), Some(lambda)) => dispersion.ior(lambda),
+            _ => self.ior,
+        };
+        if rec.front_face { ior } else { 1.0 / ior }
+    }
+}
+
+impl Material for RoughDielectric {
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+        let frame = Frame::from_hit(rec);
+        let wo = frame.to_local(&ray_in.direction.unit_vector().mul(-1.0));
+        if wo.z <= 0.0 {
+            return false;
+        }
+        // The index on the far side relative to this one.
+        let eta = self.eta(ray_in, rec);
+
+        let ggx = &self.distribution;
+        let h = if ggx.is_smooth() { Vec3::new(0.0, 0.0, 1.0) } else { ggx.sample_visible_normal(&wo) };
+        let fresnel = fresnel_dielectric(wo.dot(&h), eta);
+
+        // Choose reflection or refraction with the Fresnel probability, which then drops out.
+        let (wi, scale) = if fastrand::f64() < fresnel {
+            let wi = reflect(&wo, &h);
+            if wi.z <= 0.0 {
+                return false;
+            }
+            (wi, 1.0)
+        } else {
+            let wi = match refract(&wo, &h, eta) {
+                Some(wi) if wi.z < 0.0 => wi,
+                _ => return false,
+            };
+            // Radiance is squeezed into a narrower cone entering a denser medium.
+            (wi, 1.0 / (eta * eta))
+        };
+
+        let shadowing = if ggx.is_smooth() { 1.0 } else { ggx.g2(&wo, &wi) / ggx.g1(&wo) };
+        *attenuation = Vec3::from(scale * shadowing);
+        *scattered = Ray::new(rec.point.clone(), frame.to_world(&wi), ray_in.time);
+        true
+    }
+
+    fn eval(&self, ray_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        let ggx = &self.distribution;
+        if ggx.is_smooth() {
+            return Vec3::default();
+        }
+        let frame = Frame::from_hit(rec);
+        let wo = frame.to_local(&ray_in.direction.unit_vector().mul(-1.0));
+        let wi = frame.to_local(&direction.unit_vector());
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return Vec3::default();
+        }
+        let eta = self.eta(ray_in, rec);
+
+        let reflection = wi.z > 0.0;
+        let h = if reflection { wo.clone() + wi.clone() } else { wi.mul(eta) + wo.clone() };
+        if h.near_zero() {
+            return Vec3::default();
+        }
+        let mut h = h.unit_vector();
+        if h.z < 0.0 {
+            h = h.mul(-1.0);
+        }
+        // Facets seen from behind by either direction can't take part.
+        if wo.dot(&h) <= 0.0 || wi.dot(&h) * wi.z <= 0.0 {
+            return Vec3::default();
+        }
+
+        let fresnel = fresnel_dielectric(wo.dot(&h), eta);
+        let dg = ggx.d(&h) * ggx.g2(&wo, &wi);
+        let value = if reflection {
+            fresnel * dg / (4.0 * wo.z)
+        } else {
+            // The cosine of wi cancels the one in the BSDF's denominator.
+            let denom = (wi.dot(&h) + wo.dot(&h) / eta).powi(2) * wo.z;
+            (1.0 - fresnel) * dg * (wi.dot(&h) * wo.dot(&h) / denom).abs() / (eta * eta)
+        };
+        Vec3::from(value)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.dispersion.is_some()
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::{BumpMap, ColourSpace, ImageTexture, Material, NormalMap, Principled, Texture, Vec3};
+
+// Wavefront MTL material libraries, as written alongside OBJ files. Each material becomes a
+// principled material. The classic Phong parameters are mapped across as well as they can be:
+//
+// - Kd is the base colour, and map_Kd its texture.
+// - Ks scales the usual dielectric reflectance, so a Ks of 1 gives the ordinary 4%.
+// - Ns, the Phong exponent, is turned into the equivalent roughness.
+// - Ni is the index of refraction, and a dissolve (d, or Tr as 1 - d) below 1 is transmission.
+// - Ke is the emission, and map_Ke its texture.
+// - bump (or map_Bump) is a bump map, scaled by its -bm option, and the PBR extension's norm is a
+//   tangent-space normal map.
+//
+// The PBR extension's Pr, Pm, Ps, Pc, Pcr and aniso set roughness, metallic, sheen, clearcoat,
+// clearcoat roughness and anisotropy directly, taking precedence over Ns. Other statements and
+// texture maps are ignored.
+
+/// Loads an MTL file, giving its materials by name. Textures are found relative to the file, and
+/// colours, which are sRGB, are converted to `working_space`.
+pub fn load_mtl(path: &str, working_space: ColourSpace) -> Result<HashMap<String, Rc<dyn Material>>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("couldn't read materials {}: {}", path, e))?;
+    let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    parse_mtl(&text, base, working_space).map_err(|e| format!("couldn't load materials {}: {}", path, e))
+}
+
+pub fn parse_mtl(text: &str, base: &Path, working_space: ColourSpace) -> Result<HashMap<String, Rc<dyn Material>>, String> {
+    let mut materials: HashMap<String, Rc<dyn Material>> = HashMap::new();
+    let mut current: Option<Surface> = None;
+    let mut has_roughness = false;
+
+    for (number, line) in text.lines().enumerate() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (keyword, args) = match words.split_first() {
+            Some((k, _)) if k.starts_with('#') => continue,
+            Some((k, args)) => (*k, args),
+            None => continue,
+        };
+
+        if keyword == "newmtl" {
+            if let Some(surface) = current.take() {
+                materials.insert(surface.name.clone(), surface.finish());
+            }
+            current = Some(Surface {
+                name: args.join(" "),
+                material: Principled::new(Vec3::from(0.8)),
+                bump: None,
+                normal_map: None,
+            });
+            has_roughness = false;
+            continue;
+        }
+        let surface = match &mut current {
+            Some(surface) => surface,
+            None => return Err(format!("line {}: {} before any newmtl", number + 1, keyword)),
+        };
+
+        let values = || -> Result<Vec<f64>, String> {
+            args.iter()
+                .map(|a| a.parse::<f64>().map_err(|_| format!("line {}: bad number {}", number + 1, a)))
+                .collect()
+        };
+        let value = || -> Result<f64, String> {
+            values()?.first().cloned().ok_or_else(|| format!("line {}: {} needs a value", number + 1, keyword))
+        };
+        let colour = || -> Result<Vec3, String> {
+            match values()?.as_slice() {
+                [r, g, b, ..] => Ok(ColourSpace::LinearSrgb.convert(&Vec3::new(*r, *g, *b), working_space)),
+                [v] => Ok(Vec3::from(*v)),
+                _ => Err(format!("line {}: {} needs a colour", number + 1, keyword)),
+            }
+        };
+        // Texture statements can have options before the file name, which always comes last.
+        let texture = |srgb: bool| -> Result<Option<Rc<dyn Texture>>, String> {
+            let file = args.last().ok_or_else(|| format!("line {}: {} needs a file", number + 1, keyword))?;
+            let path = base.join(file);
+            let mut image = ImageTexture::load(&path.to_string_lossy(), srgb)?;
+            if srgb {
+                image.convert(ColourSpace::LinearSrgb, working_space);
+            }
+            Ok(Some(Rc::new(image)))
+        };
+
+        let material = &mut surface.material;
+        match keyword {
+            "Kd" => material.base_colour = colour()?,
+            "map_Kd" => material.base_colour_texture = texture(true)?,
+            "Ks" => {
+                let ks = colour()?;
+                material.specular = (ks.x.max(ks.y).max(ks.z) * 0.5).min(1.0);
+            }
+            "Ns" if !has_roughness => {
+                // The roughness whose GGX lobe is about as wide as this Phong lobe.
+                let alpha = (2.0 / (value()?.max(0.0) + 2.0)).sqrt();
+                material.roughness = alpha.sqrt();
+            }
+            "Ni" => material.ior = value()?,
+            "d" => material.transmission = (1.0 - value()?).clamp(0.0, 1.0),
+            "Tr" => material.transmission = value()?.clamp(0.0, 1.0),
+            "Ke" => material.emissive = colour()?,
+            "map_Ke" => {
+                material.emissive_texture = texture(true)?;
+                if material.emissive.near_zero() {
+                    material.emissive = Vec3::from(1.0);
+                }
+            }
+            "Pr" => {
+                material.roughness = value()?;
+                has_roughness = true;
+            }
+            "Pm" => material.metallic = value()?,
+            "Ps" => {
+                let sheen = colour()?;
+                material.sheen = sheen.x.max(sheen.y).max(sheen.z);
+            }
+            "Pc" => material.clearcoat = value()?,
+            "Pcr" => material.clearcoat_gloss = 1.0 - value()?,
+            "aniso" => material.anisotropic = value()?,
+            "bump" | "map_Bump" | "map_bump" => {
+                let scale = match args.iter().position(|a| *a == "-bm") {
+                    Some(i) => args.get(i + 1)
+                        .and_then(|a| a.parse::<f64>().ok())
+                        .ok_or_else(|| format!("line {}: -bm needs a number", number + 1))?,
+                    None => 1.0,
+                };
+                surface.bump = texture(false)?.map(|t| (t, scale));
+            }
+            "norm" => surface.normal_map = texture(false)?,
+            _ => {}
+        }
+    }
+
+    if let Some(surface) = current.take() {
+        materials.insert(surface.name.clone(), surface.finish());
+    }
+    Ok(materials)
+}
+
+/// A material as it's being read, with the maps that go on top of it.
+struct Surface {
+    name: String,
+    material: Principled,
+    bump: Option<(Rc<dyn Texture>, f64)>,
+    normal_map: Option<Rc<dyn Texture>>,
+}
+
+impl Surface {
+    fn finish(self) -> Rc<dyn Material> {
+        let mut material: Rc<dyn Material> = Rc::new(self.material);
+        if let Some(map) = self.normal_map {
+            material = Rc::new(NormalMap::new(material, map, 1.0));
+        }
+        if let Some((height, scale)) = self.bump {
+            material = Rc::new(BumpMap::new(material, height, scale));
+        }
+        material
+    }
+}
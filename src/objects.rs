@@ -1,13 +1,31 @@
-use crate::{Vec3, unit_vector, Ray};
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use crate::lens::FILM_DIAGONAL;
+use crate::{LensSystem, Projection, Vec3, degrees_to_radians, unit_vector, Ray};
+
+// Cameras can be set up the way a photographer would: the ISO sensitivity of the film, how long
+// the shutter stays open and the f-number of the aperture together decide how bright the picture
+// comes out, taking the light in the scene to be in candelas per square metre. The shutter speed
+// also decides how much moving things blur, and the f-number how wide the lens is and so how
+// quickly things out of focus blur, as for a 35 mm full-frame camera with the same field of view.
+// Either can be set without an exposure, which leaves the scene's light as it is.
 
 pub struct Camera {
     pub width: u32,
     pub height: u32,
     pub aspect_ratio: f64,
-    pub viewport_width: u32,
-    pub viewport_height: u32,
+    pub viewport_width: f64,
+    pub viewport_height: f64,
     pub focal_length: f64,
     pub position: Vec3,
+    pub shutter_open: f64, // Rays are cast at random times between the shutter opening and closing,
+    pub shutter_close: f64, // which blurs anything moving during that interval.
+    pub projection: Projection,
+    pub lens: Option<Rc<LensSystem>>, // Traced through in place of the thin lens and field of view.
+    pub aperture_radius: f64, // Of the thin lens, or 0 for a pinhole with everything in focus.
+    pub focus_distance: f64, // How far in front of the camera the thin lens is focused.
+    pub exposure: Option<Exposure>, // Without one, the scene's light is recorded as it is.
 
     horizontal: Vec3,
     vertical: Vec3,
@@ -15,35 +33,137 @@ pub struct Camera {
 }
 
 impl Camera {
+    /// A camera at `position` looking down the negative z axis.
     pub fn new(width: u32,
                aspect_ratio: f64,
-               viewport_height: u32,
+               viewport_height: f64,
                focal_length: f64,
                position: Vec3
     ) -> Camera {
+        Camera::oriented(width, aspect_ratio, viewport_height, focal_length, position,
+                         (Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0)))
+    }
+
+    /// A camera at `look_from` pointed at `look_at`, with a vertical field of view in degrees. `up`
+    /// only needs to be roughly upwards; the camera is kept level with it.
+    pub fn looking_at(width: u32,
+                      aspect_ratio: f64,
+                      vertical_fov: f64,
+                      look_from: Vec3,
+                      look_at: Vec3,
+                      up: Vec3
+    ) -> Camera {
+        let w = (look_from.clone() - look_at).unit_vector();
+        let u = up.cross(&w).unit_vector();
+        let v = w.cross(&u);
+        let viewport_height = 2.0 * (degrees_to_radians(vertical_fov) / 2.0).tan();
+        Camera::oriented(width, aspect_ratio, viewport_height, 1.0, look_from, (u, v, w))
+    }
+
+    /// Builds a camera from the basis of its view: right, up, and backwards out of the screen.
+    fn oriented(width: u32,
+                aspect_ratio: f64,
+                viewport_height: f64,
+                focal_length: f64,
+                position: Vec3,
+                (u, v, w): (Vec3, Vec3, Vec3)
+    ) -> Camera {
+        let viewport_width = aspect_ratio * viewport_height;
+        let horizontal = u.mul(viewport_width);
+        let vertical = v.mul(viewport_height);
         Camera {
             width,
             height: (width as f64 / aspect_ratio) as u32,
             aspect_ratio,
-            viewport_width: (aspect_ratio * viewport_height as f64) as u32,
+            viewport_width,
             viewport_height,
             focal_length,
             position: position.clone(),
-            horizontal: Vec3 { x: aspect_ratio * viewport_height as f64, y: 0.0, z: 0.0, },
-            vertical: Vec3 { x: 0.0, y: viewport_height as f64, z: 0.0, },
-            lower_left_corner: position.clone()
-                - Vec3 { x: aspect_ratio * viewport_height as f64, y: 0.0, z: 0.0, }.div(2.0)
-                - Vec3 { x: 0.0, y: viewport_height as f64, z: 0.0, }.div(2.0)
-                - Vec3 {x: 0.0, y: 0.0, z: focal_length, }
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            projection: Projection::Perspective,
+            lens: None,
+            aperture_radius: 0.0,
+            focus_distance: 1.0,
+            exposure: None,
+            lower_left_corner: position - horizontal.div(2.0) - vertical.div(2.0) - w.mul(focal_length),
+            horizontal,
+            vertical,
         }
     }
+
+    /// Changes how the camera lays the world out on its film, reshaping the image to suit
+    /// projections that need a particular shape.
+    pub fn set_projection(&mut self, projection: Projection) {
+        if let Some(aspect_ratio) = projection.aspect_ratio() {
+            self.aspect_ratio = aspect_ratio;
+            self.height = ((self.width as f64 / aspect_ratio) as u32).max(1);
+        }
+        self.projection = projection;
+    }
+
+    /// Opens the thin lens to an f-number, as if it were a 35 mm full-frame camera's lens with the
+    /// same field of view.
+    pub fn set_f_number(&mut self, f_number: f64) {
+        self.aperture_radius = self.full_frame_focal_length() / f_number / 2.0;
+    }
+
+    /// The f-number the camera's lens is open at, or None for a pinhole.
+    pub fn f_number(&self) -> Option<f64> {
+        match &self.lens {
+            Some(lens) => Some(lens.f_number()),
+            None if self.aperture_radius > 0.0 => Some(self.full_frame_focal_length() / self.aperture_radius / 2.0),
+            None => None,
+        }
+    }
+
+    fn full_frame_focal_length(&self) -> f64 {
+        let film_height = FILM_DIAGONAL / (1.0 + self.aspect_ratio * self.aspect_ratio).sqrt();
+        film_height * self.focal_length / self.viewport_height
+    }
+
+    /// How much the light reaching the film is scaled by to make the picture.
+    pub fn exposure_scale(&self) -> f64 {
+        self.exposure.as_ref().map_or(1.0, Exposure::scale)
+    }
+}
+
+/// The settings that decide how bright a photograph comes out.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Exposure {
+    pub iso: f64,
+    pub shutter_speed: f64, // In seconds.
+    pub f_number: f64,
+}
+
+impl Exposure {
+    /// Reads a shutter speed in seconds, either as a number or as a fraction like 1/125.
+    pub fn parse_shutter_speed(text: &str) -> Result<f64, String> {
+        let seconds = match text.split_once('/') {
+            Some((top, bottom)) => top.parse::<f64>().ok().zip(bottom.parse::<f64>().ok()).map(|(t, b)| t / b),
+            None => text.parse().ok(),
+        };
+        seconds.filter(|s| s.is_finite() && *s > 0.0).ok_or(format!("bad shutter speed {}", text))
+    }
+
+    /// The exposure value at ISO 100 that gives the same picture: each step up halves the light
+    /// let in.
+    pub fn ev100(&self) -> f64 {
+        (self.f_number * self.f_number / self.shutter_speed * 100.0 / self.iso).log2()
+    }
+
+    /// How much the luminance in the scene is scaled by, so that the brightest that doesn't
+    /// saturate the film comes out white, using the saturation-based sensitivity in ISO 12232.
+    pub fn scale(&self) -> f64 {
+        1.0 / (1.2 * self.ev100().exp2())
+    }
 }
 
 impl Default for Camera {
     fn default() -> Self {
         Camera::new(1280,
                     16.0 / 9.0,
-                    2,
+                    2.0,
                     1.0,
                     unit_vector(0.0)
         )
@@ -51,10 +171,67 @@ impl Default for Camera {
 }
 
 impl Camera {
-    pub fn get_ray(&self, u: f64, v: f64) -> Ray {
-        Ray {
-            origin: self.position.clone(),
-            direction: self.lower_left_corner.clone() + self.horizontal.mul(u) + self.vertical.mul(v) - self.position.clone()
+    /// The ray through a point on the film, from `u` = 0 at the left to 1 at the right and `v` = 0
+    /// at the bottom to 1 at the top, with the weight of the light it brings back, or None if the
+    /// projection doesn't reach there or the lens blocks it.
+    pub fn get_ray(&self, u: f64, v: f64) -> Option<(Ray, f64)> {
+        // Projections other than the thin lens cover the film exactly, without u and v running a
+        // pixel past the far edges.
+        let s = u * (self.width as f64 - 1.0) / self.width as f64;
+        let t = v * (self.height as f64 - 1.0) / self.height as f64;
+        let (origin, direction, weight) = match (&self.lens, self.projection) {
+            (Some(lens), _) => {
+                // An ideal lens at the same f-number would bring the middle of the film π / 4N² of
+                // the radiance, which an exposure already allows for, so the lens is weighed
+                // against that. Like the thin lens, it then records the scene's light much as it
+                // is in the middle of the picture, and darker towards the corners.
+                let (offset, direction, weight) = lens.sample(s, t, self.aspect_ratio)?;
+                let ideal = PI / (4.0 * lens.f_number() * lens.f_number());
+                (self.position.clone() + self.to_world(&offset), self.to_world(&direction), weight / ideal)
+            }
+            (None, Projection::Perspective) => {
+                let direction = self.lower_left_corner.clone() + self.horizontal.mul(u) + self.vertical.mul(v) - self.position.clone();
+                if self.aperture_radius > 0.0 {
+                    // Start somewhere on the lens, aimed at where the ray through its middle meets
+                    // the plane in focus.
+                    let focus = direction.mul(self.focus_distance / self.focal_length);
+                    let (x, y) = random_in_unit_disc();
+                    let offset = self.to_world(&Vec3::new(x, y, 0.0)).mul(self.aperture_radius);
+                    (self.position.clone() + offset.clone(), focus - offset, 1.0)
+                } else {
+                    (self.position.clone(), direction, 1.0)
+                }
+            }
+            (None, projection) => {
+                let (offset, direction) = projection.sample(s, t, self.aspect_ratio)?;
+                (self.position.clone() + self.to_world(&offset), self.to_world(&direction), 1.0)
+            }
+        };
+        let ray = Ray {
+            origin,
+            direction,
+            time: self.shutter_open + fastrand::f64() * (self.shutter_close - self.shutter_open),
+            wavelength: None,
+        };
+        Some((ray, weight))
+    }
+
+    /// Turns a vector in the camera's space, with x to the right, y up and z backwards, into the
+    /// world's.
+    fn to_world(&self, v: &Vec3) -> Vec3 {
+        let right = self.horizontal.unit_vector();
+        let up = self.vertical.unit_vector();
+        let back = right.cross(&up);
+        right.mul(v.x) + up.mul(v.y) + back.mul(v.z)
+    }
+}
+
+/// A random point in the unit disc, by rejection.
+fn random_in_unit_disc() -> (f64, f64) {
+    loop {
+        let (x, y) = (fastrand::f64() * 2.0 - 1.0, fastrand::f64() * 2.0 - 1.0);
+        if x * x + y * y < 1.0 {
+            return (x, y);
         }
     }
 }
\ No newline at end of file
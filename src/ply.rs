@@ -0,0 +1,353 @@
+use crate::{TriangleMesh, Vec3};
+
+// Stanford PLY files, in ASCII or either byte order of binary. Vertices may carry normals
+// (nx, ny, nz), texture coordinates (u and v, or s and t) and colours (red, green and blue), and
+// faces with more than three corners are split into fans of triangles. Any other elements and
+// properties are read past and ignored.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+enum Property {
+    Scalar(String, Scalar),
+    List(String, Scalar, Scalar), // The type of the count, then the type of the items.
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Scalar, String> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return Err(format!("unknown property type {}", name)),
+        })
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+}
+
+impl Property {
+    fn name(&self) -> &str {
+        match self {
+            Property::Scalar(name, _) | Property::List(name, _, _) => name,
+        }
+    }
+}
+
+/// Reads values from the body of the file, whatever its format.
+struct Reader<'a> {
+    format: Format,
+    bytes: &'a [u8],
+    tokens: std::str::SplitAsciiWhitespace<'a>,
+}
+
+impl<'a> Reader<'a> {
+    fn scalar(&mut self, scalar: Scalar) -> Result<f64, String> {
+        if self.format == Format::Ascii {
+            let token = self.tokens.next().ok_or("file ends early")?;
+            return token.parse::<f64>().map_err(|_| format!("bad number {}", token));
+        }
+
+        let size = scalar.size();
+        if self.bytes.len() < size {
+            return Err(String::from("file ends early"));
+        }
+        let (raw, rest) = self.bytes.split_at(size);
+        self.bytes = rest;
+
+        let mut b = [0u8; 8];
+        b[..size].copy_from_slice(raw);
+        if self.format == Format::BinaryBigEndian {
+            b[..size].reverse();
+        }
+        Ok(match scalar {
+            Scalar::I8 => b[0] as i8 as f64,
+            Scalar::U8 => b[0] as f64,
+            Scalar::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+            Scalar::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Scalar::F64 => f64::from_le_bytes(b),
+        })
+    }
+}
+
+pub fn parse_ply(bytes: &[u8]) -> Result<TriangleMesh, String> {
+    // The header is always ASCII, and ends with an end_header line.
+    let marker = b"end_header";
+    let header_end = bytes.windows(marker.len())
+        .position(|w| w == marker)
+        .ok_or("no end_header line")?;
+    let body_start = bytes[header_end..].iter()
+        .position(|b| *b == b'\n')
+        .map_or(bytes.len(), |p| header_end + p + 1);
+    let header = std::str::from_utf8(&bytes[..header_end]).map_err(|_| "header is not text")?;
+
+    let mut lines = header.lines();
+    if lines.next().map(|l| l.trim()) != Some("ply") {
+        return Err(String::from("not a PLY file"));
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["format", f, _] => {
+                format = Some(match *f {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => return Err(format!("unknown format {}", f)),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| format!("bad element count {}", count))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count_type, item_type, name] => {
+                let element = elements.last_mut().ok_or("property before any element")?;
+                element.properties.push(Property::List(
+                    name.to_string(),
+                    Scalar::parse(count_type)?,
+                    Scalar::parse(item_type)?,
+                ));
+            }
+            ["property", scalar, name] => {
+                let element = elements.last_mut().ok_or("property before any element")?;
+                element.properties.push(Property::Scalar(name.to_string(), Scalar::parse(scalar)?));
+            }
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return Err(format!("unexpected header line '{}'", line)),
+        }
+    }
+
+    let format = format.ok_or("no format line")?;
+    let body = &bytes[body_start..];
+    let mut reader = Reader {
+        format,
+        bytes: body,
+        tokens: if format == Format::Ascii {
+            std::str::from_utf8(body).map_err(|_| "ASCII body is not text")?.split_ascii_whitespace()
+        } else {
+            "".split_ascii_whitespace()
+        },
+    };
+
+    let mut mesh = TriangleMesh::new(Vec::new(), Vec::new());
+    for element in &elements {
+        match element.name.as_str() {
+            "vertex" => read_vertices(&mut reader, element, &mut mesh)?,
+            "face" => read_faces(&mut reader, element, &mut mesh)?,
+            _ => {
+                for _ in 0..element.count {
+                    read_row(&mut reader, element)?;
+                }
+            }
+        }
+    }
+
+    mesh.validate()?;
+    Ok(mesh)
+}
+
+/// Reads every property of one instance of an element, with lists given as their items.
+fn read_row(reader: &mut Reader, element: &Element) -> Result<Vec<Vec<f64>>, String> {
+    let mut row = Vec::with_capacity(element.properties.len());
+    for property in &element.properties {
+        match property {
+            Property::Scalar(_, scalar) => row.push(vec![reader.scalar(*scalar)?]),
+            Property::List(_, count_type, item_type) => {
+                // The count comes from the file, so the items are only made room for as they're
+                // read, and a count running past the end of the file just finds it ending early.
+                let count = reader.scalar(*count_type)? as usize;
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    items.push(reader.scalar(*item_type)?);
+                }
+                row.push(items);
+            }
+        }
+    }
+    Ok(row)
+}
+
+fn read_vertices(reader: &mut Reader, element: &Element, mesh: &mut TriangleMesh) -> Result<(), String> {
+    let find = |names: &[&str]| element.properties.iter().position(|p| names.contains(&p.name()));
+    let position = [find(&["x"]), find(&["y"]), find(&["z"])];
+    let normal = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+    let uv = [find(&["u", "s", "texture_u", "texture_s"]), find(&["v", "t", "texture_v", "texture_t"])];
+    let colour = [find(&["red", "r"]), find(&["green", "g"]), find(&["blue", "b"])];
+
+    if position.iter().any(|p| p.is_none()) {
+        return Err(String::from("vertices have no position"));
+    }
+    let has_normals = normal.iter().all(|p| p.is_some());
+    let has_uvs = uv.iter().all(|p| p.is_some());
+    let has_colours = colour.iter().all(|p| p.is_some());
+
+    // Integer colours run up to their type's maximum, float ones up to 1.
+    let colour_scale = match &element.properties[colour[0].unwrap_or(0)] {
+        Property::Scalar(_, Scalar::U8) => 1.0 / 255.0,
+        Property::Scalar(_, Scalar::U16) => 1.0 / 65535.0,
+        _ => 1.0,
+    };
+
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut colours = Vec::new();
+    for _ in 0..element.count {
+        let row = read_row(reader, element)?;
+        let get = |i: Option<usize>| i.and_then(|i| row[i].first().cloned()).unwrap_or(0.0);
+
+        mesh.positions.push(Vec3::new(get(position[0]), get(position[1]), get(position[2])));
+        if has_normals {
+            normals.push(Vec3::new(get(normal[0]), get(normal[1]), get(normal[2])));
+        }
+        if has_uvs {
+            uvs.push((get(uv[0]), get(uv[1])));
+        }
+        if has_colours {
+            colours.push(Vec3::new(get(colour[0]), get(colour[1]), get(colour[2])).mul(colour_scale));
+        }
+    }
+
+    mesh.normals = if has_normals { Some(normals) } else { None };
+    mesh.uvs = if has_uvs { Some(uvs) } else { None };
+    mesh.colours = if has_colours { Some(colours) } else { None };
+    Ok(())
+}
+
+fn read_faces(reader: &mut Reader, element: &Element, mesh: &mut TriangleMesh) -> Result<(), String> {
+    let corners = element.properties.iter()
+        .position(|p| p.name() == "vertex_indices" || p.name() == "vertex_index")
+        .ok_or("faces have no vertex indices")?;
+
+    for _ in 0..element.count {
+        let row = read_row(reader, element)?;
+        let face = &row[corners];
+        for i in 1..face.len().saturating_sub(1) {
+            mesh.indices.push([face[0] as usize, face[i] as usize, face[i + 1] as usize]);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORNERS: [[f32; 3]; 4] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+    const RED: [u8; 4] = [255, 0, 0, 51];
+
+    fn header(format: &str) -> String {
+        format!("ply\nformat {} 1.0\ncomment a unit square\nelement vertex 4\nproperty float x\n\
+                 property float y\nproperty float z\nproperty uchar red\nproperty uchar green\n\
+                 property uchar blue\nelement face 1\nproperty list uchar int vertex_indices\n\
+                 end_header\n", format)
+    }
+
+    /// The square in binary, with `f32_bytes` and `i32_bytes` giving numbers in the file's byte order.
+    fn binary(format: &str,
+              f32_bytes: fn(f32) -> [u8; 4],
+              i32_bytes: fn(i32) -> [u8; 4]
+    ) -> Vec<u8> {
+        let mut bytes = header(format).into_bytes();
+        for (corner, red) in CORNERS.iter().zip(RED) {
+            for v in corner {
+                bytes.extend(f32_bytes(*v));
+            }
+            bytes.extend([red, 0, 0]);
+        }
+        bytes.push(4);
+        for i in 0..4 {
+            bytes.extend(i32_bytes(i));
+        }
+        bytes
+    }
+
+    fn assert_square(mesh: &TriangleMesh) {
+        let positions: Vec<_> = mesh.positions.iter().map(|p| [p.x, p.y, p.z]).collect();
+        let expected: Vec<_> = CORNERS.iter().map(|c| c.map(f64::from)).collect();
+        assert_eq!(positions, expected);
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+        let reds: Vec<_> = mesh.colours.as_ref().unwrap().iter().map(|c| c.x).collect();
+        assert_eq!(reds, RED.map(|r| r as f64 / 255.0));
+        assert!(mesh.normals.is_none() && mesh.uvs.is_none());
+    }
+
+    #[test]
+    fn ascii() {
+        let mut text = header("ascii");
+        for (corner, red) in CORNERS.iter().zip(RED) {
+            text += &format!("{} {} {} {} 0 0\n", corner[0], corner[1], corner[2], red);
+        }
+        text += "4 0 1 2 3\n";
+        assert_square(&parse_ply(text.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn binary_little_endian() {
+        let bytes = binary("binary_little_endian", f32::to_le_bytes, i32::to_le_bytes);
+        assert_square(&parse_ply(&bytes).unwrap());
+    }
+
+    #[test]
+    fn binary_big_endian() {
+        let bytes = binary("binary_big_endian", f32::to_be_bytes, i32::to_be_bytes);
+        assert_square(&parse_ply(&bytes).unwrap());
+    }
+
+    #[test]
+    fn huge_list_count() {
+        // A face claiming four billion corners, with the file ending straight after.
+        let mut bytes = b"ply\nformat binary_little_endian 1.0\nelement face 1\n\
+                          property list uint int vertex_indices\nend_header\n".to_vec();
+        bytes.extend(u32::MAX.to_le_bytes());
+        assert_eq!(parse_ply(&bytes).err().as_deref(), Some("file ends early"));
+    }
+
+    #[test]
+    fn missing_vertex() {
+        let text = "ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nproperty float y\n\
+                    property float z\nelement face 1\nproperty list uchar int vertex_indices\n\
+                    end_header\n0 0 0\n3 0 0 1\n";
+        assert!(parse_ply(text.as_bytes()).is_err());
+    }
+}
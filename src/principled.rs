@@ -0,0 +1,314 @@
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use crate::microfacet::{Frame, fresnel_dielectric, reflect, refract};
+use crate::{Ggx, HitRecord, Material, Ray, Texture, Vec3, luminance};
+
+/// One material for nearly everything, after Disney's principled BRDF (Burley 2012) with
+/// transmission added. Each slider runs from 0 to 1, apart from the index of refraction:
+///
+/// - `metallic` blends from a dielectric to a metal tinted by the base colour.
+/// - `subsurface` flattens the diffuse falloff to look like light scattered under the surface.
+/// - `specular` is the strength of reflection off dielectrics, where 0.5 is the usual 4%.
+/// - `specular_tint` tints that reflection towards the base colour.
+/// - `anisotropic` stretches the highlight along the surface's tangent.
+/// - `sheen` adds a soft rim at grazing angles, for cloth, with `sheen_tint` tinting it.
+/// - `clearcoat` adds a second, clear specular layer, sharp when `clearcoat_gloss` is 1.
+/// - `transmission` makes the dielectric part clear, like glass, tinted by the base colour.
+///
+/// The base colour, metalness and roughness are multiplied by their textures where there are
+/// any, as in glTF, and the base colour also by any vertex colours.
+pub struct Principled {
+    pub base_colour: Vec3,
+    pub base_colour_texture: Option<Rc<dyn Texture>>,
+    pub metallic: f64,
+    pub roughness: f64,
+    pub metallic_roughness_texture: Option<Rc<dyn Texture>>, // Roughness in green, metalness in blue.
+    pub subsurface: f64,
+    pub specular: f64,
+    pub specular_tint: f64,
+    pub anisotropic: f64,
+    pub sheen: f64,
+    pub sheen_tint: f64,
+    pub clearcoat: f64,
+    pub clearcoat_gloss: f64,
+    pub transmission: f64,
+    pub ior: f64,
+    pub emissive: Vec3,
+    pub emissive_texture: Option<Rc<dyn Texture>>,
+}
+
+/// The material's parameters at one hit, with textures applied, in the hit's shading frame.
+struct Lobes<'a> {
+    material: &'a Principled,
+    base_colour: Vec3,
+    metallic: f64,
+    roughness: f64,
+    eta: f64, // The index on the far side of the surface relative to this side.
+    specular: Ggx,
+    clearcoat: Ggx, // Only used for its shadowing.
+    clearcoat_alpha: f64,
+    weights: [f64; 4], // The chance of sampling each lobe: diffuse, specular, clearcoat, transmission.
+}
+
+// Roughness is kept just above zero, so every lobe has a density to evaluate.
+const MIN_ALPHA: f64 = 2e-3;
+
+impl Principled {
+    pub fn new(base_colour: Vec3) -> Principled {
+        Principled {
+            base_colour,
+            base_colour_texture: None,
+            metallic: 0.0,
+            roughness: 0.5,
+            metallic_roughness_texture: None,
+            subsurface: 0.0,
+            specular: 0.5,
+            specular_tint: 0.0,
+            anisotropic: 0.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            transmission: 0.0,
+            ior: 1.5,
+            emissive: Vec3::default(),
+            emissive_texture: None,
+        }
+    }
+
+    fn lobes(&self, rec: &HitRecord) -> Lobes<'_> {
+        let mut base_colour = self.base_colour.clone();
+        if let Some(texture) = &self.base_colour_texture {
+            base_colour = base_colour.mul_vec(&texture.value(rec));
+        }
+        if let Some(colour) = &rec.colour {
+            base_colour = base_colour.mul_vec(colour);
+        }
+        let (mut metallic, mut roughness) = (self.metallic, self.roughness);
+        if let Some(texture) = &self.metallic_roughness_texture {
+            let texel = texture.value(rec);
+            roughness *= texel.y;
+            metallic *= texel.z;
+        }
+
+        let aspect = (1.0 - 0.9 * self.anisotropic).sqrt();
+        let alpha = roughness * roughness;
+        let specular = Ggx {
+            alpha_x: (alpha / aspect).max(MIN_ALPHA),
+            alpha_y: (alpha * aspect).max(MIN_ALPHA),
+        };
+
+        let dielectric = 1.0 - metallic;
+        let mut weights = [
+            dielectric * (1.0 - self.transmission) * luminance(&base_colour).max(0.05),
+            0.25 + 0.75 * luminance(&self.specular_colour(&base_colour, metallic)),
+            0.25 * self.clearcoat,
+            dielectric * self.transmission,
+        ];
+        let total: f64 = weights.iter().sum();
+        for w in weights.iter_mut() {
+            *w /= total;
+        }
+
+        Lobes {
+            material: self,
+            base_colour,
+            metallic,
+            roughness,
+            eta: if rec.front_face { self.ior } else { 1.0 / self.ior },
+            specular,
+            clearcoat: Ggx { alpha_x: 0.25, alpha_y: 0.25 },
+            clearcoat_alpha: 0.1 + (0.001 - 0.1) * self.clearcoat_gloss,
+            weights,
+        }
+    }
+
+    /// The colour of reflection seen head on.
+    fn specular_colour(&self, base_colour: &Vec3, metallic: f64) -> Vec3 {
+        let dielectric = mix(&Vec3::from(1.0), &tint(base_colour), self.specular_tint).mul(0.08 * self.specular);
+        mix(&dielectric, base_colour, metallic)
+    }
+}
+
+/// The hue of a colour, with its brightness taken out.
+fn tint(c: &Vec3) -> Vec3 {
+    let l = luminance(c);
+    if l > 0.0 { c.div(l) } else { Vec3::from(1.0) }
+}
+
+fn mix(a: &Vec3, b: &Vec3, t: f64) -> Vec3 {
+    a.mul(1.0 - t) + b.mul(t)
+}
+
+fn schlick_weight(cos_theta: f64) -> f64 {
+    (1.0 - cos_theta).clamp(0.0, 1.0).powi(5)
+}
+
+/// Burley's GTR1 distribution, which has the long tails of a clear coat.
+fn gtr1(cos_h: f64, alpha: f64) -> f64 {
+    let a2 = alpha * alpha;
+    (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * cos_h * cos_h))
+}
+
+impl Lobes<'_> {
+    /// The sum of all the lobes, times the cosine of `wi`.
+    fn eval(&self, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        let m = self.material;
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return Vec3::default();
+        }
+
+        if wi.z < 0.0 {
+            return self.eval_transmission(wo, wi);
+        }
+
+        let h = (wo.clone() + wi.clone()).unit_vector();
+        let cos_d = wi.dot(&h);
+        let (fl, fv, fh) = (schlick_weight(wi.z), schlick_weight(wo.z), schlick_weight(cos_d));
+
+        // Diffuse, with retro-reflection at grazing angles on rough surfaces, blended towards a
+        // flatter subsurface look. Sheen goes with it.
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+        let fss90 = self.roughness * cos_d * cos_d;
+        let fss = (1.0 + (fss90 - 1.0) * fl) * (1.0 + (fss90 - 1.0) * fv);
+        let ss = 1.25 * (fss * (1.0 / (wi.z + wo.z) - 0.5) + 0.5);
+        let sheen = mix(&Vec3::from(1.0), &tint(&self.base_colour), m.sheen_tint).mul(m.sheen * fh);
+        let diffuse = (self.base_colour.mul((fd + (ss - fd) * m.subsurface) / PI) + sheen)
+            .mul((1.0 - self.metallic) * (1.0 - m.transmission));
+
+        let fresnel = mix(&m.specular_colour(&self.base_colour, self.metallic), &Vec3::from(1.0), fh);
+        let specular = fresnel.mul(self.specular.d(&h) * self.specular.g2(wo, wi) / (4.0 * wi.z * wo.z));
+
+        let clearcoat = 0.25 * m.clearcoat * gtr1(h.z, self.clearcoat_alpha) * (0.04 + 0.96 * fh)
+            * self.clearcoat.g1(wo) * self.clearcoat.g1(wi) / (4.0 * wi.z * wo.z);
+
+        (diffuse + specular + Vec3::from(clearcoat)).mul(wi.z)
+    }
+
+    fn eval_transmission(&self, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        let weight = (1.0 - self.metallic) * self.material.transmission;
+        let h = match self.transmission_normal(wo, wi) {
+            Some(h) if weight > 0.0 => h,
+            _ => return Vec3::default(),
+        };
+
+        let fresnel = fresnel_dielectric(wo.dot(&h), self.eta);
+        let denom = (wi.dot(&h) + wo.dot(&h) / self.eta).powi(2) * wo.z;
+        let value = (1.0 - fresnel) * self.specular.d(&h) * self.specular.g2(wo, wi)
+            * (wi.dot(&h) * wo.dot(&h) / denom).abs() / (self.eta * self.eta);
+
+        // Light picks up the base colour on the way in and again on the way out.
+        let colour = Vec3::new(self.base_colour.x.sqrt(), self.base_colour.y.sqrt(), self.base_colour.z.sqrt());
+        colour.mul(weight * value)
+    }
+
+    /// The facet normal that refracts `wo` into `wi`, if a facet facing both could.
+    fn transmission_normal(&self, wo: &Vec3, wi: &Vec3) -> Option<Vec3> {
+        let h = wi.mul(self.eta) + wo.clone();
+        if h.near_zero() {
+            return None;
+        }
+        let mut h = h.unit_vector();
+        if h.z < 0.0 {
+            h = h.mul(-1.0);
+        }
+        if wo.dot(&h) <= 0.0 || wi.dot(&h) >= 0.0 {
+            return None;
+        }
+        Some(h)
+    }
+
+    /// The density of sampling `wi` from `wo`, over all the lobes together.
+    fn pdf(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        let [diffuse, specular, clearcoat, transmission] = self.weights;
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+
+        if wi.z < 0.0 {
+            return match self.transmission_normal(wo, wi) {
+                Some(h) => {
+                    let visible = self.specular.d(&h) * self.specular.g1(wo) * wo.dot(&h) / wo.z;
+                    transmission * visible * wi.dot(&h).abs() / (wi.dot(&h) + wo.dot(&h) / self.eta).powi(2)
+                }
+                None => 0.0,
+            };
+        }
+
+        let h = (wo.clone() + wi.clone()).unit_vector();
+        diffuse * wi.z / PI
+            + specular * self.specular.d(&h) * self.specular.g1(wo) / (4.0 * wo.z)
+            + clearcoat * gtr1(h.z, self.clearcoat_alpha) * h.z / (4.0 * wo.dot(&h))
+    }
+
+    /// Picks a lobe and samples a direction from it. Gives None if the direction is lost below
+    /// the surface.
+    fn sample(&self, wo: &Vec3) -> Option<Vec3> {
+        let [diffuse, specular, clearcoat, _] = self.weights;
+        let u = fastrand::f64();
+
+        let wi = if u < diffuse {
+            let wi = Vec3::new(0.0, 0.0, 1.0) + Vec3::random_unit_vector();
+            if wi.near_zero() { Vec3::new(0.0, 0.0, 1.0) } else { wi.unit_vector() }
+        } else if u < diffuse + specular {
+            reflect(wo, &self.specular.sample_visible_normal(wo))
+        } else if u < diffuse + specular + clearcoat {
+            let a2 = self.clearcoat_alpha * self.clearcoat_alpha;
+            let cos_h = ((1.0 - a2.powf(1.0 - fastrand::f64())) / (1.0 - a2)).sqrt();
+            let sin_h = (1.0 - cos_h * cos_h).max(0.0).sqrt();
+            let phi = 2.0 * PI * fastrand::f64();
+            reflect(wo, &Vec3::new(sin_h * phi.cos(), sin_h * phi.sin(), cos_h))
+        } else {
+            let wi = refract(wo, &self.specular.sample_visible_normal(wo), self.eta)?;
+            if wi.z >= 0.0 {
+                return None;
+            }
+            return Some(wi);
+        };
+
+        if wi.z <= 0.0 { None } else { Some(wi) }
+    }
+}
+
+impl Material for Principled {
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+        let frame = Frame::from_hit(rec);
+        let wo = frame.to_local(&ray_in.direction.unit_vector().mul(-1.0));
+        let lobes = self.lobes(rec);
+
+        // Whichever lobe the direction came from, weigh it against the density of every lobe
+        // sampling it, so a lobe that samples a direction badly doesn't cause fireflies.
+        let wi = match lobes.sample(&wo) {
+            Some(wi) => wi,
+            None => return false,
+        };
+        let pdf = lobes.pdf(&wo, &wi);
+        if pdf <= 0.0 {
+            return false;
+        }
+
+        *attenuation = lobes.eval(&wo, &wi).div(pdf);
+        *scattered = Ray::new(rec.point.clone(), frame.to_world(&wi), ray_in.time);
+        true
+    }
+
+    fn eval(&self, ray_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        let frame = Frame::from_hit(rec);
+        let wo = frame.to_local(&ray_in.direction.unit_vector().mul(-1.0));
+        let wi = frame.to_local(&direction.unit_vector());
+        self.lobes(rec).eval(&wo, &wi)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Vec3 {
+        match &self.emissive_texture {
+            Some(texture) => self.emissive.mul_vec(&texture.value(rec)),
+            None => self.emissive.clone(),
+        }
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.lobes(rec).base_colour
+    }
+}
@@ -0,0 +1,186 @@
+use std::f64::consts::PI;
+
+use crate::film::Reader;
+use crate::{Vec3, degrees_to_radians};
+
+// The ways a camera can lay the world out on its film. Perspective is the usual pinhole camera;
+// the rest are worked out here in the camera's own space, with x to the right, y up and the camera
+// looking down -z, from where a sample lands on the film: s across from the left and t up from
+// the bottom, both 0 to 1.
+//
+// The 360° projections cover every direction, so they want images of a particular shape:
+// equirectangular maps longitude and latitude straight onto a 2:1 image, and the cube map lays six
+// 90° views out in a 3×2 grid. Fisheyes fit their image circle into the shorter side of the film.
+// Omnidirectional stereo (ODS) renders an equirectangular image for each eye, with every ray
+// starting from a circle the width of the eyes apart, so that looking any way around gives the
+// right parallax (as described in Google's "Rendering Omni-directional Stereo Content").
+
+/// How a camera maps its film onto directions in the world.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Projection {
+    #[default]
+    Perspective,
+    Orthographic { height: f64 }, // How much of the world the film covers top to bottom.
+    Equirectangular,
+    CubeMap, // Faces for +x, -x, +y along the top, and -y, +z, -z along the bottom.
+    Fisheye { mapping: FisheyeMapping, fov: f64 }, // Degrees across the image circle.
+    Stereo { layout: StereoLayout, eye_separation: f64 },
+}
+
+/// How far a fisheye's image is from the middle for a given angle from the view direction.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FisheyeMapping {
+    Equidistant, // In proportion to the angle.
+    Equisolid, // Keeping equal areas of the sphere equal on the film.
+}
+
+/// Where each eye's image goes in a stereo pair.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StereoLayout {
+    TopBottom, // The left eye above the right.
+    SideBySide, // The left eye to the left of the right.
+}
+
+// About the distance between people's eyes, in metres.
+const DEFAULT_EYE_SEPARATION: f64 = 0.064;
+
+impl Projection {
+    /// Reads a projection from its name on the command line, with an optional setting after a
+    /// colon: the height of an orthographic view, the field of view of a fisheye in degrees, or the
+    /// eye separation of a stereo pair.
+    pub fn parse(text: &str) -> Result<Projection, String> {
+        let (name, setting) = match text.split_once(':') {
+            Some((name, setting)) => {
+                let setting = setting.parse::<f64>().ok().filter(|s| *s > 0.0).ok_or(format!("bad projection {}", text))?;
+                (name, Some(setting))
+            }
+            None => (text, None),
+        };
+        let fisheye = |mapping| Projection::Fisheye { mapping, fov: setting.unwrap_or(180.0).min(360.0) };
+        let stereo = |layout| Projection::Stereo { layout, eye_separation: setting.unwrap_or(DEFAULT_EYE_SEPARATION) };
+        match name.to_ascii_lowercase().as_str() {
+            "perspective" => Ok(Projection::Perspective),
+            "orthographic" => Ok(Projection::Orthographic { height: setting.unwrap_or(2.0) }),
+            "equirectangular" | "360" => Ok(Projection::Equirectangular),
+            "cubemap" | "cube-map" => Ok(Projection::CubeMap),
+            "fisheye" | "equidistant" => Ok(fisheye(FisheyeMapping::Equidistant)),
+            "equisolid" => Ok(fisheye(FisheyeMapping::Equisolid)),
+            "ods-top-bottom" => Ok(stereo(StereoLayout::TopBottom)),
+            "ods-side-by-side" => Ok(stereo(StereoLayout::SideBySide)),
+            _ => Err(format!("unknown projection {}", name)),
+        }
+    }
+
+    /// The width over the height of the image the projection fills, if it needs a particular one.
+    pub fn aspect_ratio(&self) -> Option<f64> {
+        match self {
+            Projection::Perspective | Projection::Orthographic { .. } => None,
+            Projection::Equirectangular => Some(2.0),
+            Projection::CubeMap => Some(1.5),
+            Projection::Fisheye { .. } => Some(1.0),
+            Projection::Stereo { layout: StereoLayout::TopBottom, .. } => Some(1.0),
+            Projection::Stereo { layout: StereoLayout::SideBySide, .. } => Some(4.0),
+        }
+    }
+
+    /// Where a ray for a point on the film starts, relative to the camera, and which way it goes,
+    /// in the camera's space. Gives None outside a fisheye's image circle, and for perspective,
+    /// which the camera works out from its viewport.
+    pub fn sample(&self, s: f64, t: f64, aspect_ratio: f64) -> Option<(Vec3, Vec3)> {
+        match *self {
+            Projection::Perspective => None,
+            Projection::Orthographic { height } => {
+                let offset = Vec3::new((s - 0.5) * height * aspect_ratio, (t - 0.5) * height, 0.0);
+                Some((offset, Vec3::new(0.0, 0.0, -1.0)))
+            }
+            Projection::Equirectangular => Some((Vec3::default(), equirectangular(s, t))),
+            Projection::CubeMap => Some((Vec3::default(), cube_map(s, t))),
+            Projection::Fisheye { mapping, fov } => {
+                let (x, y) = ((s - 0.5) * aspect_ratio, t - 0.5);
+                let r = (x * x + y * y).sqrt() / (aspect_ratio.min(1.0) / 2.0);
+                if r > 1.0 {
+                    return None;
+                }
+                let max_angle = degrees_to_radians(fov) / 2.0;
+                let angle = match mapping {
+                    FisheyeMapping::Equidistant => r * max_angle,
+                    FisheyeMapping::Equisolid => 2.0 * (r * (max_angle / 2.0).sin()).asin(),
+                };
+                let around = y.atan2(x);
+                let direction = Vec3::new(angle.sin() * around.cos(), angle.sin() * around.sin(), -angle.cos());
+                Some((Vec3::default(), direction))
+            }
+            Projection::Stereo { layout, eye_separation } => {
+                let (left, s, t) = match layout {
+                    StereoLayout::TopBottom if t >= 0.5 => (true, s, (t - 0.5) * 2.0),
+                    StereoLayout::TopBottom => (false, s, t * 2.0),
+                    StereoLayout::SideBySide if s < 0.5 => (true, s * 2.0, t),
+                    StereoLayout::SideBySide => (false, (s - 0.5) * 2.0, t),
+                };
+                // Each eye sits to the side of the way it's looking, on the level.
+                let longitude = (s - 0.5) * 2.0 * PI;
+                let side = Vec3::new(longitude.cos(), 0.0, longitude.sin()).mul(eye_separation / 2.0);
+                let offset = if left { side.mul(-1.0) } else { side };
+                Some((offset, equirectangular(s, t)))
+            }
+        }
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let (kind, setting) = match *self {
+            Projection::Perspective => (0_u32, 0.0),
+            Projection::Orthographic { height } => (1, height),
+            Projection::Equirectangular => (2, 0.0),
+            Projection::CubeMap => (3, 0.0),
+            Projection::Fisheye { mapping: FisheyeMapping::Equidistant, fov } => (4, fov),
+            Projection::Fisheye { mapping: FisheyeMapping::Equisolid, fov } => (5, fov),
+            Projection::Stereo { layout: StereoLayout::TopBottom, eye_separation } => (6, eye_separation),
+            Projection::Stereo { layout: StereoLayout::SideBySide, eye_separation } => (7, eye_separation),
+        };
+        out.extend(kind.to_le_bytes());
+        out.extend(setting.to_le_bytes());
+    }
+
+    pub fn read(reader: &mut Reader) -> Result<Projection, String> {
+        let kind = reader.u32()?;
+        let setting = reader.f64()?;
+        match kind {
+            0 => Ok(Projection::Perspective),
+            1 => Ok(Projection::Orthographic { height: setting }),
+            2 => Ok(Projection::Equirectangular),
+            3 => Ok(Projection::CubeMap),
+            4 => Ok(Projection::Fisheye { mapping: FisheyeMapping::Equidistant, fov: setting }),
+            5 => Ok(Projection::Fisheye { mapping: FisheyeMapping::Equisolid, fov: setting }),
+            6 => Ok(Projection::Stereo { layout: StereoLayout::TopBottom, eye_separation: setting }),
+            7 => Ok(Projection::Stereo { layout: StereoLayout::SideBySide, eye_separation: setting }),
+            _ => Err(format!("unknown projection {}", kind)),
+        }
+    }
+}
+
+/// The direction at a longitude and latitude spread across the film, straight ahead in the
+/// middle.
+fn equirectangular(s: f64, t: f64) -> Vec3 {
+    let longitude = (s - 0.5) * 2.0 * PI;
+    let latitude = (t - 0.5) * PI;
+    Vec3::new(latitude.cos() * longitude.sin(), latitude.sin(), -latitude.cos() * longitude.cos())
+}
+
+/// The direction through a point on one of the cube's faces, each seen from inside the cube the
+/// right way round.
+fn cube_map(s: f64, t: f64) -> Vec3 {
+    let (x, y) = (s * 3.0, (1.0 - t) * 2.0);
+    let (column, row) = (x.floor().clamp(0.0, 2.0), y.floor().clamp(0.0, 1.0));
+    // Across the face from left to right, and down it from top to bottom, from -1 to 1.
+    let a = (x - column) * 2.0 - 1.0;
+    let b = (y - row) * 2.0 - 1.0;
+    let (forward, right, up) = match (row as u32, column as u32) {
+        (0, 0) => ((1.0, 0.0, 0.0), (0.0, 0.0, 1.0), (0.0, 1.0, 0.0)),
+        (0, 1) => ((-1.0, 0.0, 0.0), (0.0, 0.0, -1.0), (0.0, 1.0, 0.0)),
+        (0, _) => ((0.0, 1.0, 0.0), (1.0, 0.0, 0.0), (0.0, 0.0, 1.0)),
+        (_, 0) => ((0.0, -1.0, 0.0), (1.0, 0.0, 0.0), (0.0, 0.0, -1.0)),
+        (_, 1) => ((0.0, 0.0, 1.0), (-1.0, 0.0, 0.0), (0.0, 1.0, 0.0)),
+        (_, _) => ((0.0, 0.0, -1.0), (1.0, 0.0, 0.0), (0.0, 1.0, 0.0)),
+    };
+    Vec3::from(forward) + Vec3::from(right).mul(a) - Vec3::from(up).mul(b)
+}
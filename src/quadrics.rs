@@ -0,0 +1,527 @@
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use crate::math::{solve_quadratic, solve_quartic, INFINITY};
+use crate::{Aabb, HitRecord, Hittable, Material, Ray, Span, Vec3};
+
+// The quadrics all sit in their own object space with y as their axis of symmetry, and can be
+// moved into place with an `Instance`. Each can be cut down to a sweep of `phi_max` radians
+// around the axis, starting from the +x axis towards +z.
+
+/// A cylinder of `radius` around the y axis, from `y_min` to `y_max`.
+pub struct Cylinder {
+    pub radius: f64,
+    pub y_min: f64,
+    pub y_max: f64,
+    pub phi_max: f64,
+    pub capped: bool,
+    pub material: Rc<dyn Material>,
+}
+
+/// A cone with its base of `radius` at y = 0 and its tip at y = `height`.
+pub struct Cone {
+    pub radius: f64,
+    pub height: f64,
+    pub phi_max: f64,
+    pub capped: bool,
+    pub material: Rc<dyn Material>,
+}
+
+/// A hyperboloid of one sheet, `x^2 + z^2 = radius^2 (1 + y^2 / c^2)`, from `y_min` to `y_max`.
+/// `radius` is the radius at its narrowest, and a smaller `c` flares it out more quickly.
+pub struct Hyperboloid {
+    pub radius: f64,
+    pub c: f64,
+    pub y_min: f64,
+    pub y_max: f64,
+    pub phi_max: f64,
+    pub material: Rc<dyn Material>,
+}
+
+/// A paraboloid with its tip at the origin, opening upwards to `radius` at y = `height`.
+pub struct Paraboloid {
+    pub radius: f64,
+    pub height: f64,
+    pub phi_max: f64,
+    pub capped: bool,
+    pub material: Rc<dyn Material>,
+}
+
+/// A torus lying in the xz plane, with its tube of `minor_radius` running around a circle of
+/// `major_radius`.
+pub struct Torus {
+    pub major_radius: f64,
+    pub minor_radius: f64,
+    pub phi_max: f64,
+    pub material: Rc<dyn Material>,
+}
+
+/// Somewhere a ray crosses a surface, with the normal pointing out of the shape.
+struct Crossing {
+    t: f64,
+    normal: Vec3,
+    u: f64,
+    v: f64,
+    dpdu: Vec3,
+    dpdv: Vec3,
+}
+
+impl Cylinder {
+    pub fn new(radius: f64, y_min: f64, y_max: f64, capped: bool, material: Rc<dyn Material>) -> Cylinder {
+        Cylinder {
+            radius,
+            y_min,
+            y_max,
+            phi_max: 2.0 * PI,
+            capped,
+            material,
+        }
+    }
+
+    fn crossings(&self, ray: &Ray) -> Vec<Crossing> {
+        let (o, d) = (&ray.origin, &ray.direction);
+        let a = d.x * d.x + d.z * d.z;
+        let b = 2.0 * (o.x * d.x + o.z * d.z);
+        let c = o.x * o.x + o.z * o.z - self.radius * self.radius;
+
+        let mut crossings = Vec::new();
+        for t in solve_quadratic(a, b, c) {
+            let p = ray.at(t);
+            let phi = phi_of(&p);
+            if p.y < self.y_min || p.y > self.y_max || phi > self.phi_max {
+                continue;
+            }
+            crossings.push(Crossing {
+                t,
+                normal: Vec3::new(p.x, 0.0, p.z).div(self.radius),
+                u: phi / self.phi_max,
+                v: (p.y - self.y_min) / (self.y_max - self.y_min),
+                dpdu: around_y(&p, self.phi_max),
+                dpdv: Vec3::new(0.0, self.y_max - self.y_min, 0.0),
+            });
+        }
+
+        if self.capped {
+            crossings.extend(disc_crossing(ray, self.y_min, self.radius, -1.0, self.phi_max));
+            crossings.extend(disc_crossing(ray, self.y_max, self.radius, 1.0, self.phi_max));
+        }
+        crossings
+    }
+
+    fn closed(&self) -> bool {
+        self.capped && self.phi_max >= 2.0 * PI
+    }
+}
+
+impl Cone {
+    pub fn new(radius: f64, height: f64, capped: bool, material: Rc<dyn Material>) -> Cone {
+        Cone {
+            radius,
+            height,
+            phi_max: 2.0 * PI,
+            capped,
+            material,
+        }
+    }
+
+    fn crossings(&self, ray: &Ray) -> Vec<Crossing> {
+        // x^2 + z^2 = k^2 (h - y)^2, where k is the slope of the side.
+        let (o, d) = (&ray.origin, &ray.direction);
+        let h = self.height;
+        let k2 = (self.radius / h) * (self.radius / h);
+        let a = d.x * d.x + d.z * d.z - k2 * d.y * d.y;
+        let b = 2.0 * (o.x * d.x + o.z * d.z + k2 * (h - o.y) * d.y);
+        let c = o.x * o.x + o.z * o.z - k2 * (h - o.y) * (h - o.y);
+
+        let mut crossings = Vec::new();
+        for t in solve_quadratic(a, b, c) {
+            let p = ray.at(t);
+            let phi = phi_of(&p);
+            if p.y < 0.0 || p.y > h || phi > self.phi_max {
+                continue;
+            }
+            crossings.push(Crossing {
+                t,
+                normal: Vec3::new(p.x, k2 * (h - p.y), p.z).unit_vector(),
+                u: phi / self.phi_max,
+                v: p.y / h,
+                dpdu: around_y(&p, self.phi_max),
+                dpdv: outwards(phi).mul(-self.radius) + Vec3::new(0.0, h, 0.0),
+            });
+        }
+
+        if self.capped {
+            crossings.extend(disc_crossing(ray, 0.0, self.radius, -1.0, self.phi_max));
+        }
+        crossings
+    }
+
+    fn closed(&self) -> bool {
+        self.capped && self.phi_max >= 2.0 * PI
+    }
+}
+
+impl Hyperboloid {
+    pub fn new(radius: f64, c: f64, y_min: f64, y_max: f64, material: Rc<dyn Material>) -> Hyperboloid {
+        Hyperboloid {
+            radius,
+            c,
+            y_min,
+            y_max,
+            phi_max: 2.0 * PI,
+            material,
+        }
+    }
+
+    fn crossings(&self, ray: &Ray) -> Vec<Crossing> {
+        let (o, d) = (&ray.origin, &ray.direction);
+        let r2 = self.radius * self.radius;
+        let k = r2 / (self.c * self.c);
+        let a = d.x * d.x + d.z * d.z - k * d.y * d.y;
+        let b = 2.0 * (o.x * d.x + o.z * d.z - k * o.y * d.y);
+        let c = o.x * o.x + o.z * o.z - k * o.y * o.y - r2;
+
+        let mut crossings = Vec::new();
+        for t in solve_quadratic(a, b, c) {
+            let p = ray.at(t);
+            let phi = phi_of(&p);
+            if p.y < self.y_min || p.y > self.y_max || phi > self.phi_max {
+                continue;
+            }
+            let ring = (p.x * p.x + p.z * p.z).sqrt();
+            crossings.push(Crossing {
+                t,
+                normal: Vec3::new(p.x, -k * p.y, p.z).unit_vector(),
+                u: phi / self.phi_max,
+                v: (p.y - self.y_min) / (self.y_max - self.y_min),
+                dpdu: around_y(&p, self.phi_max),
+                dpdv: (outwards(phi).mul(k * p.y / ring) + Vec3::new(0.0, 1.0, 0.0)).mul(self.y_max - self.y_min),
+            });
+        }
+        crossings
+    }
+
+    fn max_radius(&self) -> f64 {
+        let y = self.y_min.abs().max(self.y_max.abs());
+        self.radius * (1.0 + y * y / (self.c * self.c)).sqrt()
+    }
+}
+
+impl Paraboloid {
+    pub fn new(radius: f64, height: f64, capped: bool, material: Rc<dyn Material>) -> Paraboloid {
+        Paraboloid {
+            radius,
+            height,
+            phi_max: 2.0 * PI,
+            capped,
+            material,
+        }
+    }
+
+    fn crossings(&self, ray: &Ray) -> Vec<Crossing> {
+        // x^2 + z^2 = k y, with k chosen so the radius is right at the top.
+        let (o, d) = (&ray.origin, &ray.direction);
+        let k = self.radius * self.radius / self.height;
+        let a = d.x * d.x + d.z * d.z;
+        let b = 2.0 * (o.x * d.x + o.z * d.z) - k * d.y;
+        let c = o.x * o.x + o.z * o.z - k * o.y;
+
+        let mut crossings = Vec::new();
+        for t in solve_quadratic(a, b, c) {
+            let p = ray.at(t);
+            let phi = phi_of(&p);
+            if p.y < 0.0 || p.y > self.height || phi > self.phi_max {
+                continue;
+            }
+            crossings.push(Crossing {
+                t,
+                normal: Vec3::new(2.0 * p.x, -k, 2.0 * p.z).unit_vector(),
+                u: phi / self.phi_max,
+                v: p.y / self.height,
+                dpdu: around_y(&p, self.phi_max),
+                dpdv: paraboloid_dpdv(&p, phi, k, self.height),
+            });
+        }
+
+        if self.capped {
+            crossings.extend(disc_crossing(ray, self.height, self.radius, 1.0, self.phi_max));
+        }
+        crossings
+    }
+
+    fn closed(&self) -> bool {
+        self.capped && self.phi_max >= 2.0 * PI
+    }
+}
+
+impl Torus {
+    pub fn new(major_radius: f64, minor_radius: f64, material: Rc<dyn Material>) -> Torus {
+        Torus {
+            major_radius,
+            minor_radius,
+            phi_max: 2.0 * PI,
+            material,
+        }
+    }
+
+    fn bounds(&self) -> Aabb {
+        let outer = self.major_radius + self.minor_radius;
+        Aabb::new(
+            Vec3::new(-outer, -self.minor_radius, -outer),
+            Vec3::new(outer, self.minor_radius, outer),
+        )
+    }
+
+    fn crossings(&self, ray: &Ray) -> Vec<Crossing> {
+        // Quartic roots lose precision far from the origin, so solve from where the ray meets
+        // the bounding box instead.
+        let start = match self.bounds().intersect(ray, -INFINITY, INFINITY) {
+            Some((t0, _)) => t0,
+            None => return Vec::new(),
+        };
+        let o = ray.at(start);
+        let d = &ray.direction;
+
+        // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2), expanded in t.
+        let big_r2 = self.major_radius * self.major_radius;
+        let a = d.length_sq();
+        let b = 2.0 * o.dot(d);
+        let k = o.length_sq() + big_r2 - self.minor_radius * self.minor_radius;
+        let roots = solve_quartic(
+            a * a,
+            2.0 * a * b,
+            b * b + 2.0 * a * k - 4.0 * big_r2 * (d.x * d.x + d.z * d.z),
+            2.0 * b * k - 8.0 * big_r2 * (o.x * d.x + o.z * d.z),
+            k * k - 4.0 * big_r2 * (o.x * o.x + o.z * o.z),
+        );
+
+        let mut crossings = Vec::new();
+        for t in roots {
+            let t = t + start;
+            let p = ray.at(t);
+            let phi = phi_of(&p);
+            if phi > self.phi_max {
+                continue;
+            }
+            let ring = (p.x * p.x + p.z * p.z).sqrt();
+            let s = p.length_sq() + big_r2 - self.minor_radius * self.minor_radius;
+            crossings.push(Crossing {
+                t,
+                normal: Vec3::new(p.x * (s - 2.0 * big_r2), p.y * s, p.z * (s - 2.0 * big_r2)).unit_vector(),
+                u: phi / self.phi_max,
+                v: (p.y.atan2(ring - self.major_radius) + PI) / (2.0 * PI),
+                dpdu: around_y(&p, self.phi_max),
+                dpdv: (outwards(phi).mul(-p.y) + Vec3::new(0.0, ring - self.major_radius, 0.0)).mul(2.0 * PI),
+            });
+        }
+        crossings
+    }
+}
+
+/// The angle of a point around the y axis, from 0 to 2 pi.
+fn phi_of(p: &Vec3) -> f64 {
+    let phi = p.z.atan2(p.x);
+    if phi < 0.0 {
+        phi + 2.0 * PI
+    } else {
+        phi
+    }
+}
+
+/// Where a ray crosses a disc capping one end of a shape, with the normal facing `side` along y.
+fn disc_crossing(ray: &Ray, y: f64, radius: f64, side: f64, phi_max: f64) -> Option<Crossing> {
+    if ray.direction.y == 0.0 {
+        return None;
+    }
+    let t = (y - ray.origin.y) / ray.direction.y;
+    let p = ray.at(t);
+    let dist = (p.x * p.x + p.z * p.z).sqrt();
+    let phi = phi_of(&p);
+    if dist > radius || phi > phi_max {
+        return None;
+    }
+    Some(Crossing {
+        t,
+        normal: Vec3::new(0.0, side, 0.0),
+        u: phi / phi_max,
+        v: dist / radius,
+        dpdu: around_y(&p, phi_max),
+        dpdv: outwards(phi).mul(radius),
+    })
+}
+
+/// How a point moves as u sweeps it around the y axis through `phi_max`.
+fn around_y(p: &Vec3, phi_max: f64) -> Vec3 {
+    Vec3::new(-p.z, 0.0, p.x).mul(phi_max)
+}
+
+/// The horizontal unit vector pointing away from the y axis at angle `phi`.
+fn outwards(phi: f64) -> Vec3 {
+    Vec3::new(phi.cos(), 0.0, phi.sin())
+}
+
+/// How a point on a paraboloid moves with v, which is zero at the tip where it's undefined.
+fn paraboloid_dpdv(p: &Vec3, phi: f64, k: f64, height: f64) -> Vec3 {
+    let ring = (p.x * p.x + p.z * p.z).sqrt();
+    if ring < 1e-12 {
+        return Vec3::default();
+    }
+    (outwards(phi).mul(k / (2.0 * ring)) + Vec3::new(0.0, 1.0, 0.0)).mul(height)
+}
+
+fn crossing_record(crossing: &Crossing, ray: &Ray, material: &Rc<dyn Material>) -> HitRecord {
+    HitRecord {
+        point: ray.at(crossing.t),
+        normal: crossing.normal.clone(),
+        t: crossing.t,
+        u: crossing.u,
+        v: crossing.v,
+        dpdu: crossing.dpdu.clone(),
+        dpdv: crossing.dpdv.clone(),
+        material: Some(material.clone()),
+        ..Default::default()
+    }
+}
+
+/// Turns the nearest crossing between `t_min` and `t_max` into a hit.
+fn nearest_crossing(crossings: Vec<Crossing>,
+                    ray: &Ray,
+                    t_min: f64,
+                    t_max: f64,
+                    material: &Rc<dyn Material>,
+                    rec: &mut HitRecord
+) -> bool {
+    let nearest = crossings.iter()
+        .filter(|c| c.t >= t_min && c.t <= t_max)
+        .min_by(|a, b| a.t.partial_cmp(&b.t).unwrap_or(std::cmp::Ordering::Equal));
+
+    match nearest {
+        Some(crossing) => {
+            *rec = crossing_record(crossing, ray, material);
+            rec.set_face_normal(ray, &crossing.normal);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Pairs up the crossings of a closed shape into the spans between them.
+fn crossing_spans(mut crossings: Vec<Crossing>, ray: &Ray, material: &Rc<dyn Material>, spans: &mut Vec<Span>) {
+    crossings.sort_by(|a, b| a.t.partial_cmp(&b.t).unwrap_or(std::cmp::Ordering::Equal));
+    for pair in crossings.chunks_exact(2) {
+        spans.push(Span::new(crossing_record(&pair[0], ray, material), crossing_record(&pair[1], ray, material)));
+    }
+}
+
+impl Hittable for Cylinder {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        nearest_crossing(self.crossings(ray), ray, t_min, t_max, &self.material, rec)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
+        *output_box = Aabb::new(
+            Vec3::new(-self.radius, self.y_min, -self.radius),
+            Vec3::new(self.radius, self.y_max, self.radius),
+        );
+        true
+    }
+
+    fn is_solid(&self) -> bool {
+        self.closed()
+    }
+
+    fn spans(&self, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+        if !self.is_solid() {
+            return false;
+        }
+        crossing_spans(self.crossings(ray), ray, &self.material, spans);
+        true
+    }
+}
+
+impl Hittable for Cone {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        nearest_crossing(self.crossings(ray), ray, t_min, t_max, &self.material, rec)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
+        *output_box = Aabb::new(
+            Vec3::new(-self.radius, 0.0, -self.radius),
+            Vec3::new(self.radius, self.height, self.radius),
+        );
+        true
+    }
+
+    fn is_solid(&self) -> bool {
+        self.closed()
+    }
+
+    fn spans(&self, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+        if !self.is_solid() {
+            return false;
+        }
+        crossing_spans(self.crossings(ray), ray, &self.material, spans);
+        true
+    }
+}
+
+impl Hittable for Hyperboloid {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        nearest_crossing(self.crossings(ray), ray, t_min, t_max, &self.material, rec)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
+        let r = self.max_radius();
+        *output_box = Aabb::new(Vec3::new(-r, self.y_min, -r), Vec3::new(r, self.y_max, r));
+        true
+    }
+}
+
+impl Hittable for Paraboloid {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        nearest_crossing(self.crossings(ray), ray, t_min, t_max, &self.material, rec)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
+        *output_box = Aabb::new(
+            Vec3::new(-self.radius, 0.0, -self.radius),
+            Vec3::new(self.radius, self.height, self.radius),
+        );
+        true
+    }
+
+    fn is_solid(&self) -> bool {
+        self.closed()
+    }
+
+    fn spans(&self, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+        if !self.is_solid() {
+            return false;
+        }
+        crossing_spans(self.crossings(ray), ray, &self.material, spans);
+        true
+    }
+}
+
+impl Hittable for Torus {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        nearest_crossing(self.crossings(ray), ray, t_min, t_max, &self.material, rec)
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
+        *output_box = self.bounds();
+        true
+    }
+
+    fn is_solid(&self) -> bool {
+        self.phi_max >= 2.0 * PI
+    }
+
+    fn spans(&self, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+        if !self.is_solid() {
+            return false;
+        }
+        crossing_spans(self.crossings(ray), ray, &self.material, spans);
+        true
+    }
+}
@@ -0,0 +1,498 @@
+use std::rc::Rc;
+
+use crate::math::INFINITY;
+use crate::{
+    Camera, ColourSpace, Film, Filter, Fog, HitRecord, Hittable, HittableList, Lambertian, Light, LightSample, Material, PhaseMaterial,
+    PixelBounds, PixelStats, Progress, Ray, Sphere, Vec3, Wavelengths, encode_display, luminance, unit_vector,
+};
+
+/// Everything that gets rendered: the objects, the lights that are not objects themselves, and
+/// any cameras placed in the world.
+pub struct Scene {
+    pub world: Rc<dyn Hittable>,
+    pub lights: Vec<Rc<dyn Light>>,
+    pub cameras: Vec<Camera>,
+    pub duration: f64, // How many seconds the scene's animation runs for, or 0 if it is still.
+    pub sky: f64, // How bright the sky is, in cd/m² when it's photographed with an exposure.
+}
+
+// A clear daytime sky, in cd/m², for the default sky to be photographed with an exposure.
+pub const SKY_LUMINANCE: f64 = 8000.0;
+
+/// A rendered image, or the part of it inside the crop window: linear colours in the working space,
+/// with what the denoiser and adaptive sampling need to know about each pixel. Everything is stored
+/// top scan-line first.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub colour: Vec<Vec3>,
+    pub variance: Vec<f64>, // Of the luminance of each pixel's average, which shrinks with more samples.
+    pub albedo: Vec<Vec3>, // The colour of the surface each pixel first sees, or black for the sky.
+    pub normal: Vec<Vec3>, // The normal there, facing the camera, or zero for the sky.
+    pub samples: Vec<u32>, // How many samples each pixel took.
+}
+
+/// Keeps sampling only the pixels that are still noisy, after every pixel has had the usual
+/// number of samples.
+pub struct AdaptiveSampling {
+    pub threshold: f64, // The noise, relative to the pixel's brightness, that is good enough.
+    pub max_samples: u32,
+}
+
+/// Settings for how a scene is traced, as opposed to what is in it.
+pub struct RenderSettings {
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    pub fog: Option<Fog>,
+    pub spectral: bool, // Trace wavelengths of light rather than RGB, for dispersion and physical light colours.
+    pub working_space: ColourSpace, // The space the scene's colours are given in and lighting is worked out in.
+    pub adaptive: Option<AdaptiveSampling>, // Samples noisy pixels more, with samples_per_pixel as the least any get.
+    pub filter: Filter, // How samples are weighed into the pixels around them.
+    pub crop: Option<PixelBounds>, // Renders only these pixels, and gives an image of just them.
+    pub seed: u64, // Every sample's random numbers come from this, so renders can be repeated exactly.
+}
+
+impl Scene {
+    pub fn new(world: Rc<dyn Hittable>) -> Scene {
+        Scene {
+            world,
+            lights: Vec::new(),
+            cameras: Vec::new(),
+            duration: 0.0,
+            sky: 1.0,
+        }
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            samples_per_pixel: 50,
+            max_depth: 50,
+            fog: None,
+            spectral: false,
+            working_space: ColourSpace::LinearSrgb,
+            adaptive: None,
+            filter: Filter::default(),
+            crop: None,
+            seed: 0,
+        }
+    }
+}
+
+impl RenderSettings {
+    /// The most passes a render makes over the image, each taking a sample in every pixel that
+    /// still needs one.
+    pub fn passes(&self) -> u32 {
+        match &self.adaptive {
+            Some(adaptive) => adaptive.max_samples.max(self.samples_per_pixel),
+            None => self.samples_per_pixel,
+        }
+    }
+}
+
+impl AdaptiveSampling {
+    pub fn new(threshold: f64) -> AdaptiveSampling {
+        AdaptiveSampling {
+            threshold,
+            max_samples: 1024,
+        }
+    }
+}
+
+/// The scene the binaries render when they are not given anything else.
+pub fn default_world() -> HittableList {
+    let grey = Rc::new(Lambertian::new(Vec3::from(0.5)));
+
+    let mut world: HittableList = Default::default();
+    world.add(Rc::new(Sphere {
+        centre: Vec3::from((0.0, -105.5, -1.0)),
+        radius: 100.0,
+        material: grey.clone(),
+    }));
+    world.add(Rc::new(Sphere {
+        centre: Vec3::from((0.0, 0.0, -1.0)),
+        radius: 0.5,
+        material: grey,
+    }));
+    world
+}
+
+/// The camera the binaries use for scenes that don't have one of their own.
+pub fn default_camera(width: u32) -> Camera {
+    Camera::new(width, 16.0 / 9.0, 2.0, 1.0, unit_vector(0.0))
+}
+
+/// Used to paint the scene to an array of ARGB pixel components, top scan-line first, encoded
+/// for an sRGB display.
+pub fn paint_pixels(camera: &Camera, scene: &Scene, settings: &RenderSettings) -> Vec<u8> {
+    encode_display(&render_image(camera, scene, settings).colour, settings.working_space, ColourSpace::LinearSrgb)
+}
+
+/// Renders the scene to linear colours in the working space, along with the albedo and normal
+/// seen through each pixel.
+pub fn render_image(camera: &Camera, scene: &Scene, settings: &RenderSettings) -> Image {
+    let mut progress = start_render(camera, settings);
+    continue_render(camera, scene, settings, &mut progress, &mut |_| true);
+    progress.image()
+}
+
+/// A render of the scene through the camera that has yet to take any samples.
+pub fn start_render(camera: &Camera, settings: &RenderSettings) -> Progress {
+    let full = PixelBounds::new(0, 0, camera.width, camera.height);
+    let window = settings.crop.unwrap_or(full).intersect(&full);
+    Progress::new(Film::cropped(camera.width, camera.height, window, settings.filter), settings.seed)
+}
+
+/// Carries on a render until every pixel has all the samples the settings ask for, calling
+/// `after_pass` after each pass over the image. The render stops early if it returns false.
+pub fn continue_render(camera: &Camera,
+                       scene: &Scene,
+                       settings: &RenderSettings,
+                       progress: &mut Progress,
+                       after_pass: &mut dyn FnMut(&Progress) -> bool
+) {
+    let window = progress.bounds;
+    loop {
+        let mut sampled = false;
+        for index in 0..window.area() {
+            let x = window.x0 + index as u32 % window.width();
+            let y = window.y0 + index as u32 / window.width();
+            // A pixel takes part in a pass if it hasn't yet had the samples it needs. That depends
+            // only on its own samples, so a render resumed with more samples carries on from
+            // wherever each pixel got to.
+            let stats = &mut progress.pixels[index];
+            let needed = stats.count < settings.samples_per_pixel
+                || settings.adaptive.as_ref().is_some_and(|adaptive| {
+                    stats.count < adaptive.max_samples && stats.error() > adaptive.threshold
+                });
+            if !needed {
+                continue;
+            }
+
+            fastrand::seed(sample_seed(progress.seed, x, y, stats.count));
+            take_sample(camera, scene, settings, x, y, stats, &mut progress.film);
+            sampled = true;
+        }
+        if !sampled {
+            break;
+        }
+        progress.passes += 1;
+        if !after_pass(progress) {
+            break;
+        }
+    }
+}
+
+/// Takes one sample somewhere in a pixel, adding it to the film and the pixel's statistics.
+fn take_sample(camera: &Camera,
+               scene: &Scene,
+               settings: &RenderSettings,
+               x: u32,
+               y: u32,
+               stats: &mut PixelStats,
+               film: &mut Film
+) {
+    let img_width = camera.width as f64;
+    let img_height = camera.height as f64;
+
+    // Where the sample lands on the film, in pixels down from the top left.
+    let film_x = x as f64 + fastrand::f64();
+    let film_y = y as f64 + fastrand::f64();
+    let u = film_x / (img_width - 1.0); // Scan across left to right of the viewport
+    let v = (img_height - film_y) / (img_height - 1.0); // Scan from bottom to top of the viewport
+    let (mut ray, weight) = match camera.get_ray(u, v) {
+        Some(sample) => sample,
+        None => {
+            // Off the edge of what the camera sees, which is left black.
+            film.add_sample(film_x, film_y, &Vec3::default());
+            stats.add(&Vec3::default(), Vec3::default(), Vec3::default());
+            return;
+        }
+    };
+
+    // The first surface the ray sees is what the denoiser is told about, and where the path
+    // carries on from, so it's only looked for once.
+    let wavelengths = settings.spectral.then(|| Wavelengths::sample(fastrand::f64()));
+    ray.wavelength = wavelengths.as_ref().map(Wavelengths::hero);
+    let first = first_hit(&ray, scene);
+    let (albedo, normal) = surface_features(first.as_ref());
+    let radiance = trace_from(&ray, first, scene, settings, settings.max_depth, wavelengths.as_ref());
+    let col = match &wavelengths {
+        Some(wavelengths) => settings.working_space.from_xyz(&wavelengths.to_xyz(&radiance)),
+        None => radiance,
+    };
+    let col = col.mul(weight * camera.exposure_scale());
+    film.add_sample(film_x, film_y, &col);
+    stats.add(&col, albedo, normal);
+}
+
+/// The seed for a pixel's `index`th sample, mixing everything together with SplitMix64 so that
+/// nearby pixels and samples get unrelated random numbers.
+fn sample_seed(seed: u64, x: u32, y: u32, index: u32) -> u64 {
+    let mix = |z: u64| {
+        let z = z.wrapping_add(0x9e3779b97f4a7c15);
+        let z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        let z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    mix(mix(mix(mix(seed) ^ x as u64) ^ y as u64) ^ index as u64)
+}
+
+/// Shows how many samples each pixel took, from dark blue for the fewest through green and yellow
+/// to red for the most, for seeing where adaptive sampling spent its time.
+pub fn sample_heatmap(image: &Image) -> Vec<Vec3> {
+    let ramp = [
+        Vec3::new(0.0, 0.0, 0.3),
+        Vec3::new(0.0, 0.6, 0.2),
+        Vec3::new(1.0, 0.9, 0.0),
+        Vec3::new(1.0, 0.0, 0.0),
+    ];
+    let least = image.samples.iter().copied().min().unwrap_or(0) as f64;
+    let most = image.samples.iter().copied().max().unwrap_or(0) as f64;
+    image.samples.iter().map(|&count| {
+        let t = if most > least { (count as f64 - least) / (most - least) } else { 0.0 };
+        let position = t * (ramp.len() - 1) as f64;
+        let k = (position as usize).min(ramp.len() - 2);
+        let f = position - k as f64;
+        ramp[k].mul(1.0 - f) + ramp[k + 1].mul(f)
+    }).collect()
+}
+
+/// What a ray hits first, if anything.
+fn first_hit(ray: &Ray, scene: &Scene) -> Option<HitRecord> {
+    let mut rec: HitRecord = Default::default();
+    scene.world.hit(ray, 0.001, INFINITY, &mut rec).then_some(rec)
+}
+
+/// The albedo and normal of the first surface a camera ray sees, or black and zero if it escapes.
+fn surface_features(hit: Option<&HitRecord>) -> (Vec3, Vec3) {
+    match hit {
+        Some(rec) => match &rec.material {
+            Some(material) => (material.albedo(rec), rec.normal.clone()),
+            None => (Vec3::default(), rec.normal.clone()),
+        },
+        None => (Vec3::default(), Vec3::default()),
+    }
+}
+
+/// Traces a ray in a scene to a colour, with each component in the range 0 to 1.
+pub fn ray_to_pixel(ray: &Ray, scene: &Scene, settings: &RenderSettings, depth: u32) -> Vec3 {
+    trace(ray, scene, settings, depth, None)
+}
+
+/// Traces a ray to the light arriving along it, as RGB or, given the wavelengths a spectral path
+/// carries, as the radiance at each of them.
+fn trace(ray: &Ray, scene: &Scene, settings: &RenderSettings, depth: u32, wavelengths: Option<&Wavelengths>) -> Vec3 {
+    if depth == 0 {
+        return Vec3::default();
+    }
+    trace_from(ray, first_hit(ray, scene), scene, settings, depth, wavelengths)
+}
+
+/// Traces a ray as `trace` does, once what it hits first is known.
+fn trace_from(ray: &Ray,
+              hit: Option<HitRecord>,
+              scene: &Scene,
+              settings: &RenderSettings,
+              depth: u32,
+              wavelengths: Option<&Wavelengths>
+) -> Vec3 {
+    if depth == 0 {
+        return Vec3::default();
+    }
+
+    // Light may scatter off the fog before it reaches whatever the ray hit.
+    if let Some(fog) = &settings.fog {
+        if let Some(t) = fog.sample_distance(ray, hit.as_ref().map_or(INFINITY, |rec| rec.t)) {
+            let fog_rec = HitRecord {
+                point: ray.at(t),
+                ..Default::default()
+            };
+            let medium = PhaseMaterial {
+                albedo: fog.albedo.clone(),
+                phase: fog.phase.clone(),
+            };
+            let direct = direct_light(ray, &fog_rec, &medium, scene, settings, wavelengths);
+
+            let mut scattered = Ray::new(fog_rec.point.clone(), fog.phase.sample(&ray.direction), ray.time);
+            scattered.wavelength = ray.wavelength;
+            let albedo = reflectance(&fog.albedo, settings, wavelengths);
+            return direct + albedo.mul_vec(&trace(&scattered, scene, settings, depth - 1, wavelengths));
+        }
+    }
+
+    if let Some(rec) = hit {
+        let material = match &rec.material {
+            Some(material) => material.clone(),
+            None => return Vec3::default(),
+        };
+
+        // Each wavelength would leave a dispersive material in a different direction, so from
+        // here on only the hero is followed.
+        if let Some(w) = wavelengths {
+            if material.dispersive() && !w.terminated {
+                let hero = w.terminate_secondary();
+                return w.hero_weight(&shade(ray, &rec, material.as_ref(), scene, settings, depth, Some(&hero)));
+            }
+        }
+        return shade(ray, &rec, material.as_ref(), scene, settings, depth, wavelengths);
+    }
+
+    let unit_direction = ray.direction.unit_vector(); // Get the unit vector of the ray
+
+    let t = (unit_direction.y + 1.0) * 0.5;
+    let sky = unit_vector(1.0).mul(1.0-t) + Vec3{x: 0.5, y: 0.7, z: 1.0,}.mul(t);
+    illuminant(&sky.mul(scene.sky), settings, wavelengths)
+}
+
+/// The light leaving a surface along a ray that hit it: what it gives off, what it reflects
+/// straight from the lights, and what it scatters from elsewhere.
+fn shade(ray: &Ray,
+         rec: &HitRecord,
+         material: &dyn Material,
+         scene: &Scene,
+         settings: &RenderSettings,
+         depth: u32,
+         wavelengths: Option<&Wavelengths>
+) -> Vec3 {
+    let colour = illuminant(&material.emitted(rec), settings, wavelengths)
+        + direct_light(ray, rec, material, scene, settings, wavelengths);
+
+    let mut attenuation = Vec3::default();
+    let mut scattered = Ray::default();
+    if !material.scatter(ray, rec, &mut attenuation, &mut scattered) {
+        return colour;
+    }
+    scattered.wavelength = ray.wavelength;
+    let attenuation = reflectance(&attenuation, settings, wavelengths);
+    colour + attenuation.mul_vec(&trace(&scattered, scene, settings, depth - 1, wavelengths))
+}
+
+/// A colour that scales light, such as a reflectance, at the path's wavelengths if it has any.
+fn reflectance(rgb: &Vec3, settings: &RenderSettings, wavelengths: Option<&Wavelengths>) -> Vec3 {
+    match wavelengths {
+        Some(w) => w.reflectance(&settings.working_space.convert(rgb, ColourSpace::LinearSrgb)),
+        None => rgb.clone(),
+    }
+}
+
+/// A colour of light at the path's wavelengths if it has any.
+fn illuminant(rgb: &Vec3, settings: &RenderSettings, wavelengths: Option<&Wavelengths>) -> Vec3 {
+    match wavelengths {
+        Some(w) if !rgb.near_zero() => w.illuminant(&settings.working_space.convert(rgb, ColourSpace::LinearSrgb)),
+        Some(_) => Vec3::default(),
+        None => rgb.clone(),
+    }
+}
+
+/// The light from a light sample, at the path's wavelengths if it has any. Lights with a physical
+/// spectrum use it, at the brightness of their colour, which comes from the spectrum in linear
+/// sRGB rather than the working space.
+fn light_irradiance(sample: &LightSample, settings: &RenderSettings, wavelengths: Option<&Wavelengths>) -> Vec3 {
+    match (wavelengths, &sample.spectrum) {
+        (Some(w), Some(spectrum)) => {
+            w.spectrum(spectrum).mul(luminance(&sample.irradiance) / luminance(&spectrum.colour()))
+        }
+        (None, Some(_)) => ColourSpace::LinearSrgb.convert(&sample.irradiance, settings.working_space),
+        (_, None) => illuminant(&sample.irradiance, settings, wavelengths),
+    }
+}
+
+/// The light reaching a hit straight from the scene's lights and scattered back along the ray.
+/// Rays can never hit these lights, so this is the only way they are seen, and nothing is counted
+/// twice.
+fn direct_light(ray: &Ray,
+                rec: &HitRecord,
+                material: &dyn Material,
+                scene: &Scene,
+                settings: &RenderSettings,
+                wavelengths: Option<&Wavelengths>
+) -> Vec3 {
+    let mut total = Vec3::default();
+    for light in &scene.lights {
+        let sample = match light.sample(&rec.point) {
+            Some(sample) => sample,
+            None => continue,
+        };
+        let f = material.eval(ray, rec, &sample.direction);
+        if f.near_zero() {
+            continue;
+        }
+
+        // Surfaces in the way cast a shadow, while media dim the light by how much of it gets
+        // through. Fog is hit at random with the chance that it scatters the light away, so on
+        // average it lets the right amount through.
+        let shadow_ray = Ray::new(rec.point.clone(), sample.direction.clone(), ray.time);
+        let transmittance = scene.world.transmittance(&shadow_ray, 0.001, sample.distance);
+        if transmittance <= 0.0 {
+            continue;
+        }
+        if let Some(fog) = &settings.fog {
+            if fog.sample_distance(&shadow_ray, sample.distance).is_some() {
+                continue;
+            }
+        }
+
+        let irradiance = light_irradiance(&sample, settings, wavelengths).mul(transmittance);
+        total = total + reflectance(&f, settings, wavelengths).mul_vec(&irradiance);
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_scene() -> (Camera, Scene) {
+        let camera = Camera::new(8, 2.0, 2.0, 1.0, Vec3::default());
+        (camera, Scene::new(Rc::new(default_world())))
+    }
+
+    /// Renders `first` samples, saves and reloads the render, then resumes it with `then`.
+    fn resume(first: RenderSettings, then: RenderSettings) -> Progress {
+        let (camera, scene) = small_scene();
+        let mut progress = start_render(&camera, &first);
+        continue_render(&camera, &scene, &first, &mut progress, &mut |_| true);
+        let mut progress = Progress::decode(&progress.encode()).unwrap();
+        continue_render(&camera, &scene, &then, &mut progress, &mut |_| true);
+        progress
+    }
+
+    fn settings(samples_per_pixel: u32, adaptive: Option<AdaptiveSampling>) -> RenderSettings {
+        RenderSettings {
+            samples_per_pixel,
+            max_depth: 4,
+            adaptive,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn resuming_adds_samples() {
+        let progress = resume(settings(2, None), settings(5, None));
+        assert!(progress.pixels.iter().all(|stats| stats.count == 5));
+    }
+
+    #[test]
+    fn resuming_adds_samples_to_converged_adaptive_renders() {
+        // Every pixel is good enough straight away, so the first render stops at the minimum.
+        let converged = || Some(AdaptiveSampling {
+            threshold: f64::INFINITY,
+            max_samples: 16,
+        });
+        let progress = resume(settings(2, converged()), settings(6, converged()));
+        assert!(progress.pixels.iter().all(|stats| stats.count == 6));
+    }
+
+    #[test]
+    fn resumed_renders_match_uninterrupted_ones() {
+        let (camera, scene) = small_scene();
+        let whole = render_image(&camera, &scene, &settings(4, None));
+        let resumed = resume(settings(1, None), settings(4, None)).image();
+        assert_eq!(whole.samples, resumed.samples);
+        for (a, b) in whole.colour.iter().zip(&resumed.colour) {
+            assert!((a.clone() - b.clone()).length() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::rc::Rc;
+
+use crate::{Aabb, HitRecord, Hittable, Material, Ray, Vec3, clamp};
+
+/// A shape described by the distance from any point to its surface, negative inside.
+pub trait Sdf {
+    fn distance(&self, p: &Vec3) -> f64;
+}
+
+/// Renders a signed distance field by sphere tracing: stepping along the ray by the distance to
+/// the nearest surface, which can never overshoot it.
+pub struct SdfHittable {
+    pub sdf: Rc<dyn Sdf>,
+    pub bounds: Aabb, // Tracing only happens inside here, so the field needs nothing outside.
+    pub material: Rc<dyn Material>,
+    pub epsilon: f64, // How close to the surface counts as a hit.
+    pub max_steps: u32,
+    pub step_scale: f64, // Fields that overestimate distance, like twisted ones, need this below 1.
+}
+
+pub struct SdfSphere {
+    pub radius: f64,
+}
+
+/// A box centred on the origin, with its edges rounded off by `radius`.
+pub struct SdfRoundBox {
+    pub half_extents: Vec3,
+    pub radius: f64,
+}
+
+/// A torus lying in the xz plane.
+pub struct SdfTorus {
+    pub major_radius: f64,
+    pub minor_radius: f64,
+}
+
+/// The Mandelbulb fractal, which fits within a sphere of radius 1.2.
+pub struct SdfMandelbulb {
+    pub power: f64,
+    pub iterations: u32,
+}
+
+pub struct SdfTranslate {
+    pub sdf: Rc<dyn Sdf>,
+    pub offset: Vec3,
+}
+
+/// Repeats a field forever along each axis with a non-zero period.
+pub struct SdfRepeat {
+    pub sdf: Rc<dyn Sdf>,
+    pub period: Vec3,
+}
+
+/// Merges two fields, rounding off the join between them over a width of `k`. A `k` of 0 gives a
+/// plain union.
+pub struct SdfBlend {
+    pub a: Rc<dyn Sdf>,
+    pub b: Rc<dyn Sdf>,
+    pub k: f64,
+}
+
+/// Twists a field about the y axis by `rate` radians per unit of height.
+pub struct SdfTwist {
+    pub sdf: Rc<dyn Sdf>,
+    pub rate: f64,
+}
+
+impl SdfHittable {
+    pub fn new(sdf: Rc<dyn Sdf>, bounds: Aabb, material: Rc<dyn Material>) -> SdfHittable {
+        SdfHittable {
+            sdf,
+            bounds,
+            material,
+            epsilon: 1e-4,
+            max_steps: 256,
+            step_scale: 1.0,
+        }
+    }
+
+    /// The surface normal at a point, from the gradient of the field. Sampling at the corners of
+    /// a tetrahedron needs one fewer lookup than central differences.
+    pub fn normal(&self, p: &Vec3) -> Vec3 {
+        let h = self.epsilon;
+        let mut n = Vec3::default();
+        for k in [(1.0, -1.0, -1.0), (-1.0, -1.0, 1.0), (-1.0, 1.0, -1.0), (1.0, 1.0, 1.0)].iter() {
+            let k = Vec3::from(*k);
+            n = n + k.mul(self.sdf.distance(&(p.clone() + k.mul(h))));
+        }
+        n.unit_vector()
+    }
+}
+
+impl Hittable for SdfHittable {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let (t0, t1) = match self.bounds.intersect(ray, t_min, t_max) {
+            Some(interval) => interval,
+            None => return false,
+        };
+
+        let ray_length = ray.direction.length();
+        let mut t = t0;
+
+        // Rays leaving the surface start within epsilon of it, so a hit only counts once the ray
+        // has been clear of the surface.
+        let mut clear = false;
+        for _ in 0..self.max_steps {
+            let d = self.sdf.distance(&ray.at(t)).abs();
+            if d >= self.epsilon {
+                clear = true;
+            } else if clear {
+                rec.t = t;
+                rec.point = ray.at(t);
+                let outward_normal = self.normal(&rec.point);
+                rec.set_face_normal(ray, &outward_normal);
+                rec.material = Some(self.material.clone());
+                return true;
+            }
+
+            t += d.max(self.epsilon) * self.step_scale / ray_length;
+            if t > t1 {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
+        *output_box = self.bounds.clone();
+        true
+    }
+}
+
+impl Sdf for SdfSphere {
+    fn distance(&self, p: &Vec3) -> f64 {
+        p.length() - self.radius
+    }
+}
+
+impl Sdf for SdfRoundBox {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let q = Vec3::new(p.x.abs(), p.y.abs(), p.z.abs()) - self.half_extents.clone() + Vec3::from(self.radius);
+        q.max(&Vec3::default()).length() + q.x.max(q.y).max(q.z).min(0.0) - self.radius
+    }
+}
+
+impl Sdf for SdfTorus {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let ring = (p.x * p.x + p.z * p.z).sqrt() - self.major_radius;
+        (ring * ring + p.y * p.y).sqrt() - self.minor_radius
+    }
+}
+
+impl Sdf for SdfMandelbulb {
+    fn distance(&self, p: &Vec3) -> f64 {
+        // The usual distance estimator, tracking the running derivative of the iteration.
+        let mut z = p.clone();
+        let mut dr = 1.0;
+        let mut r = 0.0;
+        for _ in 0..self.iterations {
+            r = z.length();
+            if r > 2.0 {
+                break;
+            }
+
+            let theta = (z.z / r).acos() * self.power;
+            let phi = z.y.atan2(z.x) * self.power;
+            dr = r.powf(self.power - 1.0) * self.power * dr + 1.0;
+
+            let zr = r.powf(self.power);
+            z = Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()).mul(zr) + p.clone();
+        }
+        if r == 0.0 {
+            return 0.0;
+        }
+        0.5 * r.ln() * r / dr
+    }
+}
+
+impl Sdf for SdfTranslate {
+    fn distance(&self, p: &Vec3) -> f64 {
+        self.sdf.distance(&(p.clone() - self.offset.clone()))
+    }
+}
+
+impl Sdf for SdfRepeat {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let wrap = |x: f64, period: f64| if period > 0.0 { x - period * (x / period).round() } else { x };
+        let q = Vec3::new(wrap(p.x, self.period.x), wrap(p.y, self.period.y), wrap(p.z, self.period.z));
+        self.sdf.distance(&q)
+    }
+}
+
+impl Sdf for SdfBlend {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let a = self.a.distance(p);
+        let b = self.b.distance(p);
+        if self.k <= 0.0 {
+            return a.min(b);
+        }
+
+        // Polynomial smooth minimum.
+        let h = clamp(0.5 + 0.5 * (b - a) / self.k, 0.0, 1.0);
+        b + (a - b) * h - self.k * h * (1.0 - h)
+    }
+}
+
+impl Sdf for SdfTwist {
+    fn distance(&self, p: &Vec3) -> f64 {
+        let angle = self.rate * p.y;
+        let (s, c) = angle.sin_cos();
+        self.sdf.distance(&Vec3::new(c * p.x - s * p.z, p.y, s * p.x + c * p.z))
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{Value, json};
+
+use crate::distributed::DEFAULT_FOCUS_DISTANCE;
+use crate::lens::FILM_DIAGONAL;
+use crate::{
+    ColourSpace, Exposure, Filter, Job, Projection, RenderSettings, Vec3, continue_render, encode_display, start_render,
+};
+
+// A small HTTP server that renders jobs one after another, for tools that want to render without
+// going through the command line. The API:
+//
+//   POST   /jobs             queues the glTF or GLB scene in the body, or the default scene if the
+//                            body is empty. Options go in the query: width (from 2 to 8192
+//                            pixels), samples, filter, spectral, time (the seconds into the
+//                            scene's animation), projection, shutter (how long moving things
+//                            blur for), f-number (for depth of field), and with both of those,
+//                            iso for a photographic exposure. Answers with the job's id. Scenes
+//                            can be up to 256 MiB, and the server won't read files for them, so
+//                            a .gltf has to embed its buffers and images as data URIs. Up to 16
+//                            jobs can wait in the queue, and more are turned away with a 503.
+//   GET    /jobs             the status of every job.
+//   GET    /jobs/ID          the status of a job: queued, rendering, done, cancelled or failed,
+//                            and how far through it is.
+//   GET    /jobs/ID/image    the job's image so far, as a PNG.
+//   DELETE /jobs/ID          cancels a job, whether it's queued or rendering.
+//
+// Requests are each handled on a thread of their own, while the rendering happens on one thread
+// that takes jobs from the queue. Scenes can't be shared between threads, so the render thread
+// builds each job's scene itself.
+
+/// The jobs the server knows about, and the queue of those waiting to be rendered.
+struct Jobs {
+    queue: Mutex<VecDeque<(u64, Job)>>,
+    queued: Condvar,
+    status: Mutex<BTreeMap<u64, JobStatus>>,
+}
+
+struct JobStatus {
+    state: State,
+    passes: u32,
+    total_passes: u32,
+    cancel: bool, // Asked for while rendering, and seen after the next pass.
+    error: Option<String>,
+    preview: Option<Preview>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Queued,
+    Rendering,
+    Done,
+    Cancelled,
+    Failed,
+}
+
+/// The image so far, as 8-bit sRGB.
+struct Preview {
+    width: u32,
+    height: u32,
+    rgb: Vec<u8>,
+}
+
+// How often the preview is brought up to date while rendering.
+const PREVIEW_INTERVAL: Duration = Duration::from_millis(500);
+// How long a client can keep the server waiting to read or write part of a request.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+// The biggest scene that can be uploaded, in bytes, and the longest line in a request's head.
+const MAX_BODY_LENGTH: usize = 256 << 20;
+const MAX_LINE_LENGTH: u64 = 8192;
+// The widest image that can be asked for, in pixels.
+const MAX_WIDTH: u32 = 8192;
+// The most jobs that can wait to be rendered, as each holds on to its scene until then.
+const MAX_QUEUED_JOBS: usize = 16;
+
+/// Serves the API on `address` until the process is stopped, passing anything that goes wrong
+/// with a request to `report`.
+pub fn serve(address: &str, report: fn(String)) -> Result<(), String> {
+    let listener = TcpListener::bind(address).map_err(|e| format!("couldn't listen on {}: {}", address, e))?;
+
+    let jobs = Arc::new(Jobs {
+        queue: Mutex::new(VecDeque::new()),
+        queued: Condvar::new(),
+        status: Mutex::new(BTreeMap::new()),
+    });
+    let renderer = jobs.clone();
+    thread::spawn(move || render_jobs(&renderer));
+
+    for stream in listener.incoming().flatten() {
+        let jobs = jobs.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &jobs) {
+                report(e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Renders queued jobs one at a time, forever.
+fn render_jobs(jobs: &Jobs) {
+    loop {
+        let (id, job) = {
+            let mut queue = match jobs.queue.lock() {
+                Ok(queue) => queue,
+                Err(_) => return,
+            };
+            loop {
+                if let Some(next) = queue.pop_front() {
+                    break next;
+                }
+                queue = match jobs.queued.wait(queue) {
+                    Ok(queue) => queue,
+                    Err(_) => return,
+                };
+            }
+        };
+        // It may have been cancelled between leaving the queue and getting here.
+        let mut start = false;
+        update(jobs, id, |status| {
+            if status.state == State::Queued {
+                status.state = State::Rendering;
+                start = true;
+            }
+        });
+        if !start {
+            continue;
+        }
+
+        // A scene that trips up the renderer only fails its own job, rather than stopping the
+        // jobs after it from ever being rendered.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| render_job(jobs, id, &job)))
+            .unwrap_or_else(|panic| Err(match panic.downcast_ref::<&str>() {
+                Some(message) => format!("the renderer failed: {}", message),
+                None => match panic.downcast_ref::<String>() {
+                    Some(message) => format!("the renderer failed: {}", message),
+                    None => String::from("the renderer failed"),
+                },
+            }));
+        update(jobs, id, |status| match &result {
+            Ok(()) if status.cancel => status.state = State::Cancelled,
+            Ok(()) => status.state = State::Done,
+            Err(e) => {
+                status.state = State::Failed;
+                status.error = Some(e.clone());
+            }
+        });
+    }
+}
+
+fn render_job(jobs: &Jobs, id: u64, job: &Job) -> Result<(), String> {
+    let scene = job.scene()?;
+    let camera = &scene.cameras[0];
+
+    let mut progress = start_render(camera, &job.settings);
+    let mut last_preview = Instant::now();
+    continue_render(camera, &scene, &job.settings, &mut progress, &mut |progress| {
+        let preview = if last_preview.elapsed() >= PREVIEW_INTERVAL {
+            last_preview = Instant::now();
+            Some(preview(&progress.image().colour, progress.bounds.width(), progress.bounds.height(), &job.settings))
+        } else {
+            None
+        };
+        let mut keep_going = true;
+        update(jobs, id, |status| {
+            status.passes = progress.passes;
+            if preview.is_some() {
+                status.preview = preview;
+            }
+            keep_going = !status.cancel;
+        });
+        keep_going
+    });
+
+    let image = progress.image();
+    let finished = preview(&image.colour, image.width, image.height, &job.settings);
+    update(jobs, id, |status| status.preview = Some(finished));
+    Ok(())
+}
+
+fn preview(pixels: &[Vec3], width: u32, height: u32, settings: &RenderSettings) -> Preview {
+    let argb = encode_display(pixels, settings.working_space, ColourSpace::LinearSrgb);
+    Preview {
+        width,
+        height,
+        rgb: argb.chunks(4).flat_map(|p| [p[1], p[2], p[3]]).collect(),
+    }
+}
+
+/// Changes a job's status, if it has one.
+fn update(jobs: &Jobs, id: u64, change: impl FnOnce(&mut JobStatus)) {
+    if let Ok(mut status) = jobs.status.lock() {
+        if let Some(status) = status.get_mut(&id) {
+            change(status);
+        }
+    }
+}
+
+/// Answers one request.
+fn handle(stream: TcpStream, jobs: &Jobs) -> Result<(), String> {
+    stream.set_read_timeout(Some(SOCKET_TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(SOCKET_TIMEOUT)).map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+    let mut line = String::new();
+    read_line(&mut reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("/").to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        read_line(&mut reader, &mut header)?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| String::from("bad Content-Length"))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY_LENGTH {
+        return Response::error(413, "the scene is too big").send(stream);
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match (method.as_str(), segments.as_slice()) {
+        ("POST", ["jobs"]) => submit(jobs, query, body),
+        ("GET", ["jobs"]) => {
+            let status = jobs.status.lock().map_err(|_| String::from("the jobs are poisoned"))?;
+            let all: Vec<Value> = status.iter().map(|(id, status)| describe(*id, status)).collect();
+            Response::json(200, &Value::from(all))
+        }
+        ("GET", ["jobs", id]) => with_job(jobs, id, |id, status| Response::json(200, &describe(id, status))),
+        ("GET", ["jobs", id, "image"]) => with_job(jobs, id, |_, status| match &status.preview {
+            Some(preview) => Response::png(preview),
+            None => Response::error(404, "nothing has been rendered yet"),
+        }),
+        ("DELETE", ["jobs", id]) => cancel(jobs, id),
+        _ => Response::error(404, "no such resource"),
+    };
+    response.send(stream)
+}
+
+/// Reads a line of the request's head, which has to end within a sensible length.
+fn read_line(reader: &mut BufReader<TcpStream>, line: &mut String) -> Result<(), String> {
+    reader.take(MAX_LINE_LENGTH).read_line(line).map_err(|e| e.to_string())?;
+    if !line.ends_with('\n') {
+        return Err(String::from("the request's head has a line that's too long or cut short"));
+    }
+    Ok(())
+}
+
+/// Queues a new job.
+fn submit(jobs: &Jobs, query: &str, scene: Vec<u8>) -> Response {
+    let mut settings = RenderSettings::default();
+    let mut width = 400;
+    let mut time = 0.0;
+    let mut projection = None;
+    let (mut shutter_speed, mut f_number, mut iso) = (None, None, None);
+    for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        let valid = match name {
+            "width" => value.parse().ok().filter(|w| (2..=MAX_WIDTH).contains(w)).map(|w| width = w).is_some(),
+            "samples" => value.parse().map(|s| settings.samples_per_pixel = s).is_ok(),
+            "filter" => Filter::parse(value).map(|f| settings.filter = f).is_ok(),
+            "spectral" => value.parse().map(|s| settings.spectral = s).is_ok(),
+            "time" => value.parse().map(|t| time = t).is_ok(),
+            "projection" => Projection::parse(value).map(|p| projection = Some(p)).is_ok(),
+            "iso" => positive(value).map(|v| iso = Some(v)).is_some(),
+            "shutter" => Exposure::parse_shutter_speed(value).map(|s| shutter_speed = Some(s)).is_ok(),
+            "f-number" => positive(value).map(|f| f_number = Some(f)).is_some(),
+            _ => false,
+        };
+        if !valid {
+            return Response::error(400, &format!("bad option {}={}", name, value));
+        }
+    }
+
+    if iso.is_some() && (shutter_speed.is_none() || f_number.is_none()) {
+        return Response::error(400, "an exposure needs a shutter speed and an f-number as well as an ISO");
+    }
+
+    let total_passes = settings.passes();
+    let job = Job {
+        scene_path: None,
+        scene,
+        width,
+        settings,
+        time,
+        projection,
+        lens: None,
+        focus_distance: DEFAULT_FOCUS_DISTANCE,
+        film_diagonal: FILM_DIAGONAL,
+        shutter_speed,
+        f_number,
+        iso,
+    };
+    let (mut queue, mut status) = match (jobs.queue.lock(), jobs.status.lock()) {
+        (Ok(queue), Ok(status)) => (queue, status),
+        _ => return Response::error(500, "the jobs are poisoned"),
+    };
+    if queue.len() >= MAX_QUEUED_JOBS {
+        return Response::error(503, "too many jobs are waiting to be rendered");
+    }
+    let id = status.keys().next_back().map_or(1, |last| last + 1);
+    status.insert(id, JobStatus {
+        state: State::Queued,
+        passes: 0,
+        total_passes,
+        cancel: false,
+        error: None,
+        preview: None,
+    });
+    queue.push_back((id, job));
+    jobs.queued.notify_one();
+    Response::json(201, &json!({ "id": id }))
+}
+
+/// A number above zero, or None.
+fn positive(text: &str) -> Option<f64> {
+    text.parse().ok().filter(|v: &f64| *v > 0.0 && v.is_finite())
+}
+
+fn cancel(jobs: &Jobs, id: &str) -> Response {
+    let id = match id.parse::<u64>() {
+        Ok(id) => id,
+        Err(_) => return Response::error(404, "no such job"),
+    };
+    if let Ok(mut queue) = jobs.queue.lock() {
+        queue.retain(|(queued, _)| *queued != id);
+    }
+    with_job(jobs, &id.to_string(), |_, status| {
+        match status.state {
+            State::Queued => status.state = State::Cancelled,
+            State::Rendering => status.cancel = true,
+            _ => return Response::error(409, "the job has already finished"),
+        }
+        Response::json(200, &describe(id, status))
+    })
+}
+
+/// Runs `action` on a job's status, or answers that there's no such job.
+fn with_job(jobs: &Jobs, id: &str, action: impl FnOnce(u64, &mut JobStatus) -> Response) -> Response {
+    let mut status = match jobs.status.lock() {
+        Ok(status) => status,
+        Err(_) => return Response::error(500, "the jobs are poisoned"),
+    };
+    match id.parse::<u64>().ok().and_then(|id| status.get_mut(&id).map(|status| (id, status))) {
+        Some((id, status)) => action(id, status),
+        None => Response::error(404, "no such job"),
+    }
+}
+
+fn describe(id: u64, status: &JobStatus) -> Value {
+    let state = match status.state {
+        State::Queued => "queued",
+        State::Rendering => "rendering",
+        State::Done => "done",
+        State::Cancelled => "cancelled",
+        State::Failed => "failed",
+    };
+    let progress = match status.state {
+        State::Done => 1.0,
+        _ => (status.passes as f64 / status.total_passes.max(1) as f64).min(1.0),
+    };
+    json!({
+        "id": id,
+        "state": state,
+        "progress": progress,
+        "passes": status.passes,
+        "error": status.error,
+    })
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, value: &Value) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response::json(status, &json!({ "error": message }))
+    }
+
+    fn png(preview: &Preview) -> Response {
+        let image = image::RgbImage::from_raw(preview.width, preview.height, preview.rgb.clone());
+        let mut bytes = Vec::new();
+        match image.map(|image| image.write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)) {
+            Some(Ok(())) => Response {
+                status: 200,
+                content_type: "image/png",
+                body: bytes,
+            },
+            _ => Response::error(500, "couldn't encode the image"),
+        }
+    }
+
+    fn send(&self, mut stream: TcpStream) -> Result<(), String> {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            404 => "Not Found",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len()
+        );
+        stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(&self.body)).map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,164 @@
+use std::rc::Rc;
+
+use crate::microfacet::Frame;
+use crate::{HitRecord, Material, Ray, Texture, Vec3};
+
+// Materials that bend the shading normal before handing the hit on to another material, giving
+// flat geometry the look of fine surface detail. Both work around the surface's own tangents
+// (dpdu and dpdv), falling back to an arbitrary frame around the normal where a surface has none.
+
+/// Bends the normal using a tangent-space normal map, where red, green and blue hold the normal
+/// along u, v and out of the surface, as written by most tools (and glTF).
+pub struct NormalMap {
+    pub material: Rc<dyn Material>,
+    pub map: Rc<dyn Texture>,
+    pub strength: f64, // Scales the sideways part of the map's normals.
+}
+
+/// Bends the normal as if the surface were raised along it by a height texture, whose channels
+/// are averaged.
+pub struct BumpMap {
+    pub material: Rc<dyn Material>,
+    pub height: Rc<dyn Texture>,
+    pub scale: f64,
+}
+
+// How far along u and v to look either side of a hit when finding the slope of a height texture.
+const BUMP_DELTA: f64 = 5e-4;
+
+impl NormalMap {
+    pub fn new(material: Rc<dyn Material>, map: Rc<dyn Texture>, strength: f64) -> NormalMap {
+        NormalMap {
+            material,
+            map,
+            strength,
+        }
+    }
+
+    fn shade(&self, ray_in: &Ray, rec: &HitRecord) -> HitRecord {
+        let (dpdu, dpdv, normal) = tangents(rec);
+        let frame = Frame::from_hit(&HitRecord {
+            normal: normal.clone(),
+            dpdu,
+            ..Default::default()
+        });
+
+        // Mirrored texture coordinates flip which way v runs across the surface.
+        let handedness = if dpdv.dot(&frame.bitangent) < 0.0 { -1.0 } else { 1.0 };
+        let c = self.map.value(rec).mul(2.0).sub(1.0);
+        let local = Vec3::new(c.x * self.strength, c.y * self.strength * handedness, c.z.max(0.0));
+        if local.near_zero() {
+            return rec.clone();
+        }
+        with_normal(ray_in, rec, &frame.to_world(&local))
+    }
+}
+
+impl BumpMap {
+    pub fn new(material: Rc<dyn Material>, height: Rc<dyn Texture>, scale: f64) -> BumpMap {
+        BumpMap {
+            material,
+            height,
+            scale,
+        }
+    }
+
+    fn shade(&self, ray_in: &Ray, rec: &HitRecord) -> HitRecord {
+        let (dpdu, dpdv, normal) = tangents(rec);
+        let height_at = |du: f64, dv: f64| {
+            height(&*self.height, &HitRecord {
+                point: rec.point.clone() + dpdu.mul(du) + dpdv.mul(dv),
+                u: rec.u + du,
+                v: rec.v + dv,
+                ..rec.clone()
+            })
+        };
+        let d = BUMP_DELTA;
+        let slope_u = (height_at(d, 0.0) - height_at(-d, 0.0)) / (2.0 * d) * self.scale;
+        let slope_v = (height_at(0.0, d) - height_at(0.0, -d)) / (2.0 * d) * self.scale;
+
+        // The tangents of the raised surface, ignoring how the normal itself turns.
+        let bumped_u = dpdu + normal.mul(slope_u);
+        let bumped_v = dpdv + normal.mul(slope_v);
+        let mut bumped = bumped_u.cross(&bumped_v);
+        if bumped.dot(&normal) < 0.0 {
+            bumped = bumped.mul(-1.0);
+        }
+        with_normal(ray_in, rec, &bumped)
+    }
+}
+
+impl Material for NormalMap {
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+        self.material.scatter(ray_in, &self.shade(ray_in, rec), attenuation, scattered)
+    }
+
+    fn eval(&self, ray_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        self.material.eval(ray_in, &self.shade(ray_in, rec), direction)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Vec3 {
+        self.material.emitted(rec)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.material.dispersive()
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.material.albedo(rec)
+    }
+}
+
+impl Material for BumpMap {
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+        self.material.scatter(ray_in, &self.shade(ray_in, rec), attenuation, scattered)
+    }
+
+    fn eval(&self, ray_in: &Ray, rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        self.material.eval(ray_in, &self.shade(ray_in, rec), direction)
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Vec3 {
+        self.material.emitted(rec)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.material.dispersive()
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.material.albedo(rec)
+    }
+}
+
+/// A height texture's value at a hit, as the average of its channels.
+pub fn height(texture: &dyn Texture, rec: &HitRecord) -> f64 {
+    let c = texture.value(rec);
+    (c.x + c.y + c.z) / 3.0
+}
+
+/// The surface's tangents and outward normal at a hit, making some up if the surface has none.
+fn tangents(rec: &HitRecord) -> (Vec3, Vec3, Vec3) {
+    let normal = if rec.front_face { rec.normal.clone() } else { rec.normal.mul(-1.0) };
+    if rec.dpdu.near_zero() || rec.dpdv.near_zero() {
+        let frame = Frame::new(&normal);
+        return (frame.tangent, frame.bitangent, frame.normal);
+    }
+    (rec.dpdu.clone(), rec.dpdv.clone(), normal)
+}
+
+/// The hit with its outward normal replaced, flipped back to face the ray as before. A normal
+/// bent so far that the ray would arrive from behind it is ignored, as it would make the
+/// surface black.
+fn with_normal(ray_in: &Ray, rec: &HitRecord, outward: &Vec3) -> HitRecord {
+    let outward = outward.unit_vector();
+    let normal = if rec.front_face { outward } else { outward.mul(-1.0) };
+    if normal.dot(&ray_in.direction) >= 0.0 {
+        return rec.clone();
+    }
+    HitRecord {
+        normal,
+        ..rec.clone()
+    }
+}
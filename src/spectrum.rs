@@ -0,0 +1,373 @@
+use std::sync::OnceLock;
+
+use crate::colour::invert;
+use crate::{ColourSpace, Vec3};
+
+// Spectral rendering, where each path carries a few wavelengths of light instead of red, green and
+// blue. The first is chosen at random (the hero wavelength) and the others are spread evenly from
+// it, so one path covers the spectrum and the colour noise averages out quickly.
+//
+// Colours given as RGB are turned into smooth spectra with a basis of three curves, one per
+// primary, chosen so that any reflectance lit by D65 comes back as exactly the colour it started
+// as. Lights and other emitters given as RGB are the same curves times D65. Finished samples are
+// weighted by the CIE 1931 colour matching functions into XYZ.
+//
+// Everything is in nanometres, over the range the colour matching functions are tabulated for.
+
+pub const LAMBDA_MIN: f64 = 360.0;
+pub const LAMBDA_MAX: f64 = 830.0;
+
+// The wavelength that a single index of refraction is usually quoted for, the sodium D line.
+pub const SODIUM_D: f64 = 589.3;
+
+/// The wavelengths one path carries, with radiance at each held in the matching component of a
+/// Vec3.
+#[derive(Clone, Copy)]
+pub struct Wavelengths {
+    pub lambda: [f64; 3],
+    pub pdf: [f64; 3],
+    pub terminated: bool, // Whether only the hero wavelength is still being followed.
+}
+
+/// The physical spectrum of a light, scaled to a luminance of 1.
+#[derive(Clone)]
+pub struct Spectrum {
+    kind: SpectrumKind,
+    scale: f64,
+    colour: Vec3,
+}
+
+#[derive(Clone)]
+enum SpectrumKind {
+    Blackbody(f64),
+    Daylight(f64, f64), // The weights of the daylight components after the mean.
+}
+
+/// How the index of refraction of a material changes with wavelength, which is what splits white
+/// light into a rainbow through a prism.
+#[derive(Clone)]
+pub enum Dispersion {
+    Cauchy { a: f64, b: f64 }, // n = a + b / lambda^2, with lambda in micrometres.
+    Sellmeier { b: [f64; 3], c: [f64; 3] }, // With c in square micrometres.
+}
+
+impl Wavelengths {
+    /// Picks three wavelengths evenly spread through the visible spectrum, starting from `u`, a
+    /// random number from 0 to 1.
+    pub fn sample(u: f64) -> Wavelengths {
+        let mut lambda = [0.0; 3];
+        let mut pdf = [0.0; 3];
+        for i in 0..3 {
+            lambda[i] = sample_visible((u + i as f64 / 3.0).fract());
+            pdf[i] = visible_pdf(lambda[i]);
+        }
+        Wavelengths {
+            lambda,
+            pdf,
+            terminated: false,
+        }
+    }
+
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+
+    /// The same wavelengths, following only the hero from here on. The hero then has to stand in
+    /// for the others, so radiance found along the path should be scaled by `hero_weight`.
+    pub fn terminate_secondary(&self) -> Wavelengths {
+        Wavelengths {
+            terminated: true,
+            ..*self
+        }
+    }
+
+    /// Moves all the radiance onto the hero wavelength, weighted to make up for the others.
+    pub fn hero_weight(&self, radiance: &Vec3) -> Vec3 {
+        Vec3::new(radiance.x * 3.0, 0.0, 0.0)
+    }
+
+    /// A reflectance given as RGB, at each wavelength.
+    pub fn reflectance(&self, rgb: &Vec3) -> Vec3 {
+        self.each(|lambda| {
+            let b = tables().basis_at(lambda);
+            rgb.x * b.x + rgb.y * b.y + rgb.z * b.z
+        })
+    }
+
+    /// Light given as RGB, at each wavelength.
+    pub fn illuminant(&self, rgb: &Vec3) -> Vec3 {
+        self.reflectance(rgb).mul_vec(&self.each(|lambda| lookup(&tables().d65, lambda)))
+    }
+
+    /// A physical spectrum at each wavelength.
+    pub fn spectrum(&self, spectrum: &Spectrum) -> Vec3 {
+        self.each(|lambda| spectrum.value(lambda))
+    }
+
+    /// Turns radiance at these wavelengths into an estimate of its XYZ colour.
+    pub fn to_xyz(&self, radiance: &Vec3) -> Vec3 {
+        let tables = tables();
+        let mut xyz = Vec3::default();
+        for i in 0..3 {
+            if self.pdf[i] > 0.0 {
+                xyz = xyz + cmf(self.lambda[i]).mul(radiance[i] / self.pdf[i]);
+            }
+        }
+        xyz.div(3.0 * tables.y_integral)
+    }
+
+    fn each(&self, f: impl Fn(f64) -> f64) -> Vec3 {
+        Vec3::new(f(self.lambda[0]), f(self.lambda[1]), f(self.lambda[2]))
+    }
+}
+
+impl Spectrum {
+    /// The light given off by a black body at a temperature in kelvin.
+    pub fn blackbody(kelvin: f64) -> Spectrum {
+        Spectrum::normalised(SpectrumKind::Blackbody(kelvin))
+    }
+
+    /// The CIE daylight illuminant for a correlated colour temperature from 4000 to 25000 kelvin.
+    pub fn daylight(kelvin: f64) -> Spectrum {
+        let t = kelvin.clamp(4000.0, 25000.0);
+        let x = if t <= 7000.0 {
+            -4.6070e9 / t.powi(3) + 2.9678e6 / t.powi(2) + 0.09911e3 / t + 0.244063
+        } else {
+            -2.0064e9 / t.powi(3) + 1.9018e6 / t.powi(2) + 0.24748e3 / t + 0.237040
+        };
+        let y = -3.0 * x * x + 2.870 * x - 0.275;
+        let m = 0.0241 + 0.2562 * x - 0.7341 * y;
+        let m1 = (-1.3515 - 1.7703 * x + 5.9114 * y) / m;
+        let m2 = (0.0300 - 31.4424 * x + 30.0717 * y) / m;
+        Spectrum::normalised(SpectrumKind::Daylight(m1, m2))
+    }
+
+    /// Average daylight, the white point of sRGB.
+    pub fn d65() -> Spectrum {
+        Spectrum::daylight(6504.0)
+    }
+
+    /// Horizon daylight, the white point used in printing.
+    pub fn d50() -> Spectrum {
+        Spectrum::daylight(5003.0)
+    }
+
+    fn normalised(kind: SpectrumKind) -> Spectrum {
+        let mut spectrum = Spectrum {
+            kind,
+            scale: 1.0,
+            colour: Vec3::default(),
+        };
+        let xyz = integrate_xyz(|lambda| spectrum.value(lambda));
+        spectrum.scale = 1.0 / xyz.y;
+        spectrum.colour = ColourSpace::LinearSrgb.from_xyz(&xyz.div(xyz.y));
+        spectrum
+    }
+
+    pub fn value(&self, lambda: f64) -> f64 {
+        let raw = match self.kind {
+            SpectrumKind::Blackbody(kelvin) => planck(lambda, kelvin),
+            SpectrumKind::Daylight(m1, m2) => {
+                daylight_component(&DAYLIGHT_S0, lambda)
+                    + m1 * daylight_component(&DAYLIGHT_S1, lambda)
+                    + m2 * daylight_component(&DAYLIGHT_S2, lambda)
+            }
+        };
+        raw * self.scale
+    }
+
+    /// The linear sRGB colour of the spectrum, with a luminance of 1.
+    pub fn colour(&self) -> Vec3 {
+        self.colour.clone()
+    }
+}
+
+impl Dispersion {
+    /// Schott N-BK7, the usual optical crown glass.
+    pub fn bk7() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        }
+    }
+
+    /// Fused silica, which disperses light less than most glasses.
+    pub fn fused_silica() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [0.6961663, 0.4079426, 0.8974794],
+            c: [0.00467914826, 0.0135120631, 97.9340025],
+        }
+    }
+
+    /// Schott N-SF11, a dense flint glass that spreads colours out strongly.
+    pub fn dense_flint() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [1.73759695, 0.313747346, 1.89878101],
+            c: [0.013188707, 0.0623068142, 155.23629],
+        }
+    }
+
+    /// The index of refraction at a wavelength in nanometres.
+    pub fn ior(&self, lambda: f64) -> f64 {
+        let l2 = (lambda / 1000.0).powi(2);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let n2 = 1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>();
+                n2.max(1.0).sqrt()
+            }
+        }
+    }
+}
+
+/// The luminance, or Y, of a linear sRGB colour.
+pub fn luminance(c: &Vec3) -> f64 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+/// Everything tabulated once at every nanometre from LAMBDA_MIN to LAMBDA_MAX.
+struct Tables {
+    basis: Vec<Vec3>, // The spectra of pure red, green and blue reflectances.
+    d65: Vec<f64>, // Scaled to a luminance of 1.
+    y_integral: f64,
+}
+
+impl Tables {
+    fn basis_at(&self, lambda: f64) -> Vec3 {
+        let (i, t) = table_position(lambda);
+        let next = (i + 1).min(self.basis.len() - 1);
+        self.basis[i].mul(1.0 - t) + self.basis[next].mul(t)
+    }
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let wavelengths: Vec<f64> = (0..=(LAMBDA_MAX - LAMBDA_MIN) as usize).map(|i| LAMBDA_MIN + i as f64).collect();
+        let y_integral: f64 = wavelengths.iter().map(|l| cmf(*l).y).sum();
+        let d65 = Spectrum::d65();
+        let d65: Vec<f64> = wavelengths.iter().map(|l| d65.value(*l)).collect();
+
+        // Start from three overlapping bands that add up to 1 everywhere, then mix them so that
+        // each comes out as exactly one primary under D65.
+        let band = |lambda: f64| {
+            let blue = 1.0 - logistic((lambda - 490.0) / 12.0);
+            let red = logistic((lambda - 590.0) / 12.0);
+            Vec3::new(red, 1.0 - red - blue, blue)
+        };
+        let mut responses = [[0.0; 3]; 3];
+        for channel in 0..3 {
+            let xyz = integrate_xyz(|lambda| band(lambda)[channel] * lookup(&d65, lambda));
+            let rgb = ColourSpace::LinearSrgb.from_xyz(&xyz);
+            for (row, response) in responses.iter_mut().enumerate() {
+                response[channel] = rgb[row];
+            }
+        }
+        let mix = invert(&responses);
+        let basis = wavelengths.iter().map(|l| {
+            let b = band(*l);
+            Vec3::new(
+                b.x * mix[0][0] + b.y * mix[1][0] + b.z * mix[2][0],
+                b.x * mix[0][1] + b.y * mix[1][1] + b.z * mix[2][1],
+                b.x * mix[0][2] + b.y * mix[1][2] + b.z * mix[2][2],
+            )
+        }).collect();
+
+        Tables {
+            basis,
+            d65,
+            y_integral,
+        }
+    })
+}
+
+/// The index in the tables at or below a wavelength, and how far it is towards the next.
+fn table_position(lambda: f64) -> (usize, f64) {
+    let x = (lambda - LAMBDA_MIN).clamp(0.0, LAMBDA_MAX - LAMBDA_MIN);
+    (x.floor() as usize, x.fract())
+}
+
+fn lookup(table: &[f64], lambda: f64) -> f64 {
+    let (i, t) = table_position(lambda);
+    let next = (i + 1).min(table.len() - 1);
+    table[i] * (1.0 - t) + table[next] * t
+}
+
+/// The XYZ colour of a spectrum, where an equal-energy spectrum of 1 has a Y of 1.
+fn integrate_xyz(spectrum: impl Fn(f64) -> f64) -> Vec3 {
+    let mut xyz = Vec3::default();
+    let mut y_integral = 0.0;
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+        let c = cmf(lambda);
+        y_integral += c.y;
+        xyz = xyz + c.mul(spectrum(lambda));
+        lambda += 1.0;
+    }
+    xyz.div(y_integral)
+}
+
+/// The CIE 1931 colour matching functions, from the multi-lobe Gaussian fit by Wyman, Sloan and
+/// Shirley.
+fn cmf(lambda: f64) -> Vec3 {
+    let g = |mu: f64, below: f64, above: f64| {
+        let t = (lambda - mu) * if lambda < mu { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    Vec3::new(
+        1.056 * g(599.8, 0.0264, 0.0323) + 0.362 * g(442.0, 0.0624, 0.0374) - 0.065 * g(501.1, 0.0490, 0.0382),
+        0.821 * g(568.8, 0.0213, 0.0247) + 0.286 * g(530.9, 0.0613, 0.0322),
+        1.217 * g(437.0, 0.0845, 0.0278) + 0.681 * g(459.0, 0.0385, 0.0725),
+    )
+}
+
+/// Picks a wavelength with a chance roughly following how visible it is, from a random number
+/// from 0 to 1.
+fn sample_visible(u: f64) -> f64 {
+    538.0 - 138.888889 * (0.85691062 - 1.82750197 * u).atanh()
+}
+
+fn visible_pdf(lambda: f64) -> f64 {
+    if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        return 0.0;
+    }
+    0.0039398042 / (0.0072 * (lambda - 538.0)).cosh().powi(2)
+}
+
+/// Planck's law, for the spectral radiance of a black body.
+fn planck(lambda: f64, kelvin: f64) -> f64 {
+    const C: f64 = 299792458.0;
+    const H: f64 = 6.62607015e-34;
+    const K_B: f64 = 1.380649e-23;
+    let l = lambda * 1e-9;
+    2.0 * H * C * C / (l.powi(5) * ((H * C / (l * K_B * kelvin)).exp() - 1.0))
+}
+
+fn logistic(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// One of the components of CIE daylight, tabulated every 10nm from 380nm to 780nm and held
+/// constant beyond.
+fn daylight_component(table: &[f64; 41], lambda: f64) -> f64 {
+    let x = ((lambda - 380.0) / 10.0).clamp(0.0, 40.0);
+    let i = (x.floor() as usize).min(39);
+    let t = x - i as f64;
+    table[i] * (1.0 - t) + table[i + 1] * t
+}
+
+const DAYLIGHT_S0: [f64; 41] = [
+    63.4, 65.8, 94.8, 104.8, 105.9, 96.8, 113.9, 125.6, 125.5, 121.3, 121.3, 113.5, 113.1, 110.8,
+    106.5, 108.8, 105.3, 104.4, 100.0, 96.0, 95.1, 89.1, 90.5, 90.3, 88.4, 84.0, 85.1, 81.9, 82.6,
+    84.9, 81.3, 71.9, 74.3, 76.4, 63.3, 71.7, 77.0, 65.2, 47.7, 68.6, 65.0,
+];
+const DAYLIGHT_S1: [f64; 41] = [
+    38.5, 35.0, 43.4, 46.3, 43.9, 37.1, 36.7, 35.9, 32.6, 27.9, 24.3, 20.1, 16.2, 13.2, 8.6, 6.1,
+    4.2, 1.9, 0.0, -1.6, -3.5, -3.5, -5.8, -7.2, -8.6, -9.5, -10.9, -10.7, -12.0, -14.0, -13.6,
+    -12.0, -13.3, -12.9, -10.6, -11.6, -12.2, -10.2, -7.8, -11.2, -10.4,
+];
+const DAYLIGHT_S2: [f64; 41] = [
+    3.0, 1.2, -1.1, -0.5, -0.7, -1.2, -2.6, -2.9, -2.8, -2.6, -2.6, -1.8, -1.5, -1.3, -1.2, -1.0,
+    -0.5, -0.3, 0.0, 0.2, 0.5, 2.1, 3.2, 4.1, 4.7, 5.1, 6.7, 7.3, 8.6, 9.8, 10.2, 8.3, 9.6, 8.5,
+    7.0, 7.6, 8.0, 6.7, 5.2, 7.4, 6.8,
+];
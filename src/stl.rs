@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+
+use crate::{TriangleMesh, Vec3};
+
+// STL files, in ASCII or binary. STL stores every triangle with its own copy of each corner, so
+// corners in exactly the same place are merged back into shared vertices. The normals in the
+// file are left out, as the ones worked out from each triangle are at least as good.
+
+pub fn parse_stl(bytes: &[u8]) -> Result<TriangleMesh, String> {
+    // Plenty of binary files also start with "solid", so go by whether the size adds up.
+    let binary_size = if bytes.len() >= 84 {
+        Some(84 + 50 * u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize)
+    } else {
+        None
+    };
+
+    let triangles = if bytes.starts_with(b"solid") && binary_size != Some(bytes.len()) {
+        parse_ascii(bytes)?
+    } else {
+        parse_binary(bytes)?
+    };
+
+    let mut mesh = TriangleMesh::new(Vec::new(), Vec::new());
+    let mut seen: HashMap<[u64; 3], usize> = HashMap::new();
+    for triangle in triangles {
+        let mut face = [0usize; 3];
+        for (corner, p) in face.iter_mut().zip(triangle.iter()) {
+            let key = [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+            *corner = *seen.entry(key).or_insert_with(|| {
+                mesh.positions.push(p.clone());
+                mesh.positions.len() - 1
+            });
+        }
+        mesh.indices.push(face);
+    }
+
+    mesh.validate()?;
+    Ok(mesh)
+}
+
+fn parse_binary(bytes: &[u8]) -> Result<Vec<[Vec3; 3]>, String> {
+    if bytes.len() < 84 {
+        return Err(String::from("file is too short to be a binary STL"));
+    }
+    let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+    if bytes.len() < 84 + count * 50 {
+        return Err(String::from("file ends early"));
+    }
+
+    let read_f32 = |at: usize| f32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]) as f64;
+    let read_vec = |at: usize| Vec3::new(read_f32(at), read_f32(at + 4), read_f32(at + 8));
+
+    let mut triangles = Vec::with_capacity(count);
+    for i in 0..count {
+        // Each record is a normal, three corners and two bytes of attributes.
+        let at = 84 + i * 50;
+        triangles.push([read_vec(at + 12), read_vec(at + 24), read_vec(at + 36)]);
+    }
+    Ok(triangles)
+}
+
+fn parse_ascii(bytes: &[u8]) -> Result<Vec<[Vec3; 3]>, String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "ASCII STL is not text")?;
+    let mut tokens = text.split_ascii_whitespace();
+
+    let mut triangles = Vec::new();
+    let mut corners = Vec::with_capacity(3);
+    while let Some(token) = tokens.next() {
+        match token {
+            "vertex" => {
+                let mut p = [0.0; 3];
+                for v in p.iter_mut() {
+                    let token = tokens.next().ok_or("file ends early")?;
+                    *v = token.parse::<f64>().map_err(|_| format!("bad number {}", token))?;
+                }
+                corners.push(Vec3::new(p[0], p[1], p[2]));
+            }
+            "endloop" => {
+                if corners.len() != 3 {
+                    return Err(String::from("only triangular facets are supported"));
+                }
+                let c = std::mem::take(&mut corners);
+                triangles.push([c[0].clone(), c[1].clone(), c[2].clone()]);
+            }
+            _ => {}
+        }
+    }
+    Ok(triangles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A square as two triangles, which share the corners on its diagonal.
+    const TRIANGLES: [[[f32; 3]; 3]; 2] = [
+        [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+        [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+    ];
+
+    fn assert_square(mesh: &TriangleMesh) {
+        let positions: Vec<_> = mesh.positions.iter().map(|p| [p.x, p.y, p.z]).collect();
+        assert_eq!(positions, vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]]);
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+    }
+
+    #[test]
+    fn ascii() {
+        let mut text = String::from("solid square\n");
+        for triangle in TRIANGLES {
+            text += "facet normal 0 0 1\nouter loop\n";
+            for [x, y, z] in triangle {
+                text += &format!("vertex {} {} {}\n", x, y, z);
+            }
+            text += "endloop\nendfacet\n";
+        }
+        text += "endsolid square\n";
+        assert_square(&parse_stl(text.as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn binary() {
+        // Starting with "solid" as plenty of binary files do, which the size tells apart.
+        let mut bytes = b"solid but binary".to_vec();
+        bytes.resize(80, 0);
+        bytes.extend(2u32.to_le_bytes());
+        for triangle in TRIANGLES {
+            for v in [0.0f32, 0.0, 1.0].iter().chain(triangle.iter().flatten()) {
+                bytes.extend(v.to_le_bytes());
+            }
+            bytes.extend([0, 0]);
+        }
+        assert_square(&parse_stl(&bytes).unwrap());
+    }
+
+    #[test]
+    fn binary_ending_early() {
+        let mut bytes = vec![0; 80];
+        bytes.extend(u32::MAX.to_le_bytes());
+        assert_eq!(parse_stl(&bytes).err().as_deref(), Some("file ends early"));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::colour::srgb_to_linear;
+use crate::{ColourSpace, HitRecord, Vec3};
+
+/// A colour that can vary over a surface.
+pub trait Texture {
+    fn value(&self, rec: &HitRecord) -> Vec3;
+}
+
+pub struct SolidColour {
+    pub colour: Vec3,
+}
+
+/// How texture coordinates outside 0 to 1 land on an image.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+/// An image stretched over a surface's texture coordinates, with v running up the image, and
+/// filtered bilinearly.
+pub struct ImageTexture {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3>, // Linear colours, top row first.
+    pub wrap_u: Wrap,
+    pub wrap_v: Wrap,
+}
+
+/// The colour interpolated from the vertices of a mesh, for meshes loaded with vertex colours.
+/// Anything without them gets the fallback colour.
+pub struct VertexColour {
+    pub fallback: Vec3,
+}
+
+impl SolidColour {
+    pub fn new(colour: Vec3) -> SolidColour {
+        SolidColour {
+            colour,
+        }
+    }
+}
+
+impl Texture for SolidColour {
+    fn value(&self, _rec: &HitRecord) -> Vec3 {
+        self.colour.clone()
+    }
+}
+
+impl VertexColour {
+    pub fn new(fallback: Vec3) -> VertexColour {
+        VertexColour {
+            fallback,
+        }
+    }
+}
+
+impl Texture for VertexColour {
+    fn value(&self, rec: &HitRecord) -> Vec3 {
+        match &rec.colour {
+            Some(colour) => colour.clone(),
+            None => self.fallback.clone(),
+        }
+    }
+}
+
+impl ImageTexture {
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>) -> ImageTexture {
+        ImageTexture {
+            width,
+            height,
+            pixels,
+            wrap_u: Wrap::Repeat,
+            wrap_v: Wrap::Repeat,
+        }
+    }
+
+    /// Loads a PNG or JPEG image. Colour images are stored with the sRGB curve, which `srgb` undoes;
+    /// data such as roughness maps are stored linearly.
+    pub fn load(path: &str, srgb: bool) -> Result<ImageTexture, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("couldn't read image {}: {}", path, e))?;
+        ImageTexture::decode(&bytes, srgb).map_err(|e| format!("couldn't load image {}: {}", path, e))
+    }
+
+    pub fn decode(bytes: &[u8], srgb: bool) -> Result<ImageTexture, String> {
+        let image = image::load_from_memory(bytes).map_err(|e| e.to_string())?.to_rgb32f();
+        let decode = |c: f32| if srgb { srgb_to_linear(c as f64) } else { c as f64 };
+        let pixels = image.pixels().map(|p| Vec3::new(decode(p[0]), decode(p[1]), decode(p[2]))).collect();
+        Ok(ImageTexture::new(image.width() as usize, image.height() as usize, pixels))
+    }
+
+    /// Converts every pixel from one colour space to another, as when bringing an sRGB image
+    /// into the working space.
+    pub fn convert(&mut self, from: ColourSpace, to: ColourSpace) {
+        if from != to {
+            for pixel in self.pixels.iter_mut() {
+                *pixel = from.convert(pixel, to);
+            }
+        }
+    }
+
+    fn texel(&self, x: i64, y: i64) -> &Vec3 {
+        let x = wrap(x, self.width, self.wrap_u);
+        let y = wrap(y, self.height, self.wrap_v);
+        &self.pixels[y * self.width + x]
+    }
+}
+
+/// Brings a texel index that may be off the edge of the image back onto it.
+fn wrap(i: i64, size: usize, mode: Wrap) -> usize {
+    let n = size as i64;
+    (match mode {
+        Wrap::Repeat => i.rem_euclid(n),
+        Wrap::Clamp => i.max(0).min(n - 1),
+        Wrap::Mirror => {
+            let i = i.rem_euclid(2 * n);
+            if i < n { i } else { 2 * n - 1 - i }
+        }
+    }) as usize
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, rec: &HitRecord) -> Vec3 {
+        if self.pixels.is_empty() {
+            return Vec3::new(0.0, 1.0, 1.0); // Cyan, to stand out as missing.
+        }
+
+        // Texel centres sit half a texel in from the edges.
+        let x = rec.u * self.width as f64 - 0.5;
+        let y = (1.0 - rec.v) * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = self.texel(x0, y0).mul(1.0 - fx) + self.texel(x0 + 1, y0).mul(fx);
+        let bottom = self.texel(x0, y0 + 1).mul(1.0 - fx) + self.texel(x0 + 1, y0 + 1).mul(fx);
+        top.mul(1.0 - fy) + bottom.mul(fy)
+    }
+}
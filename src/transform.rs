@@ -0,0 +1,434 @@
+use std::rc::Rc;
+
+use crate::bvh::surrounding_box;
+use crate::{Aabb, HitRecord, Hittable, Interpolation, Keyframe, Ray, Span, Track, Vec3};
+
+/// A rotation, stored as a unit quaternion.
+#[derive(Clone)]
+pub struct Quaternion {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+/// A transform split into its translation, rotation and scale, applied in scale, rotate,
+/// translate order. Keeping the parts separate lets us interpolate between two transforms
+/// without the shearing you get from blending matrices.
+#[derive(Clone)]
+pub struct Trs {
+    pub translation: Vec3,
+    pub rotation: Quaternion,
+    pub scale: Vec3,
+}
+
+/// A transform matrix, along with its inverse.
+#[derive(Clone)]
+pub struct Transform {
+    pub m: [[f64; 4]; 4],
+    pub m_inv: [[f64; 4]; 4],
+}
+
+/// A transform that changes over time, with a track for each of its parts, so each can have
+/// keyframes of its own.
+#[derive(Clone)]
+pub struct AnimatedTransform {
+    pub translation: Track<Vec3>,
+    pub rotation: Track<Quaternion>,
+    pub scale: Track<Vec3>,
+}
+
+/// An object placed in the world by an animated transform.
+pub struct Instance {
+    pub object: Rc<dyn Hittable>,
+    pub transform: AnimatedTransform,
+}
+
+impl Quaternion {
+    pub fn identity() -> Quaternion {
+        Quaternion { w: 1.0, x: 0.0, y: 0.0, z: 0.0 }
+    }
+
+    /// A rotation of `angle` radians about `axis`.
+    pub fn from_axis_angle(axis: &Vec3, angle: f64) -> Quaternion {
+        let axis = axis.unit_vector();
+        let s = (angle * 0.5).sin();
+        Quaternion {
+            w: (angle * 0.5).cos(),
+            x: axis.x * s,
+            y: axis.y * s,
+            z: axis.z * s,
+        }
+    }
+
+    pub fn dot(&self, o: &Quaternion) -> f64 {
+        self.w * o.w + self.x * o.x + self.y * o.y + self.z * o.z
+    }
+
+    pub fn normalised(&self) -> Quaternion {
+        let len = self.dot(self).sqrt();
+        Quaternion { w: self.w / len, x: self.x / len, y: self.y / len, z: self.z / len }
+    }
+
+    /// Spherical linear interpolation, taking the shorter way around.
+    pub fn slerp(&self, o: &Quaternion, t: f64) -> Quaternion {
+        let mut cos_theta = self.dot(o);
+        let o = if cos_theta < 0.0 {
+            cos_theta = -cos_theta;
+            Quaternion { w: -o.w, x: -o.x, y: -o.y, z: -o.z }
+        } else {
+            o.clone()
+        };
+
+        // Nearly parallel, so a straight blend is accurate and avoids dividing by zero.
+        if cos_theta > 0.9995 {
+            return Quaternion {
+                w: self.w + (o.w - self.w) * t,
+                x: self.x + (o.x - self.x) * t,
+                y: self.y + (o.y - self.y) * t,
+                z: self.z + (o.z - self.z) * t,
+            }.normalised();
+        }
+
+        let theta = cos_theta.acos();
+        let a = ((1.0 - t) * theta).sin() / theta.sin();
+        let b = (t * theta).sin() / theta.sin();
+        Quaternion {
+            w: self.w * a + o.w * b,
+            x: self.x * a + o.x * b,
+            y: self.y * a + o.y * b,
+            z: self.z * a + o.z * b,
+        }
+    }
+
+    /// The rotation a 3x3 rotation matrix makes.
+    pub fn from_matrix(r: &[[f64; 3]; 3]) -> Quaternion {
+        // Work from the largest of the four components, to keep the division well conditioned.
+        let trace = r[0][0] + r[1][1] + r[2][2];
+        let q = if trace > 0.0 {
+            let s = 2.0 * (trace + 1.0).sqrt();
+            Quaternion { w: 0.25 * s, x: (r[2][1] - r[1][2]) / s, y: (r[0][2] - r[2][0]) / s, z: (r[1][0] - r[0][1]) / s }
+        } else if r[0][0] > r[1][1] && r[0][0] > r[2][2] {
+            let s = 2.0 * (1.0 + r[0][0] - r[1][1] - r[2][2]).sqrt();
+            Quaternion { w: (r[2][1] - r[1][2]) / s, x: 0.25 * s, y: (r[0][1] + r[1][0]) / s, z: (r[0][2] + r[2][0]) / s }
+        } else if r[1][1] > r[2][2] {
+            let s = 2.0 * (1.0 + r[1][1] - r[0][0] - r[2][2]).sqrt();
+            Quaternion { w: (r[0][2] - r[2][0]) / s, x: (r[0][1] + r[1][0]) / s, y: 0.25 * s, z: (r[1][2] + r[2][1]) / s }
+        } else {
+            let s = 2.0 * (1.0 + r[2][2] - r[0][0] - r[1][1]).sqrt();
+            Quaternion { w: (r[1][0] - r[0][1]) / s, x: (r[0][2] + r[2][0]) / s, y: (r[1][2] + r[2][1]) / s, z: 0.25 * s }
+        };
+        q.normalised()
+    }
+
+    /// The equivalent 3x3 rotation matrix.
+    pub fn to_matrix(&self) -> [[f64; 3]; 3] {
+        let (w, x, y, z) = (self.w, self.x, self.y, self.z);
+        [
+            [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - w * z), 2.0 * (x * z + w * y)],
+            [2.0 * (x * y + w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - w * x)],
+            [2.0 * (x * z - w * y), 2.0 * (y * z + w * x), 1.0 - 2.0 * (x * x + y * y)],
+        ]
+    }
+}
+
+impl Trs {
+    pub fn new(translation: Vec3, rotation: Quaternion, scale: Vec3) -> Trs {
+        Trs {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    pub fn translation(translation: Vec3) -> Trs {
+        Trs::new(translation, Quaternion::identity(), Vec3::from(1.0))
+    }
+
+    /// Splits an affine matrix back into its parts. Any shear is lost, and a mirroring is kept as
+    /// a negative scale along x.
+    pub fn from_matrix(m: &[[f64; 4]; 4]) -> Trs {
+        let column = |j: usize| Vec3::new(m[0][j], m[1][j], m[2][j]);
+        let mut scale = Vec3::new(column(0).length(), column(1).length(), column(2).length());
+        if column(0).dot(&column(1).cross(&column(2))) < 0.0 {
+            scale.x = -scale.x;
+        }
+
+        let s = [scale.x, scale.y, scale.z];
+        let mut r = [[0.0; 3]; 3];
+        for (i, row) in r.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = if s[j] != 0.0 { m[i][j] / s[j] } else if i == j { 1.0 } else { 0.0 };
+            }
+        }
+
+        Trs::new(column(3), Quaternion::from_matrix(&r), scale)
+    }
+
+    pub fn lerp(&self, o: &Trs, t: f64) -> Trs {
+        Trs {
+            translation: self.translation.mul(1.0 - t) + o.translation.mul(t),
+            rotation: self.rotation.slerp(&o.rotation, t),
+            scale: self.scale.mul(1.0 - t) + o.scale.mul(t),
+        }
+    }
+
+    pub fn to_transform(&self) -> Transform {
+        let r = self.rotation.normalised().to_matrix();
+        let s = [self.scale.x, self.scale.y, self.scale.z];
+        let t = [self.translation.x, self.translation.y, self.translation.z];
+
+        // M = T * R * S, and the inverse is S^-1 * R^T * T^-1.
+        let mut m = [[0.0; 4]; 4];
+        let mut m_inv = [[0.0; 4]; 4];
+        for i in 0..3 {
+            for j in 0..3 {
+                m[i][j] = r[i][j] * s[j];
+                m_inv[i][j] = r[j][i] / s[i];
+            }
+            m[i][3] = t[i];
+        }
+        for row in m_inv.iter_mut().take(3) {
+            row[3] = -(0..3).map(|j| row[j] * t[j]).sum::<f64>();
+        }
+        m[3][3] = 1.0;
+        m_inv[3][3] = 1.0;
+
+        Transform {
+            m,
+            m_inv,
+        }
+    }
+}
+
+impl Default for Trs {
+    fn default() -> Trs {
+        Trs::translation(Vec3::default())
+    }
+}
+
+impl Transform {
+    pub fn apply_point(&self, p: &Vec3) -> Vec3 {
+        mul_point(&self.m, p)
+    }
+
+    pub fn apply_vector(&self, v: &Vec3) -> Vec3 {
+        mul_vector(&self.m, v)
+    }
+
+    /// Normals are transformed by the inverse transpose, so they stay perpendicular to the
+    /// surface under non-uniform scaling.
+    pub fn apply_normal(&self, n: &Vec3) -> Vec3 {
+        let m = &self.m_inv;
+        Vec3::new(
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        )
+    }
+
+    /// The transform applying `inner` first and then this one.
+    pub fn compose(&self, inner: &Transform) -> Transform {
+        Transform {
+            m: mul_matrix(&self.m, &inner.m),
+            m_inv: mul_matrix(&inner.m_inv, &self.m_inv),
+        }
+    }
+
+    pub fn inverse_point(&self, p: &Vec3) -> Vec3 {
+        mul_point(&self.m_inv, p)
+    }
+
+    pub fn inverse_vector(&self, v: &Vec3) -> Vec3 {
+        mul_vector(&self.m_inv, v)
+    }
+
+    /// The box containing the transformed corners of another box.
+    pub fn apply_box(&self, bbox: &Aabb) -> Aabb {
+        let corners = bbox.corners();
+        let first = self.apply_point(&corners[0]);
+        let mut output = Aabb::new(first.clone(), first);
+        for c in corners.iter().skip(1) {
+            output = output.expand(&self.apply_point(c));
+        }
+        output
+    }
+}
+
+fn mul_matrix(a: &[[f64; 4]; 4], b: &[[f64; 4]; 4]) -> [[f64; 4]; 4] {
+    let mut m = [[0.0; 4]; 4];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+fn mul_point(m: &[[f64; 4]; 4], p: &Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+        m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+        m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+    )
+}
+
+fn mul_vector(m: &[[f64; 4]; 4], v: &Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    )
+}
+
+// The number of steps taken across the shutter interval when bounding a moving transform.
+const MOTION_BOUND_STEPS: u32 = 32;
+
+impl AnimatedTransform {
+    /// A transform that never moves.
+    pub fn fixed(trs: Trs) -> AnimatedTransform {
+        AnimatedTransform {
+            translation: Track::constant(trs.translation),
+            rotation: Track::constant(trs.rotation),
+            scale: Track::constant(trs.scale),
+        }
+    }
+
+    /// Moves linearly from `start` at `time0` to `end` at `time1`.
+    pub fn linear(time0: f64, start: Trs, time1: f64, end: Trs) -> AnimatedTransform {
+        AnimatedTransform::keyframed(vec![(time0, start), (time1, end)])
+    }
+
+    /// Moves through each keyframe in turn, interpolating linearly between them.
+    pub fn keyframed(mut keyframes: Vec<(f64, Trs)>) -> AnimatedTransform {
+        if keyframes.is_empty() {
+            return AnimatedTransform::fixed(Trs::default());
+        }
+        keyframes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let translation = keyframes.iter().map(|(time, trs)| Keyframe::new(*time, trs.translation.clone())).collect();
+        let rotation = keyframes.iter().map(|(time, trs)| Keyframe::new(*time, trs.rotation.clone())).collect();
+        let scale = keyframes.iter().map(|(time, trs)| Keyframe::new(*time, trs.scale.clone())).collect();
+        AnimatedTransform {
+            translation: Track { keyframes: translation, interpolation: Interpolation::Linear },
+            rotation: Track { keyframes: rotation, interpolation: Interpolation::Linear },
+            scale: Track { keyframes: scale, interpolation: Interpolation::Linear },
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.translation.is_animated() || self.rotation.is_animated() || self.scale.is_animated()
+    }
+
+    /// The interpolated transform at a given time.
+    pub fn at(&self, time: f64) -> Transform {
+        self.trs_at(time).to_transform()
+    }
+
+    pub fn trs_at(&self, time: f64) -> Trs {
+        Trs::new(self.translation.at(time), self.rotation.at(time), self.scale.at(time))
+    }
+
+    /// Bounds a box in object space over every position it takes between `time0` and `time1`.
+    pub fn motion_bounds(&self, bbox: &Aabb, time0: f64, time1: f64) -> Aabb {
+        if !self.is_animated() || time1 <= time0 {
+            return self.at(time0).apply_box(bbox);
+        }
+
+        // Rotations sweep corners along arcs, so sample the interval as well as the keyframes.
+        let mut output = self.at(time0).apply_box(bbox);
+        for i in 1..=MOTION_BOUND_STEPS {
+            let time = time0 + (time1 - time0) * i as f64 / MOTION_BOUND_STEPS as f64;
+            output = surrounding_box(&output, &self.at(time).apply_box(bbox));
+        }
+        let times = self.translation.keyframes.iter().map(|k| k.time)
+            .chain(self.rotation.keyframes.iter().map(|k| k.time))
+            .chain(self.scale.keyframes.iter().map(|k| k.time));
+        for time in times {
+            if time > time0 && time < time1 {
+                output = surrounding_box(&output, &self.at(time).apply_box(bbox));
+            }
+        }
+
+        // Pad for any bulge between the samples.
+        let pad = (output.maximum.clone() - output.minimum.clone()).mul(0.5 / MOTION_BOUND_STEPS as f64);
+        Aabb::new(output.minimum - pad.clone(), output.maximum + pad)
+    }
+}
+
+impl Instance {
+    pub fn new(object: Rc<dyn Hittable>, transform: AnimatedTransform) -> Instance {
+        Instance {
+            object,
+            transform,
+        }
+    }
+}
+
+impl Hittable for Instance {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        // Move the ray into the object's space at the moment the ray was cast. The direction is
+        // left unnormalised so that distances along the ray stay the same in both spaces.
+        let transform = self.transform.at(ray.time);
+        let local_ray = Ray::new(
+            transform.inverse_point(&ray.origin),
+            transform.inverse_vector(&ray.direction),
+            ray.time,
+        );
+
+        if !self.object.hit(&local_ray, t_min, t_max, rec) {
+            return false;
+        }
+
+        rec.point = transform.apply_point(&rec.point);
+        rec.normal = transform.apply_normal(&rec.normal).unit_vector();
+        rec.dpdu = transform.apply_vector(&rec.dpdu);
+        rec.dpdv = transform.apply_vector(&rec.dpdv);
+        true
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut Aabb) -> bool {
+        let mut local_box = Aabb::default();
+        if !self.object.bounding_box(time0, time1, &mut local_box) {
+            return false;
+        }
+        *output_box = self.transform.motion_bounds(&local_box, time0, time1);
+        true
+    }
+
+    fn is_solid(&self) -> bool {
+        self.object.is_solid()
+    }
+
+    fn spans(&self, ray: &Ray, spans: &mut Vec<Span>) -> bool {
+        let transform = self.transform.at(ray.time);
+        let local_ray = Ray::new(
+            transform.inverse_point(&ray.origin),
+            transform.inverse_vector(&ray.direction),
+            ray.time,
+        );
+
+        let start = spans.len();
+        if !self.object.spans(&local_ray, spans) {
+            return false;
+        }
+        for span in spans[start..].iter_mut() {
+            for rec in [&mut span.enter, &mut span.exit] {
+                rec.point = transform.apply_point(&rec.point);
+                rec.normal = transform.apply_normal(&rec.normal).unit_vector();
+                rec.dpdu = transform.apply_vector(&rec.dpdu);
+                rec.dpdv = transform.apply_vector(&rec.dpdv);
+            }
+        }
+        true
+    }
+
+    fn transmittance(&self, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let transform = self.transform.at(ray.time);
+        let local_ray = Ray::new(
+            transform.inverse_point(&ray.origin),
+            transform.inverse_vector(&ray.direction),
+            ray.time,
+        );
+        self.object.transmittance(&local_ray, t_min, t_max)
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+// Videos of rendered frames, for looking over an animation without reaching for other tools.
+// Frames come in as 8-bit ARGB encoded for an sRGB display, as encode_display gives them.
+//
+// YUV4MPEG2 is uncompressed: a line of text saying the size and frame rate, then each frame's
+// luma and chroma planes. Chroma is averaged over 2×2 blocks (4:2:0), and colours are converted
+// with the BT.601 matrix to the limited range, which is what players assume for Y4M.
+//
+// GIFs hold at most 256 colours in a frame, so each frame gets a palette of its own by median cut
+// over a histogram of its colours. Pixels are then matched to the palette with Floyd–Steinberg
+// dithering, which spreads each pixel's error over the pixels to its right and below, so smooth
+// gradients like the sky don't band. The indices are packed with GIF's variable-width LZW.
+
+/// Somewhere frames of video can be written one after another.
+pub trait VideoWriter {
+    /// Adds a frame of ARGB pixels, top row first.
+    fn write_frame(&mut self, argb: &[u8]) -> Result<(), String>;
+
+    /// Finishes off the file once every frame is written.
+    fn finish(&mut self) -> Result<(), String>;
+}
+
+/// Writes uncompressed YUV4MPEG2 video.
+pub struct Y4mWriter<W: Write> {
+    out: W,
+    width: u32,
+    height: u32,
+}
+
+/// Writes an animated GIF that loops forever.
+pub struct GifWriter<W: Write> {
+    out: W,
+    width: u32,
+    height: u32,
+    delay: u16, // How long each frame is shown, in hundredths of a second.
+    pub dither: bool,
+}
+
+const PALETTE_SIZE: usize = 256;
+
+// The histogram for the palette groups colours by their top five bits of each channel.
+const HISTOGRAM_BITS: u32 = 5;
+
+// The largest code GIF's LZW can use is 12 bits wide.
+const MAX_CODES: u16 = 4096;
+
+/// Opens a video for writing, as a GIF if the path ends in .gif, or Y4M otherwise.
+pub fn create_video(path: &str, width: u32, height: u32, fps: f64) -> Result<Box<dyn VideoWriter>, String> {
+    let file = File::create(path).map_err(|e| format!("couldn't create video {}: {}", path, e))?;
+    let out = BufWriter::new(file);
+    if path.to_ascii_lowercase().ends_with(".gif") {
+        Ok(Box::new(GifWriter::new(out, width, height, fps)?))
+    } else {
+        Ok(Box::new(Y4mWriter::new(out, width, height, fps)?))
+    }
+}
+
+fn check_frame(argb: &[u8], width: u32, height: u32) -> Result<(), String> {
+    if argb.len() != width as usize * height as usize * 4 {
+        return Err(String::from("the frame isn't the size of the video"));
+    }
+    Ok(())
+}
+
+fn write_error(e: std::io::Error) -> String {
+    format!("couldn't write video: {}", e)
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Starts a video, writing its header.
+    pub fn new(mut out: W, width: u32, height: u32, fps: f64) -> Result<Y4mWriter<W>, String> {
+        if width == 0 || height == 0 {
+            return Err(String::from("a video needs at least one pixel"));
+        }
+        // The frame rate is a ratio of whole numbers, so keep it to a thousandth of a frame.
+        let (mut numerator, mut denominator) = ((fps * 1000.0).round().max(1.0) as u64, 1000);
+        let divisor = gcd(numerator, denominator);
+        numerator /= divisor;
+        denominator /= divisor;
+        let header = format!("YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C420jpeg\n", width, height, numerator, denominator);
+        out.write_all(header.as_bytes()).map_err(write_error)?;
+        Ok(Y4mWriter { out, width, height })
+    }
+}
+
+impl<W: Write> VideoWriter for Y4mWriter<W> {
+    fn write_frame(&mut self, argb: &[u8]) -> Result<(), String> {
+        check_frame(argb, self.width, self.height)?;
+        let (width, height) = (self.width as usize, self.height as usize);
+        let rgb = |x: usize, y: usize| {
+            let p = &argb[(y * width + x) * 4..];
+            [p[1] as f64 / 255.0, p[2] as f64 / 255.0, p[3] as f64 / 255.0]
+        };
+
+        let mut frame = Vec::with_capacity(width * height * 3 / 2 + 6);
+        frame.extend(b"FRAME\n");
+        for y in 0..height {
+            for x in 0..width {
+                let [r, g, b] = rgb(x, y);
+                frame.push(to_byte(16.0 + 65.481 * r + 128.553 * g + 24.966 * b));
+            }
+        }
+
+        // The conversion is linear, so averaging the colours of a block averages its chroma.
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        let mut blocks = Vec::with_capacity(chroma_width * chroma_height);
+        for cy in 0..chroma_height {
+            for cx in 0..chroma_width {
+                let (mut total, mut count) = ([0.0; 3], 0.0);
+                for y in cy * 2..(cy * 2 + 2).min(height) {
+                    for x in cx * 2..(cx * 2 + 2).min(width) {
+                        for (t, c) in total.iter_mut().zip(rgb(x, y)) {
+                            *t += c;
+                        }
+                        count += 1.0;
+                    }
+                }
+                blocks.push([total[0] / count, total[1] / count, total[2] / count]);
+            }
+        }
+        frame.extend(blocks.iter().map(|[r, g, b]| to_byte(128.0 - 37.797 * r - 74.203 * g + 112.0 * b)));
+        frame.extend(blocks.iter().map(|[r, g, b]| to_byte(128.0 + 112.0 * r - 93.786 * g - 18.214 * b)));
+        self.out.write_all(&frame).map_err(write_error)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.out.flush().map_err(write_error)
+    }
+}
+
+impl<W: Write> GifWriter<W> {
+    /// Starts a GIF, writing its header. GIFs time frames in hundredths of a second, and many
+    /// viewers slow down anything quicker than two, so the frame rate is rounded to fit.
+    pub fn new(mut out: W, width: u32, height: u32, fps: f64) -> Result<GifWriter<W>, String> {
+        if width == 0 || height == 0 || width > u16::MAX as u32 || height > u16::MAX as u32 {
+            return Err(String::from("GIFs must be between 1 and 65535 pixels on a side"));
+        }
+        let delay = (100.0 / fps).round().clamp(2.0, u16::MAX as f64) as u16;
+
+        let mut header = Vec::new();
+        header.extend(b"GIF89a");
+        header.extend((width as u16).to_le_bytes());
+        header.extend((height as u16).to_le_bytes());
+        header.extend([0, 0, 0]); // No global palette, as every frame has its own.
+        // The NETSCAPE2.0 extension, looping forever.
+        header.extend([0x21, 0xff, 11]);
+        header.extend(b"NETSCAPE2.0");
+        header.extend([3, 1, 0, 0, 0]);
+        out.write_all(&header).map_err(write_error)?;
+
+        Ok(GifWriter {
+            out,
+            width,
+            height,
+            delay,
+            dither: true,
+        })
+    }
+}
+
+impl<W: Write> VideoWriter for GifWriter<W> {
+    fn write_frame(&mut self, argb: &[u8]) -> Result<(), String> {
+        check_frame(argb, self.width, self.height)?;
+        let pixels: Vec<[u8; 3]> = argb.chunks(4).map(|p| [p[1], p[2], p[3]]).collect();
+        let palette = median_cut(&pixels);
+        let indices = if self.dither {
+            dither(&pixels, self.width as usize, &palette)
+        } else {
+            pixels.iter().map(|p| nearest(&palette, [p[0] as f64, p[1] as f64, p[2] as f64])).collect()
+        };
+
+        let mut frame = Vec::new();
+        // Graphic control: each frame replaces the last, and is shown for the delay.
+        frame.extend([0x21, 0xf9, 4, 0x04]);
+        frame.extend(self.delay.to_le_bytes());
+        frame.extend([0, 0]);
+        // The image covers the whole screen, with a local palette of 256 colours.
+        frame.push(0x2c);
+        frame.extend([0, 0, 0, 0]);
+        frame.extend((self.width as u16).to_le_bytes());
+        frame.extend((self.height as u16).to_le_bytes());
+        frame.push(0x80 | 7);
+        for i in 0..PALETTE_SIZE {
+            frame.extend(palette.get(i).unwrap_or(&[0, 0, 0]));
+        }
+        frame.push(8); // The smallest code size, for 8-bit indices.
+        for block in lzw(&indices).chunks(255) {
+            frame.push(block.len() as u8);
+            frame.extend(block);
+        }
+        frame.push(0);
+        self.out.write_all(&frame).map_err(write_error)
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        self.out.write_all(&[0x3b]).and_then(|_| self.out.flush()).map_err(write_error)
+    }
+}
+
+fn to_byte(v: f64) -> u8 {
+    v.round().clamp(0.0, 255.0) as u8
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// One bucket of the palette histogram.
+#[derive(Clone, Copy)]
+struct Bin {
+    key: [u8; 3], // The colour with its low bits dropped.
+    count: u64,
+    sum: [u64; 3],
+}
+
+/// A palette of up to 256 colours for the pixels. The box of colours with the most pixels in it
+/// is split in two along its longest side, at the median pixel, until there are enough boxes,
+/// and each box's colour is the average of the pixels in it.
+fn median_cut(pixels: &[[u8; 3]]) -> Vec<[u8; 3]> {
+    let shift = 8 - HISTOGRAM_BITS;
+    let mut histogram: HashMap<[u8; 3], Bin> = HashMap::new();
+    for p in pixels {
+        let key = [p[0] >> shift, p[1] >> shift, p[2] >> shift];
+        let bin = histogram.entry(key).or_insert(Bin { key, count: 0, sum: [0; 3] });
+        bin.count += 1;
+        for (s, c) in bin.sum.iter_mut().zip(p) {
+            *s += *c as u64;
+        }
+    }
+
+    let mut boxes = vec![histogram.into_values().collect::<Vec<Bin>>()];
+    while boxes.len() < PALETTE_SIZE {
+        let splittable = boxes.iter().enumerate()
+            .filter(|(_, bins)| bins.len() > 1)
+            .max_by_key(|(_, bins)| bins.iter().map(|b| b.count).sum::<u64>());
+        let index = match splittable {
+            Some((index, _)) => index,
+            None => break,
+        };
+        let mut bins = boxes.swap_remove(index);
+        let channel = (0..3).max_by_key(|&c| {
+            let (low, high) = bins.iter().fold((u8::MAX, 0), |(low, high), b| (low.min(b.key[c]), high.max(b.key[c])));
+            high - low
+        }).unwrap_or(0);
+        bins.sort_by_key(|b| b.key[channel]);
+
+        let total: u64 = bins.iter().map(|b| b.count).sum();
+        let mut seen = 0;
+        let mut split = 1;
+        for (i, b) in bins.iter().enumerate() {
+            seen += b.count;
+            if seen * 2 >= total {
+                split = (i + 1).clamp(1, bins.len() - 1);
+                break;
+            }
+        }
+        let upper = bins.split_off(split);
+        boxes.push(bins);
+        boxes.push(upper);
+    }
+
+    boxes.iter().filter(|bins| !bins.is_empty()).map(|bins| {
+        let count: u64 = bins.iter().map(|b| b.count).sum();
+        let mean = |c: usize| ((bins.iter().map(|b| b.sum[c]).sum::<u64>() + count / 2) / count) as u8;
+        [mean(0), mean(1), mean(2)]
+    }).collect()
+}
+
+/// The palette entry closest to a colour.
+fn nearest(palette: &[[u8; 3]], colour: [f64; 3]) -> u8 {
+    let mut best = (f64::INFINITY, 0);
+    for (i, p) in palette.iter().enumerate() {
+        let d: f64 = p.iter().zip(colour).map(|(p, c)| (*p as f64 - c) * (*p as f64 - c)).sum();
+        if d < best.0 {
+            best = (d, i);
+        }
+    }
+    best.1 as u8
+}
+
+/// Matches each pixel to the palette, carrying the error on to its neighbours with the
+/// Floyd–Steinberg weights.
+fn dither(pixels: &[[u8; 3]], width: usize, palette: &[[u8; 3]]) -> Vec<u8> {
+    let mut colours: Vec<[f64; 3]> = pixels.iter().map(|p| [p[0] as f64, p[1] as f64, p[2] as f64]).collect();
+    let height = pixels.len() / width;
+    let mut indices = Vec::with_capacity(pixels.len());
+    for y in 0..height {
+        for x in 0..width {
+            let colour = colours[y * width + x].map(|c| c.clamp(0.0, 255.0));
+            let index = nearest(palette, colour);
+            indices.push(index);
+
+            let chosen = palette[index as usize];
+            let error = [0, 1, 2].map(|c| colour[c] - chosen[c] as f64);
+            let mut spread = |dx: isize, dy: usize, weight: f64| {
+                let nx = x as isize + dx;
+                if nx < 0 || nx >= width as isize || y + dy >= height {
+                    return;
+                }
+                let neighbour = &mut colours[(y + dy) * width + nx as usize];
+                for c in 0..3 {
+                    neighbour[c] += error[c] * weight;
+                }
+            };
+            spread(1, 0, 7.0 / 16.0);
+            spread(-1, 1, 3.0 / 16.0);
+            spread(0, 1, 5.0 / 16.0);
+            spread(1, 1, 1.0 / 16.0);
+        }
+    }
+    indices
+}
+
+/// Compresses 8-bit palette indices with GIF's LZW, starting with 9-bit codes and widening them
+/// as the dictionary grows, and starting the dictionary again once it's full.
+fn lzw(indices: &[u8]) -> Vec<u8> {
+    const CLEAR: u16 = 256;
+    const END: u16 = 257;
+    let mut out = BitWriter::default();
+    let mut dictionary: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = END + 1;
+    let mut size = 9;
+
+    out.write(CLEAR, size);
+    let mut codes = indices.iter();
+    let mut prefix = match codes.next() {
+        Some(first) => *first as u16,
+        None => {
+            out.write(END, size);
+            return out.finish();
+        }
+    };
+    for &index in codes {
+        if let Some(&code) = dictionary.get(&(prefix, index)) {
+            prefix = code;
+            continue;
+        }
+        out.write(prefix, size);
+        if next < MAX_CODES {
+            dictionary.insert((prefix, index), next);
+            next += 1;
+            // The decoder adds each code a step behind, so widen once it will need to.
+            if next > 1 << size && size < 12 {
+                size += 1;
+            }
+        } else {
+            out.write(CLEAR, size);
+            dictionary.clear();
+            next = END + 1;
+            size = 9;
+        }
+        prefix = index as u16;
+    }
+    out.write(prefix, size);
+    // The decoder adds a code for that last one too, which may widen the end code.
+    if next < MAX_CODES && next + 1 > 1 << size && size < 12 {
+        size += 1;
+    }
+    out.write(END, size);
+    out.finish()
+}
+
+/// Packs codes into bytes, least significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u32) {
+        self.buffer |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unpacks GIF's LZW the way a viewer would, adding a code to the dictionary for each one read
+    /// and widening the codes as soon as the dictionary fills their width.
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>, String> {
+        const CLEAR: usize = 256;
+        const END: usize = 257;
+        let roots = || (0..=255).map(|i| vec![i]).chain([vec![], vec![]]).collect::<Vec<Vec<u8>>>();
+        let mut dictionary = roots();
+        let mut previous: Option<Vec<u8>> = None;
+        let mut size = 9;
+        let (mut at, mut out) = (0, Vec::new());
+        loop {
+            if at + size > bytes.len() * 8 {
+                return Err(String::from("no end code"));
+            }
+            // Codes are packed least significant bit first.
+            let bit = |i: usize| (bytes[i / 8] >> (i % 8) & 1) as usize;
+            let code = (at..at + size).rev().fold(0, |code, i| code << 1 | bit(i));
+            at += size;
+            if code == CLEAR {
+                dictionary = roots();
+                previous = None;
+                size = 9;
+                continue;
+            }
+            if code == END {
+                return Ok(out);
+            }
+            let entry = match (dictionary.get(code), &previous) {
+                (Some(entry), _) => entry.clone(),
+                // The code the encoder has only just added, which is the last entry and its start.
+                (None, Some(previous)) if code == dictionary.len() => [previous.as_slice(), &previous[..1]].concat(),
+                _ => return Err(format!("code {} isn't in the dictionary", code)),
+            };
+            out.extend(&entry);
+            if let Some(previous) = previous {
+                if dictionary.len() < MAX_CODES as usize {
+                    dictionary.push([previous.as_slice(), &entry[..1]].concat());
+                }
+            }
+            if dictionary.len() == 1 << size && size < 12 {
+                size += 1;
+            }
+            previous = Some(entry);
+        }
+    }
+
+    fn assert_round_trip(indices: &[u8]) {
+        assert_eq!(decode(&lzw(indices)).as_deref(), Ok(indices));
+    }
+
+    #[test]
+    fn short() {
+        assert_round_trip(&[]);
+        assert_round_trip(&[7]);
+        assert_round_trip(&[7, 7]);
+        assert_round_trip(&[0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn repeats() {
+        // Runs of one index give the codes the encoder has only just added.
+        assert_round_trip(&[42; 10000]);
+        assert_round_trip(&[1, 2].repeat(5000));
+    }
+
+    #[test]
+    fn long_patterns() {
+        // A pattern that takes longer and longer codes, well past where the dictionary fills.
+        let indices: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 13) as u8).collect();
+        for length in (0..indices.len()).step_by(97) {
+            assert_round_trip(&indices[..length]);
+        }
+    }
+
+    #[test]
+    fn noise() {
+        // Hardly anything repeats, so the dictionary fills and is cleared many times over.
+        let mut state = 1u32;
+        let indices: Vec<u8> = (0..100000).map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 24) as u8
+        }).collect();
+        assert_round_trip(&indices);
+        // Noise takes a code for nearly every index, so stopping every few indices ends the stream
+        // either side of each point where the codes widen and where the dictionary starts again.
+        for length in (0..4200).step_by(11) {
+            assert_round_trip(&indices[..length]);
+        }
+    }
+}
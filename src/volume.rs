@@ -0,0 +1,456 @@
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use crate::math::INFINITY;
+use crate::{Aabb, HitRecord, Hittable, Material, Ray, Vec3, VoxelGrid};
+
+/// The Henyey-Greenstein phase function. `g` runs from -1 (everything scatters straight back)
+/// through 0 (isotropic) to 1 (everything carries straight on).
+#[derive(Clone)]
+pub struct HenyeyGreenstein {
+    pub g: f64,
+}
+
+/// Scatters light inside a medium according to a phase function.
+pub struct PhaseMaterial {
+    pub albedo: Vec3,
+    pub phase: HenyeyGreenstein,
+}
+
+/// A medium of constant density filling the inside of a closed boundary.
+pub struct ConstantMedium {
+    pub boundary: Rc<dyn Hittable>,
+    pub phase_function: Rc<dyn Material>,
+    neg_inv_density: f64,
+}
+
+/// A medium whose density varies over a regular grid of voxels filling a box. Scattering is
+/// sampled with delta tracking against a coarser grid of majorants, so that thin or empty regions
+/// are crossed in a few large steps. Shadow rays are dimmed by ratio tracking against the same grid.
+pub struct GridMedium {
+    pub bounds: Aabb,
+    pub resolution: (usize, usize, usize),
+    pub density: Vec<f64>, // Indexed by x first, then y, then z.
+    pub density_scale: f64,
+    pub phase_function: Rc<dyn Material>,
+    majorants: MajorantGrid,
+}
+
+/// The highest density found in each block of a grid medium's voxels.
+struct MajorantGrid {
+    resolution: (usize, usize, usize),
+    values: Vec<f64>,
+}
+
+// How many voxels along each axis share a majorant.
+const MAJORANT_BLOCK: usize = 8;
+
+/// Fog filling the whole world, thinning out exponentially with height. A `falloff` of 0 gives
+/// fog of even density everywhere.
+pub struct Fog {
+    pub density: f64,
+    pub falloff: f64,
+    pub base_height: f64, // The height at which the fog has its full density.
+    pub albedo: Vec3,
+    pub phase: HenyeyGreenstein,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f64) -> HenyeyGreenstein {
+        HenyeyGreenstein {
+            g,
+        }
+    }
+
+    /// The probability density of scattering by an angle whose cosine is `cos_theta`.
+    pub fn p(&self, cos_theta: f64) -> f64 {
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+    }
+
+    /// Samples a new direction for light travelling along `direction`.
+    pub fn sample(&self, direction: &Vec3) -> Vec3 {
+        let g = self.g;
+        let u = fastrand::f64();
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u
+        } else {
+            let sq = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+            (1.0 + g * g - sq * sq) / (2.0 * g)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * fastrand::f64();
+
+        let w = direction.unit_vector();
+        let (s, t) = w.orthonormal_basis();
+        s.mul(sin_theta * phi.cos()) + t.mul(sin_theta * phi.sin()) + w.mul(cos_theta)
+    }
+}
+
+impl PhaseMaterial {
+    pub fn new(albedo: Vec3, g: f64) -> PhaseMaterial {
+        PhaseMaterial {
+            albedo,
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+}
+
+impl Material for PhaseMaterial {
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, attenuation: &mut Vec3, scattered: &mut Ray) -> bool {
+        // The phase function is sampled exactly, so the albedo is all that is left of the weight.
+        *scattered = Ray::new(rec.point.clone(), self.phase.sample(&ray_in.direction), ray_in.time);
+        *attenuation = self.albedo.clone();
+        true
+    }
+
+    fn eval(&self, ray_in: &Ray, _rec: &HitRecord, direction: &Vec3) -> Vec3 {
+        let cos_theta = ray_in.direction.unit_vector().dot(&direction.unit_vector());
+        self.albedo.mul(self.phase.p(cos_theta))
+    }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        self.albedo.clone()
+    }
+}
+
+/// Fills in a hit record for a scattering event inside a medium, which has no real surface.
+fn medium_hit(ray: &Ray, t: f64, material: &Rc<dyn Material>, rec: &mut HitRecord) {
+    rec.t = t;
+    rec.point = ray.at(t);
+    rec.normal = Vec3::new(1.0, 0.0, 0.0); // Arbitrary
+    rec.front_face = true; // Also arbitrary
+    rec.material = Some(material.clone());
+}
+
+impl ConstantMedium {
+    pub fn new(boundary: Rc<dyn Hittable>, density: f64, albedo: Vec3, g: f64) -> ConstantMedium {
+        ConstantMedium {
+            boundary,
+            phase_function: Rc::new(PhaseMaterial::new(albedo, g)),
+            neg_inv_density: -1.0 / density,
+        }
+    }
+
+    /// The part of the ray between `t_min` and `t_max` that lies inside the boundary.
+    fn inside(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64)> {
+        let mut rec1 = HitRecord::default();
+        let mut rec2 = HitRecord::default();
+
+        if !self.boundary.hit(ray, -INFINITY, INFINITY, &mut rec1) {
+            return None;
+        }
+        if !self.boundary.hit(ray, rec1.t + 0.0001, INFINITY, &mut rec2) {
+            return None;
+        }
+
+        let t0 = rec1.t.max(t_min).max(0.0);
+        let t1 = rec2.t.min(t_max);
+        if t0 >= t1 {
+            return None;
+        }
+        Some((t0, t1))
+    }
+}
+
+impl Hittable for ConstantMedium {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let (t0, t1) = match self.inside(ray, t_min, t_max) {
+            Some(interval) => interval,
+            None => return false,
+        };
+
+        let ray_length = ray.direction.length();
+        let distance_inside_boundary = (t1 - t0) * ray_length;
+        let hit_distance = self.neg_inv_density * (1.0 - fastrand::f64()).ln();
+
+        if hit_distance > distance_inside_boundary {
+            return false;
+        }
+
+        medium_hit(ray, t0 + hit_distance / ray_length, &self.phase_function, rec);
+        true
+    }
+
+    fn bounding_box(&self, time0: f64, time1: f64, output_box: &mut Aabb) -> bool {
+        self.boundary.bounding_box(time0, time1, output_box)
+    }
+
+    /// The fraction of light passing straight through the medium between `t_min` and `t_max`.
+    fn transmittance(&self, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        match self.inside(ray, t_min, t_max) {
+            Some((t0, t1)) => ((t1 - t0) * ray.direction.length() / self.neg_inv_density).exp(),
+            None => 1.0,
+        }
+    }
+}
+
+impl GridMedium {
+    pub fn new(bounds: Aabb,
+               resolution: (usize, usize, usize),
+               density: Vec<f64>,
+               density_scale: f64,
+               albedo: Vec3,
+               g: f64
+    ) -> GridMedium {
+        let majorants = MajorantGrid::new(resolution, &density, density_scale);
+        GridMedium {
+            bounds,
+            resolution,
+            density,
+            density_scale,
+            phase_function: Rc::new(PhaseMaterial::new(albedo, g)),
+            majorants,
+        }
+    }
+
+    /// Makes a medium from a voxel grid loaded from a file.
+    pub fn from_voxels(grid: VoxelGrid, density_scale: f64, albedo: Vec3, g: f64) -> GridMedium {
+        GridMedium::new(grid.bounds, grid.resolution, grid.density, density_scale, albedo, g)
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f64 {
+        let (nx, ny, _) = self.resolution;
+        self.density[(z * ny + y) * nx + x]
+    }
+
+    /// The scaled density at a point, blended between the nearest voxel centres.
+    pub fn density_at(&self, p: &Vec3) -> f64 {
+        let (nx, ny, nz) = self.resolution;
+        let size = self.bounds.maximum.clone() - self.bounds.minimum.clone();
+        let local = p.clone() - self.bounds.minimum.clone();
+
+        let mut index = [0usize; 3];
+        let mut next = [0usize; 3];
+        let mut frac = [0.0; 3];
+        for (axis, n) in [nx, ny, nz].iter().enumerate() {
+            let g = (local[axis] / size[axis] * *n as f64 - 0.5).max(0.0).min(*n as f64 - 1.0);
+            index[axis] = g.floor() as usize;
+            next[axis] = (index[axis] + 1).min(n - 1);
+            frac[axis] = g - g.floor();
+        }
+
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let d = |x: usize, y: usize, z: usize| self.voxel(x, y, z);
+        let (x0, y0, z0) = (index[0], index[1], index[2]);
+        let (x1, y1, z1) = (next[0], next[1], next[2]);
+
+        let c00 = lerp(d(x0, y0, z0), d(x1, y0, z0), frac[0]);
+        let c10 = lerp(d(x0, y1, z0), d(x1, y1, z0), frac[0]);
+        let c01 = lerp(d(x0, y0, z1), d(x1, y0, z1), frac[0]);
+        let c11 = lerp(d(x0, y1, z1), d(x1, y1, z1), frac[0]);
+        let c0 = lerp(c00, c10, frac[1]);
+        let c1 = lerp(c01, c11, frac[1]);
+        lerp(c0, c1, frac[2]) * self.density_scale
+    }
+
+    /// Walks the majorant cells the ray passes through between `t0` and `t1`, handing each
+    /// stretch of the ray and its majorant to `visit` until it returns false.
+    fn walk_majorants<F: FnMut(f64, f64, f64) -> bool>(&self, ray: &Ray, t0: f64, t1: f64, mut visit: F) {
+        let (mx, my, mz) = self.majorants.resolution;
+        let res = [mx, my, mz];
+        let size = self.bounds.maximum.clone() - self.bounds.minimum.clone();
+        let entry = ray.at(t0) - self.bounds.minimum.clone();
+
+        let mut cell = [0usize; 3];
+        let mut step = [0i64; 3];
+        let mut next_t = [INFINITY; 3];
+        let mut delta_t = [INFINITY; 3];
+        for axis in 0..3 {
+            let cell_size = size[axis] / res[axis] as f64;
+            let c = ((entry[axis] / cell_size).floor().max(0.0) as usize).min(res[axis] - 1);
+            cell[axis] = c;
+
+            let d = ray.direction[axis];
+            if d > 0.0 {
+                step[axis] = 1;
+                delta_t[axis] = cell_size / d;
+                next_t[axis] = t0 + ((c + 1) as f64 * cell_size - entry[axis]) / d;
+            } else if d < 0.0 {
+                step[axis] = -1;
+                delta_t[axis] = -cell_size / d;
+                next_t[axis] = t0 + (c as f64 * cell_size - entry[axis]) / d;
+            }
+        }
+
+        let mut t = t0;
+        loop {
+            let axis = if next_t[0] < next_t[1] && next_t[0] < next_t[2] {
+                0
+            } else if next_t[1] < next_t[2] {
+                1
+            } else {
+                2
+            };
+            let exit = next_t[axis].min(t1);
+
+            if !visit(t, exit, self.majorants.get(cell[0], cell[1], cell[2])) || exit >= t1 {
+                return;
+            }
+
+            let moved = cell[axis] as i64 + step[axis];
+            if moved < 0 || moved >= res[axis] as i64 {
+                return;
+            }
+            cell[axis] = moved as usize;
+            t = exit;
+            next_t[axis] += delta_t[axis];
+        }
+    }
+}
+
+impl MajorantGrid {
+    fn new(resolution: (usize, usize, usize), density: &[f64], density_scale: f64) -> MajorantGrid {
+        let (nx, ny, nz) = resolution;
+        let blocks = |n: usize| n.div_ceil(MAJORANT_BLOCK).max(1);
+        let (mx, my, mz) = (blocks(nx), blocks(ny), blocks(nz));
+
+        // Blending reaches one voxel past the edge of each block, so the maximum has to as well.
+        let span = |cell: usize, n: usize| {
+            let lo = (cell * n / blocks(n)).saturating_sub(1);
+            let hi = (((cell + 1) * n).div_ceil(blocks(n)) + 1).min(n);
+            lo..hi
+        };
+
+        let mut values = Vec::with_capacity(mx * my * mz);
+        for cz in 0..mz {
+            for cy in 0..my {
+                for cx in 0..mx {
+                    let mut max: f64 = 0.0;
+                    for z in span(cz, nz) {
+                        for y in span(cy, ny) {
+                            for x in span(cx, nx) {
+                                max = max.max(density[(z * ny + y) * nx + x]);
+                            }
+                        }
+                    }
+                    values.push(max * density_scale);
+                }
+            }
+        }
+
+        MajorantGrid {
+            resolution: (mx, my, mz),
+            values,
+        }
+    }
+
+    fn get(&self, x: usize, y: usize, z: usize) -> f64 {
+        let (mx, my, _) = self.resolution;
+        self.values[(z * my + y) * mx + x]
+    }
+}
+
+impl Hittable for GridMedium {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+        let (t0, t1) = match self.bounds.intersect(ray, t_min, t_max) {
+            Some(interval) => interval,
+            None => return false,
+        };
+
+        // Delta tracking: within each majorant cell, take steps as if the cell were at its
+        // majorant density, and accept each step as a real collision in proportion to the actual
+        // density there. Free paths are memoryless, so we can start afresh in every cell.
+        let ray_length = ray.direction.length();
+        let mut collision = None;
+        self.walk_majorants(ray, t0, t1, |start, end, majorant| {
+            if majorant <= 0.0 {
+                return true;
+            }
+            let mut t = start;
+            loop {
+                t -= (1.0 - fastrand::f64()).ln() / (majorant * ray_length);
+                if t >= end {
+                    return true;
+                }
+                if fastrand::f64() * majorant < self.density_at(&ray.at(t)) {
+                    collision = Some(t);
+                    return false;
+                }
+            }
+        });
+
+        match collision {
+            Some(t) => {
+                medium_hit(ray, t, &self.phase_function, rec);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn bounding_box(&self, _time0: f64, _time1: f64, output_box: &mut Aabb) -> bool {
+        *output_box = self.bounds.clone();
+        true
+    }
+
+    /// Estimates the fraction of light passing straight through the medium between `t_min` and
+    /// `t_max`, using ratio tracking.
+    fn transmittance(&self, ray: &Ray, t_min: f64, t_max: f64) -> f64 {
+        let (t0, t1) = match self.bounds.intersect(ray, t_min, t_max) {
+            Some(interval) => interval,
+            None => return 1.0,
+        };
+
+        let ray_length = ray.direction.length();
+        let mut transmittance = 1.0;
+        self.walk_majorants(ray, t0, t1, |start, end, majorant| {
+            if majorant <= 0.0 {
+                return true;
+            }
+            let mut t = start;
+            loop {
+                t -= (1.0 - fastrand::f64()).ln() / (majorant * ray_length);
+                if t >= end {
+                    return true;
+                }
+                transmittance *= 1.0 - self.density_at(&ray.at(t)) / majorant;
+            }
+        });
+        transmittance
+    }
+}
+
+impl Fog {
+    pub fn new(density: f64, falloff: f64, base_height: f64, albedo: Vec3, g: f64) -> Fog {
+        Fog {
+            density,
+            falloff,
+            base_height,
+            albedo,
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+
+    /// Samples where along the ray light first scatters off the fog. Returns None if it gets to
+    /// `t_max` without scattering.
+    pub fn sample_distance(&self, ray: &Ray, t_max: f64) -> Option<f64> {
+        if self.density <= 0.0 {
+            return None;
+        }
+
+        // The optical depth along the ray is the integral of the density, which for exponential
+        // height fog has a closed form that we can invert.
+        let ray_length = ray.direction.length();
+        let sigma = self.density * (-self.falloff * (ray.origin.y - self.base_height)).exp() * ray_length;
+        let a = self.falloff * ray.direction.y;
+        let depth = -(1.0 - fastrand::f64()).ln();
+
+        let t = if a.abs() < 1e-9 {
+            depth / sigma
+        } else {
+            let x = 1.0 - depth * a / sigma;
+            if x <= 0.0 {
+                return None; // The ray climbs out of the fog first.
+            }
+            -x.ln() / a
+        };
+
+        if t < t_max {
+            Some(t)
+        } else {
+            None
+        }
+    }
+}
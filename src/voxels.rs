@@ -0,0 +1,461 @@
+//! Dense voxel grids of density, read from and written to files.
+//!
+//! Two formats can be read. The first is our own `rtvol` format, which is simple enough to write
+//! from any simulation exporter. A file starts with a few lines of ASCII header, where lines
+//! starting with `#` are comments:
+//!
+//! ```text
+//! rtvol binary 1
+//! resolution 64 48 64
+//! bounds -1 0 -1 1 1.5 1
+//! brick 8
+//! data
+//! ```
+//!
+//! The first line names the encoding of the payload, `ascii` or `binary`, then the format
+//! version. `resolution` gives the number of voxels along x, y and z, and `bounds` the corners of
+//! the box the grid fills, minimum first. The optional `brick` line splits the grid into cubes
+//! of that many voxels a side, so that empty space need not be stored. Everything after the
+//! `data` line is the payload:
+//!
+//! - Without bricks, the densities of all the voxels, with x varying fastest, then y, then z.
+//! - With bricks, the bricks in the same order as voxels. Each brick starts with a flag: `0` for
+//!   a brick that is empty, with nothing following it, or `1` for a brick whose densities follow,
+//!   again with x varying fastest. Bricks hanging off the edge of the grid are stored whole and
+//!   the voxels outside it ignored.
+//!
+//! In an ASCII payload the flags and densities are whitespace-separated numbers. In a binary
+//! payload the flags are single bytes and the densities little-endian 32-bit floats.
+//!
+//! The second is the binary grid format written by Mitsuba and many fluid simulators that export
+//! to it, usually with a `.vol` extension. Only the first channel of multi-channel grids is used.
+
+use std::fs;
+
+use crate::{Aabb, Vec3};
+
+// The most voxels a grid can have, as many as one 512 voxels a side. Bricked files can describe
+// far bigger grids than they take up, so the size of the file alone doesn't limit them.
+const MAX_VOXELS: usize = 1 << 27;
+
+/// A box filled with a regular grid of densities.
+pub struct VoxelGrid {
+    pub bounds: Aabb,
+    pub resolution: (usize, usize, usize),
+    pub density: Vec<f64>, // Indexed by x first, then y, then z.
+}
+
+impl VoxelGrid {
+    pub fn new(bounds: Aabb, resolution: (usize, usize, usize), density: Vec<f64>) -> VoxelGrid {
+        VoxelGrid {
+            bounds,
+            resolution,
+            density,
+        }
+    }
+
+    /// Loads a grid, working out the format from the start of the file.
+    pub fn load(path: &str) -> Result<VoxelGrid, String> {
+        let bytes = fs::read(path).map_err(|e| format!("couldn't read volume {}: {}", path, e))?;
+        VoxelGrid::parse(&bytes).map_err(|e| format!("couldn't load volume {}: {}", path, e))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<VoxelGrid, String> {
+        if bytes.starts_with(b"VOL") {
+            parse_mitsuba(bytes)
+        } else if bytes.starts_with(b"rtvol") {
+            parse_rtvol(bytes)
+        } else {
+            Err(String::from("not an rtvol or Mitsuba grid volume"))
+        }
+    }
+
+    /// Writes the grid in binary `rtvol` format, splitting it into bricks of `brick` voxels a side
+    /// if given.
+    pub fn save(&self, path: &str, brick: Option<usize>) -> Result<(), String> {
+        fs::write(path, self.to_rtvol(brick)).map_err(|e| format!("couldn't write volume {}: {}", path, e))
+    }
+
+    pub fn to_rtvol(&self, brick: Option<usize>) -> Vec<u8> {
+        let (nx, ny, nz) = self.resolution;
+        let (min, max) = (&self.bounds.minimum, &self.bounds.maximum);
+
+        let mut header = String::from("rtvol binary 1\n");
+        header.push_str(&format!("resolution {} {} {}\n", nx, ny, nz));
+        header.push_str(&format!("bounds {} {} {} {} {} {}\n", min.x, min.y, min.z, max.x, max.y, max.z));
+        if let Some(size) = brick {
+            header.push_str(&format!("brick {}\n", size));
+        }
+        header.push_str("data\n");
+
+        let mut out = header.into_bytes();
+        match brick {
+            None => {
+                for d in &self.density {
+                    out.extend_from_slice(&(*d as f32).to_le_bytes());
+                }
+            }
+            Some(size) => {
+                for voxels in bricks(self.resolution, size) {
+                    let values: Vec<f64> = voxels.map(|v| v.map_or(0.0, |i| self.density[i])).collect();
+                    if values.iter().all(|d| *d == 0.0) {
+                        out.push(0);
+                    } else {
+                        out.push(1);
+                        for d in values {
+                            out.extend_from_slice(&(d as f32).to_le_bytes());
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Goes through the bricks of the grid in file order, giving the index of each voxel in a brick or
+/// None where the brick hangs off the edge of the grid. Nothing is listed up front, as a file's
+/// bricks can be much bigger than its grid.
+fn bricks(resolution: (usize, usize, usize),
+          size: usize
+) -> impl Iterator<Item = impl Iterator<Item = Option<usize>>> {
+    let (nx, ny, nz) = resolution;
+    let count = move |n: usize| n.div_ceil(size);
+    let span = move |b: usize| b * size..(b + 1) * size;
+
+    (0..count(nz)).flat_map(move |bz| {
+        (0..count(ny)).flat_map(move |by| {
+            (0..count(nx)).map(move |bx| {
+                span(bz).flat_map(move |z| {
+                    span(by).flat_map(move |y| {
+                        span(bx).map(move |x| (x < nx && y < ny && z < nz).then(|| (z * ny + y) * nx + x))
+                    })
+                })
+            })
+        })
+    })
+}
+
+/// Reads values from an `rtvol` payload in either encoding.
+enum Payload<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary(&'a [u8]),
+}
+
+impl<'a> Payload<'a> {
+    fn flag(&mut self) -> Result<bool, String> {
+        match self {
+            Payload::Ascii(tokens) => match tokens.next() {
+                Some("0") => Ok(false),
+                Some("1") => Ok(true),
+                Some(other) => Err(format!("bad brick flag {}", other)),
+                None => Err(String::from("data ends early")),
+            },
+            Payload::Binary(bytes) => {
+                let (first, rest) = bytes.split_first().ok_or("data ends early")?;
+                *bytes = rest;
+                Ok(*first != 0)
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<f64, String> {
+        match self {
+            Payload::Ascii(tokens) => {
+                let token = tokens.next().ok_or("data ends early")?;
+                token.parse::<f64>().map_err(|_| format!("bad density {}", token))
+            }
+            Payload::Binary(bytes) => {
+                if bytes.len() < 4 {
+                    return Err(String::from("data ends early"));
+                }
+                let (value, rest) = bytes.split_at(4);
+                *bytes = rest;
+                Ok(f32::from_le_bytes([value[0], value[1], value[2], value[3]]) as f64)
+            }
+        }
+    }
+}
+
+fn parse_rtvol(bytes: &[u8]) -> Result<VoxelGrid, String> {
+    let mut binary = None;
+    let mut resolution = None;
+    let mut bounds = None;
+    let mut brick = None;
+
+    // Read the header a line at a time, up to the start of the payload.
+    let mut rest = bytes;
+    loop {
+        let end = rest.iter().position(|b| *b == b'\n').ok_or("header has no data line")?;
+        let line = std::str::from_utf8(&rest[..end]).map_err(|_| "header is not text")?.trim();
+        rest = &rest[end + 1..];
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let values = |count: usize| -> Result<&[&str], String> {
+            if words.len() != count + 1 {
+                return Err(format!("expected {} values in '{}'", count, line));
+            }
+            Ok(&words[1..])
+        };
+        let numbers = |count: usize| -> Result<Vec<f64>, String> {
+            values(count)?.iter().map(|w| w.parse::<f64>().map_err(|_| format!("bad number in '{}'", line))).collect()
+        };
+        let sizes = |count: usize| -> Result<Vec<usize>, String> {
+            values(count)?.iter().map(|w| w.parse::<usize>().map_err(|_| format!("bad size in '{}'", line))).collect()
+        };
+
+        match words.first() {
+            None => continue,
+            Some(w) if w.starts_with('#') => continue,
+            Some(&"rtvol") => {
+                binary = match words.get(1) {
+                    Some(&"ascii") => Some(false),
+                    Some(&"binary") => Some(true),
+                    _ => return Err(format!("unknown encoding in '{}'", line)),
+                };
+                if words.get(2) != Some(&"1") {
+                    return Err(format!("unsupported version in '{}'", line));
+                }
+            }
+            Some(&"resolution") => {
+                let n = sizes(3)?;
+                resolution = Some((n[0], n[1], n[2]));
+            }
+            Some(&"bounds") => {
+                let n = numbers(6)?;
+                bounds = Some(Aabb::new(Vec3::new(n[0], n[1], n[2]), Vec3::new(n[3], n[4], n[5])));
+            }
+            Some(&"brick") => brick = Some(sizes(1)?[0]),
+            Some(&"data") => break,
+            Some(other) => return Err(format!("unknown header line {}", other)),
+        }
+    }
+
+    let binary = binary.ok_or("missing rtvol line")?;
+    let resolution = resolution.ok_or("missing resolution")?;
+    let bounds = bounds.ok_or("missing bounds")?;
+    let (nx, ny, nz) = resolution;
+    if nx == 0 || ny == 0 || nz == 0 || brick == Some(0) {
+        return Err(String::from("grid has no voxels"));
+    }
+    let count = voxel_count(nx, ny, nz)?;
+    // Without bricks, every voxel takes at least four bytes, or in ASCII a digit and a space.
+    let smallest = if binary { 4 } else { 2 };
+    if brick.is_none() && count > (rest.len() + 1) / smallest {
+        return Err(String::from("data ends early"));
+    }
+
+    let mut payload = if binary {
+        Payload::Binary(rest)
+    } else {
+        Payload::Ascii(std::str::from_utf8(rest).map_err(|_| "ASCII data is not text")?.split_ascii_whitespace())
+    };
+
+    let mut density = vec![0.0; count];
+    match brick {
+        None => {
+            for d in density.iter_mut() {
+                *d = payload.value()?;
+            }
+        }
+        Some(size) => {
+            for voxels in bricks(resolution, size) {
+                if !payload.flag()? {
+                    continue;
+                }
+                for voxel in voxels {
+                    let value = payload.value()?;
+                    if let Some(i) = voxel {
+                        density[i] = value;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(VoxelGrid::new(bounds, resolution, density))
+}
+
+fn parse_mitsuba(bytes: &[u8]) -> Result<VoxelGrid, String> {
+    let read_i32 = |at: usize| -> Result<i32, String> {
+        bytes.get(at..at + 4)
+            .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| String::from("header ends early"))
+    };
+    let read_f32 = |at: usize| -> Result<f32, String> {
+        bytes.get(at..at + 4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| String::from("data ends early"))
+    };
+
+    if bytes.get(3) != Some(&3) {
+        return Err(String::from("only version 3 of the Mitsuba format is supported"));
+    }
+
+    let encoding = read_i32(4)?;
+    let size = match encoding {
+        1 => 4,
+        2 => 2,
+        3 => 1,
+        _ => return Err(format!("unsupported encoding {}", encoding)),
+    };
+    let [nx, ny, nz, channels] = [read_i32(8)?, read_i32(12)?, read_i32(16)?, read_i32(20)?];
+    let mut b = [0.0; 6];
+    for (i, v) in b.iter_mut().enumerate() {
+        *v = read_f32(24 + i * 4)? as f64;
+    }
+    if nx <= 0 || ny <= 0 || nz <= 0 || channels <= 0 {
+        return Err(String::from("grid has no voxels"));
+    }
+    let (nx, ny, nz, channels) = (nx as usize, ny as usize, nz as usize, channels as usize);
+
+    let data = 48;
+    let count = voxel_count(nx, ny, nz)?;
+    let length = count.checked_mul(channels).and_then(|values| values.checked_mul(size));
+    if length.is_none_or(|length| length > bytes.len() - data) {
+        return Err(String::from("data ends early"));
+    }
+
+    let mut density = Vec::with_capacity(count);
+    for i in 0..count {
+        let at = data + i * channels * size;
+        let value = match encoding {
+            1 => read_f32(at)? as f64,
+            2 => half_to_f64(u16::from_le_bytes([bytes[at], bytes[at + 1]])),
+            _ => bytes[at] as f64 / 255.0,
+        };
+        density.push(value);
+    }
+
+    let bounds = Aabb::new(Vec3::new(b[0], b[1], b[2]), Vec3::new(b[3], b[4], b[5]));
+    Ok(VoxelGrid::new(bounds, (nx, ny, nz), density))
+}
+
+/// How many voxels a grid of the given resolution has, if it isn't too many to hold.
+fn voxel_count(nx: usize, ny: usize, nz: usize) -> Result<usize, String> {
+    nx.checked_mul(ny)
+        .and_then(|n| n.checked_mul(nz))
+        .filter(|n| *n <= MAX_VOXELS)
+        .ok_or_else(|| format!("a grid of {}×{}×{} voxels is too big", nx, ny, nz))
+}
+
+/// Widens an IEEE 754 half precision float.
+fn half_to_f64(h: u16) -> f64 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((h >> 10) & 0x1f) as i32;
+    let mantissa = (h & 0x3ff) as f64;
+    sign * match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 => if mantissa == 0.0 { f64::INFINITY } else { f64::NAN },
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(exponent - 15),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid() -> VoxelGrid {
+        // Empty at one end, so some of its bricks are too.
+        let density = (0..30).map(|i| if i % 5 < 2 { 0.0 } else { i as f64 / 4.0 }).collect();
+        VoxelGrid::new(Aabb::new(Vec3::new(-1.0, 0.0, -2.0), Vec3::new(1.0, 1.5, 2.0)), (5, 3, 2), density)
+    }
+
+    fn assert_same(parsed: &VoxelGrid, expected: &VoxelGrid) {
+        let corners = |g: &VoxelGrid| [&g.bounds.minimum, &g.bounds.maximum].map(|v| [v.x, v.y, v.z]);
+        assert_eq!(corners(parsed), corners(expected));
+        assert_eq!(parsed.resolution, expected.resolution);
+        assert_eq!(parsed.density, expected.density);
+    }
+
+    fn mitsuba(encoding: i32, resolution: [i32; 3], channels: i32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"VOL\x03".to_vec();
+        for v in [encoding, resolution[0], resolution[1], resolution[2], channels] {
+            bytes.extend(v.to_le_bytes());
+        }
+        for v in [-1.0f32, 0.0, -2.0, 1.0, 1.5, 2.0] {
+            bytes.extend(v.to_le_bytes());
+        }
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn rtvol_ascii() {
+        let text = "rtvol ascii 1\n# a comment\nresolution 2 1 1\nbounds 0 0 0 2 1 1\ndata\n0.5 1e-1\n";
+        let grid = VoxelGrid::parse(text.as_bytes()).unwrap();
+        assert_eq!((grid.resolution, grid.density), ((2, 1, 1), vec![0.5, 0.1]));
+
+        // The first brick covers the first two voxels and hangs off the grid, and the second is
+        // empty.
+        let text = "rtvol ascii 1\nresolution 3 1 1\nbounds 0 0 0 3 1 1\nbrick 2\ndata\n1 1 2 0 0 0 0 0 0 0\n";
+        assert_eq!(VoxelGrid::parse(text.as_bytes()).unwrap().density, vec![1.0, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn rtvol_binary() {
+        let grid = grid();
+        assert_same(&VoxelGrid::parse(&grid.to_rtvol(None)).unwrap(), &grid);
+    }
+
+    #[test]
+    fn rtvol_bricked() {
+        let grid = grid();
+        for size in [1, 2, 4, 100] {
+            assert_same(&VoxelGrid::parse(&grid.to_rtvol(Some(size))).unwrap(), &grid);
+        }
+    }
+
+    #[test]
+    fn rtvol_malformed() {
+        let parse = |header: &str, data: &[u8]| {
+            let mut bytes = format!("rtvol binary 1\nbounds 0 0 0 1 1 1\n{}data\n", header).into_bytes();
+            bytes.extend(data);
+            VoxelGrid::parse(&bytes)
+        };
+        assert!(parse("resolution 1 1 1\n", &[0; 4]).is_ok());
+        assert!(parse("resolution 1 1 1\n", &[0; 3]).is_err());
+        assert!(parse("resolution -1 1 1\n", &[0; 4]).is_err());
+        assert!(parse("resolution 0 1 1\n", &[]).is_err());
+        assert!(parse("resolution 1.5 1 1\n", &[0; 8]).is_err());
+        assert!(parse("resolution 100000 100000 100000\n", &[0; 4]).is_err());
+        assert!(parse("resolution 18446744073709551615 2 1\n", &[0; 4]).is_err());
+        assert!(parse("resolution 1000 1000 1000\n", &[0; 4]).is_err());
+        assert!(parse("resolution 1 1 1\nbrick 0\n", &[1, 0, 0, 0, 0]).is_err());
+        // A brick far bigger than the grid, which is fine while it's empty but has to hold all
+        // its voxels otherwise.
+        assert!(parse("resolution 1 1 1\nbrick 1000000\n", &[0]).is_ok());
+        assert!(parse("resolution 1 1 1\nbrick 1000000\n", &[1, 0, 0, 0, 0]).is_err());
+        assert!(parse("resolution 1 1 1\n", &[]).is_err());
+        assert!(VoxelGrid::parse(b"rtvol binary 1\nresolution 1 1 1\n").is_err());
+    }
+
+    #[test]
+    fn mitsuba_floats() {
+        let data: Vec<u8> = [0.25f32, 0.5, 0.75, 1.0].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let grid = VoxelGrid::parse(&mitsuba(1, [2, 2, 1], 1, &data)).unwrap();
+        assert_eq!((grid.resolution, grid.density), ((2, 2, 1), vec![0.25, 0.5, 0.75, 1.0]));
+    }
+
+    #[test]
+    fn mitsuba_channels() {
+        // Only the first of each voxel's channels counts.
+        let grid = VoxelGrid::parse(&mitsuba(3, [2, 1, 1], 2, &[255, 9, 51, 9])).unwrap();
+        assert_eq!(grid.density, vec![1.0, 0.2]);
+        let grid = VoxelGrid::parse(&mitsuba(2, [1, 1, 1], 1, &[0x00, 0x38])).unwrap();
+        assert_eq!(grid.density, vec![0.5]);
+    }
+
+    #[test]
+    fn mitsuba_malformed() {
+        assert!(VoxelGrid::parse(&mitsuba(1, [1, 1, 1], 1, &[0; 4])).is_ok());
+        assert!(VoxelGrid::parse(&mitsuba(1, [-1, 1, 1], 1, &[])).is_err());
+        assert!(VoxelGrid::parse(&mitsuba(1, [1, 1, 1], -1, &[0; 4])).is_err());
+        assert!(VoxelGrid::parse(&mitsuba(1, [0, 1, 1], 1, &[])).is_err());
+        assert!(VoxelGrid::parse(&mitsuba(1, [65536, 65536, 65536], 1, &[0; 4])).is_err());
+        assert!(VoxelGrid::parse(&mitsuba(1, [100, 100, 100], i32::MAX, &[0; 4])).is_err());
+        assert!(VoxelGrid::parse(&mitsuba(1, [2, 1, 1], 1, &[0; 7])).is_err());
+        assert!(VoxelGrid::parse(&mitsuba(4, [1, 1, 1], 1, &[0; 4])).is_err());
+        assert!(VoxelGrid::parse(&mitsuba(1, [1, 1, 1], 1, &[])[..40]).is_err());
+    }
+}